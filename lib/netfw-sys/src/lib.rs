@@ -204,4 +204,10 @@ extern "system" {
         rgIndices: *const LONG,
         pv: *mut c_void,
     ) -> HRESULT;
+    pub fn SafeArrayCreateVector(vt: VARTYPE, lLbound: LONG, cElements: ULONG) -> *mut SAFEARRAY;
+    pub fn SafeArrayPutElement(
+        psa: *mut SAFEARRAY,
+        rgIndices: *const LONG,
+        pv: *mut c_void,
+    ) -> HRESULT;
 }
@@ -1,7 +1,9 @@
 use crate::{
     variant::VariantType,
+    SafeArrayCreateVector,
     SafeArrayGetElement,
     SafeArrayGetVartype,
+    SafeArrayPutElement,
 };
 /// This is needed by Variant, so this is in sys. Read Variant's docs as to why its in sys.
 use bitflags::bitflags;
@@ -11,7 +13,10 @@ use winapi::{
     shared::{
         ntdef::LONG,
         wtypes::VT_EMPTY,
-        wtypesbase::USHORT,
+        wtypesbase::{
+            ULONG,
+            USHORT,
+        },
     },
     um::{
         oaidl::{
@@ -63,6 +68,27 @@ impl SafeArray {
         SafeArray(ptr)
     }
 
+    /// Make a one-dimensional array of `len` elements of the given type, indexed from 0.
+    pub fn new_vector(var_type: VariantType, len: usize) -> Result<Self, std::io::Error> {
+        let ptr = unsafe { SafeArrayCreateVector(var_type.into(), 0, len as ULONG) };
+
+        if ptr.is_null() {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "failed to allocate a SAFEARRAY",
+            ))
+        } else {
+            Ok(SafeArray(ptr))
+        }
+    }
+
+    /// Give up ownership of the array, which the caller must destroy.
+    pub fn into_raw(self) -> *mut SAFEARRAY {
+        let ptr = self.0;
+        std::mem::forget(self);
+        ptr
+    }
+
     pub fn dimension(&self) -> usize {
         self.get_inner_ref().cDims.into()
     }
@@ -130,6 +156,26 @@ impl SafeArray {
             Ok(el)
         }
     }
+
+    /// Copy an element into the array.
+    ///
+    /// # Safety
+    /// T must be the right type.
+    pub unsafe fn put<T>(&mut self, indexes: &[LONG], el: &T) -> Result<(), std::io::Error> {
+        assert_eq!(
+            indexes.len(),
+            self.dimension(),
+            "The dimension of the array does not match the dimension of the indexes"
+        );
+
+        let ret = SafeArrayPutElement(self.0, indexes.as_ptr(), el as *const T as *mut c_void);
+
+        if FAILED(ret) {
+            Err(std::io::Error::from_raw_os_error(ret))
+        } else {
+            Ok(())
+        }
+    }
 }
 
 impl Drop for SafeArray {
@@ -117,6 +117,28 @@ impl Variant {
         Variant(variant)
     }
 
+    /// Make a variant owning a BSTR, which is freed when the variant is.
+    pub fn from_bstr(bstr: BSTR) -> Self {
+        let mut variant = Variant::new();
+        unsafe {
+            let inner = variant.0.n1.n2_mut();
+            inner.vt = VT_BSTR as u16;
+            *inner.n3.bstrVal_mut() = bstr;
+        }
+        variant
+    }
+
+    /// Make a variant owning an array of variants.
+    pub fn from_variant_array(array: SafeArray) -> Self {
+        let mut variant = Variant::new();
+        unsafe {
+            let inner = variant.0.n1.n2_mut();
+            inner.vt = VT_VARIANT_ARRAY as u16;
+            *inner.n3.parray_mut() = array.into_raw();
+        }
+        variant
+    }
+
     pub fn as_mut_ptr(&mut self) -> *mut VARIANT {
         &mut self.0
     }
@@ -0,0 +1,368 @@
+//! Parsed address lists, like the `LocalAddresses` and `RemoteAddresses` of a rule.
+
+use crate::FirewallRuleData;
+use std::net::{
+    IpAddr,
+    Ipv4Addr,
+    Ipv6Addr,
+};
+
+/// Address keywords Windows accepts in place of addresses, in their canonical spelling.
+const KEYWORDS: [&str; 20] = [
+    "DefaultGateway",
+    "DefaultGateway4",
+    "DefaultGateway6",
+    "DHCP",
+    "DHCP4",
+    "DHCP6",
+    "DNS",
+    "DNS4",
+    "DNS6",
+    "Internet",
+    "Intranet",
+    "IntranetRemoteAccess",
+    "LocalSubnet",
+    "LocalSubnet4",
+    "LocalSubnet6",
+    "PlayToDevice",
+    "RmtIntrAnet",
+    "WINS",
+    "WINS4",
+    "WINS6",
+];
+
+/// An inclusive range of addresses of one family.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum AddressRange {
+    V4(u32, u32),
+    V6(u128, u128),
+}
+
+impl AddressRange {
+    pub fn contains(&self, address: IpAddr) -> bool {
+        match (*self, address) {
+            (AddressRange::V4(start, end), IpAddr::V4(address)) => {
+                (start..=end).contains(&u32::from(address))
+            }
+            (AddressRange::V6(start, end), IpAddr::V6(address)) => {
+                (start..=end).contains(&u128::from(address))
+            }
+            _ => false,
+        }
+    }
+
+    /// Merge with a range that overlaps or touches this one.
+    fn merge(&mut self, next: &AddressRange) -> bool {
+        match (self, next) {
+            (AddressRange::V4(_, end), AddressRange::V4(start, next_end))
+                if *start <= end.saturating_add(1) =>
+            {
+                *end = (*end).max(*next_end);
+                true
+            }
+            (AddressRange::V6(_, end), AddressRange::V6(start, next_end))
+                if *start <= end.saturating_add(1) =>
+            {
+                *end = (*end).max(*next_end);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Formats as a single address, a prefix like `10.0.0.0/8` when the range is one, or `start-end`.
+impl std::fmt::Display for AddressRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            AddressRange::V4(start, end) => {
+                let (start_ip, end_ip) = (Ipv4Addr::from(start), Ipv4Addr::from(end));
+                match prefix_len(u128::from(start), u128::from(end), 32) {
+                    Some(32) => write!(f, "{}", start_ip),
+                    Some(len) => write!(f, "{}/{}", start_ip, len),
+                    None => write!(f, "{}-{}", start_ip, end_ip),
+                }
+            }
+            AddressRange::V6(start, end) => {
+                let (start_ip, end_ip) = (Ipv6Addr::from(start), Ipv6Addr::from(end));
+                match prefix_len(start, end, 128) {
+                    Some(128) => write!(f, "{}", start_ip),
+                    Some(len) => write!(f, "{}/{}", start_ip, len),
+                    None => write!(f, "{}-{}", start_ip, end_ip),
+                }
+            }
+        }
+    }
+}
+
+/// The prefix length if `start..=end` is exactly one aligned block in a `bits` wide address space.
+fn prefix_len(start: u128, end: u128, bits: u32) -> Option<u32> {
+    let host_bits = (end - start).checked_add(1).map_or(128, |size| {
+        if size.is_power_of_two() {
+            size.trailing_zeros()
+        } else {
+            u32::MAX
+        }
+    });
+    if host_bits > bits || (host_bits < 128 && start & ((1 << host_bits) - 1) != 0) {
+        return None;
+    }
+    Some(bits - host_bits)
+}
+
+/// A set of addresses in canonical form.
+///
+/// Ranges are sorted and merged and keywords are sorted, so lists naming the same addresses compare equal however
+/// they were written: `10.0.0.0/255.0.0.0`, `10.0.0.0/8` and `10.0.0.0-10.255.255.255` are all the same list.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct AddressList {
+    any: bool,
+    ranges: Vec<AddressRange>,
+    keywords: Vec<String>,
+}
+
+impl AddressList {
+    /// The list matching every address.
+    pub fn any() -> Self {
+        AddressList {
+            any: true,
+            ranges: Vec::new(),
+            keywords: Vec::new(),
+        }
+    }
+
+    /// Parse a comma separated list of addresses, prefixes, ranges and keywords like `LocalSubnet`.
+    ///
+    /// Prefixes may be written as a length or, for IPv4, a mask. `*` and `Any` stand for every address.
+    pub fn parse(s: &str) -> Result<Self, std::io::Error> {
+        let mut list = AddressList {
+            any: false,
+            ranges: Vec::new(),
+            keywords: Vec::new(),
+        };
+
+        for entry in s
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+        {
+            if entry == "*" || entry.eq_ignore_ascii_case("any") {
+                return Ok(Self::any());
+            }
+
+            if let Some(keyword) = KEYWORDS
+                .iter()
+                .find(|keyword| keyword.eq_ignore_ascii_case(entry))
+            {
+                list.keywords.push(keyword.to_string());
+                continue;
+            }
+
+            list.ranges
+                .push(parse_range(entry).ok_or_else(|| invalid(s))?);
+        }
+
+        if list.ranges.is_empty() && list.keywords.is_empty() {
+            return Ok(Self::any());
+        }
+
+        list.ranges.sort_unstable();
+        let mut merged: Vec<AddressRange> = Vec::with_capacity(list.ranges.len());
+        for range in list.ranges.drain(..) {
+            let absorbed = match merged.last_mut() {
+                Some(last) => last.merge(&range),
+                None => false,
+            };
+            if !absorbed {
+                merged.push(range);
+            }
+        }
+        list.ranges = merged;
+
+        list.keywords.sort_unstable();
+        list.keywords.dedup();
+
+        Ok(list)
+    }
+
+    /// Parse an optional rule field, where unset means every address.
+    pub fn from_field(field: Option<&str>) -> Result<Self, std::io::Error> {
+        field.map_or_else(|| Ok(Self::any()), Self::parse)
+    }
+
+    pub fn is_any(&self) -> bool {
+        self.any
+    }
+
+    /// The address ranges, sorted with IPv4 first and non-overlapping.
+    pub fn ranges(&self) -> &[AddressRange] {
+        &self.ranges
+    }
+
+    pub fn keywords(&self) -> &[String] {
+        &self.keywords
+    }
+
+    /// Whether an address is in the list. Keywords never match, as what they stand for depends on the host.
+    pub fn contains(&self, address: IpAddr) -> bool {
+        self.any || self.ranges.iter().any(|range| range.contains(address))
+    }
+}
+
+/// Formats in canonical form, like `10.0.0.0/8,192.168.1.1,LocalSubnet`, or `Any`.
+impl std::fmt::Display for AddressList {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.any {
+            return f.write_str("Any");
+        }
+
+        let entries: Vec<_> = self
+            .ranges
+            .iter()
+            .map(AddressRange::to_string)
+            .chain(self.keywords.iter().cloned())
+            .collect();
+        f.write_str(&entries.join(","))
+    }
+}
+
+impl std::str::FromStr for AddressList {
+    type Err = std::io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+fn parse_range(entry: &str) -> Option<AddressRange> {
+    if let Some((start, end)) = split_once(entry, '-') {
+        return match (start.parse().ok()?, end.parse().ok()?) {
+            (IpAddr::V4(start), IpAddr::V4(end)) if start <= end => {
+                Some(AddressRange::V4(start.into(), end.into()))
+            }
+            (IpAddr::V6(start), IpAddr::V6(end)) if start <= end => {
+                Some(AddressRange::V6(start.into(), end.into()))
+            }
+            _ => None,
+        };
+    }
+
+    let (address, prefix) = match split_once(entry, '/') {
+        Some((address, prefix)) => (address.parse().ok()?, Some(prefix)),
+        None => (entry.parse().ok()?, None),
+    };
+
+    match address {
+        IpAddr::V4(address) => {
+            let len = match prefix {
+                None => 32,
+                Some(prefix) => match prefix.parse::<Ipv4Addr>() {
+                    Ok(mask) => {
+                        let mask = u32::from(mask);
+                        if mask.leading_ones() + mask.trailing_zeros() != 32 {
+                            return None;
+                        }
+                        mask.leading_ones()
+                    }
+                    Err(_) => prefix.parse().ok().filter(|len| *len <= 32)?,
+                },
+            };
+            let host = u32::MAX.checked_shr(len).unwrap_or(0);
+            let start = u32::from(address) & !host;
+            Some(AddressRange::V4(start, start | host))
+        }
+        IpAddr::V6(address) => {
+            let len = match prefix {
+                None => 128,
+                Some(prefix) => prefix.parse().ok().filter(|len| *len <= 128)?,
+            };
+            let host = u128::MAX.checked_shr(len).unwrap_or(0);
+            let start = u128::from(address) & !host;
+            Some(AddressRange::V6(start, start | host))
+        }
+    }
+}
+
+fn split_once(s: &str, delimiter: char) -> Option<(&str, &str)> {
+    let mut parts = s.splitn(2, delimiter);
+    Some((parts.next()?.trim(), parts.next()?.trim()))
+}
+
+fn invalid(list: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        format!("invalid address list '{}'", list),
+    )
+}
+
+impl FirewallRuleData {
+    pub fn local_address_list(&self) -> Result<AddressList, std::io::Error> {
+        AddressList::from_field(self.local_addresses.as_deref())
+    }
+
+    pub fn remote_address_list(&self) -> Result<AddressList, std::io::Error> {
+        AddressList::from_field(self.remote_addresses.as_deref())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn equivalent_spellings() {
+        let forms = [
+            "10.0.0.0/255.0.0.0",
+            "10.0.0.0/8",
+            "10.1.2.3/8",
+            "10.0.0.0-10.255.255.255",
+            "10.0.0.0-10.127.255.255, 10.128.0.0/9",
+        ];
+        for form in forms.iter() {
+            let list = AddressList::parse(form).unwrap();
+            assert_eq!(list.to_string(), "10.0.0.0/8", "{}", form);
+        }
+    }
+
+    #[test]
+    fn canonical_form() {
+        let list =
+            AddressList::parse("localsubnet, fe80::/64, 192.168.1.1, 192.168.1.2-192.168.1.5, DNS")
+                .unwrap();
+        assert_eq!(
+            list.to_string(),
+            "192.168.1.1-192.168.1.5,fe80::/64,DNS,LocalSubnet"
+        );
+        assert!(list.contains("192.168.1.3".parse().unwrap()));
+        assert!(list.contains("fe80::1".parse().unwrap()));
+        assert!(!list.contains("192.168.1.6".parse().unwrap()));
+    }
+
+    #[test]
+    fn any() {
+        for s in &["*", "Any", ""] {
+            assert!(AddressList::parse(s).unwrap().is_any());
+        }
+        assert_eq!(
+            AddressList::parse("0.0.0.0/0").unwrap().to_string(),
+            "0.0.0.0/0"
+        );
+        assert_eq!(AddressList::parse("::/0").unwrap().to_string(), "::/0");
+    }
+
+    #[test]
+    fn invalid_lists() {
+        for s in &[
+            "10.0.0.0/33",
+            "10.0.0.0/255.0.255.0",
+            "10.0.0.2-10.0.0.1",
+            "10.0.0.1-::1",
+            "example.com",
+        ] {
+            assert_eq!(
+                AddressList::parse(s).unwrap_err().kind(),
+                std::io::ErrorKind::InvalidInput
+            );
+        }
+    }
+}
@@ -0,0 +1,657 @@
+//! Importing rules from PowerShell exports of the `NetSecurity` CIM classes.
+//!
+//! `Get-NetFirewallRule` returns `MSFT_NetFirewallRule` instances that hold only part of a rule: ports, addresses,
+//! the program, the service and the interfaces live in associated filter instances, like `MSFT_NetProtocolPortFilter`,
+//! that share the rule's `InstanceID`. An `Import` collects rules and filters from any number of `ConvertTo-Json` or
+//! `Export-Clixml` files and joins them into owned rule values.
+//!
+//! Enums may be exported as their integers or their names. Values the cmdlets show as `Any` become unset fields, and
+//! the group is kept in the indirect form `FirewallRule` reports, like `@FirewallAPI.dll,-25000`, when the export
+//! has it. Security filters are accepted but not modelled.
+
+use crate::{
+    data::{
+        parse_cmdlet_icmp,
+        parse_cmdlet_port,
+        parse_protocol,
+        CMDLET_INTERFACE_TYPES,
+    },
+    xml,
+    FirewallAction,
+    FirewallProfile,
+    FirewallRuleData,
+    FirewallRuleDirection,
+};
+use std::collections::HashMap;
+
+const RULE_CLASS: &str = "MSFT_NetFirewallRule";
+
+/// The filter classes associated with a rule.
+const FILTER_CLASSES: [&str; 7] = [
+    "MSFT_NetProtocolPortFilter",
+    "MSFT_NetAddressFilter",
+    "MSFT_NetApplicationFilter",
+    "MSFT_NetServiceFilter",
+    "MSFT_NetInterfaceFilter",
+    "MSFT_NetInterfaceTypeFilter",
+    "MSFT_NetNetworkLayerSecurityFilter",
+];
+
+/// A property value, in the few shapes the exports use.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Value {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Str(String),
+    List(Vec<Value>),
+}
+
+impl Value {
+    fn as_str(&self) -> Option<String> {
+        match self {
+            Value::Null => None,
+            Value::Bool(value) => Some(if *value { "True" } else { "False" }.to_string()),
+            Value::Int(value) => Some(value.to_string()),
+            Value::Str(value) => Some(value.clone()),
+            Value::List(values) => {
+                let values: Vec<_> = values.iter().filter_map(Value::as_str).collect();
+                Some(values.join(","))
+            }
+        }
+    }
+
+    /// The entries of a list, or of a comma separated string.
+    fn entries(&self) -> Vec<String> {
+        match self {
+            Value::List(values) => values.iter().flat_map(Value::entries).collect(),
+            value => value
+                .as_str()
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|entry| !entry.is_empty())
+                .map(String::from)
+                .collect(),
+        }
+    }
+}
+
+/// A CIM instance as read from an export.
+#[derive(Debug, Clone, Default)]
+struct Instance {
+    class: Option<String>,
+    properties: Vec<(String, Value)>,
+}
+
+impl Instance {
+    /// The first of the named properties that is set, matched case-insensitively.
+    fn get(&self, names: &[&str]) -> Option<&Value> {
+        names.iter().find_map(|name| {
+            self.properties
+                .iter()
+                .find(|(key, value)| key.eq_ignore_ascii_case(name) && *value != Value::Null)
+                .map(|(_, value)| value)
+        })
+    }
+
+    fn is_rule(&self) -> bool {
+        match self.class.as_deref() {
+            Some(class) => class.eq_ignore_ascii_case(RULE_CLASS),
+            None => self.get(&["Direction"]).is_some(),
+        }
+    }
+
+    fn is_filter(&self) -> bool {
+        match self.class.as_deref() {
+            Some(class) => FILTER_CLASSES
+                .iter()
+                .any(|filter| filter.eq_ignore_ascii_case(class)),
+            None => !self.is_rule(),
+        }
+    }
+
+    fn instance_id(&self) -> Option<String> {
+        self.get(&["InstanceID", "Name", "ID"])
+            .and_then(Value::as_str)
+    }
+}
+
+/// Rules and filters collected from one or more exports.
+#[derive(Debug, Clone, Default)]
+pub struct Import {
+    instances: Vec<Instance>,
+}
+
+impl Import {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the instances in the output of `ConvertTo-Json`, either a single object or an array of them.
+    #[cfg(feature = "serde")]
+    pub fn add_json(&mut self, text: &str) -> Result<(), std::io::Error> {
+        let value: serde_json::Value = serde_json::from_str(text.trim_start_matches('\u{feff}'))
+            .map_err(|error| invalid_data(error.to_string()))?;
+        let objects = match value {
+            serde_json::Value::Array(values) => values,
+            value => vec![value],
+        };
+
+        for object in objects {
+            let object = match object {
+                serde_json::Value::Object(object) => object,
+                _ => return Err(invalid_data("expected a JSON object".to_string())),
+            };
+
+            let class = object.get("CimClass").and_then(|class| match class {
+                serde_json::Value::String(class) => Some(class_name(class)),
+                serde_json::Value::Object(class) => class
+                    .get("CimClassName")
+                    .and_then(serde_json::Value::as_str)
+                    .map(class_name),
+                _ => None,
+            });
+            let properties = object
+                .into_iter()
+                .map(|(key, value)| (key, json_value(value)))
+                .collect();
+            self.instances.push(Instance { class, properties });
+        }
+        Ok(())
+    }
+
+    /// Add the instances in a file written by `Export-Clixml`.
+    pub fn add_clixml(&mut self, text: &str) -> Result<(), std::io::Error> {
+        let root = xml::parse(text)?;
+        if root.local_name() != "Objs" {
+            return Err(invalid_data(format!(
+                "expected a CliXML document, found <{}>",
+                root.name
+            )));
+        }
+
+        let mut reader = CliXmlReader::default();
+        for object in root.elements().filter(|e| e.local_name() == "Obj") {
+            let class = reader
+                .type_names(object)
+                .iter()
+                .find_map(|name| {
+                    name.strip_prefix("Microsoft.Management.Infrastructure.CimInstance#")
+                })
+                .map(class_name);
+
+            let mut properties = Vec::new();
+            for section in object
+                .elements()
+                .filter(|e| e.local_name() == "Props" || e.local_name() == "MS")
+            {
+                for property in section.elements() {
+                    // Values are read even when unused, as later ones may refer back to them.
+                    let value = reader.value(property);
+                    match property.attribute("N") {
+                        Some("__ClassMetadata") | None => {}
+                        Some(name) => properties.push((name.to_string(), value)),
+                    }
+                }
+            }
+            self.instances.push(Instance { class, properties });
+        }
+        Ok(())
+    }
+
+    /// Join the rules with their filters.
+    ///
+    /// Rules keep the order they were added in; filters whose rule is missing are ignored.
+    pub fn rules(&self) -> Result<Vec<FirewallRuleData>, std::io::Error> {
+        let mut filters: HashMap<String, Vec<&Instance>> = HashMap::new();
+        for instance in self.instances.iter().filter(|i| i.is_filter()) {
+            if let Some(id) = instance.instance_id() {
+                filters.entry(id).or_default().push(instance);
+            }
+        }
+
+        let mut rules = Vec::new();
+        for instance in self.instances.iter().filter(|i| i.is_rule()) {
+            let mut rule = rule(instance)?;
+            // Flattened exports put filter properties on the rule itself.
+            apply_filter(&mut rule, instance)?;
+            let id = instance.instance_id();
+            for filter in id.and_then(|id| filters.get(&id)).into_iter().flatten() {
+                apply_filter(&mut rule, filter)?;
+            }
+            rules.push(rule);
+        }
+        Ok(rules)
+    }
+}
+
+/// Import the rules in the output of `ConvertTo-Json`.
+#[cfg(feature = "serde")]
+pub fn import_json(text: &str) -> Result<Vec<FirewallRuleData>, std::io::Error> {
+    let mut import = Import::new();
+    import.add_json(text)?;
+    import.rules()
+}
+
+/// Import the rules in a file written by `Export-Clixml`.
+pub fn import_clixml(text: &str) -> Result<Vec<FirewallRuleData>, std::io::Error> {
+    let mut import = Import::new();
+    import.add_clixml(text)?;
+    import.rules()
+}
+
+/// The class name out of a qualified one, like `root/standardcimv2:MSFT_NetFirewallRule`.
+fn class_name(qualified: &str) -> String {
+    qualified
+        .rsplit(&['/', ':', '#'][..])
+        .next()
+        .unwrap_or(qualified)
+        .to_string()
+}
+
+#[cfg(feature = "serde")]
+fn json_value(value: serde_json::Value) -> Value {
+    match value {
+        serde_json::Value::Null | serde_json::Value::Object(_) => Value::Null,
+        serde_json::Value::Bool(value) => Value::Bool(value),
+        serde_json::Value::Number(value) => value.as_i64().map_or(Value::Null, Value::Int),
+        serde_json::Value::String(value) => Value::Str(value),
+        serde_json::Value::Array(values) => {
+            Value::List(values.into_iter().map(json_value).collect())
+        }
+    }
+}
+
+/// Reads CliXML values, resolving references to type names and objects seen earlier in the document.
+#[derive(Default)]
+struct CliXmlReader {
+    type_names: HashMap<String, Vec<String>>,
+    objects: HashMap<String, Value>,
+}
+
+impl CliXmlReader {
+    fn type_names(&mut self, object: &xml::Element) -> Vec<String> {
+        if let Some(names) = object.child("TN") {
+            let types: Vec<_> = names
+                .elements()
+                .filter(|e| e.local_name() == "T")
+                .map(|e| e.text())
+                .collect();
+            if let Some(id) = names.attribute("RefId") {
+                self.type_names.insert(id.to_string(), types.clone());
+            }
+            return types;
+        }
+
+        object
+            .child("TNRef")
+            .and_then(|reference| reference.attribute("RefId"))
+            .and_then(|id| self.type_names.get(id))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn value(&mut self, element: &xml::Element) -> Value {
+        match element.local_name() {
+            "S" | "URI" | "Version" | "G" | "C" => Value::Str(decode(&element.text())),
+            "B" => Value::Bool(element.text().trim() == "true"),
+            "By" | "SB" | "I16" | "U16" | "I32" | "U32" | "I64" | "U64" => element
+                .text()
+                .trim()
+                .parse()
+                .map_or(Value::Null, Value::Int),
+            "Ref" => element
+                .attribute("RefId")
+                .and_then(|id| self.objects.get(id))
+                .cloned()
+                .unwrap_or(Value::Null),
+            "Obj" => {
+                self.type_names(element);
+                let value = self.object_value(element);
+                if let Some(id) = element.attribute("RefId") {
+                    self.objects.insert(id.to_string(), value.clone());
+                }
+                value
+            }
+            _ => Value::Null,
+        }
+    }
+
+    /// The value of a nested object: a collection, or an enum's number, or failing those its string form.
+    fn object_value(&mut self, object: &xml::Element) -> Value {
+        let mut string = None;
+        for child in object.elements() {
+            match child.local_name() {
+                "LST" | "IE" | "STK" | "QUE" => {
+                    return Value::List(child.elements().map(|item| self.value(item)).collect())
+                }
+                "ToString" => string = Some(child.text()),
+                "TN" | "TNRef" | "Props" | "MS" | "DCT" => {}
+                _ => match self.value(child) {
+                    Value::Null => {}
+                    value => return value,
+                },
+            }
+        }
+        string.map_or(Value::Null, |string| Value::Str(decode(&string)))
+    }
+}
+
+/// Undo the `_xHHHH_` escapes the serializer uses for characters XML cannot hold.
+fn decode(s: &str) -> String {
+    let mut decoded = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(index) = rest.find("_x") {
+        decoded.push_str(&rest[..index]);
+        rest = &rest[index..];
+        let escaped = rest
+            .get(2..6)
+            .filter(|_| rest.get(6..7) == Some("_"))
+            .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+            .and_then(std::char::from_u32);
+        match escaped {
+            Some(c) => {
+                decoded.push(c);
+                rest = &rest[7..];
+            }
+            None => {
+                decoded.push_str("_x");
+                rest = &rest[2..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+/// The rule-level properties of a `MSFT_NetFirewallRule` instance.
+fn rule(instance: &Instance) -> Result<FirewallRuleData, std::io::Error> {
+    let text = |names: &[&str]| instance.get(names).and_then(Value::as_str);
+    let id = text(&["Name", "InstanceID", "ID"]);
+    let name = text(&["DisplayName", "ElementName"])
+        .or_else(|| id.clone())
+        .ok_or_else(|| invalid_data("a rule has neither a display name nor an ID".to_string()))?;
+
+    let mut rule = FirewallRuleData {
+        id,
+        description: text(&["Description"]).filter(|s| !s.is_empty()),
+        grouping: text(&["Group", "RuleGroup", "DisplayGroup"]).filter(|s| !s.is_empty()),
+        ..FirewallRuleData::new(name)
+    };
+    let name = rule.name.clone();
+    let error = |what: &str, value: &Value| {
+        invalid_data(format!("rule '{}': invalid {} {:?}", name, what, value))
+    };
+
+    if let Some(value) = instance.get(&["Direction"]) {
+        rule.direction = match value {
+            Value::Int(1) => FirewallRuleDirection::In,
+            Value::Int(2) => FirewallRuleDirection::Out,
+            Value::Str(s) => s.parse().map_err(|_| error("direction", value))?,
+            _ => return Err(error("direction", value)),
+        };
+    }
+    if let Some(value) = instance.get(&["Action"]) {
+        rule.action = match value {
+            // AllowBypass allows authenticated traffic that would otherwise be blocked.
+            Value::Int(2) | Value::Int(3) => FirewallAction::Allow,
+            Value::Int(4) => FirewallAction::Block,
+            Value::Str(s) if s.eq_ignore_ascii_case("AllowBypass") => FirewallAction::Allow,
+            Value::Str(s) => s.parse().map_err(|_| error("action", value))?,
+            _ => return Err(error("action", value)),
+        };
+    }
+    if let Some(value) = instance.get(&["Enabled"]) {
+        rule.enabled = match value {
+            Value::Bool(enabled) => *enabled,
+            Value::Int(1) => true,
+            Value::Int(2) => false,
+            Value::Str(s) if s.eq_ignore_ascii_case("true") => true,
+            Value::Str(s) if s.eq_ignore_ascii_case("false") => false,
+            _ => return Err(error("enabled state", value)),
+        };
+    }
+    if let Some(value) = instance.get(&["Profile", "Profiles"]) {
+        let profiles = match value {
+            Value::Int(bits) => FirewallProfile::from_bits_truncate(*bits as u32),
+            Value::Str(s) => s.parse().map_err(|_| error("profile", value))?,
+            _ => return Err(error("profile", value)),
+        };
+        // The cmdlets say `Any` with 0 or every profile where `FirewallRule` reports `ALL`.
+        rule.profiles = if profiles.is_empty()
+            || FirewallProfile::SINGLE
+                .iter()
+                .all(|p| profiles.contains(*p))
+        {
+            FirewallProfile::ALL
+        } else {
+            profiles
+        };
+    }
+    if let Some(value) = instance.get(&["EdgeTraversalPolicy"]) {
+        // DeferToUser and DeferToApp leave `EdgeTraversal` off, as `FirewallRule` reports it.
+        rule.edge_traversal = match value {
+            Value::Int(policy) => *policy == 1,
+            Value::Str(s) => s.eq_ignore_ascii_case("allow"),
+            _ => return Err(error("edge traversal policy", value)),
+        };
+    }
+
+    Ok(rule)
+}
+
+/// Copy the properties of any filter class onto a rule.
+fn apply_filter(rule: &mut FirewallRuleData, filter: &Instance) -> Result<(), std::io::Error> {
+    if let Some(value) = filter.get(&["Protocol"]) {
+        rule.protocol = value
+            .as_str()
+            .as_deref()
+            .and_then(parse_protocol)
+            .ok_or_else(|| {
+                invalid_data(format!(
+                    "rule '{}': invalid protocol {:?}",
+                    rule.name, value
+                ))
+            })?;
+    }
+    let list = |names: &[&str], entry: fn(String) -> String| {
+        let entries = filter.get(names)?.entries();
+        if entries.is_empty() || entries.iter().any(|e| e.eq_ignore_ascii_case("any")) {
+            return Some(None);
+        }
+        Some(Some(
+            entries.into_iter().map(entry).collect::<Vec<_>>().join(","),
+        ))
+    };
+
+    if let Some(ports) = list(&["LocalPort"], port_entry) {
+        rule.local_ports = ports;
+    }
+    if let Some(ports) = list(&["RemotePort"], port_entry) {
+        rule.remote_ports = ports;
+    }
+    if let Some(types) = list(&["IcmpType"], icmp_entry) {
+        rule.icmp_types_and_codes = types;
+    }
+    if let Some(addresses) = list(&["LocalAddress"], String::from) {
+        rule.local_addresses = addresses;
+    }
+    if let Some(addresses) = list(&["RemoteAddress"], String::from) {
+        rule.remote_addresses = addresses;
+    }
+    if let Some(program) = list(&["Program"], String::from) {
+        rule.application_name = program;
+    }
+    if let Some(service) = list(&["Service"], String::from) {
+        rule.service_name = service;
+    }
+    if let Some(interfaces) = list(&["InterfaceAlias"], String::from) {
+        rule.interfaces =
+            interfaces.map(|interfaces| interfaces.split(',').map(String::from).collect());
+    }
+    if let Some(value) = filter.get(&["InterfaceType"]) {
+        rule.interface_types = Some(interface_types(value));
+    }
+
+    Ok(())
+}
+
+fn port_entry(entry: String) -> String {
+    parse_cmdlet_port(&entry).to_string()
+}
+
+fn icmp_entry(entry: String) -> String {
+    parse_cmdlet_icmp(&entry)
+}
+
+/// Translate the cmdlets' interface types, by flag or by name, into `INetFwRule`'s, like `Lan,Wireless`.
+fn interface_types(value: &Value) -> String {
+    let types: Vec<_> = match value {
+        Value::Int(bits) => CMDLET_INTERFACE_TYPES
+            .iter()
+            .filter(|(bit, _, _)| bits & bit != 0)
+            .map(|(_, _, name)| *name)
+            .collect(),
+        value => value
+            .entries()
+            .iter()
+            .filter_map(|entry| {
+                CMDLET_INTERFACE_TYPES
+                    .iter()
+                    .find(|(_, cmdlet, _)| cmdlet.eq_ignore_ascii_case(entry))
+                    .map(|(_, _, name)| *name)
+            })
+            .collect(),
+    };
+    if types.is_empty() {
+        return "All".to_string();
+    }
+    types.join(",")
+}
+
+fn invalid_data(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn imports_clixml() {
+        let rules = import_clixml(include_str!("../testdata/get-netfirewallrule.clixml")).unwrap();
+        assert_eq!(
+            rules,
+            vec![
+                FirewallRuleData {
+                    id: Some("RemoteDesktop-UserMode-In-TCP".into()),
+                    description: Some(
+                        "Inbound rule for the Remote Desktop service to allow RDP traffic. [TCP 3389]"
+                            .into()
+                    ),
+                    protocol: 6,
+                    local_ports: Some("3389".into()),
+                    grouping: Some("@FirewallAPI.dll,-28752".into()),
+                    profiles: FirewallProfile::PRIVATE | FirewallProfile::PUBLIC,
+                    ..FirewallRuleData::new("@FirewallAPI.dll,-28775")
+                },
+                FirewallRuleData {
+                    id: Some("build-agent".into()),
+                    protocol: 1,
+                    icmp_types_and_codes: Some("8:*,0:0".into()),
+                    direction: FirewallRuleDirection::Out,
+                    action: FirewallAction::Block,
+                    ..FirewallRuleData::new("Build\tagent_x0041_")
+                },
+            ]
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn imports_json() {
+        let mut import = Import::new();
+        import
+            .add_json(include_str!("../testdata/get-netfirewallrule.json"))
+            .unwrap();
+        import
+            .add_json(include_str!("../testdata/get-netfirewallportfilter.json"))
+            .unwrap();
+
+        assert_eq!(
+            import.rules().unwrap(),
+            vec![
+                FirewallRuleData {
+                    id: Some("CoreNet-DNS-Out-UDP".into()),
+                    description: Some(
+                        "Outbound rule to allow DNS requests. DNS responses based on requests that matched this \
+                         rule will be permitted regardless of source address.  This behavior is classified as \
+                         loose source mapping. [LSM] [UDP 53]"
+                            .into()
+                    ),
+                    protocol: 17,
+                    remote_ports: Some("53".into()),
+                    direction: FirewallRuleDirection::Out,
+                    grouping: Some("@FirewallAPI.dll,-25000".into()),
+                    ..FirewallRuleData::new("Core Networking - DNS (UDP-Out)")
+                },
+                FirewallRuleData {
+                    id: Some("{6E2C1B4A-8F0D-4C5B-9E7A-2B3C4D5E6F70}".into()),
+                    application_name: Some(r"C:\Program Files\Web\web.exe".into()),
+                    protocol: 6,
+                    local_ports: Some("80,443,RPC-EPMap".into()),
+                    remote_addresses: Some("LocalSubnet,10.0.0.0/255.0.0.0".into()),
+                    interface_types: Some("Lan".into()),
+                    enabled: false,
+                    profiles: FirewallProfile::DOMAIN | FirewallProfile::PRIVATE,
+                    edge_traversal: true,
+                    action: FirewallAction::Block,
+                    ..FirewallRuleData::new("Bob's \"web\" server")
+                },
+            ]
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn flattened_json() {
+        let rules = import_json(
+            r#"{ "DisplayName": "a", "Direction": "Inbound", "Action": "Block", "Enabled": "True",
+                 "Profile": "Domain, Public", "Protocol": "TCP", "LocalPort": "80, 8080",
+                 "InterfaceType": "Wired, Wireless" }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            rules,
+            vec![FirewallRuleData {
+                protocol: 6,
+                local_ports: Some("80,8080".into()),
+                interface_types: Some("Lan,Wireless".into()),
+                profiles: FirewallProfile::DOMAIN | FirewallProfile::PUBLIC,
+                action: FirewallAction::Block,
+                ..FirewallRuleData::new("a")
+            }]
+        );
+
+        for text in &[
+            r#"{ "DisplayName": "a", "Direction": 3 }"#,
+            r#"{ "DisplayName": "a", "Direction": 1, "Action": "Sometimes" }"#,
+            r#"{ "Direction": 1 }"#,
+            "[1]",
+        ] {
+            assert_eq!(
+                import_json(text).unwrap_err().kind(),
+                std::io::ErrorKind::InvalidData,
+                "{}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn decodes_escapes() {
+        assert_eq!(decode("a_x000A_b_x005F_x0041_"), "a\nb_x0041_");
+        assert_eq!(decode("_x12_ _xZZZZ_"), "_x12_ _xZZZZ_");
+    }
+}
@@ -0,0 +1,389 @@
+use crate::{
+    FirewallAction,
+    FirewallProfile,
+    FirewallRuleDirection,
+};
+
+/// The protocol number INetFwRule uses to mean "any protocol".
+pub const PROTOCOL_ANY: i32 = 256;
+
+/// Protocol numbers and the names `netsh` and PowerShell use for them.
+const PROTOCOL_NAMES: [(i32, &str); 5] = [
+    (1, "ICMPv4"),
+    (6, "TCP"),
+    (17, "UDP"),
+    (58, "ICMPv6"),
+    (PROTOCOL_ANY, "Any"),
+];
+
+/// The name of a well-known protocol number, like "TCP".
+pub fn protocol_name(protocol: i32) -> Option<&'static str> {
+    PROTOCOL_NAMES
+        .iter()
+        .find(|(number, _)| *number == protocol)
+        .map(|(_, name)| *name)
+}
+
+/// Parse a protocol name, case-insensitively, or a protocol number.
+pub fn parse_protocol(s: &str) -> Option<i32> {
+    let s = s.trim();
+    PROTOCOL_NAMES
+        .iter()
+        .find(|(_, name)| name.eq_ignore_ascii_case(s))
+        .map(|(number, _)| *number)
+        .or_else(|| s.parse().ok().filter(|n| (0..=PROTOCOL_ANY).contains(n)))
+}
+
+/// Every profile a rule can apply to, without the bits of `FirewallProfile::ALL` no profile uses.
+pub(crate) fn every_profile() -> FirewallProfile {
+    FirewallProfile::SINGLE
+        .iter()
+        .fold(FirewallProfile::empty(), |all, profile| all | *profile)
+}
+
+/// The trimmed, non-empty entries of a comma separated field, like `80, 443`.
+pub(crate) fn list_entries(field: &str) -> Vec<&str> {
+    field
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .collect()
+}
+
+/// Interface types by the flag and name the firewall cmdlets use, and the name `INetFwRule` uses.
+pub(crate) const CMDLET_INTERFACE_TYPES: [(i64, &str, &str); 3] = [
+    (1, "Wired", "Lan"),
+    (2, "Wireless", "Wireless"),
+    (4, "RemoteAccess", "RemoteAccess"),
+];
+
+/// A port entry as the cmdlets spell it; they write `RPC-EPMap` without the dash.
+pub(crate) fn cmdlet_port(entry: &str) -> &str {
+    if entry.eq_ignore_ascii_case("RPC-EPMap") {
+        "RPCEPMap"
+    } else {
+        entry
+    }
+}
+
+/// A port entry from the cmdlets as `INetFwRule` spells it.
+pub(crate) fn parse_cmdlet_port(entry: &str) -> &str {
+    if entry.eq_ignore_ascii_case("RPCEPMap") {
+        "RPC-EPMap"
+    } else {
+        entry
+    }
+}
+
+/// An ICMP entry as the cmdlets take it: `type` or `type:code`, with no wildcard for the code.
+pub(crate) fn cmdlet_icmp(entry: &str) -> &str {
+    entry.strip_suffix(":*").unwrap_or(entry)
+}
+
+/// An ICMP entry from the cmdlets as `INetFwRule` spells it, with `:*` for a type that matches every code.
+pub(crate) fn parse_cmdlet_icmp(entry: &str) -> String {
+    if entry.contains(':') {
+        entry.to_string()
+    } else {
+        format!("{}:*", entry)
+    }
+}
+
+/// An owned copy of everything a `FirewallRule` exposes.
+///
+/// Unlike `FirewallRule` this holds no COM pointers, so it is `Send` and can be built and compared on any platform.
+/// Strings are converted lossily from the BSTRs COM hands out.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct FirewallRuleData {
+    /// The ID the rule is stored under in the registry, like `CoreNet-DNS-Out-UDP` or a GUID.
+    ///
+    /// This is PowerShell's `Name`. COM does not expose it, so it is only set on rules read from the registry or
+    /// PowerShell output. It identifies the rule rather than describing it, so it is left out of `field_values`.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub id: Option<String>,
+    pub name: String,
+    pub description: Option<String>,
+    pub application_name: Option<String>,
+    pub service_name: Option<String>,
+    /// Serialized by name where it has one, like "TCP".
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_support::protocol"))]
+    pub protocol: i32,
+    pub local_ports: Option<String>,
+    pub remote_ports: Option<String>,
+    pub local_addresses: Option<String>,
+    pub remote_addresses: Option<String>,
+    pub icmp_types_and_codes: Option<String>,
+    pub direction: FirewallRuleDirection,
+    pub interfaces: Option<Vec<String>>,
+    pub interface_types: Option<String>,
+    pub enabled: bool,
+    pub grouping: Option<String>,
+    pub profiles: FirewallProfile,
+    pub edge_traversal: bool,
+    pub action: FirewallAction,
+}
+
+impl FirewallRuleData {
+    /// Make a new rule with the given name and the defaults `netsh` and `New-NetFirewallRule` use.
+    pub fn new(name: impl Into<String>) -> Self {
+        FirewallRuleData {
+            name: name.into(),
+            ..Default::default()
+        }
+    }
+}
+
+impl FirewallRuleData {
+    /// Every field but `id` formatted for display, in declaration order. Unset optional fields are `None`.
+    pub fn field_values(&self) -> Vec<(&'static str, Option<String>)> {
+        vec![
+            ("name", Some(self.name.clone())),
+            ("description", self.description.clone()),
+            ("application_name", self.application_name.clone()),
+            ("service_name", self.service_name.clone()),
+            ("protocol", Some(self.protocol.to_string())),
+            ("local_ports", self.local_ports.clone()),
+            ("remote_ports", self.remote_ports.clone()),
+            ("local_addresses", self.local_addresses.clone()),
+            ("remote_addresses", self.remote_addresses.clone()),
+            ("icmp_types_and_codes", self.icmp_types_and_codes.clone()),
+            ("direction", Some(self.direction.to_string())),
+            (
+                "interfaces",
+                self.interfaces
+                    .as_ref()
+                    .map(|interfaces| interfaces.join(",")),
+            ),
+            ("interface_types", self.interface_types.clone()),
+            ("enabled", Some(self.enabled.to_string())),
+            ("grouping", self.grouping.clone()),
+            ("profiles", Some(self.profiles.to_string())),
+            ("edge_traversal", Some(self.edge_traversal.to_string())),
+            ("action", Some(self.action.to_string())),
+        ]
+    }
+
+    /// The fields that differ between `self` and `new`.
+    pub fn changes_to(&self, new: &FirewallRuleData) -> Vec<FieldChange> {
+        field_changes(self.field_values(), new.field_values())
+    }
+
+    /// Whether overwriting this rule with `new` means removing and re-adding it rather than writing it in place.
+    ///
+    /// `FirewallRule::update_from_data` can't unset optional fields, and COM rejects ports and ICMP settings that don't
+    /// fit the current protocol.
+    pub fn needs_replacing(&self, new: &FirewallRuleData) -> bool {
+        fn cleared<T>(old: &Option<T>, new: &Option<T>) -> bool {
+            old.is_some() && new.is_none()
+        }
+
+        cleared(&self.description, &new.description)
+            || cleared(&self.application_name, &new.application_name)
+            || cleared(&self.service_name, &new.service_name)
+            || cleared(&self.local_ports, &new.local_ports)
+            || cleared(&self.remote_ports, &new.remote_ports)
+            || cleared(&self.local_addresses, &new.local_addresses)
+            || cleared(&self.remote_addresses, &new.remote_addresses)
+            || cleared(&self.icmp_types_and_codes, &new.icmp_types_and_codes)
+            || cleared(&self.interfaces, &new.interfaces)
+            || cleared(&self.interface_types, &new.interface_types)
+            || cleared(&self.grouping, &new.grouping)
+            || self.protocol != new.protocol
+    }
+}
+
+impl Default for FirewallRuleData {
+    fn default() -> Self {
+        FirewallRuleData {
+            id: None,
+            name: String::new(),
+            description: None,
+            application_name: None,
+            service_name: None,
+            protocol: PROTOCOL_ANY,
+            local_ports: None,
+            remote_ports: None,
+            local_addresses: None,
+            remote_addresses: None,
+            icmp_types_and_codes: None,
+            direction: FirewallRuleDirection::In,
+            interfaces: None,
+            interface_types: None,
+            enabled: true,
+            grouping: None,
+            profiles: FirewallProfile::ALL,
+            edge_traversal: false,
+            action: FirewallAction::Allow,
+        }
+    }
+}
+
+/// An owned copy of the per-profile settings a `FirewallPolicy` exposes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct FirewallProfileSettings {
+    pub firewall_enabled: bool,
+    pub block_all_inbound_traffic: bool,
+    pub notifications_disabled: bool,
+    pub unicast_responses_to_multicast_broadcast_disabled: bool,
+    pub default_inbound_action: FirewallAction,
+    pub default_outbound_action: FirewallAction,
+}
+
+impl FirewallProfileSettings {
+    /// Every setting formatted for display, in declaration order.
+    pub fn field_values(&self) -> Vec<(&'static str, Option<String>)> {
+        vec![
+            ("firewall_enabled", Some(self.firewall_enabled.to_string())),
+            (
+                "block_all_inbound_traffic",
+                Some(self.block_all_inbound_traffic.to_string()),
+            ),
+            (
+                "notifications_disabled",
+                Some(self.notifications_disabled.to_string()),
+            ),
+            (
+                "unicast_responses_to_multicast_broadcast_disabled",
+                Some(
+                    self.unicast_responses_to_multicast_broadcast_disabled
+                        .to_string(),
+                ),
+            ),
+            (
+                "default_inbound_action",
+                Some(self.default_inbound_action.to_string()),
+            ),
+            (
+                "default_outbound_action",
+                Some(self.default_outbound_action.to_string()),
+            ),
+        ]
+    }
+
+    /// The settings that differ between `self` and `new`.
+    pub fn changes_to(&self, new: &FirewallProfileSettings) -> Vec<FieldChange> {
+        field_changes(self.field_values(), new.field_values())
+    }
+}
+
+impl Default for FirewallProfileSettings {
+    /// The settings a freshly installed Windows uses.
+    fn default() -> Self {
+        FirewallProfileSettings {
+            firewall_enabled: true,
+            block_all_inbound_traffic: false,
+            notifications_disabled: false,
+            unicast_responses_to_multicast_broadcast_disabled: false,
+            default_inbound_action: FirewallAction::Block,
+            default_outbound_action: FirewallAction::Allow,
+        }
+    }
+}
+
+/// A single field that differs between two values. Unset optional fields are `None`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct FieldChange {
+    pub field: &'static str,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+impl std::fmt::Display for FieldChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: {} -> {}",
+            self.field,
+            self.old.as_deref().unwrap_or("<unset>"),
+            self.new.as_deref().unwrap_or("<unset>")
+        )
+    }
+}
+
+fn field_changes(
+    old: Vec<(&'static str, Option<String>)>,
+    new: Vec<(&'static str, Option<String>)>,
+) -> Vec<FieldChange> {
+    old.into_iter()
+        .zip(new)
+        .filter(|((_, old), (_, new))| old != new)
+        .map(|((field, old), (_, new))| FieldChange { field, old, new })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rule_changes() {
+        let old = FirewallRuleData::new("web");
+        let new = FirewallRuleData {
+            local_ports: Some("80,443".into()),
+            action: FirewallAction::Block,
+            ..old.clone()
+        };
+
+        assert!(old.changes_to(&old).is_empty());
+        assert_eq!(
+            old.changes_to(&new),
+            vec![
+                FieldChange {
+                    field: "local_ports",
+                    old: None,
+                    new: Some("80,443".into()),
+                },
+                FieldChange {
+                    field: "action",
+                    old: Some("Allow".into()),
+                    new: Some("Block".into()),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn replacing() {
+        let old = FirewallRuleData {
+            description: Some("Web".into()),
+            interfaces: Some(vec!["Ethernet".into()]),
+            ..FirewallRuleData::new("web")
+        };
+
+        assert!(!old.needs_replacing(&old));
+        assert!(!old.needs_replacing(&FirewallRuleData {
+            local_ports: Some("80".into()),
+            ..old.clone()
+        }));
+        assert!(old.needs_replacing(&FirewallRuleData {
+            description: None,
+            ..old.clone()
+        }));
+        assert!(old.needs_replacing(&FirewallRuleData {
+            interfaces: None,
+            ..old.clone()
+        }));
+        assert!(old.needs_replacing(&FirewallRuleData {
+            protocol: 6,
+            ..old.clone()
+        }));
+    }
+
+    #[test]
+    fn profile_display() {
+        assert_eq!(FirewallProfile::ALL.to_string(), "Any");
+        assert_eq!(
+            (FirewallProfile::DOMAIN | FirewallProfile::PUBLIC).to_string(),
+            "Domain,Public"
+        );
+        assert_eq!(FirewallProfile::empty().to_string(), "None");
+    }
+}
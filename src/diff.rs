@@ -0,0 +1,576 @@
+//! Comparing two sets of rules.
+//!
+//! Rules are matched by identity: the registry ID when both sides know it, otherwise name, direction and grouping.
+//! Names are not unique on Windows, so when several rules share an identity the closest pairs are matched first.
+//! Fields are compared by meaning rather than spelling, so `80,443` and `443, 80` are the same port list.
+
+use crate::{
+    addresses::AddressList,
+    data::{
+        every_profile,
+        FieldChange,
+    },
+    ports::PortList,
+    FirewallProfile,
+    FirewallRuleData,
+};
+use std::collections::{
+    BTreeSet,
+    HashMap,
+};
+
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const CYAN: &str = "\x1b[36m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+/// A rule present on both sides whose fields differ.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct RuleChange {
+    pub old: FirewallRuleData,
+    pub new: FirewallRuleData,
+    pub changes: Vec<FieldChange>,
+}
+
+/// The differences between two sets of rules.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct RuleSetDiff {
+    /// Rules only in the new set, in its order.
+    pub added: Vec<FirewallRuleData>,
+    /// Rules only in the old set, in its order.
+    pub removed: Vec<FirewallRuleData>,
+    /// Matched rules that differ, in the new set's order.
+    pub modified: Vec<RuleChange>,
+    /// How many matched rules are equivalent.
+    pub unchanged: usize,
+}
+
+impl RuleSetDiff {
+    /// Whether the two sets are equivalent.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty()
+    }
+
+    /// Render as a unified diff of rule fields.
+    pub fn unified(&self) -> Unified<'_> {
+        Unified {
+            diff: self,
+            color: false,
+            labels: ("old", "new"),
+        }
+    }
+
+    /// Render as pretty-printed JSON.
+    #[cfg(feature = "serde")]
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("diffs always serialize")
+    }
+}
+
+/// A short description of a rule for headings, like `'Web' (Inbound)`.
+fn label(rule: &FirewallRuleData) -> String {
+    match rule.id.as_deref() {
+        Some(id) => format!("'{}' ({}) [{}]", rule.name, rule.direction, id),
+        None => format!("'{}' ({})", rule.name, rule.direction),
+    }
+}
+
+/// A plain text summary, one line per rule and one per changed field.
+impl std::fmt::Display for RuleSetDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for rule in self.added.iter() {
+            writeln!(f, "+ added rule {}", label(rule))?;
+        }
+        for rule in self.removed.iter() {
+            writeln!(f, "- removed rule {}", label(rule))?;
+        }
+        for change in self.modified.iter() {
+            writeln!(f, "~ modified rule {}", label(&change.new))?;
+            for field in change.changes.iter() {
+                writeln!(f, "    {}", field)?;
+            }
+        }
+
+        writeln!(
+            f,
+            "{} added, {} removed, {} modified, {} unchanged.",
+            self.added.len(),
+            self.removed.len(),
+            self.modified.len(),
+            self.unchanged
+        )
+    }
+}
+
+/// A unified diff rendering of a `RuleSetDiff`, with a hunk per rule.
+#[derive(Debug, Clone, Copy)]
+pub struct Unified<'a> {
+    diff: &'a RuleSetDiff,
+    color: bool,
+    labels: (&'a str, &'a str),
+}
+
+impl<'a> Unified<'a> {
+    /// Colour removed lines red, added lines green and hunk headers cyan with ANSI escapes.
+    pub fn color(mut self, color: bool) -> Self {
+        self.color = color;
+        self
+    }
+
+    /// The names shown in the `---` and `+++` header lines.
+    pub fn labels(mut self, old: &'a str, new: &'a str) -> Self {
+        self.labels = (old, new);
+        self
+    }
+
+    fn line(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+        color: &str,
+        prefix: char,
+        field: &str,
+        value: &str,
+    ) -> std::fmt::Result {
+        if self.color && !color.is_empty() {
+            writeln!(f, "{}{}{}: {}{}", color, prefix, field, value, RESET)
+        } else {
+            writeln!(f, "{}{}: {}", prefix, field, value)
+        }
+    }
+
+    fn hunk(&self, f: &mut std::fmt::Formatter<'_>, rule: &FirewallRuleData) -> std::fmt::Result {
+        if self.color {
+            writeln!(f, "{}@@ rule {} @@{}", CYAN, label(rule), RESET)
+        } else {
+            writeln!(f, "@@ rule {} @@", label(rule))
+        }
+    }
+
+    fn whole_rule(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+        rule: &FirewallRuleData,
+        color: &str,
+        prefix: char,
+    ) -> std::fmt::Result {
+        self.hunk(f, rule)?;
+        for (field, value) in rule.field_values() {
+            if let Some(value) = value {
+                self.line(f, color, prefix, field, &value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for Unified<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.diff.is_empty() {
+            return Ok(());
+        }
+
+        let (old, new) = self.labels;
+        if self.color {
+            writeln!(f, "{}--- {}{}", BOLD, old, RESET)?;
+            writeln!(f, "{}+++ {}{}", BOLD, new, RESET)?;
+        } else {
+            writeln!(f, "--- {}", old)?;
+            writeln!(f, "+++ {}", new)?;
+        }
+
+        for rule in self.diff.removed.iter() {
+            self.whole_rule(f, rule, RED, '-')?;
+        }
+        for rule in self.diff.added.iter() {
+            self.whole_rule(f, rule, GREEN, '+')?;
+        }
+        for change in self.diff.modified.iter() {
+            self.hunk(f, &change.new)?;
+            for (field, value) in change.new.field_values() {
+                match change.changes.iter().find(|c| c.field == field) {
+                    Some(c) => {
+                        if let Some(old) = c.old.as_deref() {
+                            self.line(f, RED, '-', field, old)?;
+                        }
+                        if let Some(new) = c.new.as_deref() {
+                            self.line(f, GREEN, '+', field, new)?;
+                        }
+                    }
+                    None => {
+                        if let Some(value) = value {
+                            self.line(f, "", ' ', field, &value)?;
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The fields that differ in meaning between two rules.
+///
+/// Port and address lists are compared in canonical form, other lists as unordered case-insensitive sets, and an
+/// unset list is the same as one matching everything. Lists that fail to parse are compared as written.
+pub fn semantic_changes(old: &FirewallRuleData, new: &FirewallRuleData) -> Vec<FieldChange> {
+    let mut changes = old.changes_to(new);
+    changes
+        .retain(|change| !equivalent(change.field, change.old.as_deref(), change.new.as_deref()));
+    changes
+}
+
+pub(crate) fn equivalent(field: &str, old: Option<&str>, new: Option<&str>) -> bool {
+    match field {
+        "local_ports" | "remote_ports" => {
+            match (PortList::from_field(old), PortList::from_field(new)) {
+                (Ok(old), Ok(new)) => old == new,
+                _ => false,
+            }
+        }
+        "local_addresses" | "remote_addresses" => {
+            match (AddressList::from_field(old), AddressList::from_field(new)) {
+                (Ok(old), Ok(new)) => old == new,
+                _ => false,
+            }
+        }
+        "profiles" => match (profile_set(old), profile_set(new)) {
+            (Some(old), Some(new)) => old == new,
+            _ => false,
+        },
+        "icmp_types_and_codes" | "interface_types" | "interfaces" => {
+            token_set(old) == token_set(new)
+        }
+        _ => false,
+    }
+}
+
+/// A profile mask with every profile as `ALL`, as `FirewallRule` reads a rule for every profile.
+fn profile_set(profiles: Option<&str>) -> Option<FirewallProfile> {
+    let profiles: FirewallProfile = profiles?.parse().ok()?;
+    if profiles.contains(every_profile()) {
+        Some(FirewallProfile::ALL)
+    } else {
+        Some(profiles)
+    }
+}
+
+/// A comma separated list as a lowercase set, with unset and wildcards as `None`.
+fn token_set(list: Option<&str>) -> Option<BTreeSet<String>> {
+    let set: BTreeSet<_> = list?
+        .split(',')
+        .map(|token| token.trim().to_ascii_lowercase())
+        .filter(|token| !token.is_empty())
+        .collect();
+
+    let wildcard = set
+        .iter()
+        .any(|token| token == "*" || token == "any" || token == "all");
+    if set.is_empty() || wildcard {
+        None
+    } else {
+        Some(set)
+    }
+}
+
+/// Compare two sets of rules.
+pub fn diff_rules(old: &[FirewallRuleData], new: &[FirewallRuleData]) -> RuleSetDiff {
+    let new_match = match_rules(old, new);
+    let mut old_matched = vec![false; old.len()];
+    for i in new_match.iter().flatten() {
+        old_matched[*i] = true;
+    }
+
+    let mut diff = RuleSetDiff::default();
+    for (j, rule) in new.iter().enumerate() {
+        match new_match[j] {
+            Some(i) => {
+                let changes = semantic_changes(&old[i], rule);
+                if changes.is_empty() {
+                    diff.unchanged += 1;
+                } else {
+                    diff.modified.push(RuleChange {
+                        old: old[i].clone(),
+                        new: rule.clone(),
+                        changes,
+                    });
+                }
+            }
+            None => diff.added.push(rule.clone()),
+        }
+    }
+    diff.removed = old
+        .iter()
+        .zip(old_matched)
+        .filter(|(_, matched)| !matched)
+        .map(|(rule, _)| rule.clone())
+        .collect();
+
+    diff
+}
+
+/// Pair up rules between two sets by identity, returning the index in `old` matched to each rule in `new`.
+pub fn match_rules(old: &[FirewallRuleData], new: &[FirewallRuleData]) -> Vec<Option<usize>> {
+    let mut old_matched = vec![false; old.len()];
+    let mut new_match: Vec<Option<usize>> = vec![None; new.len()];
+
+    // Registry IDs are unique, so they settle a match outright.
+    let mut by_id: HashMap<&str, usize> = HashMap::new();
+    for (i, rule) in old.iter().enumerate() {
+        if let Some(id) = rule.id.as_deref() {
+            by_id.entry(id).or_insert(i);
+        }
+    }
+    for (j, rule) in new.iter().enumerate() {
+        if let Some(&i) = rule.id.as_deref().and_then(|id| by_id.get(id)) {
+            if !old_matched[i] {
+                old_matched[i] = true;
+                new_match[j] = Some(i);
+            }
+        }
+    }
+
+    // Everything else is grouped by name, direction and grouping.
+    let identity = |rule: &FirewallRuleData| {
+        (
+            rule.name.clone(),
+            rule.direction.to_string(),
+            rule.grouping.clone(),
+        )
+    };
+    let mut groups: HashMap<_, Vec<usize>> = HashMap::new();
+    for (i, rule) in old.iter().enumerate() {
+        if !old_matched[i] {
+            groups.entry(identity(rule)).or_default().push(i);
+        }
+    }
+
+    let mut pending: Vec<usize> = (0..new.len()).filter(|j| new_match[*j].is_none()).collect();
+
+    // Exact matches first, so a duplicate that did not change is never reported as modified.
+    pending.retain(|&j| {
+        let candidates = match groups.get_mut(&identity(&new[j])) {
+            Some(candidates) => candidates,
+            None => return true,
+        };
+        let found = candidates.iter().position(|&i| {
+            compatible(&old[i], &new[j]) && semantic_changes(&old[i], &new[j]).is_empty()
+        });
+        match found {
+            Some(position) => {
+                let i = candidates.remove(position);
+                new_match[j] = Some(i);
+                false
+            }
+            None => true,
+        }
+    });
+
+    // Then the closest remaining rule with the same identity.
+    for j in pending {
+        let candidates = match groups.get_mut(&identity(&new[j])) {
+            Some(candidates) => candidates,
+            None => continue,
+        };
+        let best = candidates
+            .iter()
+            .enumerate()
+            .filter(|(_, &i)| compatible(&old[i], &new[j]))
+            .min_by_key(|(_, &i)| semantic_changes(&old[i], &new[j]).len())
+            .map(|(position, _)| position);
+        if let Some(position) = best {
+            let i = candidates.remove(position);
+            new_match[j] = Some(i);
+        }
+    }
+
+    new_match
+}
+
+/// Rules with different registry IDs are different rules, whatever their names.
+fn compatible(old: &FirewallRuleData, new: &FirewallRuleData) -> bool {
+    match (old.id.as_deref(), new.id.as_deref()) {
+        (Some(old), Some(new)) => old == new,
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        FirewallAction,
+        FirewallRuleDirection,
+    };
+
+    fn rule(name: &str, ports: &str) -> FirewallRuleData {
+        FirewallRuleData {
+            local_ports: Some(ports.into()),
+            ..FirewallRuleData::new(name)
+        }
+    }
+
+    #[test]
+    fn added_removed_modified() {
+        let old = vec![rule("a", "1"), rule("b", "2"), rule("c", "3")];
+        let new = vec![rule("b", "2"), rule("c", "30"), rule("d", "4")];
+
+        let diff = diff_rules(&old, &new);
+        assert_eq!(diff.added, vec![rule("d", "4")]);
+        assert_eq!(diff.removed, vec![rule("a", "1")]);
+        assert_eq!(diff.unchanged, 1);
+        assert_eq!(diff.modified.len(), 1);
+        assert_eq!(
+            diff.modified[0].changes[0].to_string(),
+            "local_ports: 3 -> 30"
+        );
+    }
+
+    #[test]
+    fn semantic_equality() {
+        let old = FirewallRuleData {
+            remote_addresses: Some("10.0.0.0/255.0.0.0,LocalSubnet".into()),
+            interface_types: Some("Lan,Wireless".into()),
+            ..rule("a", "80,443")
+        };
+        let new = FirewallRuleData {
+            remote_addresses: Some("localsubnet, 10.0.0.0/8".into()),
+            interface_types: Some("wireless, lan".into()),
+            ..rule("a", "443, 80")
+        };
+        assert!(diff_rules(std::slice::from_ref(&old), &[new]).is_empty());
+
+        let any = FirewallRuleData {
+            local_ports: Some("*".into()),
+            ..old.clone()
+        };
+        let unset = FirewallRuleData {
+            local_ports: None,
+            ..old
+        };
+        assert!(semantic_changes(&any, &unset).is_empty());
+
+        let all = FirewallRuleData {
+            profiles: FirewallProfile::ALL,
+            ..rule("a", "80")
+        };
+        let every = FirewallRuleData {
+            profiles: FirewallProfile::DOMAIN | FirewallProfile::PRIVATE | FirewallProfile::PUBLIC,
+            ..rule("a", "80")
+        };
+        let some = FirewallRuleData {
+            profiles: FirewallProfile::DOMAIN | FirewallProfile::PRIVATE,
+            ..rule("a", "80")
+        };
+        assert!(semantic_changes(&all, &every).is_empty());
+        assert_eq!(semantic_changes(&all, &some)[0].field, "profiles");
+    }
+
+    #[test]
+    fn duplicate_names() {
+        let old = vec![rule("dup", "1"), rule("dup", "2"), rule("dup", "3")];
+        let new = vec![rule("dup", "3"), rule("dup", "20"), rule("dup", "1")];
+
+        let diff = diff_rules(&old, &new);
+        assert_eq!(diff.unchanged, 2);
+        assert!(diff.added.is_empty() && diff.removed.is_empty());
+        assert_eq!(diff.modified[0].old, rule("dup", "2"));
+        assert_eq!(diff.modified[0].new, rule("dup", "20"));
+    }
+
+    #[test]
+    fn identity_includes_direction_and_grouping() {
+        let outbound = FirewallRuleData {
+            direction: FirewallRuleDirection::Out,
+            ..rule("a", "1")
+        };
+        let grouped = FirewallRuleData {
+            grouping: Some("G".into()),
+            ..rule("a", "1")
+        };
+
+        let diff = diff_rules(&[rule("a", "1")], &[outbound, grouped]);
+        assert_eq!(diff.added.len(), 2);
+        assert_eq!(diff.removed.len(), 1);
+    }
+
+    #[test]
+    fn registry_ids() {
+        let with_id = |id: &str, name: &str| FirewallRuleData {
+            id: Some(id.into()),
+            ..rule(name, "1")
+        };
+
+        // Renamed, but the ID says it is the same rule.
+        let diff = diff_rules(&[with_id("{1}", "old name")], &[with_id("{1}", "new name")]);
+        assert_eq!(diff.modified.len(), 1);
+        assert_eq!(diff.modified[0].changes[0].field, "name");
+
+        // Same name, but different rules.
+        let diff = diff_rules(&[with_id("{1}", "a")], &[with_id("{2}", "a")]);
+        assert_eq!((diff.added.len(), diff.removed.len()), (1, 1));
+
+        // Only one side knows the ID, so fall back to the name.
+        let diff = diff_rules(&[with_id("{1}", "a")], &[rule("a", "1")]);
+        assert!(diff.is_empty());
+    }
+
+    fn sample() -> RuleSetDiff {
+        let old = vec![rule("gone", "1"), rule("web", "80")];
+        let new = vec![
+            FirewallRuleData {
+                action: FirewallAction::Block,
+                ..rule("web", "80,443")
+            },
+            rule("new", "2"),
+        ];
+        diff_rules(&old, &new)
+    }
+
+    #[test]
+    fn render_text() {
+        assert_eq!(
+            sample().to_string(),
+            "+ added rule 'new' (Inbound)\n\
+             - removed rule 'gone' (Inbound)\n\
+             ~ modified rule 'web' (Inbound)\n    \
+             local_ports: 80 -> 80,443\n    \
+             action: Allow -> Block\n\
+             1 added, 1 removed, 1 modified, 0 unchanged.\n"
+        );
+    }
+
+    #[test]
+    fn render_unified() {
+        let text = sample().unified().labels("before", "after").to_string();
+        assert!(
+            text.starts_with("--- before\n+++ after\n@@ rule 'gone' (Inbound) @@\n-name: gone\n")
+        );
+        assert!(text.contains("@@ rule 'new' (Inbound) @@\n+name: new\n"));
+        assert!(text.contains(
+            "@@ rule 'web' (Inbound) @@\n name: web\n protocol: 256\n-local_ports: 80\n+local_ports: 80,443\n"
+        ));
+        assert!(text.contains("-action: Allow\n+action: Block\n"));
+
+        let colored = sample().unified().color(true).to_string();
+        assert!(colored.contains("\x1b[31m-local_ports: 80\x1b[0m\n"));
+        assert!(colored.contains("\x1b[32m+local_ports: 80,443\x1b[0m\n"));
+        assert!(colored.contains("\x1b[36m@@ rule 'web' (Inbound) @@\x1b[0m\n"));
+
+        assert_eq!(RuleSetDiff::default().unified().to_string(), "");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn render_json() {
+        let value: serde_json::Value = serde_json::from_str(&sample().to_json()).unwrap();
+        assert_eq!(value["added"][0]["name"], "new");
+        assert_eq!(value["removed"][0]["name"], "gone");
+        assert_eq!(value["modified"][0]["changes"][1]["field"], "action");
+        assert_eq!(value["modified"][0]["changes"][1]["new"], "Block");
+        assert_eq!(value["unchanged"], 0);
+    }
+}
@@ -0,0 +1,840 @@
+//! Deciding offline whether the firewall would allow a connection.
+//!
+//! An `Evaluator` holds a set of rules and the settings of each profile, and answers for a `Packet` the way Windows
+//! does:
+//!
+//! 1. With the firewall off for the packet's profile, everything is allowed.
+//! 2. With "block all inbound connections" on, every inbound packet is blocked, whatever the rules say.
+//! 3. An allow rule marked as an authenticated bypass allows an authenticated packet, overriding block rules.
+//! 4. A block rule blocks.
+//! 5. An allow rule allows.
+//! 6. Otherwise the profile's default action for the direction applies.
+//!
+//! A rule matches when it is enabled and every condition it sets holds. Address and port keywords, like
+//! `LocalSubnet` or `RPC`, stand for things only the host knows, so they never match. Application paths are compared
+//! ignoring case, with the common environment variables expanded to their default values.
+
+use crate::{
+    addresses::AddressList,
+    data::PROTOCOL_ANY,
+    ports::PortList,
+    FirewallAction,
+    FirewallProfile,
+    FirewallProfileSettings,
+    FirewallRuleData,
+    FirewallRuleDirection,
+};
+use std::{
+    collections::BTreeMap,
+    net::IpAddr,
+};
+
+const TCP: i32 = 6;
+const UDP: i32 = 17;
+const ICMPV4: i32 = 1;
+const ICMPV6: i32 = 58;
+
+/// The default values of the environment variables application paths use, lowercase.
+const ENVIRONMENT: [(&str, &str); 5] = [
+    ("%systemroot%", r"c:\windows"),
+    ("%windir%", r"c:\windows"),
+    ("%programfiles%", r"c:\program files"),
+    ("%programfiles(x86)%", r"c:\program files (x86)"),
+    ("%systemdrive%", "c:"),
+];
+
+/// A packet or connection to decide on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet {
+    pub direction: FirewallRuleDirection,
+    /// The protocol number, like 6 for TCP.
+    pub protocol: i32,
+    pub local_address: IpAddr,
+    pub local_port: Option<u16>,
+    pub remote_address: IpAddr,
+    pub remote_port: Option<u16>,
+    pub icmp_type: Option<u8>,
+    pub icmp_code: Option<u8>,
+    /// The path of the application sending or receiving it.
+    pub application: Option<String>,
+    /// The short name of the service sending or receiving it.
+    pub service: Option<String>,
+    /// The type of the interface it uses: "Wireless", "Lan" or "RemoteAccess".
+    pub interface_type: Option<String>,
+    /// The alias of the interface it uses.
+    pub interface: Option<String>,
+    /// The profile of the network it is on; one of `FirewallProfile::SINGLE`.
+    pub profile: FirewallProfile,
+    /// Whether it was secured and authenticated with IPsec.
+    pub authenticated: bool,
+    /// Whether it reached the host through an edge traversal technology like Teredo.
+    pub edge_traversed: bool,
+}
+
+impl Packet {
+    /// A packet with no ports, ICMP type, application, service or interface, that was neither authenticated nor
+    /// edge traversed.
+    pub fn new(
+        direction: FirewallRuleDirection,
+        protocol: i32,
+        local_address: IpAddr,
+        remote_address: IpAddr,
+        profile: FirewallProfile,
+    ) -> Self {
+        Packet {
+            direction,
+            protocol,
+            local_address,
+            local_port: None,
+            remote_address,
+            remote_port: None,
+            icmp_type: None,
+            icmp_code: None,
+            application: None,
+            service: None,
+            interface_type: None,
+            interface: None,
+            profile,
+            authenticated: false,
+            edge_traversed: false,
+        }
+    }
+}
+
+/// Why a packet was allowed or blocked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Reason {
+    FirewallDisabled,
+    BlockAllInbound,
+    AuthenticatedBypass,
+    BlockRule,
+    AllowRule,
+    DefaultAction,
+}
+
+impl std::fmt::Display for Reason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Reason::FirewallDisabled => "the firewall is disabled",
+            Reason::BlockAllInbound => "all inbound connections are blocked",
+            Reason::AuthenticatedBypass => "an authenticated bypass rule",
+            Reason::BlockRule => "a block rule",
+            Reason::AllowRule => "an allow rule",
+            Reason::DefaultAction => "the default action",
+        })
+    }
+}
+
+/// What the firewall would do with a packet, and why.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Decision<'a> {
+    pub action: FirewallAction,
+    pub reason: Reason,
+    /// The first matching rule of the kind that decided, if a rule did.
+    pub rule: Option<&'a FirewallRuleData>,
+}
+
+impl std::fmt::Display for Decision<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} by {}", self.action, self.reason)?;
+        if let Some(rule) = self.rule {
+            write!(f, " '{}'", rule.name)?;
+        }
+        Ok(())
+    }
+}
+
+/// A rule with its lists parsed.
+#[derive(Debug)]
+struct CompiledRule<'a> {
+    rule: &'a FirewallRuleData,
+    local_ports: PortList,
+    remote_ports: PortList,
+    local_addresses: AddressList,
+    remote_addresses: AddressList,
+    /// ICMP types and codes, with `None` for any; `None` for a rule that allows every ICMP message.
+    icmp: Option<Vec<(Option<u8>, Option<u8>)>>,
+    application: Option<String>,
+    /// Interface types in lowercase, or `None` for all.
+    interface_types: Option<Vec<String>>,
+    bypass: bool,
+}
+
+impl CompiledRule<'_> {
+    // `Option::is_none_or` needs a newer compiler than the rest of the crate.
+    #[allow(clippy::unnecessary_map_or)]
+    fn matches(&self, packet: &Packet) -> bool {
+        let rule = self.rule;
+        rule.enabled
+            && rule.direction == packet.direction
+            && rule.profiles.intersects(packet.profile)
+            && (rule.protocol == PROTOCOL_ANY || rule.protocol == packet.protocol)
+            && self.ports_match(packet)
+            && self.icmp_matches(packet)
+            && self.local_addresses.contains(packet.local_address)
+            && self.remote_addresses.contains(packet.remote_address)
+            && (self.application.is_none()
+                || self.application == packet.application.as_deref().map(normalize_path))
+            && service_matches(rule.service_name.as_deref(), packet.service.as_deref())
+            && self.interface_types.as_ref().map_or(true, |types| {
+                packet
+                    .interface_type
+                    .as_ref()
+                    .is_some_and(|kind| types.contains(&kind.to_ascii_lowercase()))
+            })
+            && rule.interfaces.as_ref().map_or(true, |interfaces| {
+                packet.interface.as_ref().is_some_and(|interface| {
+                    interfaces
+                        .iter()
+                        .any(|alias| alias.eq_ignore_ascii_case(interface))
+                })
+            })
+            // Edge traversal only widens what a rule allows; block rules apply however a packet arrived.
+            && (!packet.edge_traversed || rule.edge_traversal || rule.action != FirewallAction::Allow)
+    }
+
+    /// Ports only narrow TCP and UDP rules; a packet without a port only matches rules for any.
+    fn ports_match(&self, packet: &Packet) -> bool {
+        if packet.protocol != TCP && packet.protocol != UDP {
+            return true;
+        }
+        let contains = |list: &PortList, port: Option<u16>| {
+            list.is_any() || port.is_some_and(|port| list.contains(port))
+        };
+        contains(&self.local_ports, packet.local_port)
+            && contains(&self.remote_ports, packet.remote_port)
+    }
+
+    fn icmp_matches(&self, packet: &Packet) -> bool {
+        if packet.protocol != ICMPV4 && packet.protocol != ICMPV6 {
+            return true;
+        }
+        let entries = match &self.icmp {
+            Some(entries) => entries,
+            None => return true,
+        };
+        entries.iter().any(|(kind, code)| {
+            let matches = |wanted: Option<u8>, actual: Option<u8>| {
+                wanted.is_none() || (actual.is_some() && wanted == actual)
+            };
+            matches(*kind, packet.icmp_type) && matches(*code, packet.icmp_code)
+        })
+    }
+}
+
+/// Decides on packets against a set of rules and profile settings.
+#[derive(Debug)]
+pub struct Evaluator<'a> {
+    rules: Vec<CompiledRule<'a>>,
+    profiles: BTreeMap<FirewallProfile, FirewallProfileSettings>,
+}
+
+impl<'a> Evaluator<'a> {
+    /// Parse the rules' address, port and ICMP lists, failing on any that are invalid.
+    ///
+    /// Profiles missing from `profiles` have the settings of a fresh install.
+    pub fn new(
+        rules: &'a [FirewallRuleData],
+        profiles: BTreeMap<FirewallProfile, FirewallProfileSettings>,
+    ) -> Result<Self, std::io::Error> {
+        let rules = rules
+            .iter()
+            .map(|rule| {
+                Ok(CompiledRule {
+                    rule,
+                    local_ports: rule.local_port_list()?,
+                    remote_ports: rule.remote_port_list()?,
+                    local_addresses: rule.local_address_list()?,
+                    remote_addresses: rule.remote_address_list()?,
+                    icmp: rule
+                        .icmp_types_and_codes
+                        .as_deref()
+                        .map(parse_icmp)
+                        .transpose()?
+                        .flatten(),
+                    application: rule.application_name.as_deref().map(normalize_path),
+                    interface_types: rule
+                        .interface_types
+                        .as_deref()
+                        .filter(|types| !types.eq_ignore_ascii_case("All"))
+                        .map(|types| {
+                            types
+                                .split(',')
+                                .map(|kind| kind.trim().to_ascii_lowercase())
+                                .collect()
+                        }),
+                    bypass: false,
+                })
+            })
+            .collect::<Result<_, std::io::Error>>()?;
+        Ok(Evaluator { rules, profiles })
+    }
+
+    /// Mark the allow rules with an ID, or a name if they have no ID, as allowing authenticated packets to bypass
+    /// block rules.
+    ///
+    /// `FirewallRule` does not expose this, so it is given separately.
+    pub fn authenticated_bypass(mut self, rule: &str) -> Self {
+        for compiled in &mut self.rules {
+            let key = compiled.rule.id.as_deref().unwrap_or(&compiled.rule.name);
+            if key.eq_ignore_ascii_case(rule) && compiled.rule.action == FirewallAction::Allow {
+                compiled.bypass = true;
+            }
+        }
+        self
+    }
+
+    /// Decide on a packet.
+    pub fn evaluate(&self, packet: &Packet) -> Decision<'a> {
+        let settings = self
+            .profiles
+            .get(&packet.profile)
+            .copied()
+            .unwrap_or_default();
+        let decision = |action, reason, rule| Decision {
+            action,
+            reason,
+            rule,
+        };
+
+        if !settings.firewall_enabled {
+            return decision(FirewallAction::Allow, Reason::FirewallDisabled, None);
+        }
+        if packet.direction == FirewallRuleDirection::In && settings.block_all_inbound_traffic {
+            return decision(FirewallAction::Block, Reason::BlockAllInbound, None);
+        }
+
+        let matching: Vec<&CompiledRule<'a>> = self
+            .rules
+            .iter()
+            .filter(|compiled| compiled.matches(packet))
+            .collect();
+        let first = |wanted: &dyn Fn(&CompiledRule<'a>) -> bool| {
+            matching
+                .iter()
+                .find(|compiled| wanted(compiled))
+                .map(|compiled| compiled.rule)
+        };
+
+        if packet.authenticated {
+            if let Some(rule) = first(&|compiled| compiled.bypass) {
+                return decision(
+                    FirewallAction::Allow,
+                    Reason::AuthenticatedBypass,
+                    Some(rule),
+                );
+            }
+        }
+        if let Some(rule) = first(&|compiled| compiled.rule.action == FirewallAction::Block) {
+            return decision(FirewallAction::Block, Reason::BlockRule, Some(rule));
+        }
+        if let Some(rule) = first(&|compiled| compiled.rule.action == FirewallAction::Allow) {
+            return decision(FirewallAction::Allow, Reason::AllowRule, Some(rule));
+        }
+
+        let action = match packet.direction {
+            FirewallRuleDirection::Out => settings.default_outbound_action,
+            _ => settings.default_inbound_action,
+        };
+        decision(action, Reason::DefaultAction, None)
+    }
+}
+
+/// Parse `icmp_types_and_codes`, like `8:*,3:4`. `None` if it allows every message.
+#[allow(clippy::type_complexity)]
+fn parse_icmp(list: &str) -> Result<Option<Vec<(Option<u8>, Option<u8>)>>, std::io::Error> {
+    let invalid = || {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("invalid ICMP types and codes '{}'", list),
+        )
+    };
+    let part = |part: &str| -> Result<Option<u8>, std::io::Error> {
+        match part.trim() {
+            "*" => Ok(None),
+            number => number.parse().map(Some).map_err(|_| invalid()),
+        }
+    };
+
+    let mut entries = Vec::new();
+    for entry in list
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+    {
+        if entry == "*" || entry.eq_ignore_ascii_case("any") {
+            return Ok(None);
+        }
+        let mut parts = entry.split(':');
+        let kind = part(parts.next().unwrap_or_default())?;
+        let code = parts.next().map(part).transpose()?.flatten();
+        if parts.next().is_some() {
+            return Err(invalid());
+        }
+        entries.push((kind, code));
+    }
+    Ok(if entries.is_empty() {
+        None
+    } else {
+        Some(entries)
+    })
+}
+
+/// A service condition: none for any traffic, `*` for any service, or a service's name.
+fn service_matches(rule: Option<&str>, packet: Option<&str>) -> bool {
+    match rule {
+        None => true,
+        Some("*") => packet.is_some(),
+        Some(service) => packet.is_some_and(|packet| packet.eq_ignore_ascii_case(service)),
+    }
+}
+
+fn normalize_path(path: &str) -> String {
+    let mut path = path.trim().replace('/', "\\").to_ascii_lowercase();
+    for (variable, value) in ENVIRONMENT.iter() {
+        path = path.replace(variable, value);
+    }
+    path
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn address(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    fn inbound_tcp(port: u16) -> Packet {
+        Packet {
+            local_port: Some(port),
+            remote_port: Some(50_000),
+            ..Packet::new(
+                FirewallRuleDirection::In,
+                TCP,
+                address("10.0.0.1"),
+                address("10.0.0.5"),
+                FirewallProfile::DOMAIN,
+            )
+        }
+    }
+
+    fn allow(name: &str, port: &str) -> FirewallRuleData {
+        FirewallRuleData {
+            protocol: TCP,
+            local_ports: Some(port.to_string()),
+            ..FirewallRuleData::new(name)
+        }
+    }
+
+    fn block(name: &str, port: &str) -> FirewallRuleData {
+        FirewallRuleData {
+            action: FirewallAction::Block,
+            ..allow(name, port)
+        }
+    }
+
+    fn evaluate<'a>(rules: &'a [FirewallRuleData], packet: &Packet) -> Decision<'a> {
+        Evaluator::new(rules, BTreeMap::new())
+            .unwrap()
+            .evaluate(packet)
+    }
+
+    #[test]
+    fn follows_precedence() {
+        let rules = vec![
+            allow("Web", "80,443"),
+            block("No web from the lab", "80"),
+            allow("SMB", "445"),
+        ];
+        let mut profiles = BTreeMap::new();
+
+        let decision = evaluate(&rules, &inbound_tcp(443));
+        assert_eq!(
+            (
+                decision.action,
+                decision.reason,
+                decision.rule.map(|rule| rule.name.as_str())
+            ),
+            (FirewallAction::Allow, Reason::AllowRule, Some("Web"))
+        );
+        assert_eq!(decision.to_string(), "Allow by an allow rule 'Web'");
+
+        // Block rules win whatever the order.
+        let decision = evaluate(&rules, &inbound_tcp(80));
+        assert_eq!(
+            (decision.action, decision.reason),
+            (FirewallAction::Block, Reason::BlockRule)
+        );
+        assert_eq!(decision.rule.unwrap().name, "No web from the lab");
+
+        // Defaults, per direction.
+        let decision = evaluate(&rules, &inbound_tcp(22));
+        assert_eq!(
+            (decision.action, decision.reason, decision.rule),
+            (FirewallAction::Block, Reason::DefaultAction, None)
+        );
+        let outbound = Packet {
+            direction: FirewallRuleDirection::Out,
+            ..inbound_tcp(22)
+        };
+        assert_eq!(evaluate(&rules, &outbound).action, FirewallAction::Allow);
+        profiles.insert(
+            FirewallProfile::DOMAIN,
+            FirewallProfileSettings {
+                default_outbound_action: FirewallAction::Block,
+                ..FirewallProfileSettings::default()
+            },
+        );
+        let evaluator = Evaluator::new(&rules, profiles.clone()).unwrap();
+        assert_eq!(evaluator.evaluate(&outbound).action, FirewallAction::Block);
+        // Other profiles keep the defaults of a fresh install.
+        let public = Packet {
+            profile: FirewallProfile::PUBLIC,
+            ..outbound.clone()
+        };
+        assert_eq!(evaluator.evaluate(&public).action, FirewallAction::Allow);
+
+        // Block all inbound beats allow rules, but not outbound traffic.
+        profiles.insert(
+            FirewallProfile::DOMAIN,
+            FirewallProfileSettings {
+                block_all_inbound_traffic: true,
+                ..FirewallProfileSettings::default()
+            },
+        );
+        let evaluator = Evaluator::new(&rules, profiles.clone()).unwrap();
+        assert_eq!(
+            evaluator.evaluate(&inbound_tcp(445)).reason,
+            Reason::BlockAllInbound
+        );
+        assert_eq!(evaluator.evaluate(&outbound).action, FirewallAction::Allow);
+
+        // A disabled firewall allows everything.
+        profiles.insert(
+            FirewallProfile::DOMAIN,
+            FirewallProfileSettings {
+                firewall_enabled: false,
+                block_all_inbound_traffic: true,
+                ..FirewallProfileSettings::default()
+            },
+        );
+        let evaluator = Evaluator::new(&rules, profiles).unwrap();
+        let decision = evaluator.evaluate(&inbound_tcp(80));
+        assert_eq!(
+            (decision.action, decision.reason),
+            (FirewallAction::Allow, Reason::FirewallDisabled)
+        );
+    }
+
+    #[test]
+    fn authenticated_bypass_overrides_block_rules() {
+        let rules = vec![
+            block("Block admin", "3389"),
+            FirewallRuleData {
+                id: Some("{rdp-secure}".to_string()),
+                ..allow("Secure RDP", "3389")
+            },
+            block("Block bypass", "3390"),
+        ];
+        let evaluator = Evaluator::new(&rules, BTreeMap::new())
+            .unwrap()
+            .authenticated_bypass("{RDP-SECURE}")
+            .authenticated_bypass("Block bypass");
+
+        assert_eq!(
+            evaluator.evaluate(&inbound_tcp(3389)).reason,
+            Reason::BlockRule
+        );
+        let authenticated = Packet {
+            authenticated: true,
+            ..inbound_tcp(3389)
+        };
+        let decision = evaluator.evaluate(&authenticated);
+        assert_eq!(
+            (decision.action, decision.reason),
+            (FirewallAction::Allow, Reason::AuthenticatedBypass)
+        );
+        assert_eq!(decision.rule.unwrap().name, "Secure RDP");
+
+        // Only allow rules can bypass, and nothing bypasses block all inbound.
+        let authenticated = Packet {
+            authenticated: true,
+            ..inbound_tcp(3390)
+        };
+        assert_eq!(evaluator.evaluate(&authenticated).reason, Reason::BlockRule);
+        let mut profiles = BTreeMap::new();
+        profiles.insert(
+            FirewallProfile::DOMAIN,
+            FirewallProfileSettings {
+                block_all_inbound_traffic: true,
+                ..FirewallProfileSettings::default()
+            },
+        );
+        let evaluator = Evaluator::new(&rules, profiles)
+            .unwrap()
+            .authenticated_bypass("{rdp-secure}");
+        let authenticated = Packet {
+            authenticated: true,
+            ..inbound_tcp(3389)
+        };
+        assert_eq!(
+            evaluator.evaluate(&authenticated).reason,
+            Reason::BlockAllInbound
+        );
+    }
+
+    #[test]
+    fn matches_rule_conditions() {
+        let base = inbound_tcp(8080);
+        let matches = |rule: FirewallRuleData, packet: &Packet| {
+            let rules = [FirewallRuleData {
+                action: FirewallAction::Allow,
+                ..rule
+            }];
+            evaluate(&rules, packet).reason == Reason::AllowRule
+        };
+        let rule = || allow("Rule", "8080");
+
+        assert!(matches(rule(), &base));
+        assert!(!matches(
+            FirewallRuleData {
+                enabled: false,
+                ..rule()
+            },
+            &base
+        ));
+        assert!(!matches(
+            FirewallRuleData {
+                direction: FirewallRuleDirection::Out,
+                ..rule()
+            },
+            &base
+        ));
+
+        // Profiles.
+        assert!(matches(
+            FirewallRuleData {
+                profiles: FirewallProfile::DOMAIN | FirewallProfile::PRIVATE,
+                ..rule()
+            },
+            &base
+        ));
+        assert!(!matches(
+            FirewallRuleData {
+                profiles: FirewallProfile::PUBLIC,
+                ..rule()
+            },
+            &base
+        ));
+
+        // Protocols and ports.
+        assert!(!matches(
+            FirewallRuleData {
+                protocol: UDP,
+                ..rule()
+            },
+            &base
+        ));
+        assert!(matches(
+            FirewallRuleData {
+                protocol: PROTOCOL_ANY,
+                local_ports: None,
+                ..rule()
+            },
+            &base
+        ));
+        assert!(matches(allow("Range", "8000-8100"), &base));
+        assert!(!matches(allow("Range", "8000-8079"), &base));
+        assert!(!matches(allow("Keyword", "RPC"), &base));
+        assert!(matches(
+            FirewallRuleData {
+                remote_ports: Some("49152-65535".to_string()),
+                ..rule()
+            },
+            &base
+        ));
+        assert!(!matches(
+            FirewallRuleData {
+                remote_ports: Some("1-1024".to_string()),
+                ..rule()
+            },
+            &base
+        ));
+        let portless = Packet {
+            local_port: None,
+            ..base.clone()
+        };
+        assert!(!matches(rule(), &portless));
+        assert!(matches(
+            FirewallRuleData {
+                local_ports: None,
+                ..rule()
+            },
+            &portless
+        ));
+
+        // Addresses.
+        let remote = |addresses: &str| FirewallRuleData {
+            remote_addresses: Some(addresses.to_string()),
+            ..rule()
+        };
+        assert!(matches(remote("10.0.0.0/8"), &base));
+        assert!(matches(remote("192.168.0.1,10.0.0.1-10.0.0.9"), &base));
+        assert!(!matches(remote("192.168.0.0/16"), &base));
+        assert!(!matches(remote("LocalSubnet"), &base));
+        assert!(!matches(remote("fe80::/10"), &base));
+        assert!(matches(
+            FirewallRuleData {
+                local_addresses: Some("10.0.0.1".to_string()),
+                ..rule()
+            },
+            &base
+        ));
+        assert!(!matches(
+            FirewallRuleData {
+                local_addresses: Some("10.0.0.2".to_string()),
+                ..rule()
+            },
+            &base
+        ));
+    }
+
+    #[test]
+    fn matches_programs_and_interfaces() {
+        let base = Packet {
+            application: Some(r"C:\Windows\System32\svchost.exe".to_string()),
+            service: Some("Dnscache".to_string()),
+            interface_type: Some("Wireless".to_string()),
+            interface: Some("Wi-Fi".to_string()),
+            ..inbound_tcp(53)
+        };
+        let matches = |rule: FirewallRuleData, packet: &Packet| {
+            let rules = [rule];
+            evaluate(&rules, packet).reason == Reason::AllowRule
+        };
+        let rule = || allow("Rule", "53");
+
+        let program = |path: &str| FirewallRuleData {
+            application_name: Some(path.to_string()),
+            ..rule()
+        };
+        assert!(matches(
+            program(r"%SystemRoot%\system32\SVCHOST.EXE"),
+            &base
+        ));
+        assert!(matches(program(r"%windir%/System32/svchost.exe"), &base));
+        assert!(!matches(program(r"C:\Windows\System32\lsass.exe"), &base));
+        let without_program = Packet {
+            application: None,
+            ..base.clone()
+        };
+        assert!(!matches(
+            program(r"C:\Windows\System32\svchost.exe"),
+            &without_program
+        ));
+
+        let service = |name: &str| FirewallRuleData {
+            service_name: Some(name.to_string()),
+            ..rule()
+        };
+        assert!(matches(service("dnscache"), &base));
+        assert!(matches(service("*"), &base));
+        assert!(!matches(service("W32Time"), &base));
+        let without_service = Packet {
+            service: None,
+            ..base.clone()
+        };
+        assert!(!matches(service("*"), &without_service));
+
+        let types = |types: &str| FirewallRuleData {
+            interface_types: Some(types.to_string()),
+            ..rule()
+        };
+        assert!(matches(types("All"), &base));
+        assert!(matches(types("Lan, Wireless"), &base));
+        assert!(!matches(types("RemoteAccess"), &base));
+        let interfaces = |aliases: &[&str]| FirewallRuleData {
+            interfaces: Some(aliases.iter().map(|alias| alias.to_string()).collect()),
+            ..rule()
+        };
+        assert!(matches(interfaces(&["Ethernet", "wi-fi"]), &base));
+        assert!(!matches(interfaces(&["Ethernet"]), &base));
+
+        // Edge traversed packets only match allow rules that allow edge traversal, and any block rule.
+        let traversed = Packet {
+            edge_traversed: true,
+            ..base.clone()
+        };
+        assert!(!matches(rule(), &traversed));
+        let edge = FirewallRuleData {
+            edge_traversal: true,
+            ..rule()
+        };
+        assert!(matches(edge.clone(), &traversed));
+        let rules = [edge, block("Block", "53")];
+        let decision = evaluate(&rules, &traversed);
+        assert_eq!(
+            (decision.action, decision.reason),
+            (FirewallAction::Block, Reason::BlockRule)
+        );
+    }
+
+    #[test]
+    fn matches_icmp() {
+        let ping = Packet {
+            icmp_type: Some(8),
+            icmp_code: Some(0),
+            ..Packet::new(
+                FirewallRuleDirection::In,
+                ICMPV4,
+                address("10.0.0.1"),
+                address("10.0.0.5"),
+                FirewallProfile::PRIVATE,
+            )
+        };
+        let matches = |icmp: Option<&str>, packet: &Packet| {
+            let rules = [FirewallRuleData {
+                protocol: ICMPV4,
+                icmp_types_and_codes: icmp.map(str::to_string),
+                ..FirewallRuleData::new("ICMP")
+            }];
+            evaluate(&rules, packet).reason == Reason::AllowRule
+        };
+
+        assert!(matches(None, &ping));
+        assert!(matches(Some("*"), &ping));
+        assert!(matches(Some("8:*"), &ping));
+        assert!(matches(Some("3:4,8:0"), &ping));
+        assert!(matches(Some("8"), &ping));
+        assert!(!matches(Some("0:*"), &ping));
+        assert!(!matches(Some("8:1"), &ping));
+        let unknown = Packet {
+            icmp_type: None,
+            icmp_code: None,
+            ..ping.clone()
+        };
+        assert!(!matches(Some("8:*"), &unknown));
+        assert!(matches(Some("*:*"), &unknown));
+
+        // ICMPv6 rules do not match ICMPv4.
+        let rules = [FirewallRuleData {
+            protocol: ICMPV6,
+            ..FirewallRuleData::new("ICMPv6")
+        }];
+        assert_eq!(evaluate(&rules, &ping).reason, Reason::DefaultAction);
+
+        for invalid in &["8:x", "1:2:3", "300"] {
+            let rules = [FirewallRuleData {
+                icmp_types_and_codes: Some(invalid.to_string()),
+                ..FirewallRuleData::new("Bad")
+            }];
+            assert_eq!(
+                Evaluator::new(&rules, BTreeMap::new()).unwrap_err().kind(),
+                std::io::ErrorKind::InvalidInput
+            );
+        }
+        let rules = [allow("Bad", "80-")];
+        assert!(Evaluator::new(&rules, BTreeMap::new()).is_err());
+    }
+}
@@ -0,0 +1,540 @@
+//! Firewall events exported from the Windows event log as XML.
+//!
+//! Both `wevtutil qe <log> /f:xml` output, which is a run of `<Event>` elements, and Event Viewer's "Save as XML"
+//! files, which wrap them in `<Events>`, are understood. The events read are those of the
+//! `Microsoft-Windows-Windows Firewall With Advanced Security/Firewall` log, with the rule events under both their
+//! older IDs and the ones Windows 11 uses, and the Windows Filtering Platform audits of the Security log:
+//!
+//! | IDs | Event |
+//! |-----|-------|
+//! | 2004, 2097 | A rule was added |
+//! | 2005, 2099 | A rule was modified |
+//! | 2006, 2052 | A rule was deleted |
+//! | 2033 | All rules were deleted |
+//! | 2002, 2003 | A setting was changed |
+//! | 5152, 5157 | A packet or connection was blocked |
+//! | 5156 | A connection was permitted |
+//!
+//! Other events are kept with their data so nothing is lost silently.
+
+use crate::{
+    data::{
+        every_profile,
+        PROTOCOL_ANY,
+    },
+    firewall_log::LogTimestamp,
+    xml::{
+        self,
+        Element,
+    },
+    FirewallAction,
+    FirewallProfile,
+    FirewallRuleData,
+    FirewallRuleDirection,
+};
+use std::net::IpAddr;
+
+/// A connection or packet the Windows Filtering Platform allowed or blocked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionEvent {
+    pub action: FirewallAction,
+    pub direction: Option<FirewallRuleDirection>,
+    pub process_id: Option<u32>,
+    /// The application's path, in the device form WFP uses, like `\device\harddiskvolume2\app.exe`.
+    pub application: Option<String>,
+    pub protocol: Option<i32>,
+    pub source_address: Option<IpAddr>,
+    pub source_port: Option<u16>,
+    pub destination_address: Option<IpAddr>,
+    pub destination_port: Option<u16>,
+    /// The run-time ID of the WFP filter that decided.
+    pub filter_id: Option<u64>,
+    /// The WFP layer, as its message ID like `%%14610` or its name if the event was rendered.
+    pub layer: Option<String>,
+}
+
+/// What a firewall event records.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FirewallEvent {
+    RuleAdded(Box<FirewallRuleData>),
+    RuleModified(Box<FirewallRuleData>),
+    RuleDeleted {
+        id: String,
+        name: Option<String>,
+    },
+    AllRulesDeleted,
+    SettingChanged {
+        profiles: FirewallProfile,
+        /// The setting's number, like "1" for `EnableFirewall`.
+        setting: String,
+        value: Option<String>,
+    },
+    Connection(ConnectionEvent),
+    /// An event this module does not know, with its data.
+    Other(Vec<(String, String)>),
+}
+
+/// An event and where it came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EventRecord {
+    pub event_id: u32,
+    pub record_id: Option<u64>,
+    /// When the event was logged, in UTC.
+    pub time_created: Option<LogTimestamp>,
+    pub computer: Option<String>,
+    /// The user who made a change, as a SID or name.
+    pub modifying_user: Option<String>,
+    /// The application that made a change.
+    pub modifying_application: Option<String>,
+    pub event: FirewallEvent,
+}
+
+/// Parse every event in an export.
+pub fn parse_events(text: &str) -> Result<Vec<EventRecord>, std::io::Error> {
+    // `wevtutil` writes events one after another with no root, so give them one.
+    let text = text.trim_start_matches('\u{feff}').trim_start();
+    let text = if text.starts_with("<?") {
+        text.find("?>").map_or(text, |end| &text[end + 2..])
+    } else {
+        text
+    };
+    let root = xml::parse(&format!("<Events>{}</Events>", text))?;
+
+    let mut events = Vec::new();
+    for element in root.elements() {
+        match element.local_name() {
+            "Event" => events.push(parse_event(element)?),
+            "Events" => {
+                for event in element
+                    .elements()
+                    .filter(|event| event.local_name() == "Event")
+                {
+                    events.push(parse_event(event)?);
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(events)
+}
+
+/// Parse a single `<Event>` element.
+fn parse_event(event: &Element) -> Result<EventRecord, std::io::Error> {
+    let system = event
+        .child("System")
+        .ok_or_else(|| invalid_data("an event has no System element"))?;
+    let text = |name| {
+        system
+            .child(name)
+            .map(|element| element.text().trim().to_string())
+            .filter(|text| !text.is_empty())
+    };
+    let event_id = text("EventID")
+        .and_then(|id| id.parse().ok())
+        .ok_or_else(|| invalid_data("an event has no event ID"))?;
+    let record_id = text("EventRecordID").and_then(|id| id.parse().ok());
+    let time_created = system
+        .child("TimeCreated")
+        .and_then(|time| time.attribute("SystemTime"))
+        .map(parse_system_time)
+        .transpose()?;
+
+    let data = Data(
+        event
+            .child("EventData")
+            .or_else(|| {
+                event
+                    .child("UserData")
+                    .and_then(|user| user.elements().next())
+            })
+            .map(|data| {
+                data.elements()
+                    .map(|element| {
+                        let name = element
+                            .attribute("Name")
+                            .unwrap_or_else(|| element.local_name());
+                        (name.to_string(), element.text().trim().to_string())
+                    })
+                    .collect()
+            })
+            .unwrap_or_default(),
+    );
+    let context = |error: std::io::Error| {
+        std::io::Error::new(error.kind(), format!("event {}: {}", event_id, error))
+    };
+
+    let event = match event_id {
+        2004 | 2097 => FirewallEvent::RuleAdded(Box::new(data.rule().map_err(context)?)),
+        2005 | 2099 => FirewallEvent::RuleModified(Box::new(data.rule().map_err(context)?)),
+        2006 | 2052 => FirewallEvent::RuleDeleted {
+            id: data
+                .get("RuleId")
+                .ok_or_else(|| context(invalid_data("no RuleId")))?
+                .to_string(),
+            name: data.get("RuleName").map(str::to_string),
+        },
+        2033 => FirewallEvent::AllRulesDeleted,
+        2002 | 2003 => FirewallEvent::SettingChanged {
+            profiles: data
+                .number("Profiles")
+                .map_err(context)?
+                .map_or(FirewallProfile::ALL, |bits| {
+                    FirewallProfile::from_bits_truncate(bits as u32)
+                }),
+            setting: data.get("SettingType").unwrap_or_default().to_string(),
+            value: data
+                .get("SettingValueString")
+                .or_else(|| data.get("SettingValue"))
+                .map(str::to_string),
+        },
+        5152 | 5156 | 5157 => FirewallEvent::Connection(
+            data.connection(if event_id == 5156 {
+                FirewallAction::Allow
+            } else {
+                FirewallAction::Block
+            })
+            .map_err(context)?,
+        ),
+        _ => FirewallEvent::Other(data.0.clone()),
+    };
+
+    Ok(EventRecord {
+        event_id,
+        record_id,
+        time_created,
+        computer: text("Computer"),
+        modifying_user: data.get("ModifyingUser").map(str::to_string),
+        modifying_application: data.get("ModifyingApplication").map(str::to_string),
+        event,
+    })
+}
+
+/// The named values of an event.
+struct Data(Vec<(String, String)>);
+
+impl Data {
+    /// A value, unless it is empty.
+    fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+            .filter(|value| !value.is_empty())
+    }
+
+    /// A value that means any when it is `*`.
+    fn list(&self, name: &str) -> Option<String> {
+        self.get(name)
+            .filter(|value| *value != "*")
+            .map(str::to_string)
+    }
+
+    /// A number, in decimal or with a `0x` prefix in hex.
+    fn number(&self, name: &str) -> Result<Option<u64>, std::io::Error> {
+        self.get(name)
+            .map(|value| {
+                let parsed = match value.strip_prefix("0x") {
+                    Some(hex) => u64::from_str_radix(hex, 16),
+                    None => value.parse(),
+                };
+                parsed.map_err(|_| invalid_data(&format!("{} '{}' is not a number", name, value)))
+            })
+            .transpose()
+    }
+
+    fn parsed<T: std::str::FromStr>(&self, name: &str) -> Result<Option<T>, std::io::Error> {
+        self.get(name)
+            .map(|value| {
+                value
+                    .parse()
+                    .map_err(|_| invalid_data(&format!("invalid {} '{}'", name, value)))
+            })
+            .transpose()
+    }
+
+    /// The rule described by a rule added or modified event.
+    ///
+    /// Directions and actions are the `FW_DIRECTION` and `FW_RULE_ACTION` numbers of the firewall's own API.
+    fn rule(&self) -> Result<FirewallRuleData, std::io::Error> {
+        let id = self
+            .get("RuleId")
+            .ok_or_else(|| invalid_data("no RuleId"))?;
+        let direction = match self.number("Direction")? {
+            Some(1) | None => FirewallRuleDirection::In,
+            Some(2) => FirewallRuleDirection::Out,
+            Some(direction) => {
+                return Err(invalid_data(&format!("invalid Direction '{}'", direction)))
+            }
+        };
+        let action = match self.number("Action")? {
+            Some(1) | Some(3) | None => FirewallAction::Allow,
+            Some(2) => FirewallAction::Block,
+            Some(action) => return Err(invalid_data(&format!("invalid Action '{}'", action))),
+        };
+        // Like `FirewallRule`, a rule for every profile has `ALL`.
+        let profiles = self
+            .number("Profiles")?
+            .map_or(FirewallProfile::ALL, |bits| {
+                FirewallProfile::from_bits_truncate(bits as u32)
+            });
+        let profiles = if profiles.contains(every_profile()) {
+            FirewallProfile::ALL
+        } else {
+            profiles
+        };
+        Ok(FirewallRuleData {
+            id: Some(id.to_string()),
+            name: self.get("RuleName").unwrap_or(id).to_string(),
+            application_name: self.list("ApplicationPath"),
+            service_name: self.list("ServiceName"),
+            protocol: self
+                .number("Protocol")?
+                .map_or(PROTOCOL_ANY, |protocol| protocol as i32),
+            local_ports: self.list("LocalPorts"),
+            remote_ports: self.list("RemotePorts"),
+            local_addresses: self.list("LocalAddresses"),
+            remote_addresses: self.list("RemoteAddresses"),
+            direction,
+            interface_types: Some("All".to_string()),
+            enabled: self.number("Active")? != Some(0),
+            grouping: self
+                .list("EmbeddedContext")
+                .or_else(|| self.list("RuleGroup")),
+            profiles,
+            edge_traversal: self.number("EdgeTraversal")?.is_some_and(|edge| edge != 0),
+            action,
+            ..FirewallRuleData::default()
+        })
+    }
+
+    /// The connection described by a WFP audit event.
+    fn connection(&self, action: FirewallAction) -> Result<ConnectionEvent, std::io::Error> {
+        let direction = self.get("Direction").and_then(|direction| match direction {
+            "%%14592" => Some(FirewallRuleDirection::In),
+            "%%14593" => Some(FirewallRuleDirection::Out),
+            rendered => rendered.parse().ok(),
+        });
+        Ok(ConnectionEvent {
+            action,
+            direction,
+            process_id: self.parsed("ProcessID")?,
+            application: self.get("Application").map(str::to_string),
+            protocol: self.number("Protocol")?.map(|protocol| protocol as i32),
+            source_address: self.parsed("SourceAddress")?,
+            source_port: self.parsed("SourcePort")?,
+            destination_address: self.parsed("DestAddress")?,
+            destination_port: self.parsed("DestPort")?,
+            filter_id: self.number("FilterRTID")?,
+            layer: self.get("LayerName").map(str::to_string),
+        })
+    }
+}
+
+/// Parse a `SystemTime` like "2024-05-01T13:02:59.1234567Z", dropping fractions of a second.
+fn parse_system_time(time: &str) -> Result<LogTimestamp, std::io::Error> {
+    let (date, time) = time
+        .split_once('T')
+        .ok_or_else(|| invalid_data(&format!("invalid SystemTime '{}'", time)))?;
+    let time = time.trim_end_matches('Z');
+    LogTimestamp::parse(date, time.split('.').next().unwrap_or(time))
+}
+
+fn invalid_data(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn event(id: u32, data: &[(&str, &str)]) -> String {
+        let data: String = data
+            .iter()
+            .map(|(name, value)| format!("<Data Name='{}'>{}</Data>", name, value))
+            .collect();
+        format!(
+            "<Event xmlns='http://schemas.microsoft.com/win/2004/08/events/event'><System>\
+             <Provider Name='Microsoft-Windows-Windows Firewall With Advanced Security'/><EventID>{}</EventID>\
+             <TimeCreated SystemTime='2024-05-01T13:02:59.1234567Z'/><EventRecordID>{}</EventRecordID>\
+             <Computer>web01.corp.example</Computer></System><EventData>{}</EventData></Event>",
+            id,
+            id * 10,
+            data
+        )
+    }
+
+    #[test]
+    fn reads_rule_events() {
+        let added = event(
+            2004,
+            &[
+                ("RuleId", "{web}"),
+                ("RuleName", "Web &amp; API"),
+                ("EmbeddedContext", "Web Services"),
+                ("ApplicationPath", ""),
+                ("ServiceName", ""),
+                ("Direction", "1"),
+                ("Protocol", "6"),
+                ("LocalPorts", "80,443"),
+                ("RemotePorts", "*"),
+                ("Action", "3"),
+                ("Profiles", "5"),
+                ("LocalAddresses", "*"),
+                ("RemoteAddresses", "10.0.0.0/8"),
+                ("Active", "1"),
+                ("EdgeTraversal", "0"),
+                ("ModifyingUser", "S-1-5-21-1-500"),
+                ("ModifyingApplication", "C:\\Windows\\System32\\netsh.exe"),
+            ],
+        );
+        let modified = event(
+            2099,
+            &[
+                ("RuleId", "{web}"),
+                ("RuleName", "Web"),
+                ("Direction", "2"),
+                ("Action", "2"),
+                ("Profiles", "7"),
+                ("RuleGroup", "Legacy"),
+                ("Active", "0"),
+            ],
+        );
+        let deleted = event(2006, &[("RuleId", "{web}"), ("RuleName", "Web")]);
+        let text = format!(
+            "<?xml version='1.0' encoding='UTF-8'?>\r\n<Events>{}{}{}{}</Events>",
+            added,
+            modified,
+            deleted,
+            event(2033, &[])
+        );
+
+        let events = parse_events(&text).unwrap();
+        assert_eq!(events.len(), 4);
+        assert_eq!(events[0].event_id, 2004);
+        assert_eq!(events[0].record_id, Some(20040));
+        assert_eq!(
+            events[0].time_created.unwrap().to_string(),
+            "2024-05-01 13:02:59"
+        );
+        assert_eq!(events[0].computer.as_deref(), Some("web01.corp.example"));
+        assert_eq!(events[0].modifying_user.as_deref(), Some("S-1-5-21-1-500"));
+        assert_eq!(
+            events[0].event,
+            FirewallEvent::RuleAdded(Box::new(FirewallRuleData {
+                id: Some("{web}".to_string()),
+                protocol: 6,
+                local_ports: Some("80,443".to_string()),
+                remote_addresses: Some("10.0.0.0/8".to_string()),
+                interface_types: Some("All".to_string()),
+                profiles: FirewallProfile::DOMAIN | FirewallProfile::PUBLIC,
+                grouping: Some("Web Services".to_string()),
+                ..FirewallRuleData::new("Web & API")
+            }))
+        );
+        match &events[1].event {
+            FirewallEvent::RuleModified(rule) => {
+                assert_eq!(rule.direction, FirewallRuleDirection::Out);
+                assert_eq!(rule.action, FirewallAction::Block);
+                assert!(!rule.enabled);
+                assert_eq!(rule.protocol, PROTOCOL_ANY);
+                assert_eq!(rule.profiles, FirewallProfile::ALL);
+                assert_eq!(rule.grouping.as_deref(), Some("Legacy"));
+            }
+            event => panic!("unexpected {:?}", event),
+        }
+        assert_eq!(
+            events[2].event,
+            FirewallEvent::RuleDeleted {
+                id: "{web}".to_string(),
+                name: Some("Web".to_string())
+            }
+        );
+        assert_eq!(events[3].event, FirewallEvent::AllRulesDeleted);
+
+        let bad = event(2004, &[("RuleId", "{x}"), ("Direction", "7")]);
+        let error = parse_events(&bad).unwrap_err();
+        assert_eq!(error.to_string(), "event 2004: invalid Direction '7'");
+    }
+
+    #[test]
+    fn reads_wfp_and_other_events() {
+        // As `wevtutil` writes them, without a root element.
+        let text = format!(
+            "{}\r\n{}\r\n{}\r\n{}",
+            event(
+                5157,
+                &[
+                    ("ProcessID", "4"),
+                    ("Application", "System"),
+                    ("Direction", "%%14592"),
+                    ("SourceAddress", "10.0.0.5"),
+                    ("SourcePort", "51234"),
+                    ("DestAddress", "10.0.0.1"),
+                    ("DestPort", "445"),
+                    ("Protocol", "6"),
+                    ("FilterRTID", "73921"),
+                    ("LayerName", "%%14610"),
+                ]
+            ),
+            event(
+                5156,
+                &[
+                    ("Direction", "Outbound"),
+                    ("DestAddress", "fe80::1"),
+                    ("Protocol", "17")
+                ]
+            ),
+            event(
+                2003,
+                &[
+                    ("Profiles", "4"),
+                    ("SettingType", "1"),
+                    ("SettingValue", "0"),
+                ]
+            ),
+            event(2010, &[("InterfaceGuid", "{nic}")])
+        );
+
+        let events = parse_events(&text).unwrap();
+        assert_eq!(
+            events[0].event,
+            FirewallEvent::Connection(ConnectionEvent {
+                action: FirewallAction::Block,
+                direction: Some(FirewallRuleDirection::In),
+                process_id: Some(4),
+                application: Some("System".to_string()),
+                protocol: Some(6),
+                source_address: Some("10.0.0.5".parse().unwrap()),
+                source_port: Some(51234),
+                destination_address: Some("10.0.0.1".parse().unwrap()),
+                destination_port: Some(445),
+                filter_id: Some(73921),
+                layer: Some("%%14610".to_string()),
+            })
+        );
+        match &events[1].event {
+            FirewallEvent::Connection(connection) => {
+                assert_eq!(connection.action, FirewallAction::Allow);
+                assert_eq!(connection.direction, Some(FirewallRuleDirection::Out));
+                assert_eq!(
+                    connection.destination_address,
+                    Some("fe80::1".parse().unwrap())
+                );
+            }
+            event => panic!("unexpected {:?}", event),
+        }
+        assert_eq!(
+            events[2].event,
+            FirewallEvent::SettingChanged {
+                profiles: FirewallProfile::PUBLIC,
+                setting: "1".to_string(),
+                value: Some("0".to_string()),
+            }
+        );
+        assert_eq!(
+            events[3].event,
+            FirewallEvent::Other(vec![("InterfaceGuid".to_string(), "{nic}".to_string())])
+        );
+        assert!(parse_events("<Event><EventData/></Event>").is_err());
+    }
+}
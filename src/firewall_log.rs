@@ -0,0 +1,573 @@
+//! The W3C extended log Windows Firewall writes to `pfirewall.log`.
+//!
+//! Lines are parsed one at a time in the column order of the last `#Fields:` header, or of `DEFAULT_FIELDS` before
+//! any. Values of `-` are absent, and so are the values missing from a truncated line, which is marked as such.
+//! The last value of a truncated line may have been cut off partway, so it is absent too.
+//! Fields the parser does not know are kept as text, so logs from newer Windows versions still parse.
+//!
+//! `follow` reads a log as it is written, across rotations, and `analytics` sums logs up.
+
+pub mod analytics;
+pub mod follow;
+
+use crate::{
+    data::parse_protocol,
+    FirewallAction,
+    FirewallRuleDirection,
+};
+use std::{
+    io::BufRead,
+    net::IpAddr,
+};
+
+/// The fields of a log written by current Windows versions, in the order they are written.
+pub const DEFAULT_FIELDS: [&str; 18] = [
+    "date", "time", "action", "protocol", "src-ip", "dst-ip", "src-port", "dst-port", "size",
+    "tcpflags", "tcpsyn", "tcpack", "tcpwin", "icmptype", "icmpcode", "info", "path", "pid",
+];
+
+/// What the firewall did with a connection or packet.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum LogAction {
+    Allow,
+    Drop,
+    Open,
+    OpenInbound,
+    Close,
+    /// Events were dropped before reaching the log; `info` holds how many.
+    InfoEventsLost,
+    Other(String),
+}
+
+impl LogAction {
+    /// The rule action the log action comes from, for allowed and dropped connections.
+    pub fn firewall_action(&self) -> Option<FirewallAction> {
+        match self {
+            LogAction::Allow => Some(FirewallAction::Allow),
+            LogAction::Drop => Some(FirewallAction::Block),
+            _ => None,
+        }
+    }
+}
+
+/// Formats as the log does, like "ALLOW" or "INFO-EVENTS-LOST".
+impl std::fmt::Display for LogAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            LogAction::Allow => "ALLOW",
+            LogAction::Drop => "DROP",
+            LogAction::Open => "OPEN",
+            LogAction::OpenInbound => "OPEN-INBOUND",
+            LogAction::Close => "CLOSE",
+            LogAction::InfoEventsLost => "INFO-EVENTS-LOST",
+            LogAction::Other(action) => action,
+        })
+    }
+}
+
+impl std::str::FromStr for LogAction {
+    type Err = std::io::Error;
+
+    /// Parses the log's vocabulary case-insensitively. Anything else is `Other`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.trim().to_ascii_uppercase().as_str() {
+            "ALLOW" => LogAction::Allow,
+            "DROP" => LogAction::Drop,
+            "OPEN" => LogAction::Open,
+            "OPEN-INBOUND" => LogAction::OpenInbound,
+            "CLOSE" => LogAction::Close,
+            "INFO-EVENTS-LOST" => LogAction::InfoEventsLost,
+            _ => LogAction::Other(s.trim().to_string()),
+        })
+    }
+}
+
+/// Which way a packet was going.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum LogPath {
+    Send,
+    Receive,
+    Forward,
+    Unknown,
+    Other(String),
+}
+
+impl LogPath {
+    /// The direction of the rules that apply to the packet, for sent and received ones.
+    pub fn direction(&self) -> Option<FirewallRuleDirection> {
+        match self {
+            LogPath::Send => Some(FirewallRuleDirection::Out),
+            LogPath::Receive => Some(FirewallRuleDirection::In),
+            _ => None,
+        }
+    }
+}
+
+/// Formats as the log does, like "RECEIVE".
+impl std::fmt::Display for LogPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            LogPath::Send => "SEND",
+            LogPath::Receive => "RECEIVE",
+            LogPath::Forward => "FORWARD",
+            LogPath::Unknown => "UNKNOWN",
+            LogPath::Other(path) => path,
+        })
+    }
+}
+
+impl std::str::FromStr for LogPath {
+    type Err = std::io::Error;
+
+    /// Parses the log's vocabulary case-insensitively. Anything else is `Other`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.trim().to_ascii_uppercase().as_str() {
+            "SEND" => LogPath::Send,
+            "RECEIVE" => LogPath::Receive,
+            "FORWARD" => LogPath::Forward,
+            "UNKNOWN" => LogPath::Unknown,
+            _ => LogPath::Other(s.trim().to_string()),
+        })
+    }
+}
+
+/// A date and time as the log writes them, in the host's local time unless the header says otherwise.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct LogTimestamp {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl LogTimestamp {
+    /// Parse a `date` and `time` pair, like "2024-05-01" and "13:02:59".
+    pub fn parse(date: &str, time: &str) -> Result<Self, std::io::Error> {
+        let numbers = |s: &str, separator| -> Option<Vec<u16>> {
+            s.split(separator).map(|n| n.parse().ok()).collect()
+        };
+        let invalid = || {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("invalid timestamp '{} {}'", date, time),
+            )
+        };
+        let (date, time) = match (numbers(date, '-'), numbers(time, ':')) {
+            (Some(date), Some(time)) if date.len() == 3 && time.len() == 3 => (date, time),
+            _ => return Err(invalid()),
+        };
+        let timestamp = LogTimestamp {
+            year: date[0],
+            month: date[1] as u8,
+            day: date[2] as u8,
+            hour: time[0] as u8,
+            minute: time[1] as u8,
+            second: time[2] as u8,
+        };
+        if !(1..=12).contains(&date[1])
+            || !(1..=31).contains(&date[2])
+            || time[0] > 23
+            || time[1] > 59
+            || time[2] > 60
+        {
+            return Err(invalid());
+        }
+        Ok(timestamp)
+    }
+
+    /// Seconds since 1970-01-01 00:00:00 in the same time zone as the timestamp.
+    pub fn seconds_since_epoch(&self) -> i64 {
+        // Days from the civil calendar, counting years from March so leap days come last.
+        let year = i64::from(self.year) - if self.month <= 2 { 1 } else { 0 };
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let month = i64::from(self.month);
+        let day_of_year =
+            (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + i64::from(self.day) - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146_097 + day_of_era - 719_468;
+        days * 86_400
+            + i64::from(self.hour) * 3_600
+            + i64::from(self.minute) * 60
+            + i64::from(self.second)
+    }
+
+    /// The timestamp a number of seconds after 1970-01-01 00:00:00, for years 0 to 65535.
+    pub fn from_seconds_since_epoch(seconds: i64) -> Self {
+        // The inverse of `seconds_since_epoch`.
+        let days = seconds.div_euclid(86_400) + 719_468;
+        let time = seconds.rem_euclid(86_400);
+        let era = days.div_euclid(146_097);
+        let day_of_era = days - era * 146_097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let shifted_month = (5 * day_of_year + 2) / 153;
+        let month = shifted_month + if shifted_month < 10 { 3 } else { -9 };
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+        LogTimestamp {
+            year: year.clamp(0, i64::from(u16::MAX)) as u16,
+            month: month as u8,
+            day: (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u8,
+            hour: (time / 3_600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+        }
+    }
+}
+
+/// Formats as "2024-05-01 13:02:59".
+impl std::fmt::Display for LogTimestamp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+impl std::str::FromStr for LogTimestamp {
+    type Err = std::io::Error;
+
+    /// Parses the date and time separated by whitespace, as `Display` writes them.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split_whitespace();
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(date), Some(time), None) => Self::parse(date, time),
+            _ => Self::parse(s, ""),
+        }
+    }
+}
+
+/// One line of the log.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LogRecord {
+    pub timestamp: Option<LogTimestamp>,
+    pub action: Option<LogAction>,
+    pub protocol: Option<i32>,
+    pub source_address: Option<IpAddr>,
+    pub destination_address: Option<IpAddr>,
+    pub source_port: Option<u16>,
+    pub destination_port: Option<u16>,
+    /// The packet size in bytes.
+    pub size: Option<u32>,
+    /// The TCP flags as the log writes them, like "AS" for ACK and SYN.
+    pub tcp_flags: Option<String>,
+    pub tcp_syn: Option<u32>,
+    pub tcp_ack: Option<u32>,
+    pub tcp_window: Option<u32>,
+    pub icmp_type: Option<u8>,
+    pub icmp_code: Option<u8>,
+    pub info: Option<String>,
+    pub path: Option<LogPath>,
+    pub pid: Option<u32>,
+    /// Fields the parser does not know, with their values.
+    pub other_fields: Vec<(String, String)>,
+    /// Whether the line had fewer values than there are fields.
+    pub truncated: bool,
+}
+
+/// Parses lines, keeping track of the `#Fields:` header.
+#[derive(Debug, Clone)]
+pub struct LogParser {
+    fields: Vec<String>,
+}
+
+impl Default for LogParser {
+    fn default() -> Self {
+        LogParser {
+            fields: DEFAULT_FIELDS
+                .iter()
+                .map(|field| field.to_string())
+                .collect(),
+        }
+    }
+}
+
+impl LogParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The fields values are currently read as.
+    pub fn fields(&self) -> &[String] {
+        &self.fields
+    }
+
+    /// Parse a line, or take note of a header. Headers and blank lines give `None`.
+    pub fn parse_line(&mut self, line: &str) -> Result<Option<LogRecord>, std::io::Error> {
+        let line = line.trim_end_matches(['\r', '\n']);
+        if let Some(header) = line.strip_prefix('#') {
+            if let Some(fields) = header.strip_prefix("Fields:") {
+                self.fields = fields.split_whitespace().map(str::to_string).collect();
+            }
+            return Ok(None);
+        }
+        if line.trim().is_empty() {
+            return Ok(None);
+        }
+
+        let values: Vec<&str> = line.split_whitespace().collect();
+        let mut record = LogRecord {
+            truncated: values.len() < self.fields.len(),
+            ..LogRecord::default()
+        };
+        let mut date = None;
+        let mut time = None;
+        for (index, value) in values.iter().enumerate() {
+            let field = match self.fields.get(index) {
+                Some(field) => field.as_str(),
+                None => {
+                    return Err(invalid_value(
+                        "line",
+                        line,
+                        "has more values than there are fields",
+                    ))
+                }
+            };
+            // `-` is no value, and the last value of a truncated line may have been cut off partway through.
+            if *value == "-" || (record.truncated && index == values.len() - 1) {
+                continue;
+            }
+            match field {
+                "date" => date = Some(*value),
+                "time" => time = Some(*value),
+                "action" => record.action = Some(value.parse()?),
+                "protocol" => {
+                    record.protocol = Some(if value.eq_ignore_ascii_case("ICMP") {
+                        1
+                    } else {
+                        parse_protocol(value)
+                            .ok_or_else(|| invalid_value(field, value, "is not a protocol"))?
+                    })
+                }
+                "src-ip" => record.source_address = Some(parse(field, value)?),
+                "dst-ip" => record.destination_address = Some(parse(field, value)?),
+                "src-port" => record.source_port = Some(parse(field, value)?),
+                "dst-port" => record.destination_port = Some(parse(field, value)?),
+                "size" => record.size = Some(parse(field, value)?),
+                "tcpflags" => record.tcp_flags = Some(value.to_string()),
+                "tcpsyn" => record.tcp_syn = Some(parse(field, value)?),
+                "tcpack" => record.tcp_ack = Some(parse(field, value)?),
+                "tcpwin" => record.tcp_window = Some(parse(field, value)?),
+                "icmptype" => record.icmp_type = Some(parse(field, value)?),
+                "icmpcode" => record.icmp_code = Some(parse(field, value)?),
+                "info" => record.info = Some(value.to_string()),
+                "path" => record.path = Some(value.parse()?),
+                "pid" => record.pid = Some(parse(field, value)?),
+                _ => record
+                    .other_fields
+                    .push((field.to_string(), value.to_string())),
+            }
+        }
+        if let (Some(date), Some(time)) = (date, time) {
+            record.timestamp = Some(LogTimestamp::parse(date, time)?);
+        }
+        Ok(Some(record))
+    }
+}
+
+/// Reads records from a log, one line at a time.
+///
+/// A line that fails to parse gives an error naming its line number, and reading carries on with the next.
+#[derive(Debug)]
+pub struct LogReader<R> {
+    reader: R,
+    parser: LogParser,
+    line_number: usize,
+    buffer: Vec<u8>,
+}
+
+impl<R: BufRead> LogReader<R> {
+    pub fn new(reader: R) -> Self {
+        LogReader {
+            reader,
+            parser: LogParser::new(),
+            line_number: 0,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// The number of the last line read.
+    pub fn line_number(&self) -> usize {
+        self.line_number
+    }
+}
+
+impl<R: BufRead> Iterator for LogReader<R> {
+    type Item = Result<LogRecord, std::io::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            self.buffer.clear();
+            match self.reader.read_until(b'\n', &mut self.buffer) {
+                Ok(0) => return None,
+                Ok(_) => {}
+                Err(error) => return Some(Err(error)),
+            }
+            self.line_number += 1;
+
+            let line = String::from_utf8_lossy(&self.buffer);
+            match self.parser.parse_line(&line) {
+                Ok(Some(record)) => return Some(Ok(record)),
+                Ok(None) => {}
+                Err(error) => {
+                    return Some(Err(std::io::Error::new(
+                        error.kind(),
+                        format!("line {}: {}", self.line_number, error),
+                    )))
+                }
+            }
+        }
+    }
+}
+
+fn parse<T: std::str::FromStr>(field: &str, value: &str) -> Result<T, std::io::Error> {
+    value
+        .parse()
+        .map_err(|_| invalid_value(field, value, "is not valid"))
+}
+
+fn invalid_value(field: &str, value: &str, problem: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("{} '{}' {}", field, value, problem),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const LOG: &str = "#Version: 1.5\r\n\
+        #Software: Microsoft Windows Firewall\r\n\
+        #Time Format: Local\r\n\
+        #Fields: date time action protocol src-ip dst-ip src-port dst-port size tcpflags tcpsyn tcpack tcpwin icmptype icmpcode info path pid\r\n\
+        \r\n\
+        2024-05-01 13:02:59 DROP TCP 10.0.0.5 10.0.0.1 51234 3389 52 S 1234567 0 64240 - - - RECEIVE 4\r\n\
+        2024-05-01 13:03:00 ALLOW ICMPv6 fe80::1 ff02::1 - - 72 - - - - 135 0 - SEND 0\r\n\
+        2024-05-01 13:03:01 INFO-EVENTS-LOST - - - - - - - - - - - - 12 - -\r\n\
+        2024-05-01 13:03:02 ALLOW UDP 10.0.0.5 10.0.0.53 53001 99999 - - - - - - - - SEND 1200\r\n\
+        2024-05-01 13:03:03 ALLOW UDP 10.0.0.5 10.0.0.53 53002 53 0 - - - - - - - SEND 1200\r\n\
+        2024-05-01 13:03:04 DROP TCP 10.0.0.9 10.0.0.1 5";
+
+    #[test]
+    fn reads_logs() {
+        let mut reader = LogReader::new(LOG.as_bytes());
+
+        let drop = reader.next().unwrap().unwrap();
+        assert_eq!(drop.timestamp.unwrap().to_string(), "2024-05-01 13:02:59");
+        assert_eq!(drop.action, Some(LogAction::Drop));
+        assert_eq!(
+            drop.action.as_ref().and_then(LogAction::firewall_action),
+            Some(FirewallAction::Block)
+        );
+        assert_eq!(drop.protocol, Some(6));
+        assert_eq!(drop.source_address, Some("10.0.0.5".parse().unwrap()));
+        assert_eq!(drop.destination_port, Some(3389));
+        assert_eq!(drop.tcp_flags.as_deref(), Some("S"));
+        assert_eq!(drop.tcp_window, Some(64240));
+        assert_eq!(
+            drop.path.as_ref().and_then(LogPath::direction),
+            Some(FirewallRuleDirection::In)
+        );
+        assert_eq!(drop.pid, Some(4));
+        assert!(!drop.truncated);
+
+        let icmp = reader.next().unwrap().unwrap();
+        assert_eq!(icmp.protocol, Some(58));
+        assert_eq!((icmp.icmp_type, icmp.icmp_code), (Some(135), Some(0)));
+        assert_eq!(icmp.source_port, None);
+
+        let lost = reader.next().unwrap().unwrap();
+        assert_eq!(lost.action, Some(LogAction::InfoEventsLost));
+        assert_eq!(lost.info.as_deref(), Some("12"));
+
+        // A bad value fails its line only.
+        let error = reader.next().unwrap().unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert!(error.to_string().starts_with("line 9: dst-port '99999'"));
+        assert_eq!(reader.next().unwrap().unwrap().destination_port, Some(53));
+
+        let truncated = reader.next().unwrap().unwrap();
+        assert!(truncated.truncated);
+        assert_eq!(
+            truncated.destination_address,
+            Some("10.0.0.1".parse().unwrap())
+        );
+        assert_eq!(truncated.source_port, None);
+        assert_eq!(truncated.destination_port, None);
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn follows_the_fields_header() {
+        let mut parser = LogParser::new();
+        assert_eq!(parser.fields().len(), DEFAULT_FIELDS.len());
+        let record = parser
+            .parse_line(
+                "2019-01-02 03:04:05 OPEN-INBOUND TCP 1.2.3.4 5.6.7.8 1 2 0 - - - - - - - RECEIVE",
+            )
+            .unwrap()
+            .unwrap();
+        assert_eq!(record.action, Some(LogAction::OpenInbound));
+        assert!(record.truncated);
+
+        let record = parser
+            .parse_line("2024-05-01 10:00:01 DROP TCP 10.0")
+            .unwrap()
+            .unwrap();
+        assert!(record.truncated);
+        assert_eq!(record.protocol, Some(6));
+        assert_eq!(record.source_address, None);
+        assert!(parser
+            .parse_line("2024-05-01 10:00:01 DROP TCP 10.0 10.0.0.1")
+            .is_err());
+        let record = parser.parse_line("2024-05-01 13:0").unwrap().unwrap();
+        assert!(record.truncated);
+        assert_eq!(record.timestamp, None);
+
+        assert_eq!(
+            parser.parse_line("#Fields: action path rule-id\n").unwrap(),
+            None
+        );
+        let record = parser
+            .parse_line("blocked FORWARD {1234}")
+            .unwrap()
+            .unwrap();
+        assert_eq!(record.action, Some(LogAction::Other("blocked".to_string())));
+        assert_eq!(record.path, Some(LogPath::Forward));
+        assert_eq!(
+            record.other_fields,
+            [("rule-id".to_string(), "{1234}".to_string())]
+        );
+        assert!(parser.parse_line("DROP SEND 1 2").is_err());
+        assert!(LogTimestamp::parse("2024-13-01", "00:00:00").is_err());
+    }
+
+    #[test]
+    fn counts_seconds() {
+        let seconds = |date, time| {
+            LogTimestamp::parse(date, time)
+                .unwrap()
+                .seconds_since_epoch()
+        };
+        assert_eq!(seconds("1970-01-01", "00:00:00"), 0);
+        assert_eq!(seconds("2000-03-01", "00:00:01"), 951_868_801);
+        assert_eq!(seconds("2024-02-29", "23:59:59"), 1_709_251_199);
+        for timestamp in &[
+            "1970-01-01 00:00:00",
+            "2000-02-29 12:30:01",
+            "2024-12-31 23:59:59",
+        ] {
+            let parsed: LogTimestamp = timestamp.parse().unwrap();
+            assert_eq!(
+                LogTimestamp::from_seconds_since_epoch(parsed.seconds_since_epoch()),
+                parsed
+            );
+        }
+    }
+}
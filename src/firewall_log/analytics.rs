@@ -0,0 +1,569 @@
+//! Summaries of firewall logs for people rather than parsers.
+//!
+//! An `Analyzer` takes the allowed and dropped connections of a log and counts them in fixed time buckets, ranking the
+//! remote addresses, destination ports and protocols of each bucket. It also flags sources that look like they are
+//! scanning: reaching many ports of one host (a port scan) or one port on many hosts (a sweep) within a window.
+//!
+//! The summary is plain data, and serializes with the `serde` feature for dashboards.
+
+use crate::{
+    firewall_log::{
+        LogPath,
+        LogRecord,
+        LogTimestamp,
+    },
+    FirewallAction,
+};
+use std::{
+    collections::{
+        BTreeMap,
+        HashMap,
+    },
+    hash::Hash,
+    net::IpAddr,
+    time::Duration,
+};
+
+/// When a source counts as scanning.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Thresholds {
+    /// How far apart the connections of one scan can be.
+    pub window: Duration,
+    /// How many ports of one host a source has to reach to be scanning it.
+    pub ports: usize,
+    /// How many hosts a source has to reach on one port to be sweeping it.
+    pub hosts: usize,
+}
+
+impl Default for Thresholds {
+    /// 20 ports or 10 hosts within a minute.
+    fn default() -> Self {
+        Thresholds {
+            window: Duration::from_secs(60),
+            ports: 20,
+            hosts: 10,
+        }
+    }
+}
+
+/// A value and how many connections with it were allowed and dropped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Ranked<T> {
+    pub value: T,
+    pub allowed: u64,
+    pub dropped: u64,
+}
+
+/// What happened in one time bucket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct BucketSummary {
+    pub start: LogTimestamp,
+    pub allowed: u64,
+    pub dropped: u64,
+    /// The sources of received connections and the destinations of sent ones.
+    pub remote_addresses: Vec<Ranked<IpAddr>>,
+    pub destination_ports: Vec<Ranked<u16>>,
+    /// Protocol numbers.
+    pub protocols: Vec<Ranked<i32>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum ScanKind {
+    /// Many ports of one host.
+    PortScan,
+    /// One port on many hosts.
+    Sweep,
+}
+
+/// A source that reached more ports or hosts than the thresholds allow.
+///
+/// A port scan has one destination and the ports reached on it; a sweep has one port and the destinations reached.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ScanAlert {
+    pub kind: ScanKind,
+    pub source: IpAddr,
+    pub destinations: Vec<IpAddr>,
+    pub ports: Vec<u16>,
+    pub first_seen: LogTimestamp,
+    pub last_seen: LogTimestamp,
+}
+
+impl std::fmt::Display for ScanAlert {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.kind {
+            ScanKind::PortScan => write!(
+                f,
+                "port scan of {} from {}: {} ports",
+                self.destinations[0],
+                self.source,
+                self.ports.len()
+            )?,
+            ScanKind::Sweep => write!(
+                f,
+                "sweep of port {} from {}: {} hosts",
+                self.ports[0],
+                self.source,
+                self.destinations.len()
+            )?,
+        }
+        write!(f, " between {} and {}", self.first_seen, self.last_seen)
+    }
+}
+
+/// Everything an `Analyzer` has seen.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Summary {
+    /// How many allowed and dropped connections were counted.
+    pub records: u64,
+    /// How many records were left out for having no timestamp or not being an allowed or dropped connection.
+    pub ignored: u64,
+    pub buckets: Vec<BucketSummary>,
+    pub alerts: Vec<ScanAlert>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Counts {
+    allowed: u64,
+    dropped: u64,
+}
+
+impl Counts {
+    fn add(&mut self, dropped: bool) {
+        if dropped {
+            self.dropped += 1;
+        } else {
+            self.allowed += 1;
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Bucket {
+    counts: Counts,
+    addresses: HashMap<IpAddr, Counts>,
+    ports: HashMap<u16, Counts>,
+    protocols: HashMap<i32, Counts>,
+}
+
+/// The targets one source reached recently, and the alert they raised if any.
+#[derive(Debug)]
+struct Recent<T> {
+    /// When each target was last reached.
+    targets: HashMap<T, i64>,
+    alert: Option<usize>,
+}
+
+impl<T> Default for Recent<T> {
+    fn default() -> Self {
+        Recent {
+            targets: HashMap::new(),
+            alert: None,
+        }
+    }
+}
+
+impl<T: Copy + Eq + Hash> Recent<T> {
+    /// Note a target reached at `time`, forgetting those reached before `since`.
+    fn reach(&mut self, target: T, time: i64, since: i64) {
+        let last = self.targets.entry(target).or_insert(time);
+        *last = (*last).max(time);
+        self.targets.retain(|_, last| *last >= since);
+    }
+
+    fn first(&self) -> i64 {
+        self.targets.values().copied().min().unwrap_or_default()
+    }
+}
+
+/// Counts log records into a `Summary`.
+#[derive(Debug)]
+pub struct Analyzer {
+    bucket: i64,
+    top: usize,
+    thresholds: Thresholds,
+    buckets: BTreeMap<i64, Bucket>,
+    scans: HashMap<(IpAddr, IpAddr), Recent<u16>>,
+    sweeps: HashMap<(IpAddr, u16), Recent<IpAddr>>,
+    alerts: Vec<ScanAlert>,
+    records: u64,
+    ignored: u64,
+    /// The latest time the scan state was trimmed at.
+    trimmed: i64,
+}
+
+impl Default for Analyzer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Analyzer {
+    /// Make an analyzer with 5 minute buckets, the top 10 of each, and the default thresholds.
+    pub fn new() -> Self {
+        Analyzer {
+            bucket: 300,
+            top: 10,
+            thresholds: Thresholds::default(),
+            buckets: BTreeMap::new(),
+            scans: HashMap::new(),
+            sweeps: HashMap::new(),
+            alerts: Vec::new(),
+            records: 0,
+            ignored: 0,
+            trimmed: i64::MIN,
+        }
+    }
+
+    /// How long each bucket is, rounded down to whole seconds and at least one.
+    pub fn bucket(mut self, bucket: Duration) -> Self {
+        self.bucket = (bucket.as_secs() as i64).max(1);
+        self
+    }
+
+    /// How many addresses, ports and protocols each bucket ranks.
+    pub fn top(mut self, top: usize) -> Self {
+        self.top = top;
+        self
+    }
+
+    pub fn thresholds(mut self, thresholds: Thresholds) -> Self {
+        self.thresholds = thresholds;
+        self
+    }
+
+    /// Count a record.
+    ///
+    /// Records are expected roughly in the order they were logged; a scan is only seen if its connections arrive
+    /// within the window of each other.
+    pub fn add(&mut self, record: &LogRecord) {
+        let action = record
+            .action
+            .as_ref()
+            .and_then(|action| action.firewall_action());
+        let (timestamp, action) = match (record.timestamp, action) {
+            (Some(timestamp), Some(action)) => (timestamp, action),
+            _ => {
+                self.ignored += 1;
+                return;
+            }
+        };
+        let dropped = action == FirewallAction::Block;
+        let time = timestamp.seconds_since_epoch();
+        self.records += 1;
+
+        let bucket = self
+            .buckets
+            .entry(time - time.rem_euclid(self.bucket))
+            .or_default();
+        bucket.counts.add(dropped);
+        let remote = match record.path {
+            Some(LogPath::Send) => record.destination_address,
+            _ => record.source_address,
+        };
+        if let Some(remote) = remote {
+            bucket.addresses.entry(remote).or_default().add(dropped);
+        }
+        if let Some(port) = record.destination_port {
+            bucket.ports.entry(port).or_default().add(dropped);
+        }
+        if let Some(protocol) = record.protocol {
+            bucket.protocols.entry(protocol).or_default().add(dropped);
+        }
+
+        if let (Some(source), Some(destination), Some(port)) = (
+            record.source_address,
+            record.destination_address,
+            record.destination_port,
+        ) {
+            self.detect(source, destination, port, time);
+        }
+    }
+
+    /// The summary of the records counted so far.
+    pub fn summary(&self) -> Summary {
+        Summary {
+            records: self.records,
+            ignored: self.ignored,
+            buckets: self
+                .buckets
+                .iter()
+                .map(|(start, bucket)| BucketSummary {
+                    start: LogTimestamp::from_seconds_since_epoch(*start),
+                    allowed: bucket.counts.allowed,
+                    dropped: bucket.counts.dropped,
+                    remote_addresses: rank(&bucket.addresses, self.top),
+                    destination_ports: rank(&bucket.ports, self.top),
+                    protocols: rank(&bucket.protocols, self.top),
+                })
+                .collect(),
+            alerts: self.alerts.clone(),
+        }
+    }
+
+    fn detect(&mut self, source: IpAddr, destination: IpAddr, port: u16, time: i64) {
+        let window = self.thresholds.window.as_secs() as i64;
+        let since = time - window;
+        if time >= self.trimmed.saturating_add(window) {
+            // Forget sources that have gone quiet, so memory follows the window rather than the log.
+            self.scans
+                .retain(|_, recent| recent.targets.values().any(|&last| last >= since));
+            self.sweeps
+                .retain(|_, recent| recent.targets.values().any(|&last| last >= since));
+            self.trimmed = time;
+        }
+
+        let scan = self.scans.entry((source, destination)).or_default();
+        scan.reach(port, time, since);
+        let raised = raise(
+            &mut self.alerts,
+            scan,
+            self.thresholds.ports,
+            time,
+            since,
+            |ports, first, last| ScanAlert {
+                kind: ScanKind::PortScan,
+                source,
+                destinations: vec![destination],
+                ports,
+                first_seen: first,
+                last_seen: last,
+            },
+        );
+        if let Some(alert) = raised {
+            insert_sorted(&mut self.alerts[alert].ports, port);
+        }
+
+        let sweep = self.sweeps.entry((source, port)).or_default();
+        sweep.reach(destination, time, since);
+        let raised = raise(
+            &mut self.alerts,
+            sweep,
+            self.thresholds.hosts,
+            time,
+            since,
+            |destinations, first, last| ScanAlert {
+                kind: ScanKind::Sweep,
+                source,
+                destinations,
+                ports: vec![port],
+                first_seen: first,
+                last_seen: last,
+            },
+        );
+        if let Some(alert) = raised {
+            insert_sorted(&mut self.alerts[alert].destinations, destination);
+        }
+    }
+}
+
+/// Raise an alert for a source once it reaches `threshold` targets, or extend the one it raised while it keeps
+/// going. Returns the alert to add the latest target to, if any.
+fn raise<T: Copy + Ord + Hash>(
+    alerts: &mut Vec<ScanAlert>,
+    recent: &mut Recent<T>,
+    threshold: usize,
+    time: i64,
+    since: i64,
+    alert: impl FnOnce(Vec<T>, LogTimestamp, LogTimestamp) -> ScanAlert,
+) -> Option<usize> {
+    let now = LogTimestamp::from_seconds_since_epoch(time);
+    if let Some(index) = recent.alert {
+        // An alert goes on while its source keeps reaching targets within the window of the last one.
+        let existing = &mut alerts[index];
+        if existing.last_seen.seconds_since_epoch() >= since {
+            existing.last_seen = existing.last_seen.max(now);
+            return Some(index);
+        }
+        recent.alert = None;
+    }
+    if threshold == 0 || recent.targets.len() < threshold {
+        return None;
+    }
+
+    let mut targets: Vec<T> = recent.targets.keys().copied().collect();
+    targets.sort_unstable();
+    let first = LogTimestamp::from_seconds_since_epoch(recent.first());
+    alerts.push(alert(targets, first, now));
+    recent.alert = Some(alerts.len() - 1);
+    None
+}
+
+fn insert_sorted<T: Ord>(values: &mut Vec<T>, value: T) {
+    if let Err(index) = values.binary_search(&value) {
+        values.insert(index, value);
+    }
+}
+
+/// The `top` values with the most dropped connections, then the most allowed.
+fn rank<T: Copy + Ord>(counts: &HashMap<T, Counts>, top: usize) -> Vec<Ranked<T>> {
+    let mut ranked: Vec<Ranked<T>> = counts
+        .iter()
+        .map(|(value, counts)| Ranked {
+            value: *value,
+            allowed: counts.allowed,
+            dropped: counts.dropped,
+        })
+        .collect();
+    ranked.sort_by(|a, b| {
+        b.dropped
+            .cmp(&a.dropped)
+            .then(b.allowed.cmp(&a.allowed))
+            .then(a.value.cmp(&b.value))
+    });
+    ranked.truncate(top);
+    ranked
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::firewall_log::LogAction;
+
+    fn record(
+        seconds: i64,
+        action: LogAction,
+        source: &str,
+        destination: &str,
+        port: u16,
+    ) -> LogRecord {
+        LogRecord {
+            timestamp: Some(LogTimestamp::from_seconds_since_epoch(
+                1_714_557_600 + seconds,
+            )),
+            action: Some(action),
+            protocol: Some(6),
+            source_address: Some(source.parse().unwrap()),
+            destination_address: Some(destination.parse().unwrap()),
+            source_port: Some(50_000),
+            destination_port: Some(port),
+            path: Some(LogPath::Receive),
+            ..LogRecord::default()
+        }
+    }
+
+    #[test]
+    fn ranks_each_bucket() {
+        let mut analyzer = Analyzer::new().bucket(Duration::from_secs(60)).top(2);
+        for second in 0..3 {
+            analyzer.add(&record(
+                second,
+                LogAction::Drop,
+                "10.0.0.9",
+                "10.0.0.1",
+                445,
+            ));
+        }
+        analyzer.add(&record(5, LogAction::Allow, "10.0.0.7", "10.0.0.1", 443));
+        analyzer.add(&record(6, LogAction::Allow, "10.0.0.7", "10.0.0.1", 443));
+        analyzer.add(&record(7, LogAction::Allow, "10.0.0.8", "10.0.0.1", 80));
+        analyzer.add(&LogRecord {
+            path: Some(LogPath::Send),
+            protocol: Some(17),
+            ..record(61, LogAction::Allow, "10.0.0.1", "10.0.0.53", 53)
+        });
+        analyzer.add(&record(
+            62,
+            LogAction::Other("CLOSE-X".to_string()),
+            "10.0.0.1",
+            "10.0.0.2",
+            1,
+        ));
+        analyzer.add(&LogRecord {
+            timestamp: None,
+            ..record(63, LogAction::Drop, "10.0.0.1", "10.0.0.2", 1)
+        });
+
+        let summary = analyzer.summary();
+        assert_eq!((summary.records, summary.ignored), (7, 2));
+        assert_eq!(summary.buckets.len(), 2);
+        let first = &summary.buckets[0];
+        assert_eq!(first.start.to_string(), "2024-05-01 10:00:00");
+        assert_eq!((first.allowed, first.dropped), (3, 3));
+        assert_eq!(
+            first.remote_addresses,
+            [
+                Ranked {
+                    value: "10.0.0.9".parse().unwrap(),
+                    allowed: 0,
+                    dropped: 3
+                },
+                Ranked {
+                    value: "10.0.0.7".parse().unwrap(),
+                    allowed: 2,
+                    dropped: 0
+                },
+            ]
+        );
+        assert_eq!(
+            first
+                .destination_ports
+                .iter()
+                .map(|ranked| ranked.value)
+                .collect::<Vec<_>>(),
+            [445, 443]
+        );
+        let second = &summary.buckets[1];
+        assert_eq!(
+            second.remote_addresses[0].value,
+            "10.0.0.53".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(second.protocols[0].value, 17);
+        assert!(summary.alerts.is_empty());
+    }
+
+    #[test]
+    fn flags_scans_and_sweeps() {
+        let mut analyzer = Analyzer::new().thresholds(Thresholds {
+            window: Duration::from_secs(10),
+            ports: 5,
+            hosts: 3,
+        });
+        // Four ports a minute apart is too slow to be a scan.
+        for port in 1..5 {
+            analyzer.add(&record(
+                i64::from(port) * 60,
+                LogAction::Drop,
+                "10.0.0.9",
+                "10.0.0.1",
+                port,
+            ));
+        }
+        assert!(analyzer.summary().alerts.is_empty());
+
+        for port in 20..27 {
+            analyzer.add(&record(
+                600 + i64::from(port),
+                LogAction::Drop,
+                "10.0.0.9",
+                "10.0.0.1",
+                port,
+            ));
+        }
+        for host in 1..4 {
+            let destination = format!("10.0.1.{}", host);
+            analyzer.add(&record(
+                700 + host,
+                LogAction::Allow,
+                "10.0.0.6",
+                &destination,
+                22,
+            ));
+        }
+
+        let alerts = analyzer.summary().alerts;
+        assert_eq!(alerts.len(), 2);
+        assert_eq!(alerts[0].kind, ScanKind::PortScan);
+        assert_eq!(alerts[0].ports, (20..27).collect::<Vec<u16>>());
+        assert_eq!(alerts[0].first_seen.to_string(), "2024-05-01 10:10:20");
+        assert_eq!(alerts[0].last_seen.to_string(), "2024-05-01 10:10:26");
+        assert_eq!(
+            alerts[1].to_string(),
+            "sweep of port 22 from 10.0.0.6: 3 hosts between 2024-05-01 10:11:41 and 2024-05-01 10:11:43"
+        );
+    }
+}
@@ -0,0 +1,539 @@
+//! Following `pfirewall.log` as Windows writes and rotates it.
+//!
+//! When the log reaches its size limit Windows renames it to `pfirewall.log.old`, replacing any older one, and starts
+//! a new log. A `Follower` recognises the file it was reading by its first bytes and, where the platform gives one,
+//! its file identity, since a log holding only its headers starts just like the next one. So after a rotation it
+//! finishes the `.old` file before starting on the new log, and after a truncation it starts over. Only complete lines
+//! are read from the active log; a line still being written is left for the next poll.
+//!
+//! The position reached is a `LogCursor`, which can be saved and handed to `Follower::from_cursor` after a restart.
+//! Records are returned once for each time the cursor moves past them, so saving the cursor after handling them
+//! neither repeats nor skips any.
+
+use crate::firewall_log::{
+    LogParser,
+    LogRecord,
+};
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{
+        Read,
+        Seek,
+        SeekFrom,
+    },
+    path::{
+        Path,
+        PathBuf,
+    },
+    time::Duration,
+};
+
+/// How many of a log's first bytes identify it.
+const PREFIX_LEN: usize = 1024;
+
+/// How far a log has been read.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LogCursor {
+    /// The byte offset of the first line not yet read.
+    pub offset: u64,
+    /// The first bytes of the log, up to the offset, that tell it apart from the logs before and after it.
+    pub prefix: Vec<u8>,
+    /// The identity of the log's file, if the platform gives one: its file index on Windows, its inode elsewhere.
+    pub file_id: Option<u64>,
+}
+
+impl LogCursor {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, std::io::Error> {
+        std::fs::read_to_string(path)?.parse()
+    }
+
+    /// Save the cursor, replacing the file in one step so a crash never leaves half of it.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), std::io::Error> {
+        let path = path.as_ref();
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        std::fs::write(&temporary, self.to_string())?;
+        std::fs::rename(&temporary, path)
+    }
+}
+
+/// Formats as the offset, the prefix in hex and the file identity if there is one, like "1234 23566572 5678".
+impl std::fmt::Display for LogCursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ", self.offset)?;
+        for byte in &self.prefix {
+            write!(f, "{:02x}", byte)?;
+        }
+        if let Some(file_id) = self.file_id.filter(|_| !self.prefix.is_empty()) {
+            write!(f, " {}", file_id)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for LogCursor {
+    type Err = std::io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("invalid log cursor '{}'", s),
+            )
+        };
+        let mut parts = s.split_whitespace();
+        let offset: u64 = parts
+            .next()
+            .and_then(|offset| offset.parse().ok())
+            .ok_or_else(invalid)?;
+        let hex = parts.next().unwrap_or_default();
+        let file_id = parts
+            .next()
+            .map(|file_id| file_id.parse().map_err(|_| invalid()))
+            .transpose()?;
+        if parts.next().is_some() {
+            return Err(invalid());
+        }
+        let prefix = (0..hex.len())
+            .step_by(2)
+            .map(|index| u8::from_str_radix(hex.get(index..index + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>()
+            .ok_or_else(invalid)?;
+        if prefix.len() as u64 != offset.min(PREFIX_LEN as u64) {
+            return Err(invalid());
+        }
+        Ok(LogCursor {
+            offset,
+            prefix,
+            file_id,
+        })
+    }
+}
+
+/// Reads the records added to a log since the last poll.
+#[derive(Debug)]
+pub struct Follower {
+    path: PathBuf,
+    rotated_path: PathBuf,
+    interval: Duration,
+    cursor: LogCursor,
+    parser: LogParser,
+    /// Whether the parser has seen the headers before the cursor.
+    primed: bool,
+}
+
+impl Follower {
+    /// Follow a log from its start, polling every second.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self::from_cursor(path, LogCursor::default())
+    }
+
+    /// Follow a log from where a previous follower got to.
+    pub fn from_cursor(path: impl Into<PathBuf>, cursor: LogCursor) -> Self {
+        let path = path.into();
+        let mut rotated_path = path.clone().into_os_string();
+        rotated_path.push(".old");
+        Follower {
+            path,
+            rotated_path: rotated_path.into(),
+            interval: Duration::from_secs(1),
+            primed: cursor.offset == 0,
+            cursor,
+            parser: LogParser::new(),
+        }
+    }
+
+    /// Where the log is moved when it is rotated. The log's path with `.old` added by default.
+    pub fn rotated_path(mut self, rotated_path: impl Into<PathBuf>) -> Self {
+        self.rotated_path = rotated_path.into();
+        self
+    }
+
+    /// How long `records` sleeps when there is nothing new.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// How far the log has been read, covering every record returned so far.
+    pub fn cursor(&self) -> &LogCursor {
+        &self.cursor
+    }
+
+    /// Read the records written since the last poll.
+    ///
+    /// The outer error is for files that cannot be read, and leaves the cursor where it was. Lines that do not parse
+    /// give an inner error naming the file and byte offset, and are passed over. A log that does not exist yet has
+    /// no records.
+    pub fn poll(&mut self) -> Result<Vec<Result<LogRecord, std::io::Error>>, std::io::Error> {
+        let cursor = self.cursor.clone();
+        let parser = self.parser.clone();
+        let primed = self.primed;
+        let mut records = Vec::new();
+        match self.read_new(&mut records) {
+            Ok(()) => Ok(records),
+            Err(error) => {
+                self.cursor = cursor;
+                self.parser = parser;
+                self.primed = primed;
+                Err(error)
+            }
+        }
+    }
+
+    /// Poll forever, sleeping between polls that find nothing new.
+    ///
+    /// The follower's cursor is borrowed by the iterator; `Records::cursor` gives it once every record read has been
+    /// returned.
+    pub fn records(&mut self) -> Records<'_> {
+        Records {
+            follower: self,
+            pending: VecDeque::new(),
+            idle: false,
+        }
+    }
+
+    fn read_new(
+        &mut self,
+        records: &mut Vec<Result<LogRecord, std::io::Error>>,
+    ) -> Result<(), std::io::Error> {
+        let mut active = match open(&self.path)? {
+            Some(active) => active,
+            None => return Ok(()),
+        };
+
+        if !is_at(&mut active, &self.cursor)? {
+            // The log was rotated or truncated. Finish the old one first if it is the one that was being read.
+            if let Some(mut rotated) = open(&self.rotated_path)? {
+                if self.cursor.offset > 0 && is_at(&mut rotated, &self.cursor)? {
+                    let path = self.rotated_path.clone();
+                    self.read(&mut rotated, &path, true, records)?;
+                }
+            }
+            self.cursor = LogCursor::default();
+            self.parser = LogParser::new();
+            self.primed = true;
+        }
+
+        let path = self.path.clone();
+        self.read(&mut active, &path, false, records)
+    }
+
+    /// Read the lines after the cursor, and move it past them.
+    ///
+    /// Only complete lines are read unless the file is `finished`, since a log is written a line at a time.
+    fn read(
+        &mut self,
+        file: &mut File,
+        path: &Path,
+        finished: bool,
+        records: &mut Vec<Result<LogRecord, std::io::Error>>,
+    ) -> Result<(), std::io::Error> {
+        let mut bytes = Vec::new();
+        if !self.primed {
+            // The `#Fields:` header that applies is somewhere before the cursor.
+            file.seek(SeekFrom::Start(0))?;
+            file.take(self.cursor.offset).read_to_end(&mut bytes)?;
+            for line in bytes.split(|&byte| byte == b'\n') {
+                if line.starts_with(b"#") {
+                    self.parser.parse_line(&String::from_utf8_lossy(line))?;
+                }
+            }
+            bytes.clear();
+        }
+
+        file.seek(SeekFrom::Start(self.cursor.offset))?;
+        file.read_to_end(&mut bytes)?;
+        let end = if finished {
+            bytes.len()
+        } else {
+            bytes
+                .iter()
+                .rposition(|&byte| byte == b'\n')
+                .map_or(0, |index| index + 1)
+        };
+
+        let mut offset = self.cursor.offset;
+        for line in bytes[..end].split_inclusive(|&byte| byte == b'\n') {
+            match self.parser.parse_line(&String::from_utf8_lossy(line)) {
+                Ok(Some(record)) => records.push(Ok(record)),
+                Ok(None) => {}
+                Err(error) => records.push(Err(std::io::Error::new(
+                    error.kind(),
+                    format!("{} at byte {}: {}", path.display(), offset, error),
+                ))),
+            }
+            offset += line.len() as u64;
+        }
+
+        let missing = PREFIX_LEN.saturating_sub(self.cursor.prefix.len());
+        self.cursor
+            .prefix
+            .extend_from_slice(&bytes[..end.min(missing)]);
+        self.cursor.offset = offset;
+        self.cursor.file_id = file_id(file);
+        self.primed = true;
+        Ok(())
+    }
+}
+
+/// The records of a followed log, as `Follower::records` returns them.
+#[derive(Debug)]
+pub struct Records<'a> {
+    follower: &'a mut Follower,
+    pending: VecDeque<Result<LogRecord, std::io::Error>>,
+    idle: bool,
+}
+
+impl Records<'_> {
+    /// The follower's cursor, if every record it covers has been returned.
+    pub fn cursor(&self) -> Option<&LogCursor> {
+        if self.pending.is_empty() {
+            Some(self.follower.cursor())
+        } else {
+            None
+        }
+    }
+}
+
+impl Iterator for Records<'_> {
+    type Item = Result<LogRecord, std::io::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(record) = self.pending.pop_front() {
+                return Some(record);
+            }
+            if self.idle {
+                std::thread::sleep(self.follower.interval);
+            }
+            match self.follower.poll() {
+                Ok(records) => {
+                    self.idle = records.is_empty();
+                    self.pending.extend(records);
+                }
+                Err(error) => {
+                    self.idle = true;
+                    return Some(Err(error));
+                }
+            }
+        }
+    }
+}
+
+/// Open a file, or `None` if it does not exist.
+fn open(path: &Path) -> Result<Option<File>, std::io::Error> {
+    match File::open(path) {
+        Ok(file) => Ok(Some(file)),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+/// Whether a file is the one a cursor points into: the same file if both identities are known, as long as the
+/// offset, and starting with the prefix.
+fn is_at(file: &mut File, cursor: &LogCursor) -> Result<bool, std::io::Error> {
+    if let (Some(expected), Some(actual)) = (cursor.file_id, file_id(file)) {
+        if expected != actual {
+            return Ok(false);
+        }
+    }
+    if file.metadata()?.len() < cursor.offset {
+        return Ok(false);
+    }
+    let mut prefix = vec![0; cursor.prefix.len()];
+    file.seek(SeekFrom::Start(0))?;
+    file.read_exact(&mut prefix)?;
+    Ok(prefix == cursor.prefix)
+}
+
+/// The identity of an open file, which follows it when it is renamed.
+#[cfg(windows)]
+fn file_id(file: &File) -> Option<u64> {
+    use std::os::windows::io::AsRawHandle;
+    use winapi::um::fileapi::{
+        GetFileInformationByHandle,
+        BY_HANDLE_FILE_INFORMATION,
+    };
+
+    let mut info: BY_HANDLE_FILE_INFORMATION = unsafe { std::mem::zeroed() };
+    if unsafe { GetFileInformationByHandle(file.as_raw_handle() as _, &mut info) } == 0 {
+        return None;
+    }
+    Some(u64::from(info.nFileIndexHigh) << 32 | u64::from(info.nFileIndexLow))
+}
+
+/// The identity of an open file, which follows it when it is renamed.
+#[cfg(unix)]
+fn file_id(file: &File) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+
+    file.metadata().ok().map(|metadata| metadata.ino())
+}
+
+/// The identity of an open file, which this platform does not give.
+#[cfg(not(any(windows, unix)))]
+fn file_id(_file: &File) -> Option<u64> {
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::firewall_log::LogAction;
+    use std::io::Write;
+
+    const HEADER: &str = "#Version: 1.5\r\n\
+        #Software: Microsoft Windows Firewall\r\n\
+        #Time Format: Local\r\n\
+        #Fields: date time action protocol src-ip dst-ip src-port dst-port path\r\n\
+        \r\n";
+
+    /// A log directory, removed when dropped.
+    struct Logs(PathBuf);
+
+    impl Logs {
+        fn new(name: &str) -> Self {
+            let directory =
+                std::env::temp_dir().join(format!("netfw-follow-{}-{}", name, std::process::id()));
+            std::fs::create_dir_all(&directory).unwrap();
+            Logs(directory)
+        }
+
+        fn path(&self, name: &str) -> PathBuf {
+            self.0.join(name)
+        }
+
+        fn append(&self, text: &str) {
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(self.path("pfirewall.log"))
+                .unwrap()
+                .write_all(text.as_bytes())
+                .unwrap();
+        }
+
+        /// Rotate the log as Windows does and start a new one.
+        fn rotate(&self) {
+            std::fs::rename(self.path("pfirewall.log"), self.path("pfirewall.log.old")).unwrap();
+            self.append(HEADER);
+        }
+    }
+
+    impl Drop for Logs {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn line(second: u32, port: u16) -> String {
+        format!(
+            "2024-05-01 10:00:{:02} DROP TCP 10.0.0.5 10.0.0.1 {} 445 RECEIVE\r\n",
+            second, port
+        )
+    }
+
+    fn ports(records: Vec<Result<LogRecord, std::io::Error>>) -> Vec<u16> {
+        records
+            .into_iter()
+            .map(|record| record.unwrap().source_port.unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn follows_rotation_and_truncation() {
+        let logs = Logs::new("rotation");
+        let mut follower = Follower::new(logs.path("pfirewall.log"));
+        assert!(follower.poll().unwrap().is_empty());
+
+        logs.append(HEADER);
+        logs.append(&line(0, 1));
+        logs.append("2024-05-01 10:00:01 DROP TCP 10.0.0.5 10.0");
+        assert_eq!(ports(follower.poll().unwrap()), [1]);
+
+        // The rest of the line, then more before the rotation and after it.
+        logs.append(&format!(".0.1 2 445 RECEIVE\r\n{}", line(2, 3)));
+        logs.rotate();
+        logs.append(&line(3, 4));
+        assert_eq!(ports(follower.poll().unwrap()), [2, 3, 4]);
+        assert!(follower.poll().unwrap().is_empty());
+
+        // A rotation with the old log's end cut short.
+        logs.append("2024-05-01 10:00:04 DROP TCP 10.0.0.5 10.0.0.1 5");
+        logs.rotate();
+        let records = follower.poll().unwrap();
+        assert_eq!(records.len(), 1);
+        let record = records[0].as_ref().unwrap();
+        assert!(record.truncated);
+        assert_eq!(record.timestamp.unwrap().second, 4);
+        assert_eq!(record.source_port, None);
+
+        // A truncated log is read from the start.
+        logs.append(&format!("{}{}", line(5, 6), line(6, 7)));
+        assert_eq!(ports(follower.poll().unwrap()), [6, 7]);
+        std::fs::write(
+            logs.path("pfirewall.log"),
+            format!("{}{}", HEADER, line(9, 9)),
+        )
+        .unwrap();
+        let records = follower.poll().unwrap();
+        assert_eq!(records[0].as_ref().unwrap().action, Some(LogAction::Drop));
+        assert_eq!(ports(records), [9]);
+    }
+
+    #[test]
+    fn tells_logs_with_the_same_headers_apart() {
+        let logs = Logs::new("identity");
+        logs.append(HEADER);
+        let mut follower = Follower::new(logs.path("pfirewall.log"));
+        assert!(follower.poll().unwrap().is_empty());
+        follower.cursor().save(logs.path("cursor")).unwrap();
+
+        // The saved cursor's prefix is only the headers, which the new log starts with too.
+        logs.append(&line(0, 1));
+        logs.rotate();
+        logs.append(&line(1, 2));
+
+        let cursor = LogCursor::load(logs.path("cursor")).unwrap();
+        assert_eq!(cursor, *follower.cursor());
+        let mut follower = Follower::from_cursor(logs.path("pfirewall.log"), cursor);
+        assert_eq!(ports(follower.poll().unwrap()), [1, 2]);
+    }
+
+    #[test]
+    fn resumes_from_a_saved_cursor() {
+        let logs = Logs::new("resume");
+        logs.append(&format!("{}{}{}", HEADER, line(0, 1), line(1, 2)));
+        let mut follower = Follower::new(logs.path("pfirewall.log"));
+        assert_eq!(ports(follower.poll().unwrap()), [1, 2]);
+        follower.cursor().save(logs.path("cursor")).unwrap();
+
+        // Missed while stopped: the end of the log, a rotation and a new record.
+        logs.append("2024-05-01 10:00:02 DROP bad 10.0.0.5\r\n");
+        logs.append(&line(3, 4));
+        logs.rotate();
+        logs.append(&line(4, 5));
+
+        let cursor = LogCursor::load(logs.path("cursor")).unwrap();
+        assert_eq!(cursor, *follower.cursor());
+        let mut follower = Follower::from_cursor(logs.path("pfirewall.log"), cursor)
+            .interval(Duration::from_millis(1));
+        let mut records = follower.records();
+        let error = records.next().unwrap().unwrap_err();
+        assert!(error.to_string().contains("pfirewall.log.old at byte"));
+        assert!(records.cursor().is_none());
+        let ports: Vec<u16> = records
+            .by_ref()
+            .take(2)
+            .map(|record| record.unwrap().source_port.unwrap())
+            .collect();
+        assert_eq!(ports, [4, 5]);
+        assert!(records.cursor().is_some());
+
+        assert!("12 00".parse::<LogCursor>().is_err());
+        assert!("1 00 x".parse::<LogCursor>().is_err());
+        assert!("1 0".parse::<LogCursor>().is_err());
+    }
+}
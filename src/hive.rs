@@ -0,0 +1,1168 @@
+//! Offline registry hives in the `regf` format, and the firewall policy stored in them.
+//!
+//! `netsh advfirewall export` writes `.wfw` files whose root key holds the policy, and the `SYSTEM` hive holds it
+//! under `ControlSet00N\Services\SharedAccess\Parameters\FirewallPolicy`. A `Hive` reads either kind whole and walks
+//! its keys and values without any Windows API; `firewall_policy` finds the policy and decodes it into owned values.
+//! Going the other way, `HivePolicy::to_wfw` writes a policy as a `.wfw` file through a `KeyBuilder`.
+//!
+//! Only what reading needs is interpreted: the transaction logs next to a hive are ignored, so a hive copied from a
+//! running system may miss its latest writes. Malformed hives fail with `InvalidData` naming the offset at fault.
+
+use crate::{
+    reconcile::DesiredState,
+    registry_pol::{
+        connection_security_rule_string,
+        parse_connection_security_rule,
+        parse_rule_string,
+        profile_settings,
+        profile_values,
+        rule_id,
+        rule_string_with_version,
+        rule_version_for_build,
+        ConnectionSecurityRule,
+        RegistryValue,
+        PROFILE_KEYS,
+    },
+    FirewallProfile,
+    FirewallProfileSettings,
+    FirewallRuleData,
+};
+use std::{
+    collections::BTreeMap,
+    path::Path,
+};
+
+/// The size of the base block, after which cell offsets are counted.
+const BASE_BLOCK_SIZE: usize = 4096;
+
+/// The most data a cell of a big data value holds.
+const BIG_DATA_SEGMENT_SIZE: usize = 16344;
+
+/// How deeply `ri` index lists may nest before a hive is refused.
+const MAX_INDEX_DEPTH: usize = 8;
+
+const HBIN_HEADER_SIZE: usize = 32;
+
+/// Stands for a missing cell in an offset field.
+const NO_CELL: u32 = 0xffff_ffff;
+
+/// The root key of a hive, which cannot be deleted.
+const KEY_HIVE_ENTRY: u16 = 0x4;
+const KEY_NO_DELETE: u16 = 0x8;
+
+/// Key and value names are stored as Latin-1 rather than UTF-16.
+const KEY_COMP_NAME: u16 = 0x20;
+const VALUE_COMP_NAME: u16 = 0x1;
+
+/// The longest key and value names, in UTF-16 units.
+const MAX_KEY_NAME: usize = 255;
+const MAX_VALUE_NAME: usize = 16383;
+
+const SE_DACL_PRESENT_SELF_RELATIVE: u16 = 0x8004;
+
+/// Value data up to four bytes is stored in the offset field.
+const DATA_INLINE: u32 = 0x8000_0000;
+
+/// A registry hive read into memory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hive {
+    data: Vec<u8>,
+    root: u32,
+}
+
+/// A key of a hive.
+#[derive(Debug, Clone, Copy)]
+pub struct Key<'a> {
+    hive: &'a Hive,
+    offset: u32,
+}
+
+impl Hive {
+    /// Check the base block and root key of a hive.
+    pub fn from_vec(data: Vec<u8>) -> Result<Self, std::io::Error> {
+        if data.len() < BASE_BLOCK_SIZE || &data[..4] != b"regf" {
+            return Err(invalid_data("not a registry hive".to_string()));
+        }
+        let hive = Hive {
+            root: u32_at(&data, 0x24)?,
+            data,
+        };
+        hive.key(hive.root)?;
+        Ok(hive)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, std::io::Error> {
+        Self::from_vec(std::fs::read(path)?)
+    }
+
+    pub fn root(&self) -> Key<'_> {
+        Key {
+            hive: self,
+            offset: self.root,
+        }
+    }
+
+    /// Find a key by a path of backslash separated names, matched ignoring case, relative to the root.
+    pub fn open(&self, path: &str) -> Result<Option<Key<'_>>, std::io::Error> {
+        let mut key = self.root();
+        for name in path.split('\\').filter(|name| !name.is_empty()) {
+            key = match key.subkey(name)? {
+                Some(subkey) => subkey,
+                None => return Ok(None),
+            };
+        }
+        Ok(Some(key))
+    }
+
+    /// Find and decode the firewall policy, in a `.wfw` export or a `SYSTEM` hive.
+    ///
+    /// Fails with `NotFound` if the hive holds no policy.
+    pub fn firewall_policy(&self) -> Result<HivePolicy, std::io::Error> {
+        let key = self.policy_key()?.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "the hive holds no firewall policy",
+            )
+        })?;
+        HivePolicy::read(key)
+    }
+
+    fn policy_key(&self) -> Result<Option<Key<'_>>, std::io::Error> {
+        let root = self.root();
+        for name in [
+            "FirewallRules",
+            "DomainProfile",
+            "StandardProfile",
+            "PublicProfile",
+        ]
+        .iter()
+        {
+            if root.subkey(name)?.is_some() {
+                return Ok(Some(root));
+            }
+        }
+
+        let current = self
+            .open("Select")?
+            .map(|select| select.value("Current"))
+            .transpose()?
+            .flatten()
+            .and_then(|current| current.as_dword())
+            .unwrap_or(1);
+        self.open(&format!(
+            r"ControlSet{:03}\Services\SharedAccess\Parameters\FirewallPolicy",
+            current
+        ))
+    }
+
+    /// The data of the cell at an offset, without its size.
+    fn cell(&self, offset: u32) -> Result<&[u8], std::io::Error> {
+        let start = BASE_BLOCK_SIZE + offset as usize;
+        // Allocated cells have negative sizes.
+        let size = (u32_at(&self.data, start)? as i32).unsigned_abs() as usize;
+        if size < 4 || start + size > self.data.len() {
+            return Err(invalid_data(format!("bad cell at offset {:#x}", offset)));
+        }
+        Ok(&self.data[start + 4..start + size])
+    }
+
+    /// A cell that must start with a signature.
+    fn signed_cell(&self, offset: u32, signature: &[u8; 2]) -> Result<&[u8], std::io::Error> {
+        let cell = self.cell(offset)?;
+        if !cell.starts_with(signature) {
+            return Err(invalid_data(format!(
+                "expected a '{}' cell at offset {:#x}",
+                String::from_utf8_lossy(signature),
+                offset
+            )));
+        }
+        Ok(cell)
+    }
+
+    fn key(&self, offset: u32) -> Result<&[u8], std::io::Error> {
+        let cell = self.signed_cell(offset, b"nk")?;
+        if cell.len() < 76 {
+            return Err(invalid_data(format!("short key at offset {:#x}", offset)));
+        }
+        Ok(cell)
+    }
+
+    /// The keys of a subkey list, following `ri` indexes.
+    fn subkey_offsets(
+        &self,
+        list: u32,
+        depth: usize,
+        offsets: &mut Vec<u32>,
+    ) -> Result<(), std::io::Error> {
+        if depth > MAX_INDEX_DEPTH {
+            return Err(invalid_data(format!(
+                "subkey indexes nest too deeply at offset {:#x}",
+                list
+            )));
+        }
+
+        let cell = self.cell(list)?;
+        let count = u16_at(cell, 2)? as usize;
+        let (stride, nested) = match cell.get(..2) {
+            // Fast and hash leaves pair each offset with a hash of the name.
+            Some(b"lf") | Some(b"lh") => (8, false),
+            Some(b"li") => (4, false),
+            Some(b"ri") => (4, true),
+            _ => {
+                return Err(invalid_data(format!(
+                    "unknown subkey list at offset {:#x}",
+                    list
+                )))
+            }
+        };
+        for index in 0..count {
+            let offset = u32_at(cell, 4 + index * stride)?;
+            if nested {
+                self.subkey_offsets(offset, depth + 1, offsets)?;
+            } else {
+                offsets.push(offset);
+            }
+        }
+        Ok(())
+    }
+
+    fn value(&self, offset: u32) -> Result<(String, RegistryValue), std::io::Error> {
+        let cell = self.signed_cell(offset, b"vk")?;
+        let name_len = u16_at(cell, 2)? as usize;
+        let size = u32_at(cell, 4)?;
+        let data_offset = u32_at(cell, 8)?;
+        let kind = u32_at(cell, 12)?;
+        let flags = u16_at(cell, 16)?;
+        let name = cell
+            .get(20..20 + name_len)
+            .ok_or_else(|| invalid_data(format!("short value at offset {:#x}", offset)))?;
+        let name = decode_name(name, flags & VALUE_COMP_NAME != 0)?;
+
+        let data = if size & DATA_INLINE != 0 {
+            let size = (size & !DATA_INLINE) as usize;
+            data_offset.to_le_bytes()[..size.min(4)].to_vec()
+        } else {
+            self.value_data(data_offset, size as usize)?
+        };
+        Ok((name, RegistryValue::decode(kind, &data)?))
+    }
+
+    fn value_data(&self, offset: u32, size: usize) -> Result<Vec<u8>, std::io::Error> {
+        let short = || invalid_data(format!("short value data at offset {:#x}", offset));
+        let cell = self.cell(offset)?;
+
+        if size > BIG_DATA_SEGMENT_SIZE && cell.starts_with(b"db") {
+            let count = u16_at(cell, 2)? as usize;
+            let segments = self.cell(u32_at(cell, 4)?)?;
+            let mut data = Vec::with_capacity(size);
+            for index in 0..count {
+                let segment = self.cell(u32_at(segments, index * 4)?)?;
+                let len = segment.len().min(BIG_DATA_SEGMENT_SIZE);
+                data.extend_from_slice(&segment[..len]);
+            }
+            if data.len() < size {
+                return Err(short());
+            }
+            data.truncate(size);
+            return Ok(data);
+        }
+
+        cell.get(..size).map(<[u8]>::to_vec).ok_or_else(short)
+    }
+}
+
+impl<'a> Key<'a> {
+    pub fn name(&self) -> Result<String, std::io::Error> {
+        let cell = self.hive.key(self.offset)?;
+        let flags = u16_at(cell, 2)?;
+        let len = u16_at(cell, 72)? as usize;
+        let name = cell
+            .get(76..76 + len)
+            .ok_or_else(|| invalid_data(format!("short key at offset {:#x}", self.offset)))?;
+        decode_name(name, flags & KEY_COMP_NAME != 0)
+    }
+
+    pub fn subkeys(&self) -> Result<Vec<Key<'a>>, std::io::Error> {
+        let cell = self.hive.key(self.offset)?;
+        let mut offsets = Vec::new();
+        if u32_at(cell, 20)? > 0 {
+            self.hive
+                .subkey_offsets(u32_at(cell, 28)?, 0, &mut offsets)?;
+        }
+        offsets
+            .into_iter()
+            .map(|offset| {
+                self.hive.key(offset)?;
+                Ok(Key {
+                    hive: self.hive,
+                    offset,
+                })
+            })
+            .collect()
+    }
+
+    /// A subkey, matched by name ignoring case.
+    pub fn subkey(&self, name: &str) -> Result<Option<Key<'a>>, std::io::Error> {
+        for subkey in self.subkeys()? {
+            if subkey.name()?.eq_ignore_ascii_case(name) {
+                return Ok(Some(subkey));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The values, in the order the hive lists them. The default value has an empty name.
+    pub fn values(&self) -> Result<Vec<(String, RegistryValue)>, std::io::Error> {
+        let cell = self.hive.key(self.offset)?;
+        let count = u32_at(cell, 36)? as usize;
+        if count == 0 {
+            return Ok(Vec::new());
+        }
+        let list = self.hive.cell(u32_at(cell, 40)?)?;
+        (0..count)
+            .map(|index| self.hive.value(u32_at(list, index * 4)?))
+            .collect()
+    }
+
+    /// A value, matched by name ignoring case.
+    pub fn value(&self, name: &str) -> Result<Option<RegistryValue>, std::io::Error> {
+        Ok(self
+            .values()?
+            .into_iter()
+            .find(|(other, _)| other.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value))
+    }
+}
+
+/// The firewall policy of a hive, mirroring what `FirewallPolicy` and `FirewallRule` expose.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HivePolicy {
+    /// The rules, with their value names as IDs.
+    pub rules: Vec<FirewallRuleData>,
+    pub connection_security_rules: Vec<ConnectionSecurityRule>,
+    /// The settings of each profile that has a key, with the values of a fresh install for those missing.
+    pub profiles: BTreeMap<FirewallProfile, FirewallProfileSettings>,
+    /// The logging settings of each profile that has a `Logging` key.
+    pub logging: BTreeMap<FirewallProfile, LoggingSettings>,
+}
+
+/// Where and what a profile logs to `pfirewall.log`. Unset fields are not configured.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LoggingSettings {
+    pub file_path: Option<String>,
+    pub max_file_size_kb: Option<u32>,
+    pub log_dropped_packets: Option<bool>,
+    pub log_successful_connections: Option<bool>,
+}
+
+impl HivePolicy {
+    fn read(key: Key<'_>) -> Result<Self, std::io::Error> {
+        let mut policy = HivePolicy::default();
+
+        if let Some(rules) = key.subkey("FirewallRules")? {
+            for (id, value) in rules.values()? {
+                policy
+                    .rules
+                    .push(parse_rule_string(&id, string(&id, &value)?)?);
+            }
+        }
+        if let Some(rules) = key.subkey("ConSecRules")? {
+            for (id, value) in rules.values()? {
+                policy
+                    .connection_security_rules
+                    .push(parse_connection_security_rule(&id, string(&id, &value)?)?);
+            }
+        }
+
+        for (profile, name) in PROFILE_KEYS.iter() {
+            let profile_key = match key.subkey(name)? {
+                Some(profile_key) => profile_key,
+                None => continue,
+            };
+            let values = profile_key.values()?;
+            policy.profiles.insert(
+                *profile,
+                profile_settings(values.iter().map(|(name, value)| (name.as_str(), value))),
+            );
+
+            if let Some(logging) = profile_key.subkey("Logging")? {
+                let mut settings = LoggingSettings::default();
+                for (name, value) in logging.values()? {
+                    match name.to_ascii_lowercase().as_str() {
+                        "logfilepath" => settings.file_path = value.as_str().map(str::to_string),
+                        "logfilesize" => settings.max_file_size_kb = value.as_dword(),
+                        "logdroppedpackets" => {
+                            settings.log_dropped_packets = value.as_dword().map(|v| v != 0)
+                        }
+                        "logsuccessfulconnections" => {
+                            settings.log_successful_connections = value.as_dword().map(|v| v != 0)
+                        }
+                        _ => {}
+                    }
+                }
+                policy.logging.insert(*profile, settings);
+            }
+        }
+        Ok(policy)
+    }
+
+    /// The rules and profile settings, to reconcile a live firewall against.
+    pub fn desired_state(&self) -> DesiredState {
+        DesiredState {
+            rules: self.rules.clone(),
+            profiles: self.profiles.clone(),
+        }
+    }
+
+    /// Write the policy as a `.wfw` file for `netsh advfirewall import`, with rule strings in the newest version
+    /// the target Windows build understands.
+    ///
+    /// Rules without an ID are stored under their name and direction. Fails with `InvalidInput` if a rule cannot be
+    /// written as a rule string or a profile is not a single one.
+    pub fn to_wfw(&self, build: u32) -> Result<Vec<u8>, std::io::Error> {
+        let version = rule_version_for_build(build);
+        let mut root = KeyBuilder::new("FirewallPolicy");
+
+        let rules = root.subkey("FirewallRules");
+        for rule in &self.rules {
+            let text = rule_string_with_version(rule, version)?;
+            rules.set_value(&rule_id(rule), RegistryValue::String(text));
+        }
+        if !self.connection_security_rules.is_empty() {
+            let rules = root.subkey("ConSecRules");
+            for rule in &self.connection_security_rules {
+                let text = connection_security_rule_string(rule, version)?;
+                rules.set_value(&rule.id, RegistryValue::String(text));
+            }
+        }
+
+        for (profile, name) in PROFILE_KEYS.iter() {
+            let settings = self.profiles.get(profile);
+            let logging = self.logging.get(profile);
+            if settings.is_none() && logging.is_none() {
+                continue;
+            }
+            let key = root.subkey(name);
+            if let Some(settings) = settings {
+                for (name, value) in profile_values(settings)?.iter() {
+                    key.set_value(name, RegistryValue::Dword(*value));
+                }
+            }
+            if let Some(logging) = logging {
+                let key = key.subkey("Logging");
+                if let Some(path) = &logging.file_path {
+                    key.set_value("LogFilePath", RegistryValue::String(path.clone()));
+                }
+                let dwords = [
+                    ("LogFileSize", logging.max_file_size_kb),
+                    (
+                        "LogDroppedPackets",
+                        logging.log_dropped_packets.map(u32::from),
+                    ),
+                    (
+                        "LogSuccessfulConnections",
+                        logging.log_successful_connections.map(u32::from),
+                    ),
+                ];
+                for (name, value) in dwords.iter() {
+                    if let Some(value) = value {
+                        key.set_value(name, RegistryValue::Dword(*value));
+                    }
+                }
+            }
+        }
+
+        let single = |profile: &FirewallProfile| PROFILE_KEYS.iter().any(|(p, _)| p == profile);
+        if let Some(profile) = self
+            .profiles
+            .keys()
+            .chain(self.logging.keys())
+            .find(|profile| !single(profile))
+        {
+            return Err(invalid_input(format!(
+                "'{}' is not a single profile",
+                profile
+            )));
+        }
+        root.to_hive()
+    }
+
+    pub fn save_wfw(&self, path: impl AsRef<Path>, build: u32) -> Result<(), std::io::Error> {
+        std::fs::write(path, self.to_wfw(build)?)
+    }
+}
+
+/// A key to write into a new hive, with its values and subkeys.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyBuilder {
+    pub name: String,
+    pub values: Vec<(String, RegistryValue)>,
+    pub subkeys: Vec<KeyBuilder>,
+}
+
+impl KeyBuilder {
+    pub fn new(name: impl Into<String>) -> Self {
+        KeyBuilder {
+            name: name.into(),
+            ..KeyBuilder::default()
+        }
+    }
+
+    /// The subkey with a name, matched ignoring case and added if missing.
+    pub fn subkey(&mut self, name: &str) -> &mut KeyBuilder {
+        let index = match self
+            .subkeys
+            .iter()
+            .position(|subkey| subkey.name.eq_ignore_ascii_case(name))
+        {
+            Some(index) => index,
+            None => {
+                self.subkeys.push(KeyBuilder::new(name));
+                self.subkeys.len() - 1
+            }
+        };
+        &mut self.subkeys[index]
+    }
+
+    /// Set a value, replacing one with the same name.
+    pub fn set_value(&mut self, name: &str, value: RegistryValue) {
+        match self
+            .values
+            .iter_mut()
+            .find(|(other, _)| other.eq_ignore_ascii_case(name))
+        {
+            Some((_, existing)) => *existing = value,
+            None => self.values.push((name.to_string(), value)),
+        }
+    }
+
+    /// Write a hive with this key as its root.
+    ///
+    /// Fails with `InvalidInput` if names are too long, empty or repeated.
+    pub fn to_hive(&self) -> Result<Vec<u8>, std::io::Error> {
+        let mut writer = Writer::new();
+        let root = writer.key(self, 0, true)?;
+        writer.finish(root)
+    }
+}
+
+/// Lays out the cells of a new hive in a single bin.
+struct Writer {
+    bin: Vec<u8>,
+    /// The offset of the one security cell all keys share.
+    security: u32,
+    keys: u32,
+}
+
+impl Writer {
+    fn new() -> Self {
+        let mut writer = Writer {
+            bin: b"hbin".to_vec(),
+            security: 0,
+            keys: 0,
+        };
+        writer.bin.resize(HBIN_HEADER_SIZE, 0);
+
+        let descriptor = security_descriptor();
+        writer.security = writer.bin.len() as u32;
+        let mut cell = b"sk\0\0".to_vec();
+        // The list of security cells links this one to itself.
+        cell.extend_from_slice(&writer.security.to_le_bytes());
+        cell.extend_from_slice(&writer.security.to_le_bytes());
+        // The reference count, set once the keys are counted.
+        cell.extend_from_slice(&0u32.to_le_bytes());
+        cell.extend_from_slice(&(descriptor.len() as u32).to_le_bytes());
+        cell.extend(descriptor);
+        writer.cell(&cell);
+        writer
+    }
+
+    fn cell(&mut self, data: &[u8]) -> u32 {
+        let offset = self.bin.len() as u32;
+        let size = (data.len() + 4 + 7) & !7;
+        self.bin.extend_from_slice(&(-(size as i32)).to_le_bytes());
+        self.bin.extend_from_slice(data);
+        self.bin.resize(offset as usize + size, 0);
+        offset
+    }
+
+    /// Overwrite part of a cell written earlier.
+    fn patch(&mut self, cell: u32, at: usize, value: u32) {
+        let start = cell as usize + 4 + at;
+        self.bin[start..start + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn key(&mut self, key: &KeyBuilder, parent: u32, root: bool) -> Result<u32, std::io::Error> {
+        if key.name.encode_utf16().count() > MAX_KEY_NAME || (key.name.is_empty() && !root) {
+            return Err(invalid_input(format!("invalid key name '{}'", key.name)));
+        }
+        let (name, compressed) = encode_name(&key.name);
+        let mut flags = if compressed { KEY_COMP_NAME } else { 0 };
+        if root {
+            flags |= KEY_HIVE_ENTRY | KEY_NO_DELETE;
+        }
+
+        let mut cell = vec![0; 76];
+        cell[..2].copy_from_slice(b"nk");
+        cell[2..4].copy_from_slice(&flags.to_le_bytes());
+        cell[16..20].copy_from_slice(&parent.to_le_bytes());
+        for at in [28, 32, 40, 48].iter() {
+            cell[*at..*at + 4].copy_from_slice(&NO_CELL.to_le_bytes());
+        }
+        cell[44..48].copy_from_slice(&self.security.to_le_bytes());
+        cell[72..74].copy_from_slice(&(name.len() as u16).to_le_bytes());
+        cell.extend_from_slice(&name);
+        let offset = self.cell(&cell);
+        self.keys += 1;
+
+        if !key.values.is_empty() {
+            let mut values = Vec::with_capacity(key.values.len());
+            for (index, (name, value)) in key.values.iter().enumerate() {
+                if key.values[..index]
+                    .iter()
+                    .any(|(other, _)| other.eq_ignore_ascii_case(name))
+                {
+                    return Err(invalid_input(format!(
+                        "key '{}' has two values named '{}'",
+                        key.name, name
+                    )));
+                }
+                values.push(self.value(name, value)?);
+            }
+            let list: Vec<u8> = values
+                .iter()
+                .flat_map(|value| value.to_le_bytes())
+                .collect();
+            let list = self.cell(&list);
+            self.patch(offset, 36, key.values.len() as u32);
+            self.patch(offset, 40, list);
+            let longest_name = key
+                .values
+                .iter()
+                .map(|(name, _)| name.encode_utf16().count());
+            let longest_data = key.values.iter().map(|(_, value)| value.encode().1.len());
+            self.patch(offset, 60, longest_name.max().unwrap_or(0) as u32 * 2);
+            self.patch(offset, 64, longest_data.max().unwrap_or(0) as u32);
+        }
+
+        if !key.subkeys.is_empty() {
+            // Lookups binary search the list, so it is sorted by uppercased name.
+            let mut subkeys: Vec<(String, &KeyBuilder)> = key
+                .subkeys
+                .iter()
+                .map(|subkey| (subkey.name.to_uppercase(), subkey))
+                .collect();
+            subkeys.sort_by(|(a, _), (b, _)| a.encode_utf16().cmp(b.encode_utf16()));
+            if let Some(pair) = subkeys.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+                return Err(invalid_input(format!(
+                    "key '{}' has two subkeys named '{}'",
+                    key.name, pair[1].1.name
+                )));
+            }
+
+            let mut list = b"lh".to_vec();
+            list.extend_from_slice(&(subkeys.len() as u16).to_le_bytes());
+            for (upper, subkey) in &subkeys {
+                let subkey = self.key(subkey, offset, false)?;
+                let hash = upper.encode_utf16().fold(0u32, |hash, unit| {
+                    hash.wrapping_mul(37).wrapping_add(u32::from(unit))
+                });
+                list.extend_from_slice(&subkey.to_le_bytes());
+                list.extend_from_slice(&hash.to_le_bytes());
+            }
+            let list = self.cell(&list);
+            self.patch(offset, 20, subkeys.len() as u32);
+            self.patch(offset, 28, list);
+            let longest = key
+                .subkeys
+                .iter()
+                .map(|subkey| subkey.name.encode_utf16().count());
+            self.patch(offset, 52, longest.max().unwrap_or(0) as u32 * 2);
+        }
+        Ok(offset)
+    }
+
+    fn value(&mut self, name: &str, value: &RegistryValue) -> Result<u32, std::io::Error> {
+        if name.encode_utf16().count() > MAX_VALUE_NAME {
+            return Err(invalid_input(format!("value name '{}' is too long", name)));
+        }
+        let (kind, data) = value.encode();
+        let (size, data_offset) = if data.len() <= 4 {
+            let mut inline = [0; 4];
+            inline[..data.len()].copy_from_slice(&data);
+            (data.len() as u32 | DATA_INLINE, u32::from_le_bytes(inline))
+        } else if data.len() > BIG_DATA_SEGMENT_SIZE {
+            let segments: Vec<u8> = data
+                .chunks(BIG_DATA_SEGMENT_SIZE)
+                .map(|segment| self.cell(segment))
+                .collect::<Vec<_>>()
+                .iter()
+                .flat_map(|segment| segment.to_le_bytes())
+                .collect();
+            let count = segments.len() / 4;
+            if count > usize::from(u16::MAX) {
+                return Err(invalid_input(format!("value '{}' is too big", name)));
+            }
+            let segments = self.cell(&segments);
+            let mut db = b"db".to_vec();
+            db.extend_from_slice(&(count as u16).to_le_bytes());
+            db.extend_from_slice(&segments.to_le_bytes());
+            (data.len() as u32, self.cell(&db))
+        } else {
+            (data.len() as u32, self.cell(&data))
+        };
+
+        let (name, compressed) = encode_name(name);
+        let mut cell = b"vk".to_vec();
+        cell.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        cell.extend_from_slice(&size.to_le_bytes());
+        cell.extend_from_slice(&data_offset.to_le_bytes());
+        cell.extend_from_slice(&kind.to_le_bytes());
+        let flags = if compressed { VALUE_COMP_NAME } else { 0 };
+        cell.extend_from_slice(&flags.to_le_bytes());
+        cell.extend_from_slice(&[0, 0]);
+        cell.extend_from_slice(&name);
+        Ok(self.cell(&cell))
+    }
+
+    /// Fill the bin out with a free cell and put the base block in front of it.
+    fn finish(mut self, root: u32) -> Result<Vec<u8>, std::io::Error> {
+        let (security, keys) = (self.security, self.keys);
+        self.patch(security, 12, keys);
+
+        let used = self.bin.len();
+        let size = (used + BASE_BLOCK_SIZE - 1) & !(BASE_BLOCK_SIZE - 1);
+        if size > used {
+            self.bin
+                .extend_from_slice(&((size - used) as u32).to_le_bytes());
+            self.bin.resize(size, 0);
+        }
+        self.bin[8..12].copy_from_slice(&(size as u32).to_le_bytes());
+
+        let mut hive = b"regf".to_vec();
+        hive.resize(BASE_BLOCK_SIZE, 0);
+        let mut set =
+            |at: usize, value: u32| hive[at..at + 4].copy_from_slice(&value.to_le_bytes());
+        // Matching sequence numbers mark the hive as cleanly written.
+        set(0x04, 1);
+        set(0x08, 1);
+        set(0x14, 1);
+        set(0x18, 5);
+        set(0x20, 1);
+        set(0x24, root);
+        set(0x28, size as u32);
+        set(0x2c, 1);
+        let checksum = match (0..0x1fc).step_by(4).fold(0, |checksum, at| {
+            checksum ^ u32::from_le_bytes([hive[at], hive[at + 1], hive[at + 2], hive[at + 3]])
+        }) {
+            0 => 1,
+            0xffff_ffff => 0xffff_fffe,
+            checksum => checksum,
+        };
+        hive[0x1fc..0x200].copy_from_slice(&checksum.to_le_bytes());
+
+        hive.extend(self.bin);
+        Ok(hive)
+    }
+}
+
+/// A self-relative descriptor owned by Administrators, giving them and `SYSTEM` full control and users read access,
+/// inherited by subkeys.
+fn security_descriptor() -> Vec<u8> {
+    const KEY_ALL_ACCESS: u32 = 0xf003f;
+    const KEY_READ: u32 = 0x20019;
+    const CONTAINER_INHERIT_ACE: u8 = 0x2;
+    let sid = |sub_authorities: &[u32]| {
+        let mut sid = vec![1, sub_authorities.len() as u8, 0, 0, 0, 0, 0, 5];
+        for sub_authority in sub_authorities {
+            sid.extend_from_slice(&sub_authority.to_le_bytes());
+        }
+        sid
+    };
+    let administrators = sid(&[32, 544]);
+    let users = sid(&[32, 545]);
+    let system = sid(&[18]);
+
+    let mut aces = Vec::new();
+    for (mask, sid) in [
+        (KEY_ALL_ACCESS, &administrators),
+        (KEY_ALL_ACCESS, &system),
+        (KEY_READ, &users),
+    ]
+    .iter()
+    {
+        aces.extend_from_slice(&[0, CONTAINER_INHERIT_ACE]);
+        aces.extend_from_slice(&(8 + sid.len() as u16).to_le_bytes());
+        aces.extend_from_slice(&mask.to_le_bytes());
+        aces.extend_from_slice(sid);
+    }
+    let mut acl = vec![2, 0];
+    acl.extend_from_slice(&(8 + aces.len() as u16).to_le_bytes());
+    acl.extend_from_slice(&3u16.to_le_bytes());
+    acl.extend_from_slice(&[0, 0]);
+    acl.extend(aces);
+
+    // The header, then the DACL, owner and group.
+    let dacl = 20u32;
+    let owner = dacl + acl.len() as u32;
+    let mut descriptor = vec![1, 0];
+    descriptor.extend_from_slice(&SE_DACL_PRESENT_SELF_RELATIVE.to_le_bytes());
+    descriptor.extend_from_slice(&owner.to_le_bytes());
+    descriptor.extend_from_slice(&(owner + administrators.len() as u32).to_le_bytes());
+    descriptor.extend_from_slice(&0u32.to_le_bytes());
+    descriptor.extend_from_slice(&dacl.to_le_bytes());
+    descriptor.extend(acl);
+    descriptor.extend_from_slice(&administrators);
+    descriptor.extend_from_slice(&administrators);
+    descriptor
+}
+
+/// A name as Latin-1 when it can be, or UTF-16, and whether it is Latin-1.
+fn encode_name(name: &str) -> (Vec<u8>, bool) {
+    if name.chars().all(|c| u32::from(c) <= 0xff) {
+        (name.chars().map(|c| u32::from(c) as u8).collect(), true)
+    } else {
+        (
+            name.encode_utf16().flat_map(u16::to_le_bytes).collect(),
+            false,
+        )
+    }
+}
+
+fn string<'a>(id: &str, value: &'a RegistryValue) -> Result<&'a str, std::io::Error> {
+    value
+        .as_str()
+        .ok_or_else(|| invalid_data(format!("rule '{}' is not a string", id)))
+}
+
+/// A name stored as Latin-1 or UTF-16.
+fn decode_name(bytes: &[u8], latin1: bool) -> Result<String, std::io::Error> {
+    if latin1 {
+        return Ok(bytes.iter().map(|&byte| char::from(byte)).collect());
+    }
+    let units: Vec<u16> = bytes
+        .chunks_exact(2)
+        .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+        .collect();
+    String::from_utf16(&units).map_err(|error| invalid_data(error.to_string()))
+}
+
+fn slice(bytes: &[u8], at: usize, len: usize) -> Result<&[u8], std::io::Error> {
+    bytes
+        .get(at..at + len)
+        .ok_or_else(|| invalid_data(format!("truncated at offset {:#x}", at)))
+}
+
+fn u16_at(bytes: &[u8], at: usize) -> Result<u16, std::io::Error> {
+    let bytes = slice(bytes, at, 2)?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> Result<u32, std::io::Error> {
+    let bytes = slice(bytes, at, 4)?;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn invalid_data(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+fn invalid_input(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, message)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::FirewallAction;
+
+    /// Lays out cells in a single hive bin.
+    struct Builder {
+        bin: Vec<u8>,
+    }
+
+    impl Builder {
+        fn new() -> Self {
+            let mut bin = b"hbin".to_vec();
+            bin.resize(32, 0);
+            Builder { bin }
+        }
+
+        fn cell(&mut self, data: &[u8]) -> u32 {
+            let offset = self.bin.len() as u32;
+            let size = (data.len() + 4 + 7) & !7;
+            self.bin.extend_from_slice(&(-(size as i32)).to_le_bytes());
+            self.bin.extend_from_slice(data);
+            self.bin.resize(offset as usize + size, 0);
+            offset
+        }
+
+        fn value(&mut self, name: &str, kind: u32, data: &[u8]) -> u32 {
+            let (size, offset) = if data.len() <= 4 {
+                let mut inline = [0; 4];
+                inline[..data.len()].copy_from_slice(data);
+                (data.len() as u32 | DATA_INLINE, u32::from_le_bytes(inline))
+            } else {
+                (data.len() as u32, self.cell(data))
+            };
+            let mut cell = b"vk".to_vec();
+            cell.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            cell.extend_from_slice(&size.to_le_bytes());
+            cell.extend_from_slice(&offset.to_le_bytes());
+            cell.extend_from_slice(&kind.to_le_bytes());
+            cell.extend_from_slice(&VALUE_COMP_NAME.to_le_bytes());
+            cell.extend_from_slice(&[0, 0]);
+            cell.extend_from_slice(name.as_bytes());
+            self.cell(&cell)
+        }
+
+        /// A key named in UTF-16, with its subkeys under an `ri` index of `li` lists of one key each.
+        fn key(&mut self, name: &str, subkeys: &[u32], values: &[u32]) -> u32 {
+            let mut index = b"ri".to_vec();
+            index.extend_from_slice(&(subkeys.len() as u16).to_le_bytes());
+            for subkey in subkeys {
+                let mut list = b"li\x01\0".to_vec();
+                list.extend_from_slice(&subkey.to_le_bytes());
+                let list = self.cell(&list);
+                index.extend_from_slice(&list.to_le_bytes());
+            }
+            let index = self.cell(&index);
+            let values_list: Vec<u8> = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+            let values_list = self.cell(&values_list);
+
+            let name: Vec<u8> = name.encode_utf16().flat_map(u16::to_le_bytes).collect();
+            let mut cell = vec![0; 76];
+            cell[..2].copy_from_slice(b"nk");
+            cell[20..24].copy_from_slice(&(subkeys.len() as u32).to_le_bytes());
+            cell[28..32].copy_from_slice(&index.to_le_bytes());
+            cell[36..40].copy_from_slice(&(values.len() as u32).to_le_bytes());
+            cell[40..44].copy_from_slice(&values_list.to_le_bytes());
+            cell[72..74].copy_from_slice(&(name.len() as u16).to_le_bytes());
+            cell.extend_from_slice(&name);
+            self.cell(&cell)
+        }
+
+        fn finish(mut self, root: u32) -> Vec<u8> {
+            let size = (self.bin.len() + 4095) & !4095;
+            self.bin.resize(size, 0);
+            self.bin[8..12].copy_from_slice(&(size as u32).to_le_bytes());
+
+            let mut hive = b"regf".to_vec();
+            hive.resize(BASE_BLOCK_SIZE, 0);
+            hive[0x24..0x28].copy_from_slice(&root.to_le_bytes());
+            hive.extend(self.bin);
+            hive
+        }
+    }
+
+    fn utf16(s: &str) -> Vec<u8> {
+        s.encode_utf16()
+            .chain(Some(0))
+            .flat_map(u16::to_le_bytes)
+            .collect()
+    }
+
+    #[test]
+    fn reads_a_system_hive() {
+        let mut builder = Builder::new();
+        let web = builder.value(
+            "{web}",
+            1,
+            &utf16("v2.30|Action=Allow|Active=TRUE|Dir=In|Protocol=6|LPort=80|Name=Web|"),
+        );
+        let ipsec = builder.value(
+            "{ipsec}",
+            1,
+            &utf16("v2.10|Action=RequireInRequestOut|Active=TRUE|Name=Servers|EP2_4=10.0.0.0/8|Auth1Set=Default|"),
+        );
+        let rules = builder.key("FirewallRules", &[], &[web]);
+        let consec = builder.key("ConSecRules", &[], &[ipsec]);
+        let path = builder.value("LogFilePath", 2, &utf16(r"%systemroot%\pfirewall.log"));
+        let dropped = builder.value("LogDroppedPackets", 4, &1u32.to_le_bytes());
+        let logging = builder.key("Logging", &[], &[path, dropped]);
+        let inbound = builder.value("DefaultInboundAction", 4, &1u32.to_le_bytes());
+        let public = builder.key("PublicProfile", &[logging], &[inbound]);
+        let mut key = builder.key("FirewallPolicy", &[rules, consec, public], &[]);
+        for name in ["Parameters", "SharedAccess", "Services", "ControlSet002"].iter() {
+            key = builder.key(name, &[key], &[]);
+        }
+        let current = builder.value("Current", 4, &2u32.to_le_bytes());
+        let select = builder.key("Select", &[], &[current]);
+        let root = builder.key("ROOT", &[select, key], &[]);
+        let hive = Hive::from_vec(builder.finish(root)).unwrap();
+
+        assert_eq!(hive.root().name().unwrap(), "ROOT");
+        let policy = hive.firewall_policy().unwrap();
+        assert_eq!(policy.rules.len(), 1);
+        assert_eq!(policy.rules[0].id.as_deref(), Some("{web}"));
+        assert_eq!(policy.rules[0].local_ports.as_deref(), Some("80"));
+        let ipsec = &policy.connection_security_rules[0];
+        assert_eq!(ipsec.action, "RequireInRequestOut");
+        assert_eq!(ipsec.endpoint2_addresses.as_deref(), Some("10.0.0.0/8"));
+        assert_eq!(
+            ipsec.other_fields,
+            [("Auth1Set".to_string(), "Default".to_string())]
+        );
+        assert_eq!(
+            policy.profiles[&FirewallProfile::PUBLIC].default_inbound_action,
+            FirewallAction::Block
+        );
+        assert_eq!(
+            policy.logging[&FirewallProfile::PUBLIC],
+            LoggingSettings {
+                file_path: Some(r"%systemroot%\pfirewall.log".to_string()),
+                log_dropped_packets: Some(true),
+                ..LoggingSettings::default()
+            }
+        );
+    }
+
+    #[test]
+    fn reads_big_values() {
+        let mut builder = Builder::new();
+        let data: Vec<u8> = (0..BIG_DATA_SEGMENT_SIZE + 100).map(|i| i as u8).collect();
+        let first = builder.cell(&data[..BIG_DATA_SEGMENT_SIZE]);
+        let second = builder.cell(&data[BIG_DATA_SEGMENT_SIZE..]);
+        let segments: Vec<u8> = [first, second]
+            .iter()
+            .flat_map(|s| s.to_le_bytes())
+            .collect();
+        let segments = builder.cell(&segments);
+        let mut db = b"db\x02\0".to_vec();
+        db.extend_from_slice(&segments.to_le_bytes());
+        let db = builder.cell(&db);
+
+        let mut value = b"vk\x04\0".to_vec();
+        value.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        value.extend_from_slice(&db.to_le_bytes());
+        value.extend_from_slice(&3u32.to_le_bytes());
+        value.extend_from_slice(&VALUE_COMP_NAME.to_le_bytes());
+        value.extend_from_slice(b"\0\0Blob");
+        let value = builder.cell(&value);
+        let root = builder.key("Root", &[], &[value]);
+        let hive = Hive::from_vec(builder.finish(root)).unwrap();
+
+        assert_eq!(
+            hive.root().value("blob").unwrap(),
+            Some(RegistryValue::Binary(data))
+        );
+        assert_eq!(
+            hive.firewall_policy().unwrap_err().kind(),
+            std::io::ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn writes_wfw_files() {
+        let mut policy = HivePolicy {
+            rules: vec![
+                FirewallRuleData {
+                    id: Some("{web}".to_string()),
+                    protocol: 6,
+                    local_ports: Some("80,443".to_string()),
+                    remote_addresses: Some("LocalSubnet,fe80::/64".to_string()),
+                    profiles: FirewallProfile::DOMAIN,
+                    interface_types: Some("All".to_string()),
+                    ..FirewallRuleData::new("Web")
+                },
+                FirewallRuleData {
+                    id: Some("ünïcode".to_string()),
+                    description: Some("x".repeat(BIG_DATA_SEGMENT_SIZE)),
+                    interface_types: Some("All".to_string()),
+                    ..FirewallRuleData::new("Big")
+                },
+            ],
+            ..HivePolicy::default()
+        };
+        policy.connection_security_rules.push(
+            parse_connection_security_rule(
+                "{ipsec}",
+                "v2.10|Action=RequireInRequestOut|Active=TRUE|Name=Servers|Auth1Set=Default|",
+            )
+            .unwrap(),
+        );
+        policy.profiles.insert(
+            FirewallProfile::PRIVATE,
+            FirewallProfileSettings {
+                default_inbound_action: FirewallAction::Allow,
+                ..FirewallProfileSettings::default()
+            },
+        );
+        policy.logging.insert(
+            FirewallProfile::PRIVATE,
+            LoggingSettings {
+                max_file_size_kb: Some(4096),
+                log_successful_connections: Some(false),
+                ..LoggingSettings::default()
+            },
+        );
+
+        let bytes = policy.to_wfw(17763).unwrap();
+        assert_eq!(bytes.len() % BASE_BLOCK_SIZE, 0);
+        let checksum = (0..0x200)
+            .step_by(4)
+            .fold(0, |checksum, at| checksum ^ u32_at(&bytes, at).unwrap());
+        assert_eq!(checksum, 0);
+
+        let hive = Hive::from_vec(bytes).unwrap();
+        assert_eq!(hive.firewall_policy().unwrap(), policy);
+        let root = hive.root();
+        let names: Vec<_> = root
+            .subkeys()
+            .unwrap()
+            .iter()
+            .map(|key| key.name().unwrap())
+            .collect();
+        assert_eq!(names, ["ConSecRules", "FirewallRules", "StandardProfile"]);
+        let web = root
+            .subkey("FirewallRules")
+            .unwrap()
+            .unwrap()
+            .value("{web}");
+        assert!(web
+            .unwrap()
+            .unwrap()
+            .as_str()
+            .unwrap()
+            .starts_with("v2.30|"));
+        let security = u32_at(hive.cell(root.offset).unwrap(), 44).unwrap();
+        assert!(hive.cell(security).unwrap().starts_with(b"sk"));
+
+        policy
+            .profiles
+            .insert(FirewallProfile::ALL, FirewallProfileSettings::default());
+        assert_eq!(
+            policy.to_wfw(7600).unwrap_err().kind(),
+            std::io::ErrorKind::InvalidInput
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_hives() {
+        let mut builder = Builder::new();
+        let root = builder.key("Root", &[], &[]);
+        let hive = builder.finish(root);
+
+        let mut bad_root = hive.clone();
+        bad_root[0x24..0x28].copy_from_slice(&0x10_0000u32.to_le_bytes());
+        let mut bad_list = hive.clone();
+        // Point the root at a subkey list that is not one.
+        let key = BASE_BLOCK_SIZE + root as usize + 4;
+        bad_list[key + 20..key + 24].copy_from_slice(&1u32.to_le_bytes());
+        bad_list[key + 28..key + 32].copy_from_slice(&root.to_le_bytes());
+
+        assert_eq!(
+            Hive::from_vec(b"regf".to_vec()).unwrap_err().kind(),
+            std::io::ErrorKind::InvalidData
+        );
+        assert_eq!(
+            Hive::from_vec(bad_root).unwrap_err().kind(),
+            std::io::ErrorKind::InvalidData
+        );
+        let hive = Hive::from_vec(bad_list).unwrap();
+        assert_eq!(
+            hive.root().subkeys().unwrap_err().kind(),
+            std::io::ErrorKind::InvalidData
+        );
+    }
+}
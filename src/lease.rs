@@ -0,0 +1,343 @@
+//! Time-limited rules.
+//!
+//! A leased rule carries its expiry as an `expires` tag in its ownership marker.
+//! `Leases::sweep` finds expired rules in a `RuleStore` and removes or disables them.
+
+use crate::{
+    ownership::Ownership,
+    store::RuleStore,
+    FirewallRuleData,
+};
+use std::time::Duration;
+
+/// The ownership tag holding a lease's expiry, in seconds since the Unix epoch.
+pub const EXPIRES_TAG: &str = "expires";
+
+/// A source of the current time, in seconds since the Unix epoch.
+pub trait Clock {
+    fn now(&self) -> u64;
+}
+
+/// The system clock.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    }
+}
+
+impl<C: Clock + ?Sized> Clock for &C {
+    fn now(&self) -> u64 {
+        (**self).now()
+    }
+}
+
+/// When a rule's lease runs out, if it has one.
+pub fn expiry(rule: &FirewallRuleData) -> Option<u64> {
+    rule.ownership()?.tags.get(EXPIRES_TAG)?.parse().ok()
+}
+
+/// What the sweeper does with an expired rule.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ExpiryAction {
+    Remove,
+    /// Disable the rule but keep it around for inspection. Disabled rules are skipped by later sweeps.
+    Disable,
+}
+
+/// What a sweep did.
+#[derive(Debug, Default)]
+pub struct SweepReport {
+    pub removed: Vec<String>,
+    pub disabled: Vec<String>,
+    /// Expired rules that could not be dealt with; they will be retried on the next sweep.
+    pub failed: Vec<(String, std::io::Error)>,
+    /// The earliest expiry among the leases that are still active, to schedule the next sweep.
+    pub next_expiry: Option<u64>,
+}
+
+impl SweepReport {
+    /// Whether the sweep changed nothing and hit no errors.
+    pub fn is_empty(&self) -> bool {
+        self.removed.is_empty() && self.disabled.is_empty() && self.failed.is_empty()
+    }
+}
+
+impl std::fmt::Display for SweepReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for name in self.removed.iter() {
+            writeln!(f, "removed expired rule '{}'", name)?;
+        }
+        for name in self.disabled.iter() {
+            writeln!(f, "disabled expired rule '{}'", name)?;
+        }
+        for (name, error) in self.failed.iter() {
+            writeln!(f, "failed to expire rule '{}': {}", name, error)?;
+        }
+        Ok(())
+    }
+}
+
+/// Hands out and sweeps leases for the rules of a single owner.
+#[derive(Debug, Clone)]
+pub struct Leases<C: Clock = SystemClock> {
+    owner: String,
+    clock: C,
+}
+
+impl Leases<SystemClock> {
+    pub fn new(owner: impl Into<String>) -> Self {
+        Self::with_clock(owner, SystemClock)
+    }
+}
+
+impl<C: Clock> Leases<C> {
+    pub fn with_clock(owner: impl Into<String>, clock: C) -> Self {
+        Leases {
+            owner: owner.into(),
+            clock,
+        }
+    }
+
+    pub fn owner(&self) -> &str {
+        &self.owner
+    }
+
+    /// Stamp a rule with an ownership marker expiring `ttl` from now.
+    ///
+    /// An existing marker for the same owner keeps its version and tags.
+    pub fn stamp(&self, mut rule: FirewallRuleData, ttl: Duration) -> FirewallRuleData {
+        let now = self.clock.now();
+        let mut ownership = rule
+            .ownership()
+            .filter(|ownership| ownership.owner == self.owner)
+            .unwrap_or_else(|| Ownership::new(self.owner.clone()).with_created(now));
+        ownership.tags.insert(
+            EXPIRES_TAG.into(),
+            now.saturating_add(ttl.as_secs()).to_string(),
+        );
+
+        rule.set_ownership(&ownership);
+        rule
+    }
+
+    /// Add a rule that expires `ttl` from now, returning the rule as written.
+    pub fn add<S: RuleStore>(
+        &self,
+        mut store: S,
+        rule: FirewallRuleData,
+        ttl: Duration,
+    ) -> Result<FirewallRuleData, std::io::Error> {
+        let rule = self.stamp(rule, ttl);
+        store.add_rule(&rule)?;
+        Ok(rule)
+    }
+
+    /// Push back the expiry of one of our rules to `ttl` from now.
+    pub fn renew<S: RuleStore>(
+        &self,
+        mut store: S,
+        name: &str,
+        ttl: Duration,
+    ) -> Result<FirewallRuleData, std::io::Error> {
+        let rule = store
+            .get_rule(name)?
+            .filter(|rule| rule.is_owned_by(&self.owner))
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("no rule named '{}' owned by '{}'", name, self.owner),
+                )
+            })?;
+        let rule = self.stamp(rule, ttl);
+        store.update_rule(name, &rule)?;
+        Ok(rule)
+    }
+
+    /// Deal with every expired rule we own.
+    ///
+    /// Only failing to list the rules is an error; failures on single rules are collected in the report.
+    pub fn sweep<S: RuleStore>(
+        &self,
+        mut store: S,
+        action: ExpiryAction,
+    ) -> Result<SweepReport, std::io::Error> {
+        let now = self.clock.now();
+        let mut report = SweepReport::default();
+
+        for rule in store.rules()? {
+            if !rule.is_owned_by(&self.owner) {
+                continue;
+            }
+            let expires = match expiry(&rule) {
+                Some(expires) => expires,
+                None => continue,
+            };
+
+            if expires > now {
+                report.next_expiry = Some(report.next_expiry.map_or(expires, |e| e.min(expires)));
+                continue;
+            }
+
+            match action {
+                ExpiryAction::Remove => match store.remove_rule(&rule.name) {
+                    Ok(()) => report.removed.push(rule.name),
+                    Err(e) => report.failed.push((rule.name, e)),
+                },
+                ExpiryAction::Disable if rule.enabled => {
+                    let disabled = FirewallRuleData {
+                        enabled: false,
+                        ..rule.clone()
+                    };
+                    match store.update_rule(&rule.name, &disabled) {
+                        Ok(()) => report.disabled.push(rule.name),
+                        Err(e) => report.failed.push((rule.name, e)),
+                    }
+                }
+                ExpiryAction::Disable => {}
+            }
+        }
+
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::MemoryStore;
+    use std::cell::Cell;
+
+    struct FakeClock(Cell<u64>);
+
+    impl Clock for FakeClock {
+        fn now(&self) -> u64 {
+            self.0.get()
+        }
+    }
+
+    fn setup() -> (FakeClock, MemoryStore) {
+        let store = MemoryStore::with_rules(vec![FirewallRuleData::new("os rule")]);
+        (FakeClock(Cell::new(1_000)), store)
+    }
+
+    #[test]
+    fn stamp_records_expiry() {
+        let (clock, _) = setup();
+        let leases = Leases::with_clock("support", &clock);
+
+        let rule = leases.stamp(FirewallRuleData::new("rdp"), Duration::from_secs(60));
+        assert_eq!(expiry(&rule), Some(1_060));
+
+        let ownership = rule.ownership().unwrap();
+        assert_eq!(ownership.owner, "support");
+        assert_eq!(ownership.created, Some(1_000));
+    }
+
+    #[test]
+    fn sweep_removes_expired() {
+        let (clock, mut store) = setup();
+        let leases = Leases::with_clock("support", &clock);
+        leases
+            .add(
+                &mut store,
+                FirewallRuleData::new("short"),
+                Duration::from_secs(60),
+            )
+            .unwrap();
+        leases
+            .add(
+                &mut store,
+                FirewallRuleData::new("long"),
+                Duration::from_secs(600),
+            )
+            .unwrap();
+
+        let report = leases.sweep(&mut store, ExpiryAction::Remove).unwrap();
+        assert!(report.is_empty());
+        assert_eq!(report.next_expiry, Some(1_060));
+
+        clock.0.set(1_060);
+        let report = leases.sweep(&mut store, ExpiryAction::Remove).unwrap();
+        assert_eq!(report.removed, vec!["short".to_string()]);
+        assert_eq!(report.next_expiry, Some(1_600));
+        assert_eq!(report.to_string(), "removed expired rule 'short'\n");
+
+        let names: Vec<_> = store.rules.iter().map(|rule| rule.name.as_str()).collect();
+        assert_eq!(names, vec!["os rule", "long"]);
+    }
+
+    #[test]
+    fn sweep_disables_once() {
+        let (clock, mut store) = setup();
+        let leases = Leases::with_clock("support", &clock);
+        leases
+            .add(
+                &mut store,
+                FirewallRuleData::new("rdp"),
+                Duration::from_secs(60),
+            )
+            .unwrap();
+
+        clock.0.set(2_000);
+        let report = leases.sweep(&mut store, ExpiryAction::Disable).unwrap();
+        assert_eq!(report.disabled, vec!["rdp".to_string()]);
+        assert!(!store.get_rule("rdp").unwrap().unwrap().enabled);
+
+        let report = leases.sweep(&mut store, ExpiryAction::Disable).unwrap();
+        assert!(report.is_empty());
+    }
+
+    #[test]
+    fn sweep_ignores_other_owners() {
+        let (clock, mut store) = setup();
+        Leases::with_clock("someone-else", &clock)
+            .add(
+                &mut store,
+                FirewallRuleData::new("theirs"),
+                Duration::from_secs(1),
+            )
+            .unwrap();
+
+        clock.0.set(5_000);
+        let report = Leases::with_clock("support", &clock)
+            .sweep(&mut store, ExpiryAction::Remove)
+            .unwrap();
+        assert!(report.is_empty());
+        assert_eq!(store.rules.len(), 2);
+    }
+
+    #[test]
+    fn renew_extends() {
+        let (clock, mut store) = setup();
+        let leases = Leases::with_clock("support", &clock);
+        leases
+            .add(
+                &mut store,
+                FirewallRuleData::new("rdp"),
+                Duration::from_secs(60),
+            )
+            .unwrap();
+
+        clock.0.set(1_050);
+        leases
+            .renew(&mut store, "rdp", Duration::from_secs(60))
+            .unwrap();
+        let rule = store.get_rule("rdp").unwrap().unwrap();
+        assert_eq!(expiry(&rule), Some(1_110));
+        assert_eq!(rule.ownership().unwrap().created, Some(1_000));
+
+        assert_eq!(
+            leases
+                .renew(&mut store, "os rule", Duration::from_secs(60))
+                .unwrap_err()
+                .kind(),
+            std::io::ErrorKind::NotFound
+        );
+    }
+}
@@ -1,14 +1,61 @@
+#[cfg(windows)]
 pub mod policy;
+#[cfg(windows)]
 pub mod rule;
+#[cfg(windows)]
 pub mod rules;
 
+pub mod addresses;
+pub mod cim;
+pub mod data;
+pub mod diff;
+pub mod evaluate;
+pub mod event_log;
+pub mod firewall_log;
+pub mod hive;
+pub mod lease;
+pub mod merge;
+pub mod netsh;
+pub mod ownership;
+#[cfg(any(feature = "toml", feature = "yaml"))]
+pub mod policy_file;
+pub mod ports;
+pub mod powershell;
+pub mod reconcile;
+pub mod reg_file;
+pub mod registry_pol;
+#[cfg(feature = "serde")]
+mod serde_support;
+pub mod session;
+pub mod siem;
+pub mod snapshot;
+pub mod store;
+pub mod transaction;
+pub mod watch;
+mod xml;
+
+pub use self::{
+    data::{
+        FirewallProfileSettings,
+        FirewallRuleData,
+    },
+    session::FirewallSession,
+    store::{
+        MemoryStore,
+        RuleStore,
+    },
+    transaction::Transaction,
+};
+#[cfg(windows)]
 pub use self::{
     policy::FirewallPolicy,
     rule::FirewallRule,
     rules::FirewallRules,
 };
 use bitflags::bitflags;
+#[cfg(windows)]
 use com::sys::FAILED;
+#[cfg(windows)]
 use netfw_sys::{
     variant::VariantType,
     IEnumVARIANT,
@@ -17,16 +64,13 @@ use netfw_sys::{
     NET_FW_ACTION_ALLOW,
     NET_FW_ACTION_BLOCK,
     NET_FW_ACTION_MAX,
-    NET_FW_PROFILE2_ALL,
-    NET_FW_PROFILE2_DOMAIN,
-    NET_FW_PROFILE2_PRIVATE,
-    NET_FW_PROFILE2_PUBLIC,
     NET_FW_PROFILE_TYPE2,
     NET_FW_RULE_DIRECTION,
     NET_FW_RULE_DIR_IN,
     NET_FW_RULE_DIR_MAX,
     NET_FW_RULE_DIR_OUT,
 };
+#[cfg(windows)]
 use std::{
     convert::TryFrom,
     ffi::{
@@ -39,20 +83,90 @@ use std::{
         OsStringExt,
     },
 };
+#[cfg(windows)]
 use winapi::{
     shared::wtypes::BSTR,
     um::oleauto::SysAllocString,
 };
 
+// These mirror the NET_FW_PROFILE2_* values so the type is usable off-Windows.
 bitflags! {
-    pub struct FirewallProfile: NET_FW_PROFILE_TYPE2 {
-        const DOMAIN = NET_FW_PROFILE2_DOMAIN;
-        const PRIVATE = NET_FW_PROFILE2_PRIVATE;
-        const PUBLIC = NET_FW_PROFILE2_PUBLIC;
-        const ALL = NET_FW_PROFILE2_ALL;
+    #[derive(Default)]
+    pub struct FirewallProfile: u32 {
+        const DOMAIN = 0x1;
+        const PRIVATE = 0x2;
+        const PUBLIC = 0x4;
+        const ALL = 0x7fff_ffff;
     }
 }
 
+impl FirewallProfile {
+    /// The single profiles, in the order `netsh` and PowerShell list them.
+    pub const SINGLE: [FirewallProfile; 3] = [
+        FirewallProfile::DOMAIN,
+        FirewallProfile::PRIVATE,
+        FirewallProfile::PUBLIC,
+    ];
+}
+
+/// Formats with the PowerShell vocabulary, like "Domain,Private" or "Any".
+impl std::fmt::Display for FirewallProfile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.contains(FirewallProfile::ALL) {
+            return f.write_str("Any");
+        }
+
+        let mut first = true;
+        for (profile, name) in FirewallProfile::SINGLE
+            .iter()
+            .zip(&["Domain", "Private", "Public"])
+        {
+            if self.contains(*profile) {
+                if !first {
+                    f.write_str(",")?;
+                }
+                f.write_str(name)?;
+                first = false;
+            }
+        }
+
+        if first {
+            f.write_str("None")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for FirewallProfile {
+    type Err = std::io::Error;
+
+    /// Parses the PowerShell vocabulary, case-insensitively: a comma separated list of "Domain", "Private" and
+    /// "Public", or one of "Any", "All" or "None".
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut profiles = FirewallProfile::empty();
+        for name in s.split(',').map(str::trim) {
+            profiles |= match name.to_ascii_lowercase().as_str() {
+                "domain" => FirewallProfile::DOMAIN,
+                "private" => FirewallProfile::PRIVATE,
+                "public" => FirewallProfile::PUBLIC,
+                "any" | "all" => FirewallProfile::ALL,
+                "none" => FirewallProfile::empty(),
+                _ => return Err(parse_error("profile", s)),
+            };
+        }
+        Ok(profiles)
+    }
+}
+
+fn parse_error(what: &str, s: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        format!("invalid {} '{}'", what, s),
+    )
+}
+
+#[cfg(windows)]
 impl From<FirewallProfile> for NET_FW_PROFILE_TYPE2 {
     fn from(profile: FirewallProfile) -> Self {
         profile.bits()
@@ -66,6 +180,31 @@ pub enum FirewallAction {
     Max,
 }
 
+impl std::fmt::Display for FirewallAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            FirewallAction::Block => "Block",
+            FirewallAction::Allow => "Allow",
+            FirewallAction::Max => "Max",
+        })
+    }
+}
+
+impl std::str::FromStr for FirewallAction {
+    type Err = std::io::Error;
+
+    /// Parses "Allow" or "Block", case-insensitively.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "block" => Ok(FirewallAction::Block),
+            "allow" => Ok(FirewallAction::Allow),
+            "max" => Ok(FirewallAction::Max),
+            _ => Err(parse_error("action", s)),
+        }
+    }
+}
+
+#[cfg(windows)]
 impl From<FirewallAction> for NET_FW_ACTION {
     fn from(action: FirewallAction) -> Self {
         match action {
@@ -77,6 +216,7 @@ impl From<FirewallAction> for NET_FW_ACTION {
 }
 
 // NET_FW_ACTION is only a type-def, and I would rather have a fallible TryFrom for all u32s than a panicking From that may be called accidentally.
+#[cfg(windows)]
 impl TryFrom<NET_FW_ACTION> for FirewallAction {
     type Error = NET_FW_ACTION;
 
@@ -97,6 +237,31 @@ pub enum FirewallRuleDirection {
     Max,
 }
 
+impl std::fmt::Display for FirewallRuleDirection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            FirewallRuleDirection::In => "Inbound",
+            FirewallRuleDirection::Out => "Outbound",
+            FirewallRuleDirection::Max => "Max",
+        })
+    }
+}
+
+impl std::str::FromStr for FirewallRuleDirection {
+    type Err = std::io::Error;
+
+    /// Parses "Inbound" or "Outbound", or netsh's "in" and "out", case-insensitively.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "in" | "inbound" => Ok(FirewallRuleDirection::In),
+            "out" | "outbound" => Ok(FirewallRuleDirection::Out),
+            "max" => Ok(FirewallRuleDirection::Max),
+            _ => Err(parse_error("direction", s)),
+        }
+    }
+}
+
+#[cfg(windows)]
 impl From<FirewallRuleDirection> for NET_FW_RULE_DIRECTION {
     fn from(dir: FirewallRuleDirection) -> Self {
         match dir {
@@ -108,6 +273,7 @@ impl From<FirewallRuleDirection> for NET_FW_RULE_DIRECTION {
 }
 
 // Same as above
+#[cfg(windows)]
 impl TryFrom<NET_FW_RULE_DIRECTION> for FirewallRuleDirection {
     type Error = NET_FW_RULE_DIRECTION;
 
@@ -125,6 +291,7 @@ impl TryFrom<NET_FW_RULE_DIRECTION> for FirewallRuleDirection {
 /// Panics if bstr is null or bstr data length in bytes is not a multiple of 2
 /// # Safety
 /// bstr must be a valid BSTR.
+#[cfg(windows)]
 pub unsafe fn bstr_to_os_string(bstr: BSTR) -> OsString {
     assert!(!bstr.is_null(), "Null Pointer");
 
@@ -138,6 +305,7 @@ pub unsafe fn bstr_to_os_string(bstr: BSTR) -> OsString {
     OsString::from_wide(slice)
 }
 
+#[cfg(windows)]
 pub fn os_str_to_bstr(s: &OsStr) -> BSTR {
     let data: Vec<u16> = s.encode_wide().chain(once(0)).collect();
     let ptr = unsafe { SysAllocString(data.as_ptr()) };
@@ -145,9 +313,11 @@ pub fn os_str_to_bstr(s: &OsStr) -> BSTR {
     ptr
 }
 
+#[cfg(windows)]
 #[repr(transparent)]
 pub struct VariantEnumerator(IEnumVARIANT);
 
+#[cfg(windows)]
 impl VariantEnumerator {
     pub fn from_raw(raw: IEnumVARIANT) -> Self {
         VariantEnumerator(raw)
@@ -184,15 +354,18 @@ impl VariantEnumerator {
     }
 }
 
+#[cfg(windows)]
 #[repr(transparent)]
 pub struct FirewallRulesIter(VariantEnumerator);
 
+#[cfg(windows)]
 impl FirewallRulesIter {
     pub fn new(enumerator: VariantEnumerator) -> Self {
         FirewallRulesIter(enumerator)
     }
 }
 
+#[cfg(windows)]
 impl Iterator for FirewallRulesIter {
     type Item = Result<FirewallRule, std::io::Error>;
 
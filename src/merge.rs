@@ -0,0 +1,574 @@
+//! Three-way merges of rule sets.
+//!
+//! Given the rules both sides started from and what each side turned them into, `merge_rules` keeps every change that
+//! only one side made and reports a `Conflict` wherever both sides touched the same thing differently. Conflicts are
+//! resolved by a `Strategy` so the merge always produces a usable rule set, and are returned for review.
+//! Rules are matched between sets the same way `diff::diff_rules` does.
+
+use crate::{
+    diff::{
+        equivalent,
+        match_rules,
+    },
+    FirewallAction,
+    FirewallRuleData,
+};
+
+/// One side of a merge.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Side {
+    Ours,
+    Theirs,
+}
+
+impl std::fmt::Display for Side {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Side::Ours => "ours",
+            Side::Theirs => "theirs",
+        })
+    }
+}
+
+/// How to settle a conflict.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Strategy {
+    PreferOurs,
+    PreferTheirs,
+    /// Take whichever side blocks more traffic.
+    ///
+    /// `Block` beats `Allow` in action conflicts, an enabled blocking rule survives being deleted while any other
+    /// rule is deleted, and of two conflicting additions a blocking one wins. Other conflicts prefer ours.
+    PreferStricter,
+}
+
+/// Something both sides changed differently, and how it was settled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Conflict {
+    /// Both sides changed the same field of a rule to different values.
+    Field {
+        rule: String,
+        field: &'static str,
+        base: Option<String>,
+        ours: Option<String>,
+        theirs: Option<String>,
+        resolution: Side,
+    },
+    /// One side deleted a rule the other side modified.
+    DeleteModify {
+        rule: String,
+        deleted_by: Side,
+        modified: Box<FirewallRuleData>,
+        resolution: Side,
+    },
+    /// Both sides added a rule with the same identity but different fields.
+    AddAdd {
+        rule: String,
+        ours: Box<FirewallRuleData>,
+        theirs: Box<FirewallRuleData>,
+        resolution: Side,
+    },
+}
+
+impl Conflict {
+    /// The side whose version ended up in the merged set.
+    pub fn resolution(&self) -> Side {
+        match self {
+            Conflict::Field { resolution, .. }
+            | Conflict::DeleteModify { resolution, .. }
+            | Conflict::AddAdd { resolution, .. } => *resolution,
+        }
+    }
+}
+
+impl std::fmt::Display for Conflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let unset = |value: &Option<String>| value.clone().unwrap_or_else(|| "<unset>".into());
+        match self {
+            Conflict::Field {
+                rule,
+                field,
+                base,
+                ours,
+                theirs,
+                resolution,
+            } => write!(
+                f,
+                "rule '{}' field {}: base {}, ours {}, theirs {}; took {}",
+                rule,
+                field,
+                unset(base),
+                unset(ours),
+                unset(theirs),
+                resolution
+            ),
+            Conflict::DeleteModify {
+                rule,
+                deleted_by,
+                resolution,
+                ..
+            } => {
+                let outcome = if resolution == deleted_by {
+                    "deleted"
+                } else {
+                    "kept"
+                };
+                write!(
+                    f,
+                    "rule '{}' deleted by {} but modified by the other side; {}",
+                    rule, deleted_by, outcome
+                )
+            }
+            Conflict::AddAdd {
+                rule, resolution, ..
+            } => write!(
+                f,
+                "rule '{}' added differently by both sides; took {}",
+                rule, resolution
+            ),
+        }
+    }
+}
+
+/// The result of a merge.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Merge {
+    pub rules: Vec<FirewallRuleData>,
+    /// Every conflict, already resolved in `rules`.
+    pub conflicts: Vec<Conflict>,
+}
+
+impl Merge {
+    /// Whether the merge needed no conflict resolution.
+    pub fn is_clean(&self) -> bool {
+        self.conflicts.is_empty()
+    }
+}
+
+/// Merge the changes `ours` and `theirs` each made to `base`.
+///
+/// Surviving rules keep our order, followed by rules only theirs kept or added, in their order.
+pub fn merge_rules(
+    base: &[FirewallRuleData],
+    ours: &[FirewallRuleData],
+    theirs: &[FirewallRuleData],
+    strategy: Strategy,
+) -> Merge {
+    let ours_match = match_rules(base, ours);
+    let theirs_match = match_rules(base, theirs);
+
+    let mut theirs_of_base = vec![None; base.len()];
+    for (k, i) in theirs_match.iter().enumerate() {
+        if let Some(i) = i {
+            theirs_of_base[*i] = Some(k);
+        }
+    }
+    let mut ours_of_base = vec![None; base.len()];
+    for (j, i) in ours_match.iter().enumerate() {
+        if let Some(i) = i {
+            ours_of_base[*i] = Some(j);
+        }
+    }
+
+    // Additions on both sides are matched against each other.
+    let ours_added: Vec<usize> = (0..ours.len())
+        .filter(|j| ours_match[*j].is_none())
+        .collect();
+    let theirs_added: Vec<usize> = (0..theirs.len())
+        .filter(|k| theirs_match[*k].is_none())
+        .collect();
+    let added_match = match_rules(
+        &ours_added
+            .iter()
+            .map(|j| ours[*j].clone())
+            .collect::<Vec<_>>(),
+        &theirs_added
+            .iter()
+            .map(|k| theirs[*k].clone())
+            .collect::<Vec<_>>(),
+    );
+    let mut theirs_addition_taken = vec![false; theirs.len()];
+    let mut ours_of_addition = vec![None; ours.len()];
+    for (position, ours_position) in added_match.iter().enumerate() {
+        if let Some(ours_position) = ours_position {
+            let (j, k) = (ours_added[*ours_position], theirs_added[position]);
+            ours_of_addition[j] = Some(k);
+            theirs_addition_taken[k] = true;
+        }
+    }
+
+    let mut merge = Merge::default();
+
+    for (j, our_rule) in ours.iter().enumerate() {
+        match ours_match[j] {
+            Some(i) => match theirs_of_base[i] {
+                Some(k) => {
+                    let rule = merge_rule(&base[i], our_rule, &theirs[k], strategy, &mut merge);
+                    merge.rules.push(rule);
+                }
+                None => {
+                    if !unchanged(&base[i], our_rule) {
+                        let keep = resolve_delete(strategy, Side::Theirs, our_rule);
+                        merge.conflicts.push(Conflict::DeleteModify {
+                            rule: base[i].name.clone(),
+                            deleted_by: Side::Theirs,
+                            modified: Box::new(our_rule.clone()),
+                            resolution: keep,
+                        });
+                        if keep == Side::Ours {
+                            merge.rules.push(our_rule.clone());
+                        }
+                    }
+                }
+            },
+            None => match ours_of_addition[j] {
+                Some(k) if !unchanged(our_rule, &theirs[k]) => {
+                    let resolution = resolve_addition(strategy, our_rule, &theirs[k]);
+                    merge.conflicts.push(Conflict::AddAdd {
+                        rule: our_rule.name.clone(),
+                        ours: Box::new(our_rule.clone()),
+                        theirs: Box::new(theirs[k].clone()),
+                        resolution,
+                    });
+                    merge.rules.push(match resolution {
+                        Side::Ours => our_rule.clone(),
+                        Side::Theirs => theirs[k].clone(),
+                    });
+                }
+                _ => merge.rules.push(our_rule.clone()),
+            },
+        }
+    }
+
+    for (k, their_rule) in theirs.iter().enumerate() {
+        match theirs_match[k] {
+            Some(i) if ours_of_base[i].is_none() && !unchanged(&base[i], their_rule) => {
+                let keep = resolve_delete(strategy, Side::Ours, their_rule);
+                merge.conflicts.push(Conflict::DeleteModify {
+                    rule: base[i].name.clone(),
+                    deleted_by: Side::Ours,
+                    modified: Box::new(their_rule.clone()),
+                    resolution: keep,
+                });
+                if keep == Side::Theirs {
+                    merge.rules.push(their_rule.clone());
+                }
+            }
+            None if !theirs_addition_taken[k] => merge.rules.push(their_rule.clone()),
+            _ => {}
+        }
+    }
+
+    merge
+}
+
+/// Merge a rule both sides kept, field by field.
+fn merge_rule(
+    base: &FirewallRuleData,
+    ours: &FirewallRuleData,
+    theirs: &FirewallRuleData,
+    strategy: Strategy,
+    merge: &mut Merge,
+) -> FirewallRuleData {
+    let mut merged = ours.clone();
+    if merged.id.is_none() {
+        merged.id = theirs.id.clone();
+    }
+
+    let fields = base
+        .field_values()
+        .into_iter()
+        .zip(ours.field_values())
+        .zip(theirs.field_values());
+    for (((field, base_value), (_, ours_value)), (_, theirs_value)) in fields {
+        let ours_changed = !same(field, &base_value, &ours_value);
+        let theirs_changed = !same(field, &base_value, &theirs_value);
+
+        if !theirs_changed || (ours_changed && same(field, &ours_value, &theirs_value)) {
+            continue;
+        }
+        if !ours_changed {
+            copy_field(&mut merged, theirs, field);
+            continue;
+        }
+
+        let resolution = resolve_field(strategy, field, theirs);
+        if resolution == Side::Theirs {
+            copy_field(&mut merged, theirs, field);
+        }
+        merge.conflicts.push(Conflict::Field {
+            rule: base.name.clone(),
+            field,
+            base: base_value,
+            ours: ours_value,
+            theirs: theirs_value,
+            resolution,
+        });
+    }
+
+    merged
+}
+
+fn same(field: &str, a: &Option<String>, b: &Option<String>) -> bool {
+    a == b || equivalent(field, a.as_deref(), b.as_deref())
+}
+
+fn unchanged(a: &FirewallRuleData, b: &FirewallRuleData) -> bool {
+    a.field_values()
+        .into_iter()
+        .zip(b.field_values())
+        .all(|((field, a), (_, b))| same(field, &a, &b))
+}
+
+fn blocks(rule: &FirewallRuleData) -> bool {
+    rule.enabled && rule.action == FirewallAction::Block
+}
+
+fn resolve_field(strategy: Strategy, field: &str, theirs: &FirewallRuleData) -> Side {
+    match strategy {
+        Strategy::PreferOurs => Side::Ours,
+        Strategy::PreferTheirs => Side::Theirs,
+        Strategy::PreferStricter if field == "action" && theirs.action == FirewallAction::Block => {
+            Side::Theirs
+        }
+        Strategy::PreferStricter => Side::Ours,
+    }
+}
+
+/// Which side wins when `deleted_by` deleted a rule the other side changed into `modified`.
+fn resolve_delete(strategy: Strategy, deleted_by: Side, modified: &FirewallRuleData) -> Side {
+    let modifier = match deleted_by {
+        Side::Ours => Side::Theirs,
+        Side::Theirs => Side::Ours,
+    };
+    match strategy {
+        Strategy::PreferOurs => Side::Ours,
+        Strategy::PreferTheirs => Side::Theirs,
+        Strategy::PreferStricter if blocks(modified) => modifier,
+        Strategy::PreferStricter => deleted_by,
+    }
+}
+
+fn resolve_addition(
+    strategy: Strategy,
+    ours: &FirewallRuleData,
+    theirs: &FirewallRuleData,
+) -> Side {
+    match strategy {
+        Strategy::PreferOurs => Side::Ours,
+        Strategy::PreferTheirs => Side::Theirs,
+        Strategy::PreferStricter if blocks(theirs) && !blocks(ours) => Side::Theirs,
+        Strategy::PreferStricter => Side::Ours,
+    }
+}
+
+/// Copy one field, named as in `FirewallRuleData::field_values`, from `source` to `target`.
+fn copy_field(target: &mut FirewallRuleData, source: &FirewallRuleData, field: &str) {
+    match field {
+        "name" => target.name = source.name.clone(),
+        "description" => target.description = source.description.clone(),
+        "application_name" => target.application_name = source.application_name.clone(),
+        "service_name" => target.service_name = source.service_name.clone(),
+        "protocol" => target.protocol = source.protocol,
+        "local_ports" => target.local_ports = source.local_ports.clone(),
+        "remote_ports" => target.remote_ports = source.remote_ports.clone(),
+        "local_addresses" => target.local_addresses = source.local_addresses.clone(),
+        "remote_addresses" => target.remote_addresses = source.remote_addresses.clone(),
+        "icmp_types_and_codes" => target.icmp_types_and_codes = source.icmp_types_and_codes.clone(),
+        "direction" => target.direction = source.direction,
+        "interfaces" => target.interfaces = source.interfaces.clone(),
+        "interface_types" => target.interface_types = source.interface_types.clone(),
+        "enabled" => target.enabled = source.enabled,
+        "grouping" => target.grouping = source.grouping.clone(),
+        "profiles" => target.profiles = source.profiles,
+        "edge_traversal" => target.edge_traversal = source.edge_traversal,
+        "action" => target.action = source.action,
+        _ => unreachable!("unknown rule field '{}'", field),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rule(name: &str, ports: &str) -> FirewallRuleData {
+        FirewallRuleData {
+            local_ports: Some(ports.into()),
+            ..FirewallRuleData::new(name)
+        }
+    }
+
+    fn names(merge: &Merge) -> Vec<&str> {
+        merge.rules.iter().map(|rule| rule.name.as_str()).collect()
+    }
+
+    #[test]
+    fn clean_merge() {
+        let base = vec![rule("a", "1"), rule("b", "2"), rule("c", "3")];
+        let ours = vec![
+            FirewallRuleData {
+                enabled: false,
+                ..rule("a", "1")
+            },
+            rule("b", "2"),
+            rule("ours", "4"),
+        ];
+        let theirs = vec![rule("a", "10"), rule("c", "3"), rule("theirs", "5")];
+
+        let merge = merge_rules(&base, &ours, &theirs, Strategy::PreferOurs);
+        assert!(merge.is_clean(), "{:?}", merge.conflicts);
+        // b was deleted by theirs, c by us.
+        assert_eq!(names(&merge), vec!["a", "ours", "theirs"]);
+        assert_eq!(
+            merge.rules[0],
+            FirewallRuleData {
+                enabled: false,
+                ..rule("a", "10")
+            }
+        );
+    }
+
+    #[test]
+    fn same_change_on_both_sides() {
+        let base = vec![rule("a", "1")];
+        let merge = merge_rules(
+            &base,
+            &[rule("a", "80,443")],
+            &[rule("a", "443, 80")],
+            Strategy::PreferTheirs,
+        );
+        assert!(merge.is_clean());
+        assert_eq!(merge.rules, vec![rule("a", "80,443")]);
+    }
+
+    #[test]
+    fn field_conflicts() {
+        let base = vec![rule("a", "1")];
+        let ours = vec![FirewallRuleData {
+            action: FirewallAction::Allow,
+            ..rule("a", "2")
+        }];
+        let theirs = vec![FirewallRuleData {
+            action: FirewallAction::Block,
+            ..rule("a", "3")
+        }];
+
+        // Only local_ports conflicts: ours left the action alone, so theirs' Block is taken cleanly.
+        let merge = merge_rules(&base, &ours, &theirs, Strategy::PreferOurs);
+        assert_eq!(
+            merge.rules,
+            vec![FirewallRuleData {
+                action: FirewallAction::Block,
+                ..rule("a", "2")
+            }]
+        );
+        assert_eq!(merge.conflicts.len(), 1);
+        assert_eq!(
+            merge.conflicts[0].to_string(),
+            "rule 'a' field local_ports: base 1, ours 2, theirs 3; took ours"
+        );
+
+        let merge = merge_rules(&base, &ours, &theirs, Strategy::PreferTheirs);
+        assert_eq!(merge.rules, theirs);
+
+        let merge = merge_rules(&base, &ours, &theirs, Strategy::PreferStricter);
+        assert_eq!(merge.rules[0].local_ports.as_deref(), Some("2"));
+
+        let blocked_base = vec![FirewallRuleData {
+            action: FirewallAction::Block,
+            ..rule("a", "1")
+        }];
+        let loosened = vec![FirewallRuleData {
+            action: FirewallAction::Allow,
+            ..rule("a", "1")
+        }];
+        let tightened = vec![FirewallRuleData {
+            action: FirewallAction::Max,
+            ..rule("a", "1")
+        }];
+        let merge = merge_rules(
+            &blocked_base,
+            &loosened,
+            &tightened,
+            Strategy::PreferStricter,
+        );
+        assert_eq!(merge.conflicts[0].resolution(), Side::Ours);
+    }
+
+    #[test]
+    fn stricter_action_wins() {
+        let base = vec![FirewallRuleData {
+            action: FirewallAction::Max,
+            ..rule("a", "1")
+        }];
+        let ours = vec![rule("a", "1")];
+        let theirs = vec![FirewallRuleData {
+            action: FirewallAction::Block,
+            ..rule("a", "1")
+        }];
+
+        let merge = merge_rules(&base, &ours, &theirs, Strategy::PreferStricter);
+        assert_eq!(merge.rules, theirs);
+        assert_eq!(merge.conflicts[0].resolution(), Side::Theirs);
+
+        let merge = merge_rules(&base, &theirs, &ours, Strategy::PreferStricter);
+        assert_eq!(merge.rules, theirs);
+        assert_eq!(merge.conflicts[0].resolution(), Side::Ours);
+    }
+
+    #[test]
+    fn delete_modify() {
+        let base = vec![rule("allow", "1"), rule("block", "2")];
+        let blocked = FirewallRuleData {
+            action: FirewallAction::Block,
+            ..rule("block", "20")
+        };
+        let ours: Vec<FirewallRuleData> = vec![];
+        let theirs = vec![rule("allow", "10"), blocked.clone()];
+
+        let merge = merge_rules(&base, &ours, &theirs, Strategy::PreferOurs);
+        assert!(merge.rules.is_empty());
+        assert_eq!(merge.conflicts.len(), 2);
+        assert_eq!(
+            merge.conflicts[0].to_string(),
+            "rule 'allow' deleted by ours but modified by the other side; deleted"
+        );
+
+        let merge = merge_rules(&base, &ours, &theirs, Strategy::PreferTheirs);
+        assert_eq!(merge.rules, theirs);
+
+        // Deleting the allow rule is stricter, deleting the block rule is not.
+        let merge = merge_rules(&base, &ours, &theirs, Strategy::PreferStricter);
+        assert_eq!(merge.rules, vec![blocked.clone()]);
+
+        let merge = merge_rules(&base, &theirs, &ours, Strategy::PreferStricter);
+        assert_eq!(merge.rules, vec![blocked]);
+        assert_eq!(merge.conflicts[1].resolution(), Side::Ours);
+    }
+
+    #[test]
+    fn add_add() {
+        let ours = vec![rule("new", "1")];
+        let theirs = vec![FirewallRuleData {
+            action: FirewallAction::Block,
+            ..rule("new", "2")
+        }];
+
+        let merge = merge_rules(&[], &ours, &ours, Strategy::PreferTheirs);
+        assert!(merge.is_clean());
+        assert_eq!(merge.rules, ours);
+
+        let merge = merge_rules(&[], &ours, &theirs, Strategy::PreferOurs);
+        assert_eq!(merge.rules, ours);
+        assert!(matches!(
+            merge.conflicts[0],
+            Conflict::AddAdd {
+                resolution: Side::Ours,
+                ..
+            }
+        ));
+
+        let merge = merge_rules(&[], &ours, &theirs, Strategy::PreferStricter);
+        assert_eq!(merge.rules, theirs);
+    }
+}
@@ -0,0 +1,929 @@
+//! `netsh advfirewall` command lines and output.
+//!
+//! Commands are generated from owned rule values so runbooks can be written for hosts where only `netsh` is used,
+//! and the text `netsh` prints is parsed back so support bundles can be read without COM. The parsers understand the
+//! English output of `netsh advfirewall firewall show rule name=all verbose` and `netsh advfirewall show allprofiles`.
+//!
+//! `netsh` has no notion of rule IDs and cannot set interface aliases, and it takes at most one interface type and
+//! one ICMP type per rule; generating a command for a rule that needs more fails with `InvalidInput`.
+
+use crate::{
+    data::{
+        every_profile,
+        list_entries,
+        parse_protocol,
+        protocol_name,
+    },
+    diff::{
+        semantic_changes,
+        RuleSetDiff,
+    },
+    FirewallAction,
+    FirewallProfile,
+    FirewallProfileSettings,
+    FirewallRuleData,
+    FirewallRuleDirection,
+};
+use std::collections::BTreeMap;
+
+const FIREWALL: &str = "netsh advfirewall firewall";
+
+/// The command that creates a rule.
+pub fn add_rule(rule: &FirewallRuleData) -> Result<String, std::io::Error> {
+    let mut command = format!("{} add rule", FIREWALL);
+    for (_, parameter) in rule_parameters(rule, false)? {
+        command.push(' ');
+        command.push_str(&parameter);
+    }
+    Ok(command)
+}
+
+/// The command that deletes every rule with the rule's name and direction.
+pub fn delete_rule(rule: &FirewallRuleData) -> Result<String, std::io::Error> {
+    Ok(format!("{} delete rule {}", FIREWALL, select(rule)?))
+}
+
+/// The commands that turn `old` into `new`, changing only what differs. Empty if nothing `netsh` can set does.
+///
+/// `netsh` selects rules for `set rule` by group, so a change of group deletes and re-adds the rule.
+pub fn set_rule(
+    old: &FirewallRuleData,
+    new: &FirewallRuleData,
+) -> Result<Vec<String>, std::io::Error> {
+    let changes = semantic_changes(old, new);
+    if changes.is_empty() {
+        return Ok(Vec::new());
+    }
+    if changes.iter().any(|change| change.field == "grouping") {
+        return Ok(vec![delete_rule(old)?, add_rule(new)?]);
+    }
+
+    let changed = |field| changes.iter().any(|change| change.field == field);
+    if changed("application_name") && new.application_name.is_none() {
+        return Err(unsupported(new, "netsh cannot clear a program"));
+    }
+
+    // netsh folds ICMP types into the protocol, and ports are only valid for some protocols.
+    let protocol_changed = changed("protocol") || changed("icmp_types_and_codes");
+    let parameters: Vec<_> = rule_parameters(new, true)?
+        .into_iter()
+        .filter(|(field, _)| {
+            changed(field)
+                || (protocol_changed && ["protocol", "local_ports", "remote_ports"].contains(field))
+        })
+        .map(|(_, parameter)| parameter)
+        .collect();
+    if parameters.is_empty() {
+        return Ok(Vec::new());
+    }
+    Ok(vec![format!(
+        "{} set rule {} new {}",
+        FIREWALL,
+        select(old)?,
+        parameters.join(" ")
+    )])
+}
+
+/// The commands that apply every setting of one or more profiles: once for `allprofiles` if the mask holds every
+/// profile, otherwise once per profile. Fails on a mask with no profile.
+pub fn set_profile(
+    profile: FirewallProfile,
+    settings: &FirewallProfileSettings,
+) -> Result<Vec<String>, std::io::Error> {
+    let stores: Vec<_> = if profile.contains(every_profile()) {
+        vec!["allprofiles".to_string()]
+    } else {
+        FirewallProfile::SINGLE
+            .iter()
+            .filter(|single| profile.contains(**single))
+            .map(|single| single.to_string().to_ascii_lowercase() + "profile")
+            .collect()
+    };
+    if stores.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("profile mask {:#x} names no profile", profile.bits()),
+        ));
+    }
+
+    let inbound = match (
+        settings.block_all_inbound_traffic,
+        settings.default_inbound_action,
+    ) {
+        (true, _) => "blockinboundalways",
+        (false, FirewallAction::Block) => "blockinbound",
+        (false, _) => "allowinbound",
+    };
+    let outbound = match settings.default_outbound_action {
+        FirewallAction::Block => "blockoutbound",
+        _ => "allowoutbound",
+    };
+
+    Ok(stores
+        .iter()
+        .flat_map(|store| {
+            let set = format!("netsh advfirewall set {}", store);
+            vec![
+                format!("{} state {}", set, on_off(settings.firewall_enabled)),
+                format!("{} firewallpolicy {},{}", set, inbound, outbound),
+                format!(
+                    "{} settings inboundusernotification {}",
+                    set,
+                    enable_disable(!settings.notifications_disabled)
+                ),
+                format!(
+                    "{} settings unicastresponsetomulticast {}",
+                    set,
+                    enable_disable(!settings.unicast_responses_to_multicast_broadcast_disabled)
+                ),
+            ]
+        })
+        .collect())
+}
+
+/// The commands that apply the changes in a diff, turning the old side into the new one.
+pub fn diff_commands(diff: &RuleSetDiff) -> Result<Vec<String>, std::io::Error> {
+    let mut commands = Vec::new();
+    for rule in diff.removed.iter() {
+        commands.push(delete_rule(rule)?);
+    }
+    for change in diff.modified.iter() {
+        commands.extend(set_rule(&change.old, &change.new)?);
+    }
+    for rule in diff.added.iter() {
+        commands.push(add_rule(rule)?);
+    }
+    Ok(commands)
+}
+
+/// The `name=... dir=...` arguments selecting a rule.
+fn select(rule: &FirewallRuleData) -> Result<String, std::io::Error> {
+    // `name=all` selects every rule.
+    if rule.name.eq_ignore_ascii_case("all") {
+        return Err(unsupported(rule, "a rule named 'all' cannot be selected"));
+    }
+    Ok(format!(
+        "{} {}",
+        argument(rule, "name", &rule.name)?,
+        argument(rule, "dir", direction(rule.direction))?
+    ))
+}
+
+/// The `key=value` arguments describing a rule, each with the field it comes from.
+///
+/// For `set rule` unset fields are reset to `any`.
+fn rule_parameters(
+    rule: &FirewallRuleData,
+    set: bool,
+) -> Result<Vec<(&'static str, String)>, std::io::Error> {
+    if rule.interfaces.is_some() {
+        return Err(unsupported(rule, "netsh cannot set interfaces"));
+    }
+
+    let mut parameters = Vec::new();
+    let mut push = |field, key, value: Option<&str>, unset: Option<&str>| {
+        if let Some(value) = value.or(if set { unset } else { None }) {
+            parameters.push((field, argument(rule, key, value)?));
+        }
+        Ok::<_, std::io::Error>(())
+    };
+
+    push("name", "name", Some(&rule.name), None)?;
+    push("direction", "dir", Some(direction(rule.direction)), None)?;
+    push(
+        "action",
+        "action",
+        Some(match rule.action {
+            FirewallAction::Block => "block",
+            _ => "allow",
+        }),
+        None,
+    )?;
+    push(
+        "description",
+        "description",
+        rule.description.as_deref(),
+        Some(""),
+    )?;
+    push(
+        "application_name",
+        "program",
+        rule.application_name.as_deref(),
+        None,
+    )?;
+    push(
+        "service_name",
+        "service",
+        rule.service_name.as_deref(),
+        Some("any"),
+    )?;
+
+    let protocol = protocol_name(rule.protocol)
+        .map_or_else(|| rule.protocol.to_string(), str::to_ascii_lowercase);
+    let protocol = match (rule.protocol, rule.icmp_types_and_codes.as_deref()) {
+        (1, Some(icmp)) | (58, Some(icmp)) => format!("{}:{}", protocol, icmp_type(rule, icmp)?),
+        _ => protocol,
+    };
+    push("protocol", "protocol", Some(&protocol), None)?;
+
+    let ports_allowed = rule.protocol == 6 || rule.protocol == 17;
+    let local_ports = rule.local_ports.as_deref().map(ports);
+    let remote_ports = rule.remote_ports.as_deref().map(ports);
+    push(
+        "local_ports",
+        "localport",
+        local_ports.as_deref(),
+        Some("any").filter(|_| ports_allowed),
+    )?;
+    push(
+        "remote_ports",
+        "remoteport",
+        remote_ports.as_deref(),
+        Some("any").filter(|_| ports_allowed),
+    )?;
+    let local_addresses = rule.local_addresses.as_deref().map(addresses);
+    let remote_addresses = rule.remote_addresses.as_deref().map(addresses);
+    push(
+        "local_addresses",
+        "localip",
+        local_addresses.as_deref(),
+        Some("any"),
+    )?;
+    push(
+        "remote_addresses",
+        "remoteip",
+        remote_addresses.as_deref(),
+        Some("any"),
+    )?;
+
+    let interface_type = rule
+        .interface_types
+        .as_deref()
+        .map(|types| interface_type(rule, types))
+        .transpose()?;
+    push(
+        "interface_types",
+        "interfacetype",
+        interface_type,
+        Some("any"),
+    )?;
+    push("profiles", "profile", Some(&profiles(rule.profiles)), None)?;
+    push("enabled", "enable", Some(yes_no(rule.enabled)), None)?;
+    push(
+        "edge_traversal",
+        "edge",
+        Some(yes_no(rule.edge_traversal)),
+        None,
+    )?;
+
+    Ok(parameters)
+}
+
+/// Format a `key=value` argument, quoting the value if it needs it.
+///
+/// Neither `netsh` nor `cmd.exe` have a way to escape a double quote inside a quoted value, so those are refused.
+fn argument(rule: &FirewallRuleData, key: &str, value: &str) -> Result<String, std::io::Error> {
+    if value.contains(|c: char| c == '"' || c.is_control()) {
+        return Err(unsupported(
+            rule,
+            &format!("{} cannot contain quotes or control characters", key),
+        ));
+    }
+    if value.is_empty() || value.contains(|c: char| c.is_whitespace() || "&|<>^()".contains(c)) {
+        Ok(format!("{}=\"{}\"", key, value))
+    } else {
+        Ok(format!("{}={}", key, value))
+    }
+}
+
+fn direction(direction: FirewallRuleDirection) -> &'static str {
+    match direction {
+        FirewallRuleDirection::Out => "out",
+        _ => "in",
+    }
+}
+
+fn yes_no(value: bool) -> &'static str {
+    if value {
+        "yes"
+    } else {
+        "no"
+    }
+}
+
+fn on_off(value: bool) -> &'static str {
+    if value {
+        "on"
+    } else {
+        "off"
+    }
+}
+
+fn enable_disable(value: bool) -> &'static str {
+    if value {
+        "enable"
+    } else {
+        "disable"
+    }
+}
+
+fn profiles(profiles: FirewallProfile) -> String {
+    if profiles.contains(every_profile()) {
+        return "any".to_string();
+    }
+    profiles.to_string().to_ascii_lowercase()
+}
+
+fn ports(field: &str) -> String {
+    let entries = list_entries(field);
+    if entries.is_empty() || entries.contains(&"*") {
+        return "any".to_string();
+    }
+    entries.join(",")
+}
+
+fn addresses(field: &str) -> String {
+    ports(field)
+}
+
+/// netsh takes a single `type,code` pair, with `any` for every type or code.
+fn icmp_type(rule: &FirewallRuleData, icmp: &str) -> Result<String, std::io::Error> {
+    match list_entries(icmp).as_slice() {
+        [] | ["*"] => Ok("any".to_string()),
+        [entry] => {
+            let mut parts = entry.splitn(2, ':');
+            let icmp_type = parts.next().unwrap_or_default();
+            let code = parts.next().unwrap_or("*");
+            let any = |s: &str| {
+                if s == "*" {
+                    "any".to_string()
+                } else {
+                    s.to_string()
+                }
+            };
+            Ok(format!("{},{}", any(icmp_type), any(code)))
+        }
+        _ => Err(unsupported(rule, "netsh takes a single ICMP type")),
+    }
+}
+
+/// Translate `INetFwRule` interface types, like `Lan`, into netsh's, of which a rule can have one.
+fn interface_type(rule: &FirewallRuleData, types: &str) -> Result<&'static str, std::io::Error> {
+    match list_entries(types).as_slice() {
+        [] => Ok("any"),
+        [single] => match single.to_ascii_lowercase().as_str() {
+            "all" => Ok("any"),
+            "lan" => Ok("lan"),
+            "wireless" => Ok("wireless"),
+            "remoteaccess" => Ok("ras"),
+            _ => Err(unsupported(
+                rule,
+                &format!("unknown interface type '{}'", single),
+            )),
+        },
+        types if types.iter().any(|t| t.eq_ignore_ascii_case("all")) => Ok("any"),
+        _ => Err(unsupported(rule, "netsh takes a single interface type")),
+    }
+}
+
+fn unsupported(rule: &FirewallRuleData, reason: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        format!("cannot express rule '{}' for netsh: {}", rule.name, reason),
+    )
+}
+
+/// Parse the output of `netsh advfirewall firewall show rule name=all verbose`.
+///
+/// Fields netsh prints as `Any` are left unset, except interface types, which become `All` like `FirewallRule`
+/// reports them.
+pub fn parse_rules(text: &str) -> Result<Vec<FirewallRuleData>, std::io::Error> {
+    let mut rules = Vec::new();
+    let mut rule: Option<FirewallRuleData> = None;
+    // The key an indented continuation line belongs to.
+    let mut last_key = String::new();
+
+    for line in text.lines() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed == "Ok." || trimmed.chars().all(|c| c == '-') {
+            continue;
+        }
+
+        if line.starts_with(char::is_whitespace) {
+            let rule = rule
+                .as_mut()
+                .ok_or_else(|| invalid_data(format!("unexpected line '{}'", trimmed)))?;
+            if last_key == "Protocol" {
+                parse_icmp_row(rule, trimmed)?;
+            }
+            continue;
+        }
+
+        let (key, value) = split_field(trimmed)
+            .ok_or_else(|| invalid_data(format!("unexpected line '{}'", trimmed)))?;
+        if key == "Rule Name" {
+            rules.extend(rule.take());
+            rule = Some(FirewallRuleData {
+                interface_types: Some("All".to_string()),
+                ..FirewallRuleData::new(value)
+            });
+            last_key.clear();
+            continue;
+        }
+
+        let rule = rule
+            .as_mut()
+            .ok_or_else(|| invalid_data(format!("'{}' before the first rule name", key)))?;
+        let name = rule.name.clone();
+        let field_error =
+            |error: std::io::Error| invalid_data(format!("rule '{}': {}", name, error));
+        let any = |value: &str| Some(value.to_string()).filter(|v| !v.eq_ignore_ascii_case("any"));
+
+        match key {
+            "Description" => rule.description = Some(value.to_string()),
+            "Enabled" => rule.enabled = parse_yes_no(value).map_err(field_error)?,
+            "Direction" => rule.direction = value.parse().map_err(field_error)?,
+            // netsh spells out every profile where `FirewallRule` reports `ALL`.
+            "Profiles" => {
+                let profiles = value.parse().map_err(field_error)?;
+                rule.profiles = if profiles == every_profile() {
+                    FirewallProfile::ALL
+                } else {
+                    profiles
+                }
+            }
+            "Grouping" => rule.grouping = Some(value.to_string()).filter(|v| !v.is_empty()),
+            "LocalIP" => rule.local_addresses = any(value),
+            "RemoteIP" => rule.remote_addresses = any(value),
+            "Protocol" => {
+                rule.protocol = parse_protocol(value).ok_or_else(|| {
+                    invalid_data(format!("rule '{}': invalid protocol '{}'", name, value))
+                })?
+            }
+            "LocalPort" => rule.local_ports = any(value),
+            "RemotePort" => rule.remote_ports = any(value),
+            "Edge traversal" => rule.edge_traversal = value.eq_ignore_ascii_case("yes"),
+            "Program" => rule.application_name = any(value),
+            "Service" => rule.service_name = any(value),
+            "InterfaceTypes" => {
+                rule.interface_types = Some(
+                    list_entries(value)
+                        .iter()
+                        .map(|t| match t.to_ascii_lowercase().as_str() {
+                            "any" => "All",
+                            "lan" => "Lan",
+                            "wireless" => "Wireless",
+                            "ras" => "RemoteAccess",
+                            _ => t,
+                        })
+                        .collect::<Vec<_>>()
+                        .join(","),
+                )
+                .filter(|types| !types.is_empty())
+            }
+            "Action" => rule.action = value.parse().map_err(field_error)?,
+            // Security, Rule source and anything newer versions print are not modelled.
+            _ => {}
+        }
+        last_key = key.to_string();
+    }
+
+    rules.extend(rule);
+    Ok(rules)
+}
+
+/// Add a row of the `Type Code` table netsh prints under an ICMP protocol.
+fn parse_icmp_row(rule: &mut FirewallRuleData, row: &str) -> Result<(), std::io::Error> {
+    let mut columns = row.split_whitespace();
+    let (icmp_type, code) = match (columns.next(), columns.next()) {
+        (Some(icmp_type), Some(code)) => (icmp_type, code),
+        _ => {
+            return Err(invalid_data(format!(
+                "rule '{}': invalid ICMP row '{}'",
+                rule.name, row
+            )))
+        }
+    };
+    if icmp_type.eq_ignore_ascii_case("type") {
+        return Ok(());
+    }
+
+    let star = |s: &str| {
+        if s.eq_ignore_ascii_case("any") {
+            "*".to_string()
+        } else {
+            s.to_string()
+        }
+    };
+    let entry = format!("{}:{}", star(icmp_type), star(code));
+    rule.icmp_types_and_codes = Some(match rule.icmp_types_and_codes.take() {
+        Some(entries) => entries + "," + &entry,
+        None => entry,
+    });
+    Ok(())
+}
+
+/// Split a `Key:   value` line. Values, like program paths, may contain colons of their own.
+fn split_field(line: &str) -> Option<(&str, &str)> {
+    let colon = line.find(':')?;
+    Some((line[..colon].trim(), line[colon + 1..].trim()))
+}
+
+fn parse_yes_no(value: &str) -> Result<bool, std::io::Error> {
+    match value.to_ascii_lowercase().as_str() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err(invalid_data(format!(
+            "expected Yes or No, found '{}'",
+            value
+        ))),
+    }
+}
+
+/// Parse the output of `netsh advfirewall show allprofiles`, or of a single profile's `show`.
+///
+/// Settings netsh does not print keep the values of `FirewallProfileSettings::default`.
+pub fn parse_profiles(
+    text: &str,
+) -> Result<BTreeMap<FirewallProfile, FirewallProfileSettings>, std::io::Error> {
+    let mut profiles = BTreeMap::new();
+    let mut current: Option<(FirewallProfile, FirewallProfileSettings)> = None;
+
+    for line in text.lines() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed == "Ok." || trimmed.chars().all(|c| c == '-') {
+            continue;
+        }
+
+        if let Some(name) = trimmed.strip_suffix("Profile Settings:") {
+            profiles.extend(current.take());
+            let profile = name
+                .trim()
+                .parse()
+                .map_err(|error: std::io::Error| invalid_data(error.to_string()))?;
+            current = Some((profile, FirewallProfileSettings::default()));
+            continue;
+        }
+
+        // Logging and other sections follow the settings; only the settings are modelled.
+        let (profile, settings) = match current.as_mut() {
+            Some(current) => current,
+            None => continue,
+        };
+        let mut columns = trimmed.splitn(2, "  ");
+        let (key, value) = match (columns.next(), columns.next()) {
+            (Some(key), Some(value)) => (key.trim(), value.trim()),
+            _ => continue,
+        };
+        let setting_error =
+            || invalid_data(format!("{} profile: invalid {} '{}'", profile, key, value));
+
+        match key {
+            "State" => {
+                settings.firewall_enabled = match value.to_ascii_lowercase().as_str() {
+                    "on" => true,
+                    "off" => false,
+                    _ => return Err(setting_error()),
+                }
+            }
+            "Firewall Policy" => {
+                let value = value.to_ascii_lowercase();
+                let mut policies = value.splitn(2, ',').map(str::trim);
+                let inbound = policies.next().unwrap_or_default();
+                let outbound = policies.next().ok_or_else(setting_error)?;
+                settings.block_all_inbound_traffic = inbound == "blockinboundalways";
+                settings.default_inbound_action = match inbound {
+                    "blockinbound" | "blockinboundalways" => FirewallAction::Block,
+                    "allowinbound" => FirewallAction::Allow,
+                    _ => return Err(setting_error()),
+                };
+                settings.default_outbound_action = match outbound {
+                    "blockoutbound" => FirewallAction::Block,
+                    "allowoutbound" => FirewallAction::Allow,
+                    _ => return Err(setting_error()),
+                };
+            }
+            "InboundUserNotification" => {
+                settings.notifications_disabled = !parse_enable(value).ok_or_else(setting_error)?
+            }
+            "UnicastResponseToMulticast" => {
+                settings.unicast_responses_to_multicast_broadcast_disabled =
+                    !parse_enable(value).ok_or_else(setting_error)?
+            }
+            _ => {}
+        }
+    }
+
+    profiles.extend(current);
+    Ok(profiles)
+}
+
+fn parse_enable(value: &str) -> Option<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "enable" => Some(true),
+        "disable" => Some(false),
+        _ => None,
+    }
+}
+
+fn invalid_data(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::diff::diff_rules;
+
+    const RULES: &str = "
+Rule Name:                            Core Networking - DNS (UDP-Out)
+----------------------------------------------------------------------
+Description:                          Outbound rule to allow DNS requests.
+Enabled:                              Yes
+Direction:                            Out
+Profiles:                             Domain,Private,Public
+Grouping:                             Core Networking
+LocalIP:                              Any
+RemoteIP:                             Any
+Protocol:                             UDP
+LocalPort:                            Any
+RemotePort:                           53
+Edge traversal:                       No
+Program:                              C:\\Windows\\system32\\svchost.exe
+Service:                              dnscache
+InterfaceTypes:                       Any
+Security:                             NotRequired
+Rule source:                          Local Setting
+Action:                               Allow
+
+Rule Name:                            Ping
+----------------------------------------------------------------------
+Enabled:                              No
+Direction:                            In
+Profiles:                             Private
+Grouping:
+LocalIP:                              Any
+RemoteIP:                             LocalSubnet,10.0.0.0/8
+Protocol:                             ICMPv4
+                                      Type    Code
+                                      8       Any
+                                      0       0
+Edge traversal:                       No
+InterfaceTypes:                       LAN
+Security:                             NotRequired
+Rule source:                          Local Setting
+Action:                               Block
+Ok.
+
+";
+
+    const PROFILES: &str = "
+Domain Profile Settings:
+----------------------------------------------------------------------
+State                                 ON
+Firewall Policy                       BlockInbound,AllowOutbound
+LocalFirewallRules                    N/A (GPO-store only)
+LocalConSecRules                      N/A (GPO-store only)
+InboundUserNotification               Enable
+RemoteManagement                      Disable
+UnicastResponseToMulticast            Enable
+
+Logging:
+LogAllowedConnections                 Disable
+LogDroppedConnections                 Disable
+FileName                              %systemroot%\\system32\\LogFiles\\Firewall\\pfirewall.log
+MaxFileSize                           4096
+
+Public Profile Settings:
+----------------------------------------------------------------------
+State                                 OFF
+Firewall Policy                       BlockInboundAlways,BlockOutbound
+InboundUserNotification               Disable
+UnicastResponseToMulticast            Disable
+Ok.
+";
+
+    #[test]
+    fn parses_rules() {
+        let rules = parse_rules(RULES).unwrap();
+        assert_eq!(
+            rules,
+            vec![
+                FirewallRuleData {
+                    description: Some("Outbound rule to allow DNS requests.".into()),
+                    application_name: Some(r"C:\Windows\system32\svchost.exe".into()),
+                    service_name: Some("dnscache".into()),
+                    protocol: 17,
+                    remote_ports: Some("53".into()),
+                    direction: FirewallRuleDirection::Out,
+                    interface_types: Some("All".into()),
+                    grouping: Some("Core Networking".into()),
+                    ..FirewallRuleData::new("Core Networking - DNS (UDP-Out)")
+                },
+                FirewallRuleData {
+                    protocol: 1,
+                    remote_addresses: Some("LocalSubnet,10.0.0.0/8".into()),
+                    icmp_types_and_codes: Some("8:*,0:0".into()),
+                    interface_types: Some("Lan".into()),
+                    enabled: false,
+                    profiles: FirewallProfile::PRIVATE,
+                    action: FirewallAction::Block,
+                    ..FirewallRuleData::new("Ping")
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_bad_rules() {
+        for text in &[
+            "Enabled: Yes\n",
+            "Rule Name: a\nEnabled: Maybe\n",
+            "Rule Name: a\nProtocol: Carrier Pigeon\n",
+            "Rule Name: a\nAction: Bypass\n",
+        ] {
+            assert_eq!(
+                parse_rules(text).unwrap_err().kind(),
+                std::io::ErrorKind::InvalidData,
+                "{}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn parses_profiles() {
+        let profiles = parse_profiles(PROFILES).unwrap();
+        assert_eq!(profiles.len(), 2);
+        assert_eq!(
+            profiles[&FirewallProfile::DOMAIN],
+            FirewallProfileSettings::default()
+        );
+        assert_eq!(
+            profiles[&FirewallProfile::PUBLIC],
+            FirewallProfileSettings {
+                firewall_enabled: false,
+                block_all_inbound_traffic: true,
+                notifications_disabled: true,
+                unicast_responses_to_multicast_broadcast_disabled: true,
+                default_inbound_action: FirewallAction::Block,
+                default_outbound_action: FirewallAction::Block,
+            }
+        );
+
+        let regenerated: Vec<_> = profiles
+            .iter()
+            .flat_map(|(profile, settings)| set_profile(*profile, settings).unwrap())
+            .collect();
+        assert_eq!(
+            regenerated,
+            vec![
+                "netsh advfirewall set domainprofile state on",
+                "netsh advfirewall set domainprofile firewallpolicy blockinbound,allowoutbound",
+                "netsh advfirewall set domainprofile settings inboundusernotification enable",
+                "netsh advfirewall set domainprofile settings unicastresponsetomulticast enable",
+                "netsh advfirewall set publicprofile state off",
+                "netsh advfirewall set publicprofile firewallpolicy blockinboundalways,blockoutbound",
+                "netsh advfirewall set publicprofile settings inboundusernotification disable",
+                "netsh advfirewall set publicprofile settings unicastresponsetomulticast disable",
+            ]
+        );
+    }
+
+    #[test]
+    fn sets_several_profiles() {
+        let settings = FirewallProfileSettings::default();
+        let stores = |profile| {
+            let commands = set_profile(profile, &settings).unwrap();
+            let mut stores: Vec<_> = commands
+                .iter()
+                .map(|command| command.split(' ').nth(3).unwrap().to_string())
+                .collect();
+            stores.dedup();
+            stores
+        };
+
+        assert_eq!(
+            stores(FirewallProfile::DOMAIN | FirewallProfile::PRIVATE),
+            ["domainprofile", "privateprofile"]
+        );
+        assert_eq!(stores(every_profile()), ["allprofiles"]);
+        assert_eq!(stores(FirewallProfile::ALL), ["allprofiles"]);
+        assert_eq!(
+            set_profile(FirewallProfile::PUBLIC, &settings)
+                .unwrap()
+                .len(),
+            4
+        );
+        let error = set_profile(FirewallProfile::empty(), &settings).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn generates_commands() {
+        let rules = parse_rules(RULES).unwrap();
+        assert_eq!(
+            add_rule(&rules[0]).unwrap(),
+            "netsh advfirewall firewall add rule name=\"Core Networking - DNS (UDP-Out)\" dir=out \
+             action=allow description=\"Outbound rule to allow DNS requests.\" \
+             program=C:\\Windows\\system32\\svchost.exe service=dnscache protocol=udp remoteport=53 \
+             interfacetype=any profile=any enable=yes edge=no"
+        );
+        assert_eq!(
+            add_rule(&rules[1]).unwrap_err().kind(),
+            std::io::ErrorKind::InvalidInput
+        );
+
+        let ping = FirewallRuleData {
+            icmp_types_and_codes: Some("8:*".into()),
+            ..rules[1].clone()
+        };
+        assert_eq!(
+            add_rule(&ping).unwrap(),
+            "netsh advfirewall firewall add rule name=Ping dir=in action=block protocol=icmpv4:8,any \
+             remoteip=LocalSubnet,10.0.0.0/8 interfacetype=lan profile=private enable=no edge=no"
+        );
+        assert_eq!(
+            delete_rule(&rules[1]).unwrap(),
+            "netsh advfirewall firewall delete rule name=Ping dir=in"
+        );
+
+        let errors = vec![
+            add_rule(&FirewallRuleData::new("say \"hi\"")),
+            add_rule(&FirewallRuleData {
+                interfaces: Some(vec!["Ethernet".into()]),
+                ..FirewallRuleData::new("a")
+            }),
+            delete_rule(&FirewallRuleData::new("All")),
+        ];
+        for error in errors {
+            assert_eq!(error.unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn generates_changes() {
+        let old = parse_rules(RULES).unwrap();
+        let mut new = old.clone();
+        new[0].remote_ports = Some("53,853".into());
+        new[1].protocol = 6;
+        new[1].icmp_types_and_codes = None;
+        new[1].local_ports = Some("22".into());
+
+        assert_eq!(
+            diff_commands(&diff_rules(&old, &new)).unwrap(),
+            vec![
+                "netsh advfirewall firewall set rule name=\"Core Networking - DNS (UDP-Out)\" dir=out new \
+                 remoteport=53,853",
+                "netsh advfirewall firewall set rule name=Ping dir=in new protocol=tcp localport=22 remoteport=any",
+            ]
+        );
+
+        let renamed = FirewallRuleData {
+            name: "DNS".into(),
+            application_name: None,
+            ..old[0].clone()
+        };
+        assert_eq!(
+            set_rule(&old[0], &renamed).unwrap_err().kind(),
+            std::io::ErrorKind::InvalidInput
+        );
+        assert_eq!(
+            set_rule(&old[0], &FirewallRuleData { application_name: old[0].application_name.clone(), ..renamed })
+                .unwrap(),
+            vec!["netsh advfirewall firewall set rule name=\"Core Networking - DNS (UDP-Out)\" dir=out new name=DNS"]
+        );
+
+        let inbound = FirewallRuleData {
+            direction: FirewallRuleDirection::In,
+            ..old[0].clone()
+        };
+        assert_eq!(
+            set_rule(&old[0], &inbound).unwrap(),
+            vec!["netsh advfirewall firewall set rule name=\"Core Networking - DNS (UDP-Out)\" dir=out new dir=in"]
+        );
+        let enabled = FirewallRuleData {
+            enabled: !inbound.enabled,
+            ..inbound.clone()
+        };
+        assert_eq!(
+            set_rule(&old[0], &enabled).unwrap(),
+            vec![format!(
+                "netsh advfirewall firewall set rule name=\"Core Networking - DNS (UDP-Out)\" dir=out new dir=in \
+                 enable={}",
+                yes_no(enabled.enabled)
+            )]
+        );
+
+        // Ports can't be set on an ICMP rule, so dropping stale ones leaves nothing to run.
+        let icmp = FirewallRuleData {
+            protocol: 1,
+            ..FirewallRuleData::new("ICMP")
+        };
+        let stale = FirewallRuleData {
+            local_ports: Some("5".into()),
+            ..icmp.clone()
+        };
+        assert!(set_rule(&stale, &icmp).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,324 @@
+//! Structured ownership markers for rules created by our tools.
+//!
+//! The marker is appended to a rule's description (or grouping) after any human readable text, e.g.
+//! `Allow web traffic [netfw:owner=agent-x;v=1.2;created=1600000000;t.env=prod]`.
+//! Values are percent-encoded so they can hold any text without breaking the marker.
+
+use crate::FirewallRuleData;
+use std::collections::BTreeMap;
+
+const MARKER_START: &str = "[netfw:";
+const MARKER_END: char = ']';
+
+/// Who created a rule, with what, when, and any extra tags.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub struct Ownership {
+    pub owner: String,
+    pub version: Option<String>,
+    /// Seconds since the Unix epoch.
+    pub created: Option<u64>,
+    pub tags: BTreeMap<String, String>,
+}
+
+impl Ownership {
+    pub fn new(owner: impl Into<String>) -> Self {
+        Ownership {
+            owner: owner.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_version(mut self, version: impl Into<String>) -> Self {
+        self.version = Some(version.into());
+        self
+    }
+
+    pub fn with_created(mut self, created: u64) -> Self {
+        self.created = Some(created);
+        self
+    }
+
+    /// Stamp the current time as the creation time.
+    pub fn created_now(self) -> Self {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        self.with_created(now)
+    }
+
+    pub fn with_tag(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.tags.insert(key.into(), value.into());
+        self
+    }
+
+    /// The marker on its own, like `[netfw:owner=agent-x]`.
+    pub fn marker(&self) -> String {
+        let mut marker = String::from(MARKER_START);
+        push_field(&mut marker, "owner", &self.owner);
+        if let Some(version) = self.version.as_deref() {
+            push_field(&mut marker, "v", version);
+        }
+        if let Some(created) = self.created {
+            push_field(&mut marker, "created", &created.to_string());
+        }
+        for (key, value) in self.tags.iter() {
+            push_field(&mut marker, &format!("t.{}", key), value);
+        }
+        marker.push(MARKER_END);
+        marker
+    }
+
+    /// Append the marker to some human readable text, replacing any marker already there.
+    pub fn embed(&self, text: Option<&str>) -> String {
+        let text = text.map(strip).unwrap_or("");
+        if text.is_empty() {
+            self.marker()
+        } else {
+            format!("{} {}", text, self.marker())
+        }
+    }
+
+    /// Split text into its marker, if it has a well-formed one, and the human readable rest.
+    pub fn extract(text: &str) -> (Option<Ownership>, &str) {
+        match split(text) {
+            Some((human, marker)) => match parse_marker(marker) {
+                Some(ownership) => (Some(ownership), human),
+                None => (None, text),
+            },
+            None => (None, text),
+        }
+    }
+
+    /// Parse the marker out of some text, ignoring the human readable part.
+    pub fn parse(text: &str) -> Option<Ownership> {
+        Self::extract(text).0
+    }
+}
+
+impl std::fmt::Display for Ownership {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.marker())
+    }
+}
+
+/// Remove a well-formed marker from the end of some text, leaving only the human readable part.
+pub fn strip(text: &str) -> &str {
+    Ownership::extract(text).1
+}
+
+/// Split off a trailing `[netfw:...]`, returning the text before it and the marker body.
+fn split(text: &str) -> Option<(&str, &str)> {
+    let trimmed = text.trim_end();
+    if !trimmed.ends_with(MARKER_END) {
+        return None;
+    }
+
+    let start = trimmed.rfind(MARKER_START)?;
+    let body = &trimmed[start + MARKER_START.len()..trimmed.len() - 1];
+    Some((trimmed[..start].trim_end(), body))
+}
+
+fn parse_marker(body: &str) -> Option<Ownership> {
+    let mut ownership = Ownership::default();
+    let mut owner = None;
+
+    for field in body.split(';').filter(|field| !field.is_empty()) {
+        let mut parts = field.splitn(2, '=');
+        let key = decode(parts.next()?)?;
+        let value = decode(parts.next()?)?;
+
+        match key.as_str() {
+            "owner" => owner = Some(value),
+            "v" => ownership.version = Some(value),
+            "created" => ownership.created = Some(value.parse().ok()?),
+            key if key.starts_with("t.") => {
+                ownership.tags.insert(key[2..].to_string(), value);
+            }
+            // Unknown keys are skipped so newer writers stay readable.
+            _ => {}
+        }
+    }
+
+    ownership.owner = owner?;
+    Some(ownership)
+}
+
+fn push_field(marker: &mut String, key: &str, value: &str) {
+    if !marker.ends_with(':') {
+        marker.push(';');
+    }
+    encode(marker, key);
+    marker.push('=');
+    encode(marker, value);
+}
+
+/// Percent-encode the characters that would break the marker.
+fn encode(out: &mut String, s: &str) {
+    for c in s.chars() {
+        match c {
+            '%' | ';' | '=' | '[' | ']' => out.push_str(&format!("%{:02X}", c as u32)),
+            c if c.is_control() => {
+                let mut buf = [0; 4];
+                for b in c.encode_utf8(&mut buf).bytes() {
+                    out.push_str(&format!("%{:02X}", b));
+                }
+            }
+            c => out.push(c),
+        }
+    }
+}
+
+fn decode(s: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut iter = s.bytes();
+
+    while let Some(b) = iter.next() {
+        if b == b'%' {
+            let hi = (iter.next()? as char).to_digit(16)?;
+            let lo = (iter.next()? as char).to_digit(16)?;
+            bytes.push((hi * 16 + lo) as u8);
+        } else {
+            bytes.push(b);
+        }
+    }
+
+    String::from_utf8(bytes).ok()
+}
+
+impl FirewallRuleData {
+    /// The ownership marker from the description, or failing that the grouping.
+    pub fn ownership(&self) -> Option<Ownership> {
+        self.description
+            .as_deref()
+            .and_then(Ownership::parse)
+            .or_else(|| self.grouping.as_deref().and_then(Ownership::parse))
+    }
+
+    /// Embed an ownership marker in the description, keeping its human readable text.
+    pub fn set_ownership(&mut self, ownership: &Ownership) {
+        self.description = Some(ownership.embed(self.description.as_deref()));
+    }
+
+    /// Remove any ownership marker from the description and grouping.
+    pub fn clear_ownership(&mut self) {
+        for field in [&mut self.description, &mut self.grouping].iter_mut() {
+            if let Some(text) = field.as_deref() {
+                let human = strip(text);
+                **field = if human.is_empty() {
+                    None
+                } else {
+                    Some(human.to_string())
+                };
+            }
+        }
+    }
+
+    /// The description without any ownership marker.
+    pub fn human_description(&self) -> Option<&str> {
+        self.description.as_deref().map(strip)
+    }
+
+    /// Whether this rule carries a marker naming `owner`.
+    pub fn is_owned_by(&self, owner: &str) -> bool {
+        matches!(self.ownership(), Some(ownership) if ownership.owner == owner)
+    }
+}
+
+/// A filter for rules owned by `owner`, for use with `Iterator::filter`.
+pub fn owned_by(owner: &str) -> impl Fn(&FirewallRuleData) -> bool + '_ {
+    move |rule| rule.is_owned_by(owner)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sample() -> Ownership {
+        Ownership::new("agent-x")
+            .with_version("1.2")
+            .with_created(1_600_000_000)
+            .with_tag("env", "prod")
+    }
+
+    #[test]
+    fn round_trip() {
+        let ownership = sample();
+        let text = ownership.embed(Some("Allow web traffic"));
+        assert_eq!(
+            text,
+            "Allow web traffic [netfw:owner=agent-x;v=1.2;created=1600000000;t.env=prod]"
+        );
+
+        let (parsed, human) = Ownership::extract(&text);
+        assert_eq!(parsed, Some(ownership));
+        assert_eq!(human, "Allow web traffic");
+    }
+
+    #[test]
+    fn awkward_values() {
+        let ownership = Ownership::new("a;b=c]")
+            .with_tag("k[1]", "100%\n")
+            .with_tag("", "");
+        let text = ownership.embed(None);
+        assert_eq!(Ownership::parse(&text), Some(ownership));
+    }
+
+    #[test]
+    fn embed_replaces_existing_marker() {
+        let text = Ownership::new("old").embed(Some("Text"));
+        let text = Ownership::new("new").embed(Some(&text));
+        assert_eq!(text, "Text [netfw:owner=new]");
+    }
+
+    #[test]
+    fn plain_text_is_untouched() {
+        for text in &[
+            "",
+            "Allow [inbound]",
+            "Trailing [netfw:",  // Not closed
+            "[netfw:v=1]",       // No owner
+            "[netfw:owner=%ZZ]", // Bad escape
+        ] {
+            let (ownership, human) = Ownership::extract(text);
+            assert_eq!(ownership, None);
+            assert_eq!(human, *text);
+        }
+    }
+
+    #[test]
+    fn unknown_keys_are_ignored() {
+        let parsed = Ownership::parse("[netfw:owner=a;future=1]").unwrap();
+        assert_eq!(parsed, Ownership::new("a"));
+    }
+
+    #[test]
+    fn rule_helpers() {
+        let mut rule = FirewallRuleData {
+            description: Some("Web".into()),
+            ..FirewallRuleData::new("web")
+        };
+        assert_eq!(rule.ownership(), None);
+
+        rule.set_ownership(&sample());
+        assert_eq!(rule.ownership(), Some(sample()));
+        assert_eq!(rule.human_description(), Some("Web"));
+        assert!(rule.is_owned_by("agent-x"));
+
+        let other = FirewallRuleData {
+            grouping: Some(Ownership::new("agent-y").embed(None)),
+            ..FirewallRuleData::new("other")
+        };
+        let rules = [rule.clone(), other, FirewallRuleData::new("os")];
+        let names: Vec<_> = rules
+            .iter()
+            .filter(|rule| owned_by("agent-y")(rule))
+            .map(|rule| rule.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["other"]);
+
+        rule.clear_ownership();
+        assert_eq!(rule.description.as_deref(), Some("Web"));
+        assert_eq!(rule.ownership(), None);
+    }
+}
@@ -1,6 +1,9 @@
 use crate::{
     FirewallAction,
     FirewallProfile,
+    FirewallProfileSettings,
+    FirewallRule,
+    FirewallRuleData,
     FirewallRules,
 };
 use com::{
@@ -16,6 +19,7 @@ use netfw_sys::{
 };
 use std::{
     convert::TryFrom,
+    ffi::OsStr,
     mem::MaybeUninit,
 };
 use winapi::shared::wtypes::{
@@ -56,6 +60,22 @@ impl FirewallPolicy {
         }
     }
 
+    pub fn set_firewall_enabled(
+        &self,
+        profile: FirewallProfile,
+        value: bool,
+    ) -> Result<(), std::io::Error> {
+        let profile: NET_FW_PROFILE_TYPE2 = profile.into();
+        let value = if value { VARIANT_TRUE } else { VARIANT_FALSE };
+        let ret = unsafe { self.0.put_firewall_enabled(profile, value) };
+
+        if FAILED(ret) {
+            Err(std::io::Error::from_raw_os_error(ret))
+        } else {
+            Ok(())
+        }
+    }
+
     pub fn get_excluded_interfaces(
         &self,
         profile: FirewallProfile,
@@ -86,6 +106,22 @@ impl FirewallPolicy {
         }
     }
 
+    pub fn set_block_all_inbound_traffic(
+        &self,
+        profile: FirewallProfile,
+        value: bool,
+    ) -> Result<(), std::io::Error> {
+        let profile: NET_FW_PROFILE_TYPE2 = profile.into();
+        let value = if value { VARIANT_TRUE } else { VARIANT_FALSE };
+        let ret = unsafe { self.0.put_block_all_inbound_traffic(profile, value) };
+
+        if FAILED(ret) {
+            Err(std::io::Error::from_raw_os_error(ret))
+        } else {
+            Ok(())
+        }
+    }
+
     pub fn get_notifications_disabled(
         &self,
         profile: FirewallProfile,
@@ -101,6 +137,22 @@ impl FirewallPolicy {
         }
     }
 
+    pub fn set_notifications_disabled(
+        &self,
+        profile: FirewallProfile,
+        value: bool,
+    ) -> Result<(), std::io::Error> {
+        let profile: NET_FW_PROFILE_TYPE2 = profile.into();
+        let value = if value { VARIANT_TRUE } else { VARIANT_FALSE };
+        let ret = unsafe { self.0.put_notifications_disabled(profile, value) };
+
+        if FAILED(ret) {
+            Err(std::io::Error::from_raw_os_error(ret))
+        } else {
+            Ok(())
+        }
+    }
+
     pub fn get_unicast_responses_to_multicast_broadcast_disabled(
         &self,
         profile: FirewallProfile,
@@ -119,6 +171,25 @@ impl FirewallPolicy {
         }
     }
 
+    pub fn set_unicast_responses_to_multicast_broadcast_disabled(
+        &self,
+        profile: FirewallProfile,
+        value: bool,
+    ) -> Result<(), std::io::Error> {
+        let profile: NET_FW_PROFILE_TYPE2 = profile.into();
+        let value = if value { VARIANT_TRUE } else { VARIANT_FALSE };
+        let ret = unsafe {
+            self.0
+                .put_unicast_responses_to_multicast_broadcast_disabled(profile, value)
+        };
+
+        if FAILED(ret) {
+            Err(std::io::Error::from_raw_os_error(ret))
+        } else {
+            Ok(())
+        }
+    }
+
     pub fn get_rules(&self) -> Result<FirewallRules, std::io::Error> {
         let mut rules = MaybeUninit::zeroed(); // NULL
         let ret = unsafe { self.0.get_rules(rules.as_mut_ptr()) };
@@ -145,6 +216,22 @@ impl FirewallPolicy {
         }
     }
 
+    pub fn set_default_inbound_action(
+        &self,
+        profile: FirewallProfile,
+        action: FirewallAction,
+    ) -> Result<(), std::io::Error> {
+        let profile: NET_FW_PROFILE_TYPE2 = profile.into();
+        let action: NET_FW_ACTION = action.into();
+        let ret = unsafe { self.0.put_default_inbound_action(profile, action) };
+
+        if FAILED(ret) {
+            Err(std::io::Error::from_raw_os_error(ret))
+        } else {
+            Ok(())
+        }
+    }
+
     pub fn get_default_outbound_action(
         &self,
         profile: FirewallProfile,
@@ -159,6 +246,91 @@ impl FirewallPolicy {
             Ok(FirewallAction::try_from(action).expect("Valid NET_FW_ACTION"))
         }
     }
+
+    pub fn set_default_outbound_action(
+        &self,
+        profile: FirewallProfile,
+        action: FirewallAction,
+    ) -> Result<(), std::io::Error> {
+        let profile: NET_FW_PROFILE_TYPE2 = profile.into();
+        let action: NET_FW_ACTION = action.into();
+        let ret = unsafe { self.0.put_default_outbound_action(profile, action) };
+
+        if FAILED(ret) {
+            Err(std::io::Error::from_raw_os_error(ret))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Read every setting of a single profile.
+    pub fn get_profile_settings(
+        &self,
+        profile: FirewallProfile,
+    ) -> Result<FirewallProfileSettings, std::io::Error> {
+        Ok(FirewallProfileSettings {
+            firewall_enabled: self.get_firewall_enabled(profile)?,
+            block_all_inbound_traffic: self.get_block_all_inbound_traffic(profile)?,
+            notifications_disabled: self.get_notifications_disabled(profile)?,
+            unicast_responses_to_multicast_broadcast_disabled: self
+                .get_unicast_responses_to_multicast_broadcast_disabled(profile)?,
+            default_inbound_action: self.get_default_inbound_action(profile)?,
+            default_outbound_action: self.get_default_outbound_action(profile)?,
+        })
+    }
+
+    /// Write every setting of a single profile.
+    pub fn set_profile_settings(
+        &self,
+        profile: FirewallProfile,
+        settings: &FirewallProfileSettings,
+    ) -> Result<(), std::io::Error> {
+        self.set_firewall_enabled(profile, settings.firewall_enabled)?;
+        self.set_block_all_inbound_traffic(profile, settings.block_all_inbound_traffic)?;
+        self.set_notifications_disabled(profile, settings.notifications_disabled)?;
+        self.set_unicast_responses_to_multicast_broadcast_disabled(
+            profile,
+            settings.unicast_responses_to_multicast_broadcast_disabled,
+        )?;
+        self.set_default_inbound_action(profile, settings.default_inbound_action)?;
+        self.set_default_outbound_action(profile, settings.default_outbound_action)?;
+
+        Ok(())
+    }
+
+    /// Overwrite the rule called `name` with `data`, which may carry a different name.
+    ///
+    /// The rule is written in place when possible, and removed and re-added when a field has to be cleared or the
+    /// protocol changes. If the new rule can't be added the original is put back.
+    pub fn replace_rule(
+        &self,
+        name: &OsStr,
+        data: &FirewallRuleData,
+    ) -> Result<(), std::io::Error> {
+        let rules = self.get_rules()?;
+        let existing = rules.get(name)?;
+
+        if !existing.to_data()?.needs_replacing(data) {
+            return existing.update_from_data(data);
+        }
+
+        let replacement = FirewallRule::from_data(data)?;
+        rules.remove(name)?;
+        if let Err(error) = rules.add(replacement) {
+            if let Err(restore_error) = rules.add(existing) {
+                return Err(std::io::Error::new(
+                    error.kind(),
+                    format!(
+                        "{}; the original rule could not be restored: {}",
+                        error, restore_error
+                    ),
+                ));
+            }
+            return Err(error);
+        }
+
+        Ok(())
+    }
 }
 
 #[cfg(test)]
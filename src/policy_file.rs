@@ -0,0 +1,718 @@
+//! Declarative policy files, in TOML with the `toml` feature or YAML with the `yaml` feature.
+//!
+//! A policy file describes a `DesiredState`: settings for some profiles and the rules that should exist. Keys use the
+//! names of the `FirewallProfileSettings` and `FirewallRuleData` fields, and values the spellings their `FromStr`
+//! impls accept. Everything but a rule's `name` is optional and defaults to what a new rule or a fresh Windows
+//! install has. Lists may be written as arrays or as comma separated strings.
+//!
+//! ```toml
+//! # Profiles that are not listed are left alone.
+//! [profiles.public]
+//! block_all_inbound_traffic = true
+//! default_outbound_action = "Allow"
+//!
+//! [[rules]]
+//! name = "Web"
+//! grouping = "Web server"
+//! protocol = "TCP"
+//! local_ports = [80, 443]
+//! remote_addresses = "LocalSubnet"
+//! profiles = ["Domain", "Private"]
+//!
+//! [[rules]]
+//! name = "Ping"
+//! direction = "in"
+//! protocol = "ICMPv4"
+//! icmp_types_and_codes = "8:*"
+//! action = "Block"
+//! ```
+//!
+//! The same policy in YAML:
+//!
+//! ```yaml
+//! profiles:
+//!   public:
+//!     block_all_inbound_traffic: true
+//!     default_outbound_action: Allow
+//! rules:
+//!   - name: Web
+//!     grouping: Web server
+//!     protocol: TCP
+//!     local_ports: [80, 443]
+//!     remote_addresses: LocalSubnet
+//!     profiles: [Domain, Private]
+//!   - name: Ping
+//!     direction: in
+//!     protocol: ICMPv4
+//!     icmp_types_and_codes: "8:*"
+//!     action: Block
+//! ```
+//!
+//! Files are validated as they are read: unknown keys, malformed port and address lists, ports on protocols that
+//! have none and the like fail with `InvalidData`, wrapping a `PolicyFileError` that says where the problem is.
+//!
+//! `compose` builds a policy from several files, with shared fragments, variables and per-host overlays.
+
+use crate::{
+    addresses::AddressList,
+    data::{
+        parse_protocol,
+        PROTOCOL_ANY,
+    },
+    ports::PortList,
+    reconcile::DesiredState,
+    FirewallAction,
+    FirewallProfile,
+    FirewallProfileSettings,
+    FirewallRuleData,
+    FirewallRuleDirection,
+};
+use serde::{
+    de::Error as _,
+    Deserialize,
+    Deserializer,
+};
+use std::{
+    collections::BTreeMap,
+    convert::TryFrom,
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+
+pub mod compose;
+
+/// Where and why a policy file failed to load.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PolicyFileError {
+    /// The file, when it was read from one.
+    pub path: Option<PathBuf>,
+    /// The 1-based line and column, when the parser knows them.
+    ///
+    /// YAML files report a value that fails validation at the rule or profile holding it.
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    pub message: String,
+}
+
+/// Formats like `policy.toml:3:9: invalid port list '80-'`.
+impl std::fmt::Display for PolicyFileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(path) = &self.path {
+            write!(f, "{}:", path.display())?;
+        }
+        if let Some(line) = self.line {
+            write!(f, "{}:", line)?;
+            if let Some(column) = self.column {
+                write!(f, "{}:", column)?;
+            }
+        }
+        if self.path.is_some() || self.line.is_some() {
+            f.write_str(" ")?;
+        }
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for PolicyFileError {}
+
+impl From<PolicyFileError> for std::io::Error {
+    fn from(error: PolicyFileError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, error)
+    }
+}
+
+/// Parse a policy written in TOML.
+#[cfg(feature = "toml")]
+pub fn from_toml_str(text: &str) -> Result<DesiredState, std::io::Error> {
+    toml::from_str::<PolicySpec>(text)
+        .map(PolicySpec::into_desired_state)
+        .map_err(|error| toml_error(text, &error).into())
+}
+
+/// Parse a policy written in YAML.
+#[cfg(feature = "yaml")]
+pub fn from_yaml_str(text: &str) -> Result<DesiredState, std::io::Error> {
+    serde_yaml_ng::from_str::<PolicySpec>(text)
+        .map(PolicySpec::into_desired_state)
+        .map_err(|error| yaml_error(&error).into())
+}
+
+#[cfg(feature = "toml")]
+fn toml_error(text: &str, error: &toml::de::Error) -> PolicyFileError {
+    let (line, column) = match error.span() {
+        Some(span) => {
+            let (line, column) = line_column(text, span.start);
+            (Some(line), Some(column))
+        }
+        None => (None, None),
+    };
+    PolicyFileError {
+        path: None,
+        line,
+        column,
+        message: error.message().to_string(),
+    }
+}
+
+#[cfg(feature = "yaml")]
+fn yaml_error(error: &serde_yaml_ng::Error) -> PolicyFileError {
+    let location = error.location();
+    // The error's own message repeats the location, so the message is taken from its text without it.
+    let mut message = error.to_string();
+    if let Some(index) = message.find(" at line ") {
+        message.truncate(index);
+    }
+    PolicyFileError {
+        path: None,
+        line: location.as_ref().map(|location| location.line()),
+        column: location.as_ref().map(|location| location.column()),
+        message,
+    }
+}
+
+/// Load a policy file, choosing the format by extension: `.toml`, or `.yaml` and `.yml`.
+pub fn load(path: impl AsRef<Path>) -> Result<DesiredState, std::io::Error> {
+    let path = path.as_ref();
+    let text = std::fs::read_to_string(path)?;
+    let result = match extension(path).as_str() {
+        #[cfg(feature = "toml")]
+        "toml" => from_toml_str(&text),
+        #[cfg(feature = "yaml")]
+        "yaml" | "yml" => from_yaml_str(&text),
+        extension => Err(unsupported_extension(extension).into()),
+    };
+    result.map_err(|error| in_file(path, error))
+}
+
+/// The lowercased extension of a policy file.
+fn extension(path: &Path) -> String {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase()
+}
+
+fn unsupported_extension(extension: &str) -> PolicyFileError {
+    PolicyFileError {
+        path: None,
+        line: None,
+        column: None,
+        message: format!("unsupported policy file extension '{}'", extension),
+    }
+}
+
+/// Add the path to an error about the contents of a file.
+fn in_file(path: &Path, error: std::io::Error) -> std::io::Error {
+    match error.into_inner() {
+        Some(inner) => match inner.downcast::<PolicyFileError>() {
+            Ok(inner) => PolicyFileError {
+                path: Some(path.to_path_buf()),
+                ..*inner
+            }
+            .into(),
+            Err(inner) => std::io::Error::new(std::io::ErrorKind::InvalidData, inner),
+        },
+        None => std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid policy file"),
+    }
+}
+
+/// The 1-based line and column of a byte offset.
+#[cfg(feature = "toml")]
+fn line_column(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset.min(text.len())];
+    let line_start = before.rfind('\n').map_or(0, |index| index + 1);
+    (
+        before.matches('\n').count() + 1,
+        before[line_start..].chars().count() + 1,
+    )
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PolicySpec {
+    #[serde(default)]
+    profiles: BTreeMap<ProfileKey, ProfileSpec>,
+    #[serde(default)]
+    rules: Vec<RuleSpec>,
+}
+
+impl PolicySpec {
+    fn into_desired_state(self) -> DesiredState {
+        DesiredState {
+            profiles: self
+                .profiles
+                .into_iter()
+                .map(|(key, spec)| (key.0, spec.0))
+                .collect(),
+            rules: self.rules.into_iter().map(|rule| rule.0).collect(),
+        }
+    }
+}
+
+/// A single profile, as a key of the `profiles` table.
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct ProfileKey(FirewallProfile);
+
+impl<'de> Deserialize<'de> for ProfileKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        let profile: FirewallProfile = name.parse().map_err(D::Error::custom)?;
+        if !FirewallProfile::SINGLE.contains(&profile) {
+            return Err(D::Error::custom(format!(
+                "profile '{}' is not one of domain, private or public",
+                name
+            )));
+        }
+        Ok(ProfileKey(profile))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(try_from = "ProfileFields")]
+struct ProfileSpec(FirewallProfileSettings);
+
+/// The settings of a profile, with the defaults of a fresh install.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ProfileFields {
+    firewall_enabled: bool,
+    block_all_inbound_traffic: bool,
+    notifications_disabled: bool,
+    unicast_responses_to_multicast_broadcast_disabled: bool,
+    default_inbound_action: FirewallAction,
+    default_outbound_action: FirewallAction,
+}
+
+impl Default for ProfileFields {
+    fn default() -> Self {
+        let settings = FirewallProfileSettings::default();
+        ProfileFields {
+            firewall_enabled: settings.firewall_enabled,
+            block_all_inbound_traffic: settings.block_all_inbound_traffic,
+            notifications_disabled: settings.notifications_disabled,
+            unicast_responses_to_multicast_broadcast_disabled: settings
+                .unicast_responses_to_multicast_broadcast_disabled,
+            default_inbound_action: settings.default_inbound_action,
+            default_outbound_action: settings.default_outbound_action,
+        }
+    }
+}
+
+impl TryFrom<ProfileFields> for ProfileSpec {
+    type Error = String;
+
+    fn try_from(fields: ProfileFields) -> Result<Self, Self::Error> {
+        for action in [
+            fields.default_inbound_action,
+            fields.default_outbound_action,
+        ]
+        .iter()
+        {
+            if *action == FirewallAction::Max {
+                return Err("a default action must be Allow or Block".to_string());
+            }
+        }
+
+        Ok(ProfileSpec(FirewallProfileSettings {
+            firewall_enabled: fields.firewall_enabled,
+            block_all_inbound_traffic: fields.block_all_inbound_traffic,
+            notifications_disabled: fields.notifications_disabled,
+            unicast_responses_to_multicast_broadcast_disabled: fields
+                .unicast_responses_to_multicast_broadcast_disabled,
+            default_inbound_action: fields.default_inbound_action,
+            default_outbound_action: fields.default_outbound_action,
+        }))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(try_from = "RuleFields")]
+struct RuleSpec(FirewallRuleData);
+
+/// The fields of a rule, each checked on its own as it is read.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleFields {
+    #[serde(default)]
+    id: Option<String>,
+    name: String,
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default)]
+    application_name: Option<String>,
+    #[serde(default)]
+    service_name: Option<String>,
+    #[serde(default = "any_protocol", deserialize_with = "protocol")]
+    protocol: i32,
+    #[serde(default, deserialize_with = "ports")]
+    local_ports: Option<String>,
+    #[serde(default, deserialize_with = "ports")]
+    remote_ports: Option<String>,
+    #[serde(default, deserialize_with = "addresses")]
+    local_addresses: Option<String>,
+    #[serde(default, deserialize_with = "addresses")]
+    remote_addresses: Option<String>,
+    #[serde(default, deserialize_with = "icmp_types")]
+    icmp_types_and_codes: Option<String>,
+    #[serde(default = "inbound")]
+    direction: FirewallRuleDirection,
+    #[serde(default, deserialize_with = "interfaces")]
+    interfaces: Option<Vec<String>>,
+    #[serde(default, deserialize_with = "interface_types")]
+    interface_types: Option<String>,
+    #[serde(default = "yes")]
+    enabled: bool,
+    #[serde(default)]
+    grouping: Option<String>,
+    #[serde(default = "all_profiles", deserialize_with = "profiles")]
+    profiles: FirewallProfile,
+    #[serde(default)]
+    edge_traversal: bool,
+    #[serde(default = "allow")]
+    action: FirewallAction,
+}
+
+impl TryFrom<RuleFields> for RuleSpec {
+    type Error = String;
+
+    /// Check the fields against each other.
+    fn try_from(fields: RuleFields) -> Result<Self, Self::Error> {
+        let error = |message: &str| format!("rule '{}': {}", fields.name, message);
+
+        if fields.name.trim().is_empty() {
+            return Err("a rule name must not be empty".to_string());
+        }
+        if fields.direction == FirewallRuleDirection::Max || fields.action == FirewallAction::Max {
+            return Err(error(
+                "direction must be in or out and action Allow or Block",
+            ));
+        }
+        if (fields.local_ports.is_some() || fields.remote_ports.is_some())
+            && fields.protocol != 6
+            && fields.protocol != 17
+        {
+            return Err(error("ports can only be given for TCP and UDP"));
+        }
+        if fields.icmp_types_and_codes.is_some() && fields.protocol != 1 && fields.protocol != 58 {
+            return Err(error("ICMP types can only be given for ICMPv4 and ICMPv6"));
+        }
+        if fields.edge_traversal && fields.direction == FirewallRuleDirection::Out {
+            return Err(error("edge traversal only applies to inbound rules"));
+        }
+
+        Ok(RuleSpec(FirewallRuleData {
+            id: fields.id,
+            name: fields.name,
+            description: fields.description,
+            application_name: fields.application_name,
+            service_name: fields.service_name,
+            protocol: fields.protocol,
+            local_ports: fields.local_ports,
+            remote_ports: fields.remote_ports,
+            local_addresses: fields.local_addresses,
+            remote_addresses: fields.remote_addresses,
+            icmp_types_and_codes: fields.icmp_types_and_codes,
+            direction: fields.direction,
+            interfaces: fields.interfaces,
+            interface_types: fields.interface_types,
+            enabled: fields.enabled,
+            grouping: fields.grouping,
+            profiles: fields.profiles,
+            edge_traversal: fields.edge_traversal,
+            action: fields.action,
+        }))
+    }
+}
+
+fn any_protocol() -> i32 {
+    PROTOCOL_ANY
+}
+
+fn inbound() -> FirewallRuleDirection {
+    FirewallRuleDirection::In
+}
+
+fn yes() -> bool {
+    true
+}
+
+fn all_profiles() -> FirewallProfile {
+    FirewallProfile::ALL
+}
+
+fn allow() -> FirewallAction {
+    FirewallAction::Allow
+}
+
+/// A list entry, which may be a number for ports and protocols.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Entry {
+    Number(i64),
+    Text(String),
+}
+
+impl std::fmt::Display for Entry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Entry::Number(number) => write!(f, "{}", number),
+            Entry::Text(text) => f.write_str(text),
+        }
+    }
+}
+
+/// A list written as an array or as a comma separated string.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum List {
+    Many(Vec<Entry>),
+    One(Entry),
+}
+
+impl List {
+    fn entries(self) -> Vec<String> {
+        let entries = match self {
+            List::Many(entries) => entries,
+            List::One(entry) => vec![entry],
+        };
+        entries
+            .iter()
+            .flat_map(|entry| {
+                entry
+                    .to_string()
+                    .split(',')
+                    .map(|s| s.trim().to_string())
+                    .collect::<Vec<_>>()
+            })
+            .filter(|entry| !entry.is_empty())
+            .collect()
+    }
+
+    fn joined(self) -> Option<String> {
+        Some(self.entries().join(",")).filter(|list| !list.is_empty())
+    }
+}
+
+fn protocol<'de, D: Deserializer<'de>>(deserializer: D) -> Result<i32, D::Error> {
+    let entry = Entry::deserialize(deserializer)?.to_string();
+    parse_protocol(&entry).ok_or_else(|| D::Error::custom(format!("invalid protocol '{}'", entry)))
+}
+
+fn ports<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    let list = List::deserialize(deserializer)?.joined();
+    match list.as_deref().map(PortList::parse).transpose() {
+        Ok(Some(parsed)) if !parsed.is_any() => Ok(list),
+        Ok(_) => Ok(None),
+        Err(error) => Err(D::Error::custom(error)),
+    }
+}
+
+fn addresses<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    let list = List::deserialize(deserializer)?.joined();
+    match list.as_deref().map(AddressList::parse).transpose() {
+        Ok(Some(parsed)) if !parsed.is_any() => Ok(list),
+        Ok(_) => Ok(None),
+        Err(error) => Err(D::Error::custom(error)),
+    }
+}
+
+/// ICMP types are `type` or `type:code`, with `*` for any code.
+fn icmp_types<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    let entries = List::deserialize(deserializer)?.entries();
+    if entries
+        .iter()
+        .any(|entry| entry == "*" || entry.eq_ignore_ascii_case("any"))
+    {
+        return Ok(None);
+    }
+
+    let mut list = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let mut parts = entry.splitn(2, ':');
+        let icmp_type = parts.next().unwrap_or_default();
+        let code = parts.next().unwrap_or("*");
+        let valid = |part: &str| part == "*" || part.parse::<u8>().is_ok();
+        if icmp_type == "*" || !valid(icmp_type) || !valid(code) {
+            return Err(D::Error::custom(format!("invalid ICMP type '{}'", entry)));
+        }
+        list.push(format!("{}:{}", icmp_type, code));
+    }
+    Ok(Some(list.join(",")).filter(|list| !list.is_empty()))
+}
+
+fn interfaces<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<String>>, D::Error> {
+    let entries = List::deserialize(deserializer)?.entries();
+    Ok(Some(entries).filter(|entries| !entries.is_empty()))
+}
+
+/// Interface types use the `INetFwRule` names: `All`, or some of `Lan`, `Wireless` and `RemoteAccess`.
+fn interface_types<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    const TYPES: [&str; 4] = ["All", "Lan", "Wireless", "RemoteAccess"];
+
+    let mut types = Vec::new();
+    for entry in List::deserialize(deserializer)?.entries() {
+        let name = TYPES
+            .iter()
+            .find(|name| name.eq_ignore_ascii_case(&entry))
+            .ok_or_else(|| D::Error::custom(format!("invalid interface type '{}'", entry)))?;
+        types.push(*name);
+    }
+    if types.contains(&"All") {
+        return Ok(Some("All".to_string()));
+    }
+    Ok(Some(types.join(",")).filter(|types| !types.is_empty()))
+}
+
+fn profiles<'de, D: Deserializer<'de>>(deserializer: D) -> Result<FirewallProfile, D::Error> {
+    let list = List::deserialize(deserializer)?.entries().join(",");
+    let profiles: FirewallProfile = list.parse().map_err(D::Error::custom)?;
+    if profiles.is_empty() {
+        return Err(D::Error::custom(
+            "a rule must apply to at least one profile",
+        ));
+    }
+    Ok(profiles)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn expected() -> DesiredState {
+        let mut profiles = BTreeMap::new();
+        profiles.insert(
+            FirewallProfile::PUBLIC,
+            FirewallProfileSettings {
+                block_all_inbound_traffic: true,
+                ..Default::default()
+            },
+        );
+
+        DesiredState {
+            profiles,
+            rules: vec![
+                FirewallRuleData {
+                    protocol: 6,
+                    local_ports: Some("80,443".into()),
+                    remote_addresses: Some("LocalSubnet".into()),
+                    grouping: Some("Web server".into()),
+                    profiles: FirewallProfile::DOMAIN | FirewallProfile::PRIVATE,
+                    ..FirewallRuleData::new("Web")
+                },
+                FirewallRuleData {
+                    protocol: 1,
+                    icmp_types_and_codes: Some("8:*".into()),
+                    action: FirewallAction::Block,
+                    ..FirewallRuleData::new("Ping")
+                },
+            ],
+        }
+    }
+
+    /// The examples in the module documentation.
+    fn documented(language: &str) -> String {
+        let source = include_str!("policy_file.rs");
+        let start = format!("//! ```{}\n", language);
+        let block = &source[source.find(&start).unwrap() + start.len()..];
+        block[..block.find("//! ```\n").unwrap()]
+            .lines()
+            .map(|line| {
+                line.trim_start_matches("//!")
+                    .strip_prefix(' ')
+                    .unwrap_or("")
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn location(error: std::io::Error) -> (Option<usize>, Option<usize>, String) {
+        let error = error
+            .into_inner()
+            .unwrap()
+            .downcast::<PolicyFileError>()
+            .unwrap();
+        (error.line, error.column, error.message)
+    }
+
+    #[cfg(feature = "toml")]
+    #[test]
+    fn toml() {
+        assert_eq!(from_toml_str(&documented("toml")).unwrap(), expected());
+        assert_eq!(from_toml_str("").unwrap(), DesiredState::default());
+
+        let cases = [
+            (
+                "[[rules]]\nname = \"a\"\nnmae = \"b\"\n",
+                3,
+                "unknown field `nmae`",
+            ),
+            (
+                "[[rules]]\nname = \"a\"\nprotocol = \"TCP\"\nlocal_ports = \"80-\"\n",
+                4,
+                "invalid port list '80-'",
+            ),
+            (
+                "[[rules]]\nname = \"a\"\nlocal_ports = 80\n",
+                1,
+                "rule 'a': ports can only be given for TCP and UDP",
+            ),
+            ("[profiles.everywhere]\n", 1, "invalid profile 'everywhere'"),
+            (
+                "[profiles.domain]\ndefault_inbound_action = \"Maybe\"\n",
+                2,
+                "invalid action 'Maybe'",
+            ),
+        ];
+        for (text, line, message) in cases.iter() {
+            let (error_line, column, error_message) = location(from_toml_str(text).unwrap_err());
+            assert_eq!(error_line, Some(*line), "{}", text);
+            assert!(column.is_some());
+            assert!(error_message.contains(message), "{}", error_message);
+        }
+    }
+
+    #[cfg(feature = "yaml")]
+    #[test]
+    fn yaml() {
+        assert_eq!(from_yaml_str(&documented("yaml")).unwrap(), expected());
+
+        let cases = [
+            (
+                "rules:\n  - name: a\n    nmae: b\n",
+                3,
+                "unknown field `nmae`",
+            ),
+            (
+                "rules:\n  - name: a\n    protocol: TCP\n    remote_addresses: 10.0.0.0/33\n",
+                2,
+                "invalid address list '10.0.0.0/33'",
+            ),
+            (
+                "rules:\n  - name: a\n    protocol: UDP\n    icmp_types_and_codes: 8\n",
+                2,
+                "rule 'a': ICMP types can only be given",
+            ),
+        ];
+        for (text, line, message) in cases.iter() {
+            let (error_line, _, error_message) = location(from_yaml_str(text).unwrap_err());
+            assert_eq!(error_line, Some(*line), "{}", text);
+            assert!(error_message.contains(message), "{}", error_message);
+        }
+    }
+
+    #[cfg(feature = "toml")]
+    #[test]
+    fn load_reports_the_path() {
+        let path = std::env::temp_dir().join(format!("netfw-policy-{}.toml", std::process::id()));
+        std::fs::write(&path, "rules = 5\n").unwrap();
+        let error = load(&path).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert!(error
+            .to_string()
+            .starts_with(&format!("{}:1:9: ", path.display())));
+    }
+}
@@ -0,0 +1,987 @@
+//! Policies composed from several files: shared fragments, named variables and per-host overlays.
+//!
+//! A composed policy file has the keys of a plain one, plus:
+//!
+//! - `include`: files to read first, relative to the including file. A file included more than once is read once;
+//!   a file that includes itself, directly or not, is an error.
+//! - `variables`: named lists, such as address or port sets. An entry `$name` in a list field of a rule is replaced
+//!   by the entries of the variable, which may in turn name other variables.
+//! - `overlays`: changes for some hosts only, chosen by hostname patterns with `*` and `?`, or by role. An overlay
+//!   can set variables and profile settings, `remove` rules by name, `patch` fields of rules by name and add `rules`.
+//!
+//! ```toml
+//! include = ["common.toml"]
+//!
+//! [variables]
+//! management_nets = ["10.0.0.0/24", "10.0.1.0/24"]
+//! web_ports = [80, 443]
+//!
+//! [[rules]]
+//! name = "Web"
+//! protocol = "tcp"
+//! local_ports = "$web_ports"
+//!
+//! [[overlays]]
+//! name = "jump hosts"
+//! hosts = ["jump-*"]
+//! roles = ["bastion"]
+//! remove = ["Web"]
+//!
+//! [[overlays.rules]]
+//! name = "SSH"
+//! protocol = "tcp"
+//! local_ports = 22
+//! remote_addresses = ["$management_nets"]
+//!
+//! [overlays.profiles.public]
+//! default_inbound_action = "Block"
+//! ```
+//!
+//! Included files are read depth first, so the rules of a file follow those of its includes and later definitions
+//! of a variable or profile setting win. The overlays that match the target are then applied in the order they were
+//! read, and only then are variables substituted and the rules validated, so an overlay can redefine a variable for
+//! every rule that uses it. The rules and profiles of each file are also checked as it is read, leaving out the
+//! entries that name variables, so that mistakes written in a file are reported with their line and column.
+
+use super::{
+    ProfileKey,
+    ProfileSpec,
+    RuleFields,
+    RuleSpec,
+};
+use crate::{
+    reconcile::DesiredState,
+    FirewallProfile,
+};
+use serde::{
+    de::{
+        DeserializeOwned,
+        DeserializeSeed,
+        Error as _,
+        MapAccess,
+        Visitor,
+    },
+    Deserialize,
+    Deserializer,
+};
+use serde_json::{
+    Map,
+    Value,
+};
+use std::{
+    collections::{
+        BTreeMap,
+        HashSet,
+    },
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+
+/// A table of a policy file, before it is validated.
+type Table = Map<String, Value>;
+
+/// The fields of a rule that take lists, and so may name variables.
+const LIST_FIELDS: [&str; 8] = [
+    "local_ports",
+    "remote_ports",
+    "local_addresses",
+    "remote_addresses",
+    "icmp_types_and_codes",
+    "interfaces",
+    "interface_types",
+    "profiles",
+];
+
+/// The host a policy is resolved for.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Resolver {
+    hostname: Option<String>,
+    roles: Vec<String>,
+}
+
+impl Resolver {
+    /// A target matching no overlays.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Apply overlays whose `hosts` patterns match `hostname`, ignoring case.
+    pub fn with_hostname(mut self, hostname: impl Into<String>) -> Self {
+        self.hostname = Some(hostname.into());
+        self
+    }
+
+    /// Apply overlays that list `role`, ignoring case.
+    pub fn with_role(mut self, role: impl Into<String>) -> Self {
+        self.roles.push(role.into());
+        self
+    }
+
+    /// Read a policy file and everything it includes, and flatten it for this target.
+    ///
+    /// Errors are `InvalidData` wrapping a `PolicyFileError` naming the file at fault, except that failing to read
+    /// a file keeps the kind of the underlying error.
+    pub fn resolve(&self, path: impl AsRef<Path>) -> Result<Resolved, std::io::Error> {
+        let mut composer = Composer::default();
+        composer.read(path.as_ref(), &mut Vec::new())?;
+        for overlay in std::mem::take(&mut composer.overlays) {
+            if self.matches(&overlay) {
+                composer.apply(overlay)?;
+            }
+        }
+        composer.finish()
+    }
+
+    fn matches(&self, overlay: &Overlay) -> bool {
+        let host = self.hostname.as_deref().is_some_and(|hostname| {
+            overlay.hosts.iter().any(|pattern| {
+                let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+                let hostname: Vec<char> = hostname.to_lowercase().chars().collect();
+                glob(&pattern, &hostname)
+            })
+        });
+        host || overlay
+            .roles
+            .iter()
+            .any(|role| self.roles.iter().any(|own| own.eq_ignore_ascii_case(role)))
+    }
+}
+
+/// A flattened policy and where each of its rules came from.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Resolved {
+    pub state: DesiredState,
+    /// The origin of each rule, in the order of `state.rules`.
+    pub origins: Vec<Origin>,
+}
+
+/// Where a rule was defined and what changed it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Origin {
+    /// The file the rule was written in.
+    pub file: PathBuf,
+    /// The overlay that added the rule, when it is not part of the base policy.
+    pub overlay: Option<String>,
+    /// The overlays that patched the rule, in the order they were applied.
+    pub patched_by: Vec<String>,
+}
+
+/// Explains the policy a rule per line, like `Web (Inbound): base.toml; patched by overlay 'dmz' in web.toml`.
+impl std::fmt::Display for Resolved {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (rule, origin) in self.state.rules.iter().zip(&self.origins) {
+            write!(f, "{} ({}): ", rule.name, rule.direction)?;
+            match &origin.overlay {
+                Some(overlay) => write!(f, "added by {}", overlay)?,
+                None => write!(f, "{}", origin.file.display())?,
+            }
+            if !origin.patched_by.is_empty() {
+                write!(f, "; patched by {}", origin.patched_by.join(", "))?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// A rule that is yet to be validated.
+struct Pending {
+    fields: Table,
+    origin: Origin,
+}
+
+struct Overlay {
+    label: String,
+    file: PathBuf,
+    hosts: Vec<String>,
+    roles: Vec<String>,
+    variables: Table,
+    profiles: Vec<(FirewallProfile, Table)>,
+    remove: Vec<String>,
+    patch: Vec<Table>,
+    rules: Vec<Table>,
+}
+
+#[derive(Default)]
+struct Composer {
+    /// The canonical paths of the files read so far.
+    read: HashSet<PathBuf>,
+    variables: Table,
+    /// The settings of each profile, and the file that last changed them.
+    profiles: BTreeMap<FirewallProfile, (Table, PathBuf)>,
+    rules: Vec<Pending>,
+    overlays: Vec<Overlay>,
+}
+
+impl Composer {
+    /// Read a file after its includes. `stack` holds the canonical paths of the files including it.
+    fn read(&mut self, path: &Path, stack: &mut Vec<PathBuf>) -> Result<(), std::io::Error> {
+        let canonical = std::fs::canonicalize(path).map_err(|error| {
+            std::io::Error::new(error.kind(), format!("{}: {}", path.display(), error))
+        })?;
+        if stack.contains(&canonical) {
+            return Err(invalid(path, "the file includes itself".to_string()));
+        }
+        if self.read.contains(&canonical) {
+            return Ok(());
+        }
+
+        let text = std::fs::read_to_string(path).map_err(|error| {
+            std::io::Error::new(error.kind(), format!("{}: {}", path.display(), error))
+        })?;
+        let mut document = parse(path, &text)?;
+        let error = |message: String| invalid(path, message);
+
+        if let Some(key) = document.keys().find(|key| {
+            !["include", "variables", "profiles", "rules", "overlays"].contains(&key.as_str())
+        }) {
+            return Err(error(format!("unknown key '{}'", key)));
+        }
+
+        if let Some(includes) = document.remove("include") {
+            let directory = path.parent().unwrap_or_else(|| Path::new(""));
+            stack.push(canonical.clone());
+            for include in strings(includes, "include").map_err(error)? {
+                self.read(&directory.join(include), stack)?;
+            }
+            stack.pop();
+        }
+        if let Some(variables) = document.remove("variables") {
+            let variables = variable_table(variables).map_err(error)?;
+            self.variables.extend(variables);
+        }
+        if let Some(profiles) = document.remove("profiles") {
+            for (profile, settings) in profile_table(profiles).map_err(error)? {
+                let (merged, file) = self
+                    .profiles
+                    .entry(profile)
+                    .or_insert_with(|| (Map::new(), PathBuf::new()));
+                merged.extend(settings);
+                *file = path.to_path_buf();
+            }
+        }
+        if let Some(rules) = document.remove("rules") {
+            for fields in tables(rules, "rules").map_err(error)? {
+                self.rules.push(Pending {
+                    fields,
+                    origin: Origin {
+                        file: path.to_path_buf(),
+                        overlay: None,
+                        patched_by: Vec::new(),
+                    },
+                });
+            }
+        }
+        if let Some(overlays) = document.remove("overlays") {
+            for (index, overlay) in tables(overlays, "overlays")
+                .map_err(error)?
+                .into_iter()
+                .enumerate()
+            {
+                let overlay = read_overlay(path, index, overlay).map_err(error)?;
+                self.overlays.push(overlay);
+            }
+        }
+
+        self.read.insert(canonical);
+        Ok(())
+    }
+
+    fn apply(&mut self, overlay: Overlay) -> Result<(), std::io::Error> {
+        let Overlay {
+            label,
+            file,
+            variables,
+            profiles,
+            remove,
+            patch,
+            rules,
+            ..
+        } = overlay;
+        let error = |message: String| invalid(&file, format!("{}: {}", label, message));
+
+        self.variables.extend(variables);
+        for (profile, settings) in profiles {
+            let (merged, merged_file) = self
+                .profiles
+                .entry(profile)
+                .or_insert_with(|| (Map::new(), PathBuf::new()));
+            merged.extend(settings);
+            *merged_file = file.clone();
+        }
+
+        for name in &remove {
+            let before = self.rules.len();
+            self.rules
+                .retain(|rule| rule_name(&rule.fields) != Some(name));
+            if self.rules.len() == before {
+                return Err(error(format!("there is no rule '{}' to remove", name)));
+            }
+        }
+
+        for patch in patch {
+            let name = rule_name(&patch).unwrap_or_default().to_string();
+            let mut found = false;
+            for rule in &mut self.rules {
+                if rule_name(&rule.fields) == Some(&name) {
+                    let changes = patch.iter().filter(|(key, _)| key.as_str() != "name");
+                    rule.fields
+                        .extend(changes.map(|(key, value)| (key.clone(), value.clone())));
+                    rule.origin.patched_by.push(label.clone());
+                    found = true;
+                }
+            }
+            if !found {
+                return Err(error(format!("there is no rule '{}' to patch", name)));
+            }
+        }
+
+        for fields in rules {
+            self.rules.push(Pending {
+                fields,
+                origin: Origin {
+                    file: file.clone(),
+                    overlay: Some(label.clone()),
+                    patched_by: Vec::new(),
+                },
+            });
+        }
+        Ok(())
+    }
+
+    /// Substitute variables and validate everything.
+    fn finish(self) -> Result<Resolved, std::io::Error> {
+        let mut resolved = Resolved::default();
+
+        for (profile, (settings, file)) in self.profiles {
+            let settings = ProfileSpec::deserialize(Value::Object(settings))
+                .map_err(|error| invalid(&file, format!("profile {}: {}", profile, error)))?;
+            resolved.state.profiles.insert(profile, settings.0);
+        }
+
+        for Pending { mut fields, origin } in self.rules {
+            let name = rule_name(&fields).unwrap_or_default().to_string();
+            let error = |message: String| {
+                // The checks across fields already name the rule.
+                if message.starts_with("rule '") {
+                    invalid(&origin.file, message)
+                } else {
+                    invalid(&origin.file, format!("rule '{}': {}", name, message))
+                }
+            };
+
+            for field in LIST_FIELDS.iter() {
+                if let Some(value) = fields.get_mut(*field) {
+                    let substituted =
+                        substitute(value, &self.variables, &mut Vec::new()).map_err(error)?;
+                    if let Some(substituted) = substituted {
+                        *value = substituted;
+                    }
+                }
+            }
+            let rule = RuleSpec::deserialize(Value::Object(fields))
+                .map_err(|message| error(message.to_string()))?;
+            resolved.state.rules.push(rule.0);
+            resolved.origins.push(origin);
+        }
+        Ok(resolved)
+    }
+}
+
+/// Parse a file into a table, choosing the format by extension.
+fn parse(path: &Path, text: &str) -> Result<Table, std::io::Error> {
+    match deserialize(path, text)? {
+        Value::Object(document) => {
+            // Checked now, while the parser can still say where a problem is.
+            deserialize::<Fragment>(path, text)?;
+            Ok(document)
+        }
+        // An empty YAML document.
+        Value::Null => Ok(Map::new()),
+        _ => Err(invalid(path, "a policy file must be a table".to_string())),
+    }
+}
+
+/// Deserialize a file with the parser for its extension, keeping the location of any error.
+fn deserialize<T: DeserializeOwned>(path: &Path, text: &str) -> Result<T, std::io::Error> {
+    let value = match super::extension(path).as_str() {
+        #[cfg(feature = "toml")]
+        "toml" => toml::from_str(text).map_err(|error| super::toml_error(text, &error)),
+        #[cfg(feature = "yaml")]
+        "yaml" | "yml" => serde_yaml_ng::from_str(text).map_err(|error| super::yaml_error(&error)),
+        extension => Err(super::unsupported_extension(extension)),
+    };
+    value.map_err(|error| {
+        super::PolicyFileError {
+            path: Some(path.to_path_buf()),
+            ..error
+        }
+        .into()
+    })
+}
+
+/// The rules and profiles of a file, checked as far as they can be before variables are substituted.
+#[derive(Deserialize)]
+#[allow(dead_code)]
+struct Fragment {
+    #[serde(default)]
+    profiles: BTreeMap<ProfileKey, ProfileSpec>,
+    #[serde(default)]
+    rules: Vec<FragmentRule>,
+}
+
+/// A rule checked with the list entries that name variables left out.
+///
+/// Each field is checked on its own as it is read, so that the parser can locate a bad value.
+struct FragmentRule;
+
+impl<'de> Deserialize<'de> for FragmentRule {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_map(FragmentRuleVisitor)
+    }
+}
+
+struct FragmentRuleVisitor;
+
+impl<'de> Visitor<'de> for FragmentRuleVisitor {
+    type Value = FragmentRule;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("a rule table")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<FragmentRule, A::Error> {
+        let mut fields = Table::new();
+        while let Some(key) = map.next_key::<String>()? {
+            if let Some(value) = map.next_value_seed(FieldSeed(&key))? {
+                fields.insert(key, value);
+            }
+        }
+
+        let name = rule_name(&fields).unwrap_or_default().to_string();
+        RuleSpec::deserialize(Value::Object(fields)).map_err(|error| {
+            let message = error.to_string();
+            // The checks across fields already name the rule.
+            if name.is_empty() || message.starts_with("rule '") {
+                A::Error::custom(message)
+            } else {
+                A::Error::custom(format!("rule '{}': {}", name, message))
+            }
+        })?;
+        Ok(FragmentRule)
+    }
+}
+
+/// A field of a rule, checked on its own. `None` for a list naming only variables.
+struct FieldSeed<'a>(&'a str);
+
+impl<'de> DeserializeSeed<'de> for FieldSeed<'_> {
+    type Value = Option<Value>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        let value = Value::deserialize(deserializer)?;
+        let value = if LIST_FIELDS.contains(&self.0) {
+            without_variables(value)
+        } else {
+            Some(value)
+        };
+
+        if let Some(value) = &value {
+            let mut fields = Table::new();
+            fields.insert("name".to_string(), Value::String(String::new()));
+            fields.insert(self.0.to_string(), value.clone());
+            RuleFields::deserialize(Value::Object(fields)).map_err(D::Error::custom)?;
+        }
+        Ok(value)
+    }
+}
+
+/// A list with the entries naming variables left out, or `None` if nothing else is left.
+fn without_variables(value: Value) -> Option<Value> {
+    let entries = match value {
+        Value::Array(entries) => entries,
+        Value::String(text) => vec![Value::String(text)],
+        value => return Some(value),
+    };
+    let mut kept = Vec::new();
+    for entry in entries {
+        match entry {
+            Value::String(text) if text.contains('$') => kept.extend(
+                text.split(',')
+                    .map(str::trim)
+                    .filter(|part| !part.is_empty() && !part.starts_with('$'))
+                    .map(|part| Value::String(part.to_string())),
+            ),
+            entry => kept.push(entry),
+        }
+    }
+    Some(Value::Array(kept)).filter(|kept| kept.as_array().is_some_and(|kept| !kept.is_empty()))
+}
+
+fn read_overlay(path: &Path, index: usize, mut overlay: Table) -> Result<Overlay, String> {
+    if let Some(key) = overlay.keys().find(|key| {
+        ![
+            "name",
+            "hosts",
+            "roles",
+            "variables",
+            "profiles",
+            "remove",
+            "patch",
+            "rules",
+        ]
+        .contains(&key.as_str())
+    }) {
+        return Err(format!("overlay {}: unknown key '{}'", index + 1, key));
+    }
+
+    let label = match overlay.remove("name") {
+        Some(Value::String(name)) => format!("overlay '{}' in {}", name, path.display()),
+        Some(_) => return Err(format!("overlay {}: the name must be a string", index + 1)),
+        None => format!("overlay {} in {}", index + 1, path.display()),
+    };
+    let mut take = |key: &str| overlay.remove(key).filter(|value| !value.is_null());
+    let overlay = Overlay {
+        file: path.to_path_buf(),
+        hosts: take("hosts").map_or(Ok(Vec::new()), |value| strings(value, "hosts"))?,
+        roles: take("roles").map_or(Ok(Vec::new()), |value| strings(value, "roles"))?,
+        variables: take("variables").map_or(Ok(Map::new()), variable_table)?,
+        profiles: take("profiles").map_or(Ok(Vec::new()), profile_table)?,
+        remove: take("remove").map_or(Ok(Vec::new()), |value| strings(value, "remove"))?,
+        patch: take("patch").map_or(Ok(Vec::new()), |value| tables(value, "patch"))?,
+        rules: take("rules").map_or(Ok(Vec::new()), |value| tables(value, "rules"))?,
+        label,
+    };
+
+    if overlay.hosts.is_empty() && overlay.roles.is_empty() {
+        return Err(format!(
+            "{}: give the hosts or roles it applies to",
+            overlay.label
+        ));
+    }
+    if overlay.patch.iter().any(|patch| rule_name(patch).is_none()) {
+        return Err(format!(
+            "{}: every patch needs the name of a rule",
+            overlay.label
+        ));
+    }
+    Ok(overlay)
+}
+
+fn invalid(path: &Path, message: String) -> std::io::Error {
+    super::PolicyFileError {
+        path: Some(path.to_path_buf()),
+        line: None,
+        column: None,
+        message,
+    }
+    .into()
+}
+
+fn rule_name(fields: &Table) -> Option<&str> {
+    fields.get("name").and_then(Value::as_str)
+}
+
+/// A string, or an array of strings.
+fn strings(value: Value, key: &str) -> Result<Vec<String>, String> {
+    match value {
+        Value::String(string) => Ok(vec![string]),
+        Value::Array(values) => values
+            .into_iter()
+            .map(|value| match value {
+                Value::String(string) => Ok(string),
+                _ => Err(format!("'{}' must be a list of strings", key)),
+            })
+            .collect(),
+        _ => Err(format!("'{}' must be a list of strings", key)),
+    }
+}
+
+/// An array of tables.
+fn tables(value: Value, key: &str) -> Result<Vec<Table>, String> {
+    match value {
+        Value::Array(values) => values
+            .into_iter()
+            .map(|value| match value {
+                Value::Object(table) => Ok(table),
+                _ => Err(format!("'{}' must be a list of tables", key)),
+            })
+            .collect(),
+        _ => Err(format!("'{}' must be a list of tables", key)),
+    }
+}
+
+/// Variables, checking their names and that they hold lists.
+fn variable_table(value: Value) -> Result<Table, String> {
+    let variables = match value {
+        Value::Object(variables) => variables,
+        _ => return Err("'variables' must be a table".to_string()),
+    };
+    for (name, value) in &variables {
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(format!("invalid variable name '{}'", name));
+        }
+        let entry = |value: &Value| value.is_string() || value.is_number();
+        let valid = match value {
+            Value::Array(values) => values.iter().all(entry),
+            value => entry(value),
+        };
+        if !valid {
+            return Err(format!(
+                "variable '{}' must be a string, a number or a list of them",
+                name
+            ));
+        }
+    }
+    Ok(variables)
+}
+
+/// Settings keyed by single profiles.
+fn profile_table(value: Value) -> Result<Vec<(FirewallProfile, Table)>, String> {
+    let profiles = match value {
+        Value::Object(profiles) => profiles,
+        _ => return Err("'profiles' must be a table".to_string()),
+    };
+    profiles
+        .into_iter()
+        .map(|(key, settings)| {
+            let profile = ProfileKey::deserialize(Value::String(key.clone()))
+                .map_err(|error| error.to_string())?;
+            match settings {
+                Value::Object(settings) => Ok((profile.0, settings)),
+                _ => Err(format!("profile '{}' must be a table", key)),
+            }
+        })
+        .collect()
+}
+
+/// Replace `$name` entries of a list with the entries of the variable, or return `None` if it names none.
+fn substitute(
+    value: &Value,
+    variables: &Table,
+    stack: &mut Vec<String>,
+) -> Result<Option<Value>, String> {
+    let entries = match value {
+        Value::Array(entries) => entries.as_slice(),
+        Value::String(_) | Value::Number(_) => std::slice::from_ref(value),
+        _ => return Ok(None),
+    };
+    let names_variable = |entry: &Value| entry.as_str().is_some_and(|entry| entry.contains('$'));
+    if !entries.iter().any(names_variable) {
+        return Ok(None);
+    }
+
+    let mut substituted = Vec::new();
+    for entry in entries {
+        let text = match entry {
+            Value::String(text) => text,
+            _ => {
+                substituted.push(entry.clone());
+                continue;
+            }
+        };
+        for part in text
+            .split(',')
+            .map(str::trim)
+            .filter(|part| !part.is_empty())
+        {
+            let name = match part.strip_prefix('$') {
+                Some(name) => name,
+                None => {
+                    substituted.push(Value::String(part.to_string()));
+                    continue;
+                }
+            };
+            let variable = variables
+                .get(name)
+                .ok_or_else(|| format!("undefined variable '${}'", name))?;
+            if stack.iter().any(|outer| outer == name) {
+                return Err(format!("variable '${}' refers to itself", name));
+            }
+
+            stack.push(name.to_string());
+            match substitute(variable, variables, stack)? {
+                Some(Value::Array(entries)) => substituted.extend(entries),
+                Some(entry) => substituted.push(entry),
+                None => match variable {
+                    Value::Array(entries) => substituted.extend(entries.iter().cloned()),
+                    entry => substituted.push(entry.clone()),
+                },
+            }
+            stack.pop();
+        }
+    }
+    Ok(Some(Value::Array(substituted)))
+}
+
+/// Match a lowercased pattern, where `*` is any run of characters and `?` any one character.
+fn glob(pattern: &[char], text: &[char]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some(('*', rest)) => (0..=text.len()).any(|skip| glob(rest, &text[skip..])),
+        Some(('?', rest)) => !text.is_empty() && glob(rest, &text[1..]),
+        Some((c, rest)) => text.first() == Some(c) && glob(rest, &text[1..]),
+    }
+}
+
+#[cfg(all(test, feature = "toml"))]
+mod test {
+    use super::*;
+    use crate::FirewallAction;
+
+    /// A directory of policy files, removed when dropped.
+    struct Files(PathBuf);
+
+    impl Files {
+        fn new(name: &str, files: &[(&str, &str)]) -> Self {
+            let directory =
+                std::env::temp_dir().join(format!("netfw-compose-{}-{}", name, std::process::id()));
+            for (path, text) in files {
+                let path = directory.join(path);
+                std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+                std::fs::write(path, text).unwrap();
+            }
+            Files(directory)
+        }
+
+        fn path(&self, path: &str) -> PathBuf {
+            self.0.join(path)
+        }
+    }
+
+    impl Drop for Files {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    const COMMON: &str = r#"
+[variables]
+management_nets = ["10.0.0.0/24", "10.0.1.0/24"]
+admin = ["$management_nets", "192.168.1.5"]
+
+[profiles.public]
+default_inbound_action = "Allow"
+
+[[rules]]
+name = "Ping"
+protocol = "icmpv4"
+icmp_types_and_codes = "8:*"
+"#;
+
+    const HOST: &str = r#"
+include = ["shared/common.toml", "shared/common.toml"]
+
+[variables]
+web_ports = [80, 443]
+
+[profiles.public]
+notifications_disabled = true
+
+[[rules]]
+name = "Web"
+protocol = "tcp"
+local_ports = "$web_ports, 8080"
+
+[[overlays]]
+name = "jump hosts"
+hosts = ["JUMP-*"]
+remove = ["Web"]
+
+[[overlays.rules]]
+name = "SSH"
+protocol = "tcp"
+local_ports = 22
+remote_addresses = ["$admin"]
+
+[[overlays.patch]]
+name = "Ping"
+remote_addresses = "$management_nets"
+
+[overlays.profiles.public]
+default_inbound_action = "Block"
+
+[[overlays]]
+roles = ["web"]
+
+[overlays.variables]
+web_ports = 443
+"#;
+
+    #[test]
+    fn resolves_includes_and_variables() {
+        let files = Files::new(
+            "plain",
+            &[("host.toml", HOST), ("shared/common.toml", COMMON)],
+        );
+        let resolved = Resolver::new()
+            .with_hostname("web-01")
+            .resolve(files.path("host.toml"))
+            .unwrap();
+
+        let names: Vec<_> = resolved
+            .state
+            .rules
+            .iter()
+            .map(|rule| rule.name.as_str())
+            .collect();
+        assert_eq!(names, ["Ping", "Web"]);
+        assert_eq!(
+            resolved.state.rules[1].local_ports.as_deref(),
+            Some("80,443,8080")
+        );
+        assert_eq!(resolved.origins[0].file, files.path("shared/common.toml"));
+        let public = &resolved.state.profiles[&FirewallProfile::PUBLIC];
+        assert_eq!(public.default_inbound_action, FirewallAction::Allow);
+        assert!(public.notifications_disabled);
+
+        let resolved = Resolver::new()
+            .with_role("WEB")
+            .resolve(files.path("host.toml"))
+            .unwrap();
+        assert_eq!(
+            resolved.state.rules[1].local_ports.as_deref(),
+            Some("443,8080")
+        );
+    }
+
+    #[test]
+    fn applies_overlays() {
+        let files = Files::new(
+            "overlays",
+            &[("host.toml", HOST), ("shared/common.toml", COMMON)],
+        );
+        let resolved = Resolver::new()
+            .with_hostname("jump-02")
+            .resolve(files.path("host.toml"))
+            .unwrap();
+
+        let rules = &resolved.state.rules;
+        assert_eq!(rules.len(), 2);
+        assert_eq!(
+            rules[0].remote_addresses.as_deref(),
+            Some("10.0.0.0/24,10.0.1.0/24")
+        );
+        assert_eq!(
+            rules[1].remote_addresses.as_deref(),
+            Some("10.0.0.0/24,10.0.1.0/24,192.168.1.5")
+        );
+        assert_eq!(
+            resolved.state.profiles[&FirewallProfile::PUBLIC].default_inbound_action,
+            FirewallAction::Block
+        );
+
+        let overlay = format!(
+            "overlay 'jump hosts' in {}",
+            files.path("host.toml").display()
+        );
+        assert_eq!(
+            resolved.to_string(),
+            format!(
+                "Ping (Inbound): {}; patched by {}\nSSH (Inbound): added by {}\n",
+                files.path("shared/common.toml").display(),
+                overlay,
+                overlay
+            )
+        );
+    }
+
+    #[test]
+    fn reports_errors() {
+        for (name, text, message) in &[
+            ("cycle", "include = [\"cycle.toml\"]\n", "the file includes itself"),
+            (
+                "undefined",
+                "[[rules]]\nname = \"A\"\nprotocol = \"tcp\"\nlocal_ports = \"$nope\"\n",
+                "rule 'A': undefined variable '$nope'",
+            ),
+            (
+                "recursive",
+                "[variables]\na = \"$b\"\nb = \"$a\"\n[[rules]]\nname = \"A\"\nremote_addresses = \"$a\"\n",
+                "rule 'A': variable '$a' refers to itself",
+            ),
+            (
+                "invalid",
+                "[variables]\np = \"80-\"\n[[rules]]\nname = \"A\"\nprotocol = \"tcp\"\nlocal_ports = \"$p\"\n",
+                "rule 'A': ",
+            ),
+            (
+                "missing",
+                "[[overlays]]\nroles = [\"x\"]\nremove = [\"A\"]\n",
+                "overlay 1 in ",
+            ),
+            ("untargeted", "[[overlays]]\nname = \"o\"\n", "give the hosts or roles"),
+            ("unknown", "includes = []\n", "unknown key 'includes'"),
+        ] {
+            let path = format!("{}.toml", name);
+            let files = Files::new(name, &[(path.as_str(), text)]);
+            let error = Resolver::new()
+                .with_role("x")
+                .resolve(files.path(&path))
+                .unwrap_err();
+            assert_eq!(error.kind(), std::io::ErrorKind::InvalidData, "{}", name);
+            let expected = format!("{}: ", files.path(&path).display());
+            let error = error.to_string();
+            assert!(error.starts_with(&expected), "{}", error);
+            assert!(error.contains(message), "{}", error);
+        }
+    }
+
+    #[test]
+    fn locates_errors_in_files() {
+        let common = "[[rules]]\nname = \"A\"\nprotocol = \"tcp\"\n\n[[rules]]\nname = \"B\"\nprotocol = \"tcp\"\n\
+                      local_ports = \"$web, 80-\"\n";
+        let host = "include = [\"common.toml\"]\n\n[profiles.public]\ndefault_inbound_action = \"Maybe\"\n";
+        let files = Files::new(
+            "located",
+            &[
+                ("host.toml", "include = [\"common.toml\"]\n"),
+                ("common.toml", common),
+            ],
+        );
+        let location = |error: std::io::Error| {
+            let error = error
+                .into_inner()
+                .unwrap()
+                .downcast::<super::super::PolicyFileError>()
+                .unwrap();
+            (error.path, error.line, error.message)
+        };
+
+        let (path, line, message) = location(
+            Resolver::new()
+                .resolve(files.path("host.toml"))
+                .unwrap_err(),
+        );
+        assert_eq!(path, Some(files.path("common.toml")));
+        assert_eq!(line, Some(8));
+        assert!(message.contains("'80-'"), "{}", message);
+
+        std::fs::write(files.path("host.toml"), host).unwrap();
+        std::fs::write(files.path("common.toml"), "").unwrap();
+        let (path, line, _) = location(
+            Resolver::new()
+                .resolve(files.path("host.toml"))
+                .unwrap_err(),
+        );
+        assert_eq!(path, Some(files.path("host.toml")));
+        assert_eq!(line, Some(4));
+    }
+
+    #[test]
+    fn matches_hostnames() {
+        let glob = |pattern: &str, text: &str| {
+            glob(
+                &pattern.chars().collect::<Vec<_>>(),
+                &text.chars().collect::<Vec<_>>(),
+            )
+        };
+        assert!(glob("web-*", "web-01"));
+        assert!(glob("*", ""));
+        assert!(glob("db-?", "db-1"));
+        assert!(!glob("db-?", "db-10"));
+        assert!(!glob("web-*", "db-01"));
+    }
+}
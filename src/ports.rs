@@ -0,0 +1,204 @@
+//! Parsed port lists, like the `LocalPorts` and `RemotePorts` of a rule.
+
+use crate::FirewallRuleData;
+
+/// Port keywords Windows accepts in place of numbers, in their canonical spelling.
+const KEYWORDS: [&str; 8] = [
+    "RPC",
+    "RPC-EPMap",
+    "IPHTTPS",
+    "IPHTTPSIn",
+    "IPHTTPSOut",
+    "Teredo",
+    "PlayToDiscovery",
+    "mDNS",
+];
+
+/// A set of ports in canonical form.
+///
+/// Ranges are sorted and merged and keywords are sorted, so lists naming the same ports compare equal
+/// however they were written: `80,443`, `443, 80` and `80-80,443` are all the same list.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PortList {
+    any: bool,
+    ranges: Vec<(u16, u16)>,
+    keywords: Vec<String>,
+}
+
+impl PortList {
+    /// The list matching every port.
+    pub fn any() -> Self {
+        PortList {
+            any: true,
+            ranges: Vec::new(),
+            keywords: Vec::new(),
+        }
+    }
+
+    /// Parse a comma separated list of ports, ranges like `5000-5010` and keywords like `RPC`.
+    ///
+    /// `*` and `Any` stand for every port.
+    pub fn parse(s: &str) -> Result<Self, std::io::Error> {
+        let mut list = PortList {
+            any: false,
+            ranges: Vec::new(),
+            keywords: Vec::new(),
+        };
+
+        for entry in s
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+        {
+            if entry == "*" || entry.eq_ignore_ascii_case("any") {
+                return Ok(Self::any());
+            }
+
+            if let Some(keyword) = KEYWORDS
+                .iter()
+                .find(|keyword| keyword.eq_ignore_ascii_case(entry))
+            {
+                list.keywords.push(keyword.to_string());
+                continue;
+            }
+
+            let mut bounds = entry.splitn(2, '-').map(str::trim);
+            let start = parse_port(bounds.next().unwrap_or_default(), s)?;
+            let end = match bounds.next() {
+                Some(end) => parse_port(end, s)?,
+                None => start,
+            };
+            if start > end {
+                return Err(invalid(s));
+            }
+            list.ranges.push((start, end));
+        }
+
+        if list.ranges.is_empty() && list.keywords.is_empty() {
+            return Ok(Self::any());
+        }
+
+        list.ranges.sort_unstable();
+        let mut merged: Vec<(u16, u16)> = Vec::with_capacity(list.ranges.len());
+        for (start, end) in list.ranges.drain(..) {
+            match merged.last_mut() {
+                Some(last) if start <= last.1.saturating_add(1) => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        list.ranges = merged;
+
+        list.keywords.sort_unstable();
+        list.keywords.dedup();
+
+        Ok(list)
+    }
+
+    /// Parse an optional rule field, where unset means every port.
+    pub fn from_field(field: Option<&str>) -> Result<Self, std::io::Error> {
+        field.map_or_else(|| Ok(Self::any()), Self::parse)
+    }
+
+    pub fn is_any(&self) -> bool {
+        self.any
+    }
+
+    /// The numeric port ranges, inclusive, sorted and non-overlapping.
+    pub fn ranges(&self) -> &[(u16, u16)] {
+        &self.ranges
+    }
+
+    pub fn keywords(&self) -> &[String] {
+        &self.keywords
+    }
+
+    /// Whether a numeric port is in the list. Keywords never match, as what they stand for depends on the host.
+    pub fn contains(&self, port: u16) -> bool {
+        self.any
+            || self
+                .ranges
+                .iter()
+                .any(|(start, end)| (*start..=*end).contains(&port))
+    }
+}
+
+/// Formats in canonical form, like `80,443,5000-5010,RPC`, or `Any`.
+impl std::fmt::Display for PortList {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.any {
+            return f.write_str("Any");
+        }
+
+        let ranges = self.ranges.iter().map(|(start, end)| {
+            if start == end {
+                start.to_string()
+            } else {
+                format!("{}-{}", start, end)
+            }
+        });
+        let entries: Vec<_> = ranges.chain(self.keywords.iter().cloned()).collect();
+        f.write_str(&entries.join(","))
+    }
+}
+
+impl std::str::FromStr for PortList {
+    type Err = std::io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+fn parse_port(s: &str, list: &str) -> Result<u16, std::io::Error> {
+    s.parse().map_err(|_| invalid(list))
+}
+
+fn invalid(list: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        format!("invalid port list '{}'", list),
+    )
+}
+
+impl FirewallRuleData {
+    pub fn local_port_list(&self) -> Result<PortList, std::io::Error> {
+        PortList::from_field(self.local_ports.as_deref())
+    }
+
+    pub fn remote_port_list(&self) -> Result<PortList, std::io::Error> {
+        PortList::from_field(self.remote_ports.as_deref())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn canonical_form() {
+        let list = PortList::parse(" 443, 80,rpc, 8000-8010,8005-8020,8021 ").unwrap();
+        assert_eq!(list.to_string(), "80,443,8000-8021,RPC");
+        assert_eq!(list, PortList::parse("RPC,443,80,8000-8021").unwrap());
+        assert!(list.contains(8015));
+        assert!(!list.contains(8022));
+    }
+
+    #[test]
+    fn any() {
+        for s in &["*", "Any", "", "80,any"] {
+            assert!(PortList::parse(s).unwrap().is_any());
+        }
+        assert_eq!(PortList::from_field(None).unwrap(), PortList::any());
+        assert!(PortList::any().contains(1));
+    }
+
+    #[test]
+    fn invalid_lists() {
+        for s in &["80-", "90-80", "70000", "http"] {
+            assert_eq!(
+                PortList::parse(s).unwrap_err().kind(),
+                std::io::ErrorKind::InvalidInput
+            );
+        }
+    }
+}
@@ -0,0 +1,639 @@
+//! Generating PowerShell scripts that apply rules and profile settings with the `NetSecurity` cmdlets.
+//!
+//! Scripts are safe to run more than once: each rule is looked up before it is created, so a second run updates the
+//! existing rule in place rather than adding a duplicate. Rules are found by ID when they have one, otherwise by
+//! display name and direction.
+
+use crate::{
+    data::{
+        cmdlet_icmp,
+        cmdlet_port,
+        list_entries,
+        protocol_name,
+        CMDLET_INTERFACE_TYPES,
+    },
+    diff::RuleSetDiff,
+    snapshot::Snapshot,
+    FirewallAction,
+    FirewallProfile,
+    FirewallProfileSettings,
+    FirewallRuleData,
+};
+use std::fmt::Write;
+
+/// A PowerShell script under construction.
+///
+/// Commands are appended in the order the methods are called. `Display` renders the whole script.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Script {
+    body: String,
+}
+
+impl Script {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create the rule, or bring an existing rule with the same identity in line with it.
+    ///
+    /// `Set-NetFirewallRule` cannot move a rule between groups, so an existing rule keeps its group.
+    pub fn ensure_rule(&mut self, rule: &FirewallRuleData) -> &mut Self {
+        self.heading(rule);
+        self.line(&format!("$rule = {}", select(rule)));
+        self.line("if ($rule) {");
+        self.line(&format!(
+            "    $rule | {}",
+            command("Set-NetFirewallRule", &rule_parameters(rule, true))
+        ));
+        self.line("} else {");
+        self.line(&format!(
+            "    {}",
+            command("New-NetFirewallRule", &rule_parameters(rule, false))
+        ));
+        self.line("}");
+        self
+    }
+
+    /// Remove every rule with the rule's identity. Does nothing if there is none.
+    pub fn remove_rule(&mut self, rule: &FirewallRuleData) -> &mut Self {
+        self.heading(rule);
+        self.line(&format!("{} | Remove-NetFirewallRule", select(rule)));
+        self
+    }
+
+    /// Change only the parameters that differ between `old` and `new`, finding the rule by `old`'s identity.
+    ///
+    /// A change of group can only be made by recreating the rule.
+    pub fn update_rule(&mut self, old: &FirewallRuleData, new: &FirewallRuleData) -> &mut Self {
+        let changes = crate::diff::semantic_changes(old, new);
+        if changes.is_empty() {
+            return self;
+        }
+        if changes.iter().any(|change| change.field == "grouping") {
+            self.remove_rule(old);
+            return self.ensure_rule(new);
+        }
+
+        let protocol_changed = changes.iter().any(|change| change.field == "protocol");
+        let parameters: Vec<_> = rule_parameters(new, true)
+            .into_iter()
+            .filter(|(field, _, _)| {
+                changes.iter().any(|change| change.field == *field)
+                    // Ports and ICMP types are only valid for some protocols, so they go along with a protocol change.
+                    || (protocol_changed
+                        && ["local_ports", "remote_ports", "icmp_types_and_codes"].contains(field))
+            })
+            .collect();
+
+        self.heading(old);
+        self.line(&format!(
+            "{} | {}",
+            select(old),
+            command("Set-NetFirewallRule", &parameters)
+        ));
+        self
+    }
+
+    /// Apply every setting of one or more profiles.
+    ///
+    /// Fails with `InvalidInput` if a default action is `FirewallAction::Max`, which no cmdlet accepts.
+    pub fn set_profile(
+        &mut self,
+        profile: FirewallProfile,
+        settings: &FirewallProfileSettings,
+    ) -> Result<&mut Self, std::io::Error> {
+        let parameters = profile_parameters(profile, settings)?;
+        self.line(&command("Set-NetFirewallProfile", &parameters));
+        Ok(self)
+    }
+
+    /// Apply only the settings that differ between `old` and `new`.
+    ///
+    /// Fails like `set_profile`.
+    pub fn update_profile(
+        &mut self,
+        profile: FirewallProfile,
+        old: &FirewallProfileSettings,
+        new: &FirewallProfileSettings,
+    ) -> Result<&mut Self, std::io::Error> {
+        let changes = old.changes_to(new);
+        if changes.is_empty() {
+            return Ok(self);
+        }
+
+        let parameters: Vec<_> = profile_parameters(profile, new)?
+            .into_iter()
+            .filter(|(field, _, _)| {
+                *field == "profile" || changes.iter().any(|change| change.field == *field)
+            })
+            .collect();
+        self.line(&command("Set-NetFirewallProfile", &parameters));
+        Ok(self)
+    }
+
+    fn heading(&mut self, rule: &FirewallRuleData) {
+        if !self.body.is_empty() {
+            self.body.push('\n');
+        }
+        // Newlines in a name would end the comment early.
+        let name = rule.name.replace(&['\r', '\n'][..], " ");
+        self.line(&format!("# {} ({})", name, rule.direction));
+    }
+
+    fn line(&mut self, line: &str) {
+        self.body.push_str(line);
+        self.body.push('\n');
+    }
+}
+
+impl std::fmt::Display for Script {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "#Requires -RunAsAdministrator")?;
+        writeln!(f, "#Requires -Modules NetSecurity")?;
+        writeln!(f, "$ErrorActionPreference = 'Stop'")?;
+        if !self.body.is_empty() {
+            writeln!(f)?;
+        }
+        f.write_str(&self.body)
+    }
+}
+
+/// A script that brings a host to the state of a snapshot: every rule is created or updated and every profile set.
+///
+/// Rules on the host that are not in the snapshot are left alone. Fails like `Script::set_profile`.
+pub fn snapshot_script(snapshot: &Snapshot) -> Result<String, std::io::Error> {
+    let mut script = Script::new();
+    for rule in snapshot.rules.iter() {
+        script.ensure_rule(rule);
+    }
+    if !snapshot.profiles.is_empty() {
+        script.body.push('\n');
+    }
+    for (profile, settings) in snapshot.profiles.iter() {
+        script.set_profile(*profile, settings)?;
+    }
+    Ok(script.to_string())
+}
+
+/// A script that applies only the changes in a diff, turning the old side into the new one.
+pub fn diff_script(diff: &RuleSetDiff) -> String {
+    let mut script = Script::new();
+    for rule in diff.removed.iter() {
+        script.remove_rule(rule);
+    }
+    for change in diff.modified.iter() {
+        script.update_rule(&change.old, &change.new);
+    }
+    for rule in diff.added.iter() {
+        script.ensure_rule(rule);
+    }
+    script.to_string()
+}
+
+/// Quote a string as a PowerShell single-quoted literal, in which nothing is expanded.
+///
+/// PowerShell also treats the typographic single quotes as quotes, so they are doubled like `'`.
+pub fn quote(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('\'');
+    for c in s.chars() {
+        if let '\'' | '\u{2018}' | '\u{2019}' | '\u{201a}' | '\u{201b}' = c {
+            quoted.push(c);
+        }
+        quoted.push(c);
+    }
+    quoted.push('\'');
+    quoted
+}
+
+/// Quote a string for a parameter that takes a wildcard pattern, so it only matches itself.
+fn quote_literal_pattern(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if let '*' | '?' | '[' | ']' | '`' = c {
+            escaped.push('`');
+        }
+        escaped.push(c);
+    }
+    quote(&escaped)
+}
+
+/// An expression that evaluates to the existing rules with the rule's identity, or nothing.
+fn select(rule: &FirewallRuleData) -> String {
+    match rule.id.as_deref() {
+        Some(id) => format!(
+            "Get-NetFirewallRule -Name {} -ErrorAction SilentlyContinue",
+            quote_literal_pattern(id)
+        ),
+        None => format!(
+            "Get-NetFirewallRule -DisplayName {} -ErrorAction SilentlyContinue | Where-Object Direction -eq '{}'",
+            quote_literal_pattern(&rule.name),
+            rule.direction
+        ),
+    }
+}
+
+/// A rule field, the parameter it maps to and the rendered value.
+type Parameter = (&'static str, &'static str, String);
+
+/// The parameters describing a rule.
+///
+/// For `Set-NetFirewallRule` unset fields are reset to `Any` and the identity and group are left out; for
+/// `New-NetFirewallRule` unset fields are left out.
+fn rule_parameters(rule: &FirewallRuleData, set: bool) -> Vec<Parameter> {
+    let mut parameters = Vec::new();
+    let mut optional = |field, parameter, value: Option<String>, unset: &str| match value {
+        Some(value) => parameters.push((field, parameter, value)),
+        None if set => parameters.push((field, parameter, unset.to_string())),
+        None => {}
+    };
+
+    if set {
+        optional("name", "NewDisplayName", Some(quote(&rule.name)), "");
+    } else {
+        optional("id", "Name", rule.id.as_deref().map(quote), "");
+        optional("name", "DisplayName", Some(quote(&rule.name)), "");
+        optional("grouping", "Group", rule.grouping.as_deref().map(quote), "");
+    }
+    optional(
+        "description",
+        "Description",
+        rule.description.as_deref().map(quote),
+        "''",
+    );
+    optional(
+        "direction",
+        "Direction",
+        Some(rule.direction.to_string()),
+        "",
+    );
+    optional("action", "Action", Some(rule.action.to_string()), "");
+    optional(
+        "protocol",
+        "Protocol",
+        Some(protocol_name(rule.protocol).map_or_else(|| rule.protocol.to_string(), String::from)),
+        "",
+    );
+    optional(
+        "local_ports",
+        "LocalPort",
+        list(rule.local_ports.as_deref(), port_entry),
+        "Any",
+    );
+    optional(
+        "remote_ports",
+        "RemotePort",
+        list(rule.remote_ports.as_deref(), port_entry),
+        "Any",
+    );
+    optional(
+        "local_addresses",
+        "LocalAddress",
+        list(rule.local_addresses.as_deref(), any_entry),
+        "Any",
+    );
+    optional(
+        "remote_addresses",
+        "RemoteAddress",
+        list(rule.remote_addresses.as_deref(), any_entry),
+        "Any",
+    );
+    optional(
+        "icmp_types_and_codes",
+        "IcmpType",
+        list(rule.icmp_types_and_codes.as_deref(), icmp_entry),
+        "Any",
+    );
+    optional(
+        "application_name",
+        "Program",
+        rule.application_name.as_deref().map(quote),
+        "Any",
+    );
+    optional(
+        "service_name",
+        "Service",
+        rule.service_name.as_deref().map(quote),
+        "Any",
+    );
+    optional(
+        "interfaces",
+        "InterfaceAlias",
+        rule.interfaces.as_ref().map(|interfaces| {
+            interfaces
+                .iter()
+                .map(|interface| quote_literal_pattern(interface))
+                .collect::<Vec<_>>()
+                .join(", ")
+        }),
+        "Any",
+    );
+    optional(
+        "interface_types",
+        "InterfaceType",
+        rule.interface_types.as_deref().map(interface_types),
+        "Any",
+    );
+    optional(
+        "profiles",
+        "Profile",
+        Some(quote(&rule.profiles.to_string())),
+        "",
+    );
+    optional("enabled", "Enabled", Some(boolean(rule.enabled)), "");
+    optional(
+        "edge_traversal",
+        "EdgeTraversalPolicy",
+        Some(
+            if rule.edge_traversal {
+                "Allow"
+            } else {
+                "Block"
+            }
+            .to_string(),
+        ),
+        "",
+    );
+
+    parameters
+}
+
+/// The parameters describing a profile's settings, led by the profile itself.
+fn profile_parameters(
+    profile: FirewallProfile,
+    settings: &FirewallProfileSettings,
+) -> Result<Vec<Parameter>, std::io::Error> {
+    Ok(vec![
+        ("profile", "Profile", quote(&profile.to_string())),
+        (
+            "firewall_enabled",
+            "Enabled",
+            boolean(settings.firewall_enabled),
+        ),
+        (
+            "default_inbound_action",
+            "DefaultInboundAction",
+            action(settings.default_inbound_action)?,
+        ),
+        (
+            "default_outbound_action",
+            "DefaultOutboundAction",
+            action(settings.default_outbound_action)?,
+        ),
+        (
+            "block_all_inbound_traffic",
+            "AllowInboundRules",
+            boolean(!settings.block_all_inbound_traffic),
+        ),
+        (
+            "notifications_disabled",
+            "NotifyOnListen",
+            boolean(!settings.notifications_disabled),
+        ),
+        (
+            "unicast_responses_to_multicast_broadcast_disabled",
+            "AllowUnicastResponseToMulticast",
+            boolean(!settings.unicast_responses_to_multicast_broadcast_disabled),
+        ),
+    ])
+}
+
+fn command(name: &str, parameters: &[Parameter]) -> String {
+    let mut command = name.to_string();
+    for (_, parameter, value) in parameters {
+        write!(command, " -{} {}", parameter, value).unwrap();
+    }
+    command
+}
+
+fn boolean(value: bool) -> String {
+    if value { "True" } else { "False" }.to_string()
+}
+
+fn action(action: FirewallAction) -> Result<String, std::io::Error> {
+    match action {
+        FirewallAction::Block => Ok("Block".to_string()),
+        FirewallAction::Allow => Ok("Allow".to_string()),
+        FirewallAction::Max => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "the default action Max has no cmdlet value",
+        )),
+    }
+}
+
+/// Render a comma separated field as a PowerShell array of quoted entries.
+fn list(field: Option<&str>, entry: fn(&str) -> String) -> Option<String> {
+    let entries: Vec<_> = list_entries(field?)
+        .into_iter()
+        .map(|s| quote(&entry(s)))
+        .collect();
+    if entries.is_empty() {
+        return None;
+    }
+    Some(entries.join(", "))
+}
+
+fn any_entry(entry: &str) -> String {
+    if entry == "*" {
+        "Any".to_string()
+    } else {
+        entry.to_string()
+    }
+}
+
+fn port_entry(entry: &str) -> String {
+    any_entry(cmdlet_port(entry))
+}
+
+fn icmp_entry(entry: &str) -> String {
+    any_entry(cmdlet_icmp(entry))
+}
+
+/// Translate `INetFwRule` interface types, like `Lan,Wireless`, into the cmdlets' names.
+fn interface_types(types: &str) -> String {
+    let types: Vec<_> = list_entries(types)
+        .into_iter()
+        .map(|s| {
+            if s.eq_ignore_ascii_case("all") {
+                return "Any";
+            }
+            CMDLET_INTERFACE_TYPES
+                .iter()
+                .find(|(_, _, name)| name.eq_ignore_ascii_case(s))
+                .map_or(s, |(_, cmdlet, _)| *cmdlet)
+        })
+        .collect();
+    if types.is_empty() || types.contains(&"Any") {
+        return quote("Any");
+    }
+    quote(&types.join(", "))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        diff::diff_rules,
+        FirewallRuleDirection,
+    };
+    use std::collections::BTreeMap;
+
+    fn web() -> FirewallRuleData {
+        FirewallRuleData {
+            description: Some("Bob's server".into()),
+            application_name: Some(r"C:\Program Files\Web\web.exe".into()),
+            protocol: 6,
+            local_ports: Some("80, 443".into()),
+            remote_addresses: Some("LocalSubnet,10.0.0.0/8".into()),
+            grouping: Some("Web".into()),
+            profiles: FirewallProfile::DOMAIN | FirewallProfile::PRIVATE,
+            ..FirewallRuleData::new("Web [*]")
+        }
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(quote("plain"), "'plain'");
+        assert_eq!(quote("it's"), "'it''s'");
+        assert_eq!(quote("it\u{2019}s"), "'it\u{2019}\u{2019}s'");
+        assert_eq!(quote("$env:TEMP `n"), "'$env:TEMP `n'");
+        assert_eq!(quote_literal_pattern("a*[b]"), "'a`*`[b`]'");
+    }
+
+    #[test]
+    fn snapshot() {
+        let mut profiles = BTreeMap::new();
+        profiles.insert(
+            FirewallProfile::PUBLIC,
+            FirewallProfileSettings {
+                block_all_inbound_traffic: true,
+                ..Default::default()
+            },
+        );
+        let snapshot = Snapshot {
+            rules: vec![
+                web(),
+                FirewallRuleData {
+                    id: Some("CoreNet-ICMP4-Out".into()),
+                    protocol: 1,
+                    icmp_types_and_codes: Some("8:*".into()),
+                    direction: FirewallRuleDirection::Out,
+                    interface_types: Some("Lan,Wireless".into()),
+                    enabled: false,
+                    action: FirewallAction::Block,
+                    ..FirewallRuleData::new("Ping")
+                },
+            ],
+            profiles,
+        };
+
+        assert_eq!(
+            snapshot_script(&snapshot).unwrap(),
+            r#"#Requires -RunAsAdministrator
+#Requires -Modules NetSecurity
+$ErrorActionPreference = 'Stop'
+
+# Web [*] (Inbound)
+$rule = Get-NetFirewallRule -DisplayName 'Web `[`*`]' -ErrorAction SilentlyContinue | Where-Object Direction -eq 'Inbound'
+if ($rule) {
+    $rule | Set-NetFirewallRule -NewDisplayName 'Web [*]' -Description 'Bob''s server' -Direction Inbound -Action Allow -Protocol TCP -LocalPort '80', '443' -RemotePort Any -LocalAddress Any -RemoteAddress 'LocalSubnet', '10.0.0.0/8' -IcmpType Any -Program 'C:\Program Files\Web\web.exe' -Service Any -InterfaceAlias Any -InterfaceType Any -Profile 'Domain,Private' -Enabled True -EdgeTraversalPolicy Block
+} else {
+    New-NetFirewallRule -DisplayName 'Web [*]' -Group 'Web' -Description 'Bob''s server' -Direction Inbound -Action Allow -Protocol TCP -LocalPort '80', '443' -RemoteAddress 'LocalSubnet', '10.0.0.0/8' -Program 'C:\Program Files\Web\web.exe' -Profile 'Domain,Private' -Enabled True -EdgeTraversalPolicy Block
+}
+
+# Ping (Outbound)
+$rule = Get-NetFirewallRule -Name 'CoreNet-ICMP4-Out' -ErrorAction SilentlyContinue
+if ($rule) {
+    $rule | Set-NetFirewallRule -NewDisplayName 'Ping' -Description '' -Direction Outbound -Action Block -Protocol ICMPv4 -LocalPort Any -RemotePort Any -LocalAddress Any -RemoteAddress Any -IcmpType '8' -Program Any -Service Any -InterfaceAlias Any -InterfaceType 'Wired, Wireless' -Profile 'Any' -Enabled False -EdgeTraversalPolicy Block
+} else {
+    New-NetFirewallRule -Name 'CoreNet-ICMP4-Out' -DisplayName 'Ping' -Direction Outbound -Action Block -Protocol ICMPv4 -IcmpType '8' -InterfaceType 'Wired, Wireless' -Profile 'Any' -Enabled False -EdgeTraversalPolicy Block
+}
+
+Set-NetFirewallProfile -Profile 'Public' -Enabled True -DefaultInboundAction Block -DefaultOutboundAction Allow -AllowInboundRules False -NotifyOnListen True -AllowUnicastResponseToMulticast True
+"#
+        );
+    }
+
+    #[test]
+    fn diff() {
+        let old = vec![
+            web(),
+            FirewallRuleData::new("Old"),
+            FirewallRuleData::new("Moved"),
+        ];
+        let new = vec![
+            FirewallRuleData {
+                local_ports: Some("443,80".into()),
+                protocol: 17,
+                enabled: false,
+                ..web()
+            },
+            FirewallRuleData {
+                grouping: Some("Elsewhere".into()),
+                ..FirewallRuleData::new("Moved")
+            },
+            FirewallRuleData::new("Added"),
+        ];
+
+        assert_eq!(
+            diff_script(&diff_rules(&old, &new)),
+            r#"#Requires -RunAsAdministrator
+#Requires -Modules NetSecurity
+$ErrorActionPreference = 'Stop'
+
+# Old (Inbound)
+Get-NetFirewallRule -DisplayName 'Old' -ErrorAction SilentlyContinue | Where-Object Direction -eq 'Inbound' | Remove-NetFirewallRule
+
+# Moved (Inbound)
+Get-NetFirewallRule -DisplayName 'Moved' -ErrorAction SilentlyContinue | Where-Object Direction -eq 'Inbound' | Remove-NetFirewallRule
+
+# Web [*] (Inbound)
+Get-NetFirewallRule -DisplayName 'Web `[`*`]' -ErrorAction SilentlyContinue | Where-Object Direction -eq 'Inbound' | Set-NetFirewallRule -Protocol UDP -LocalPort '443', '80' -RemotePort Any -IcmpType Any -Enabled False
+
+# Moved (Inbound)
+$rule = Get-NetFirewallRule -DisplayName 'Moved' -ErrorAction SilentlyContinue | Where-Object Direction -eq 'Inbound'
+if ($rule) {
+    $rule | Set-NetFirewallRule -NewDisplayName 'Moved' -Description '' -Direction Inbound -Action Allow -Protocol Any -LocalPort Any -RemotePort Any -LocalAddress Any -RemoteAddress Any -IcmpType Any -Program Any -Service Any -InterfaceAlias Any -InterfaceType Any -Profile 'Any' -Enabled True -EdgeTraversalPolicy Block
+} else {
+    New-NetFirewallRule -DisplayName 'Moved' -Group 'Elsewhere' -Direction Inbound -Action Allow -Protocol Any -Profile 'Any' -Enabled True -EdgeTraversalPolicy Block
+}
+
+# Added (Inbound)
+$rule = Get-NetFirewallRule -DisplayName 'Added' -ErrorAction SilentlyContinue | Where-Object Direction -eq 'Inbound'
+if ($rule) {
+    $rule | Set-NetFirewallRule -NewDisplayName 'Added' -Description '' -Direction Inbound -Action Allow -Protocol Any -LocalPort Any -RemotePort Any -LocalAddress Any -RemoteAddress Any -IcmpType Any -Program Any -Service Any -InterfaceAlias Any -InterfaceType Any -Profile 'Any' -Enabled True -EdgeTraversalPolicy Block
+} else {
+    New-NetFirewallRule -DisplayName 'Added' -Direction Inbound -Action Allow -Protocol Any -Profile 'Any' -Enabled True -EdgeTraversalPolicy Block
+}
+"#
+        );
+    }
+
+    #[test]
+    fn profile_changes() {
+        let old = FirewallProfileSettings::default();
+        let new = FirewallProfileSettings {
+            default_outbound_action: FirewallAction::Block,
+            ..old
+        };
+
+        let mut script = Script::new();
+        script
+            .update_profile(FirewallProfile::DOMAIN, &old, &old)
+            .unwrap()
+            .update_profile(FirewallProfile::DOMAIN, &old, &new)
+            .unwrap();
+        assert!(script.to_string().ends_with(
+            "\nSet-NetFirewallProfile -Profile 'Domain' -DefaultOutboundAction Block\n"
+        ));
+
+        let max = FirewallProfileSettings {
+            default_inbound_action: FirewallAction::Max,
+            ..old
+        };
+        let error = Script::new()
+            .set_profile(FirewallProfile::DOMAIN, &max)
+            .unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,521 @@
+//! Declarative desired-state reconciliation.
+//!
+//! A `Reconciler` compares a `DesiredState` against what a `RuleStore` currently holds and produces a `Plan`
+//! of creates, updates, deletes and profile changes. Plans can be rendered for review and then applied as a `Transaction`.
+//! Only rules inside the reconciler's `Scope` are ever touched, so OS and vendor rules are left alone.
+
+use crate::{
+    data::FieldChange,
+    diff,
+    store::RuleStore,
+    transaction::{
+        AppliedTransaction,
+        TransactionError,
+    },
+    FirewallProfile,
+    FirewallProfileSettings,
+    FirewallRuleData,
+    Transaction,
+};
+use std::collections::{
+    BTreeMap,
+    HashSet,
+};
+
+/// Which rules a reconciler considers its own.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Scope {
+    /// Rules whose grouping is exactly this string.
+    Grouping(String),
+    /// Rules whose name starts with this string.
+    NamePrefix(String),
+    /// Rules carrying an ownership marker naming this owner.
+    Owner(String),
+}
+
+impl Scope {
+    pub fn owns(&self, rule: &FirewallRuleData) -> bool {
+        match self {
+            Scope::Grouping(grouping) => rule.grouping.as_deref() == Some(grouping.as_str()),
+            Scope::NamePrefix(prefix) => rule.name.starts_with(prefix.as_str()),
+            Scope::Owner(owner) => rule.is_owned_by(owner),
+        }
+    }
+}
+
+impl std::fmt::Display for Scope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Scope::Grouping(grouping) => write!(f, "grouping '{}'", grouping),
+            Scope::NamePrefix(prefix) => write!(f, "name prefix '{}'", prefix),
+            Scope::Owner(owner) => write!(f, "owner '{}'", owner),
+        }
+    }
+}
+
+/// The rules and profile settings that should exist.
+///
+/// Profiles that are not listed are not touched.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DesiredState {
+    pub rules: Vec<FirewallRuleData>,
+    pub profiles: BTreeMap<FirewallProfile, FirewallProfileSettings>,
+}
+
+/// One step of a `Plan`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    Create(FirewallRuleData),
+    Update {
+        rule: FirewallRuleData,
+        changes: Vec<FieldChange>,
+    },
+    Delete(FirewallRuleData),
+    SetProfile {
+        profile: FirewallProfile,
+        settings: FirewallProfileSettings,
+        changes: Vec<FieldChange>,
+    },
+}
+
+/// The changes needed to reach a `DesiredState`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Plan {
+    pub changes: Vec<Change>,
+}
+
+impl Plan {
+    /// Whether the store is already in the desired state.
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// The transaction that carries out this plan.
+    pub fn to_transaction(&self) -> Transaction {
+        let mut transaction = Transaction::new();
+
+        for change in self.changes.iter() {
+            match change {
+                Change::Create(rule) => {
+                    transaction.add_rule(rule.clone());
+                }
+                Change::Update { rule, .. } => {
+                    transaction.update_rule(rule.name.clone(), rule.clone());
+                }
+                Change::Delete(rule) => {
+                    transaction.remove_rule(rule.name.clone());
+                }
+                Change::SetProfile {
+                    profile, settings, ..
+                } => {
+                    transaction.set_profile_settings(*profile, *settings);
+                }
+            }
+        }
+
+        transaction
+    }
+
+    /// Apply the plan as a single transaction, committing it if every change succeeds.
+    pub fn apply<S: RuleStore>(&self, store: S) -> Result<S, Box<TransactionError>> {
+        self.to_transaction()
+            .apply(store)
+            .map(AppliedTransaction::commit)
+    }
+}
+
+/// Renders the plan for review, one change per line with field changes indented below.
+impl std::fmt::Display for Plan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.changes.is_empty() {
+            return writeln!(f, "No changes.");
+        }
+
+        for change in self.changes.iter() {
+            match change {
+                Change::Create(rule) => {
+                    writeln!(f, "+ create rule '{}'", rule.name)?;
+                    for (field, value) in rule.field_values().into_iter().skip(1) {
+                        if let Some(value) = value {
+                            writeln!(f, "    {}: {}", field, value)?;
+                        }
+                    }
+                }
+                Change::Update { rule, changes } => {
+                    writeln!(f, "~ update rule '{}'", rule.name)?;
+                    for change in changes.iter() {
+                        writeln!(f, "    {}", change)?;
+                    }
+                }
+                Change::Delete(rule) => writeln!(f, "- delete rule '{}'", rule.name)?,
+                Change::SetProfile {
+                    profile, changes, ..
+                } => {
+                    writeln!(f, "~ update profile {}", profile)?;
+                    for change in changes.iter() {
+                        writeln!(f, "    {}", change)?;
+                    }
+                }
+            }
+        }
+
+        let count = |f: fn(&Change) -> bool| self.changes.iter().filter(|c| f(c)).count();
+        writeln!(
+            f,
+            "Plan: {} to create, {} to update, {} to delete, {} profile(s) to change.",
+            count(|c| matches!(c, Change::Create(_))),
+            count(|c| matches!(c, Change::Update { .. })),
+            count(|c| matches!(c, Change::Delete(_))),
+            count(|c| matches!(c, Change::SetProfile { .. })),
+        )
+    }
+}
+
+/// Computes and applies plans for rules within a `Scope`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reconciler {
+    scope: Scope,
+}
+
+impl Reconciler {
+    /// Fails on a scope with an empty value, which would own every rule on the machine.
+    pub fn new(scope: Scope) -> Result<Self, std::io::Error> {
+        let value = match &scope {
+            Scope::Grouping(value) | Scope::NamePrefix(value) | Scope::Owner(value) => value,
+        };
+        if value.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{} matches every rule", scope),
+            ));
+        }
+        Ok(Reconciler { scope })
+    }
+
+    pub fn scope(&self) -> &Scope {
+        &self.scope
+    }
+
+    /// Read the current state from `store` and work out what needs to change.
+    ///
+    /// Fails if a desired rule is outside the scope, two desired or two owned rules share a name,
+    /// or a desired rule's name is taken by a rule outside the scope.
+    pub fn plan<S: RuleStore>(
+        &self,
+        desired: &DesiredState,
+        mut store: S,
+    ) -> Result<Plan, std::io::Error> {
+        let current = store.rules()?;
+        let mut profiles = BTreeMap::new();
+        for profile in desired.profiles.keys() {
+            profiles.insert(*profile, store.get_profile_settings(*profile)?);
+        }
+
+        self.plan_against(desired, &current, &profiles)
+    }
+
+    /// Work out what needs to change given an already captured current state.
+    ///
+    /// Rules are compared with `diff::semantic_changes`, so lists Windows merely reorders or respaces are not updated.
+    pub fn plan_against(
+        &self,
+        desired: &DesiredState,
+        current_rules: &[FirewallRuleData],
+        current_profiles: &BTreeMap<FirewallProfile, FirewallProfileSettings>,
+    ) -> Result<Plan, std::io::Error> {
+        let mut seen = HashSet::new();
+        for rule in desired.rules.iter() {
+            if !self.scope.owns(rule) {
+                return Err(invalid_input(format!(
+                    "desired rule '{}' is outside of {}",
+                    rule.name, self.scope
+                )));
+            }
+            if !seen.insert(rule.name.as_str()) {
+                return Err(invalid_input(format!(
+                    "desired rule '{}' is listed more than once",
+                    rule.name
+                )));
+            }
+        }
+
+        let mut owned = BTreeMap::new();
+        for rule in current_rules.iter() {
+            if self.scope.owns(rule) {
+                if owned.insert(rule.name.as_str(), rule).is_some() {
+                    return Err(invalid_input(format!(
+                        "rule '{}' within {} is listed more than once",
+                        rule.name, self.scope
+                    )));
+                }
+            } else if seen.contains(rule.name.as_str()) {
+                return Err(invalid_input(format!(
+                    "desired rule '{}' collides with a rule outside of {}",
+                    rule.name, self.scope
+                )));
+            }
+        }
+
+        let mut changes = Vec::new();
+
+        // Deletes first so renamed or recreated rules never collide with stale ones.
+        for rule in owned.values() {
+            if !seen.contains(rule.name.as_str()) {
+                changes.push(Change::Delete((*rule).clone()));
+            }
+        }
+
+        for rule in desired.rules.iter() {
+            match owned.get(rule.name.as_str()) {
+                None => changes.push(Change::Create(rule.clone())),
+                Some(current) => {
+                    let field_changes = diff::semantic_changes(current, rule);
+                    if !field_changes.is_empty() {
+                        changes.push(Change::Update {
+                            rule: rule.clone(),
+                            changes: field_changes,
+                        });
+                    }
+                }
+            }
+        }
+
+        for (profile, settings) in desired.profiles.iter() {
+            let current = current_profiles.get(profile).copied().unwrap_or_default();
+            let field_changes = current.changes_to(settings);
+            if !field_changes.is_empty() {
+                changes.push(Change::SetProfile {
+                    profile: *profile,
+                    settings: *settings,
+                    changes: field_changes,
+                });
+            }
+        }
+
+        Ok(Plan { changes })
+    }
+
+    /// Plan and apply in one go, returning the plan that was applied.
+    pub fn apply<S: RuleStore>(
+        &self,
+        desired: &DesiredState,
+        mut store: S,
+    ) -> Result<Plan, ReconcileError> {
+        let plan = self
+            .plan(desired, &mut store)
+            .map_err(ReconcileError::Plan)?;
+        plan.apply(store).map_err(ReconcileError::Apply)?;
+        Ok(plan)
+    }
+}
+
+/// The error returned by `Reconciler::apply`.
+#[derive(Debug)]
+pub enum ReconcileError {
+    /// The current state could not be read or the desired state is invalid. Nothing was changed.
+    Plan(std::io::Error),
+    /// Applying the plan failed and was rolled back.
+    Apply(Box<TransactionError>),
+}
+
+impl std::fmt::Display for ReconcileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReconcileError::Plan(e) => write!(f, "failed to plan: {}", e),
+            ReconcileError::Apply(e) => write!(f, "failed to apply plan: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ReconcileError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ReconcileError::Plan(e) => Some(e),
+            ReconcileError::Apply(e) => Some(e.as_ref()),
+        }
+    }
+}
+
+fn invalid_input(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, message)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        ownership::Ownership,
+        FirewallAction,
+        FirewallRuleDirection,
+        MemoryStore,
+    };
+
+    fn managed(name: &str, ports: &str) -> FirewallRuleData {
+        FirewallRuleData {
+            local_ports: Some(ports.into()),
+            grouping: Some("deployer".into()),
+            ..FirewallRuleData::new(name)
+        }
+    }
+
+    fn os_rule(name: &str) -> FirewallRuleData {
+        FirewallRuleData {
+            grouping: Some("@FirewallAPI.dll,-28502".into()),
+            ..FirewallRuleData::new(name)
+        }
+    }
+
+    fn reconciler() -> Reconciler {
+        Reconciler::new(Scope::Grouping("deployer".into())).unwrap()
+    }
+
+    #[test]
+    fn plan_and_apply() {
+        let mut store = MemoryStore::with_rules(vec![
+            os_rule("Core Networking"),
+            managed("web", "80"),
+            managed("stale", "8080"),
+            managed("ssh", "22"),
+        ]);
+        let mut desired = DesiredState {
+            rules: vec![
+                managed("web", "80,443"),
+                managed("ssh", "22"),
+                managed("dns", "53"),
+            ],
+            ..Default::default()
+        };
+        desired.profiles.insert(
+            FirewallProfile::PUBLIC,
+            FirewallProfileSettings {
+                default_outbound_action: FirewallAction::Block,
+                ..Default::default()
+            },
+        );
+
+        let plan = reconciler().plan(&desired, &mut store).unwrap();
+        assert_eq!(plan.changes.len(), 4);
+        assert_eq!(plan.changes[0], Change::Delete(managed("stale", "8080")));
+        assert_eq!(
+            plan.changes[1],
+            Change::Update {
+                rule: managed("web", "80,443"),
+                changes: vec![FieldChange {
+                    field: "local_ports",
+                    old: Some("80".into()),
+                    new: Some("80,443".into()),
+                }],
+            }
+        );
+        assert_eq!(plan.changes[2], Change::Create(managed("dns", "53")));
+
+        let rendered = plan.to_string();
+        assert!(rendered.contains("- delete rule 'stale'"));
+        assert!(rendered.contains("    local_ports: 80 -> 80,443"));
+        assert!(rendered.contains("~ update profile Public"));
+        assert!(rendered
+            .contains("Plan: 1 to create, 1 to update, 1 to delete, 1 profile(s) to change."));
+
+        plan.apply(&mut store).unwrap();
+        assert!(store.get_rule("Core Networking").unwrap().is_some());
+        assert_eq!(store.get_rule("stale").unwrap(), None);
+
+        // Applying again is a no-op.
+        let plan = reconciler().apply(&desired, &mut store).unwrap();
+        assert!(plan.is_empty());
+        assert_eq!(plan.to_string(), "No changes.\n");
+    }
+
+    #[test]
+    fn never_touches_unowned_rules() {
+        let mut store = MemoryStore::with_rules(vec![os_rule("Core Networking")]);
+        let plan = reconciler()
+            .plan(&DesiredState::default(), &mut store)
+            .unwrap();
+        assert!(plan.is_empty());
+    }
+
+    #[test]
+    fn rejects_empty_scopes() {
+        for scope in [
+            Scope::Grouping(String::new()),
+            Scope::NamePrefix(String::new()),
+            Scope::Owner(String::new()),
+        ] {
+            let error = Reconciler::new(scope).unwrap_err();
+            assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn name_prefix_scope() {
+        let scope = Scope::NamePrefix("deployer: ".into());
+        assert!(scope.owns(&FirewallRuleData::new("deployer: web")));
+        assert!(!scope.owns(&FirewallRuleData::new("web")));
+    }
+
+    #[test]
+    fn owner_scope() {
+        let scope = Scope::Owner("agent-x".into());
+        let mut rule = FirewallRuleData::new("web");
+        assert!(!scope.owns(&rule));
+
+        rule.set_ownership(&Ownership::new("agent-x"));
+        assert!(scope.owns(&rule));
+    }
+
+    #[test]
+    fn invalid_desired_state() {
+        let mut store = MemoryStore::with_rules(vec![os_rule("taken")]);
+
+        let outside = DesiredState {
+            rules: vec![os_rule("mine")],
+            ..Default::default()
+        };
+        let duplicate = DesiredState {
+            rules: vec![managed("a", "1"), managed("a", "2")],
+            ..Default::default()
+        };
+        let collision = DesiredState {
+            rules: vec![managed("taken", "1")],
+            ..Default::default()
+        };
+
+        for desired in [outside, duplicate, collision].iter() {
+            let error = reconciler().plan(desired, &mut store).unwrap_err();
+            assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+        }
+
+        // Owned rules sharing a name, like an inbound and outbound pair, can't be told apart.
+        let mut store = MemoryStore::with_rules(vec![
+            managed("pair", "1"),
+            FirewallRuleData {
+                direction: FirewallRuleDirection::Out,
+                ..managed("pair", "1")
+            },
+        ]);
+        let desired = DesiredState {
+            rules: vec![managed("pair", "1")],
+            ..Default::default()
+        };
+        let error = reconciler().plan(&desired, &mut store).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn normalized_values_are_not_updated() {
+        let mut store = MemoryStore::with_rules(vec![FirewallRuleData {
+            remote_addresses: Some("LocalSubnet".into()),
+            ..managed("web", "443,80")
+        }]);
+        let desired = DesiredState {
+            rules: vec![FirewallRuleData {
+                remote_addresses: Some("localsubnet".into()),
+                ..managed("web", "80, 443")
+            }],
+            ..Default::default()
+        };
+
+        assert!(reconciler().plan(&desired, &mut store).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,546 @@
+//! Registry Editor `.reg` files, and the firewall rules and profile settings in them.
+//!
+//! Files are written as `regedit` does: `Windows Registry Editor Version 5.00`, CRLF line endings and, from
+//! `to_vec`, UTF-16 with a byte order mark. Strings that cannot be quoted, like those holding line breaks, are
+//! written as `hex(1):` bytes. Reading accepts UTF-16 and UTF-8 files and `REGEDIT4` ones, comment lines starting
+//! with `;`, comments after a value, and hex data continued over several lines with a trailing `\`.
+//!
+//! Firewall values are looked for under the local policy key `LOCAL_POLICY_KEY` and the Group Policy key
+//! `GROUP_POLICY_KEY`, in the layout `registry_pol` describes.
+
+use crate::{
+    reconcile::DesiredState,
+    registry_pol::{
+        parse_rule_string,
+        profile_settings,
+        profile_values,
+        rule_id,
+        rule_string,
+        RegistryValue,
+        PROFILE_KEYS,
+    },
+    FirewallProfile,
+    FirewallProfileSettings,
+    FirewallRuleData,
+};
+use std::{
+    collections::BTreeMap,
+    path::Path,
+};
+
+/// The key the firewall keeps its own policy under.
+pub const LOCAL_POLICY_KEY: &str =
+    r"HKEY_LOCAL_MACHINE\SYSTEM\CurrentControlSet\Services\SharedAccess\Parameters\FirewallPolicy";
+
+/// The key Group Policy writes firewall policy to.
+pub const GROUP_POLICY_KEY: &str =
+    r"HKEY_LOCAL_MACHINE\SOFTWARE\Policies\Microsoft\WindowsFirewall";
+
+const HEADER: &str = "Windows Registry Editor Version 5.00";
+const HEADER_V4: &str = "REGEDIT4";
+
+/// How long `regedit` lets a line of hex data grow before continuing it.
+const LINE_WIDTH: usize = 80;
+
+/// A `[key]` section and the values it sets or deletes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Section {
+    pub key: String,
+    /// Written `[-key]`: delete the key and its subkeys.
+    pub delete: bool,
+    /// Each value name, empty for the default value, with its data or `None` to delete it.
+    pub values: Vec<(String, Option<RegistryValue>)>,
+}
+
+/// The contents of a `.reg` file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RegFile {
+    pub sections: Vec<Section>,
+}
+
+impl RegFile {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The rules and profile settings of a state as values under a policy key, like `LOCAL_POLICY_KEY`.
+    ///
+    /// Rules without an ID are stored under their name and direction.
+    pub fn from_state(policy_key: &str, state: &DesiredState) -> Result<Self, std::io::Error> {
+        let mut file = RegFile::new();
+
+        let mut rules = Vec::with_capacity(state.rules.len());
+        for rule in &state.rules {
+            rules.push((
+                rule_id(rule),
+                Some(RegistryValue::String(rule_string(rule)?)),
+            ));
+        }
+        if !rules.is_empty() {
+            file.sections.push(Section {
+                key: format!(r"{}\FirewallRules", policy_key),
+                delete: false,
+                values: rules,
+            });
+        }
+
+        for (profile, settings) in &state.profiles {
+            let name = PROFILE_KEYS
+                .iter()
+                .find(|(single, _)| single == profile)
+                .map(|(_, name)| name)
+                .ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("'{}' is not a single profile", profile),
+                    )
+                })?;
+            file.sections.push(Section {
+                key: format!(r"{}\{}", policy_key, name),
+                delete: false,
+                values: profile_values(settings)?
+                    .iter()
+                    .map(|(name, value)| (name.to_string(), Some(RegistryValue::Dword(*value))))
+                    .collect(),
+            });
+        }
+        Ok(file)
+    }
+
+    /// Parse the text of a file.
+    pub fn parse(text: &str) -> Result<Self, std::io::Error> {
+        let mut lines = logical_lines(text).into_iter();
+        match lines.next() {
+            Some((_, header)) if header == HEADER || header == HEADER_V4 => {}
+            _ => return Err(invalid_data(0, "missing the Registry Editor header")),
+        }
+
+        let mut file = RegFile::new();
+        for (number, line) in lines {
+            if let Some(key) = line.strip_prefix('[') {
+                let key = key
+                    .rfind(']')
+                    .map(|end| &key[..end])
+                    .ok_or_else(|| invalid_data(number, "unterminated key"))?;
+                let (key, delete) = match key.strip_prefix('-') {
+                    Some(key) => (key, true),
+                    None => (key, false),
+                };
+                file.sections.push(Section {
+                    key: key.to_string(),
+                    delete,
+                    values: Vec::new(),
+                });
+                continue;
+            }
+
+            let section = file
+                .sections
+                .last_mut()
+                .ok_or_else(|| invalid_data(number, "a value before the first key"))?;
+            let value = parse_value(&line).map_err(|message| invalid_data(number, &message))?;
+            section.values.push(value);
+        }
+        Ok(file)
+    }
+
+    /// Decode a file as UTF-16 or UTF-8 by its byte order mark, or as Latin-1 if it has none and is not UTF-8.
+    pub fn from_slice(bytes: &[u8]) -> Result<Self, std::io::Error> {
+        let text = if let Some(utf16) = bytes.strip_prefix(&[0xff, 0xfe]) {
+            let units: Vec<u16> = utf16
+                .chunks_exact(2)
+                .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+                .collect();
+            String::from_utf16(&units).map_err(|error| invalid_data(0, &error.to_string()))?
+        } else {
+            let bytes = bytes.strip_prefix(&[0xef, 0xbb, 0xbf]).unwrap_or(bytes);
+            match std::str::from_utf8(bytes) {
+                Ok(text) => text.to_string(),
+                Err(_) => bytes.iter().map(|&byte| char::from(byte)).collect(),
+            }
+        };
+        Self::parse(&text)
+    }
+
+    /// The file in UTF-16 with a byte order mark, as `regedit` writes it.
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut bytes = vec![0xff, 0xfe];
+        bytes.extend(self.to_string().encode_utf16().flat_map(u16::to_le_bytes));
+        bytes
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, std::io::Error> {
+        Self::from_slice(&std::fs::read(path)?)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), std::io::Error> {
+        std::fs::write(path, self.to_vec())
+    }
+
+    /// The values a key ends up with once the file is applied, matching keys ignoring case.
+    pub fn values(&self, key: &str) -> Vec<(&str, &RegistryValue)> {
+        let mut values: Vec<(&str, &RegistryValue)> = Vec::new();
+        for section in &self.sections {
+            if section.delete {
+                if is_same_or_ancestor(&section.key, key) {
+                    values.clear();
+                }
+                continue;
+            }
+            if !section.key.eq_ignore_ascii_case(key) {
+                continue;
+            }
+            for (name, value) in &section.values {
+                values.retain(|(other, _)| !other.eq_ignore_ascii_case(name));
+                if let Some(value) = value {
+                    values.push((name, value));
+                }
+            }
+        }
+        values
+    }
+
+    /// The firewall rules under the local and Group Policy keys, with their value names as IDs.
+    pub fn firewall_rules(&self) -> Result<Vec<FirewallRuleData>, std::io::Error> {
+        let mut rules = Vec::new();
+        for policy_key in [LOCAL_POLICY_KEY, GROUP_POLICY_KEY].iter() {
+            for (id, value) in self.values(&format!(r"{}\FirewallRules", policy_key)) {
+                let text = value.as_str().ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("rule '{}' is not a string", id),
+                    )
+                })?;
+                rules.push(parse_rule_string(id, text)?);
+            }
+        }
+        Ok(rules)
+    }
+
+    /// The settings of each profile the file configures, under the local and Group Policy keys.
+    ///
+    /// Settings the file leaves out have the values of a fresh install, and Group Policy wins over local policy.
+    pub fn profile_settings(&self) -> BTreeMap<FirewallProfile, FirewallProfileSettings> {
+        let mut profiles = BTreeMap::new();
+        for (profile, name) in PROFILE_KEYS.iter() {
+            let mut values = Vec::new();
+            for policy_key in [LOCAL_POLICY_KEY, GROUP_POLICY_KEY].iter() {
+                values.extend(self.values(&format!(r"{}\{}", policy_key, name)));
+            }
+            if !values.is_empty() {
+                profiles.insert(*profile, profile_settings(values));
+            }
+        }
+        profiles
+    }
+
+    /// The rules and profile settings of the file.
+    pub fn desired_state(&self) -> Result<DesiredState, std::io::Error> {
+        Ok(DesiredState {
+            rules: self.firewall_rules()?,
+            profiles: self.profile_settings(),
+        })
+    }
+}
+
+/// Writes the file with CRLF line endings.
+impl std::fmt::Display for RegFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}\r\n", HEADER)?;
+        for section in &self.sections {
+            let delete = if section.delete { "-" } else { "" };
+            write!(f, "\r\n[{}{}]\r\n", delete, section.key)?;
+            for (name, value) in &section.values {
+                let name = if name.is_empty() {
+                    "@".to_string()
+                } else {
+                    quote(name)
+                };
+                f.write_str(&format_value(&name, value.as_ref()))?;
+                f.write_str("\r\n")?;
+            }
+        }
+        f.write_str("\r\n")
+    }
+}
+
+/// Whether `key` is `ancestor` or below it.
+fn is_same_or_ancestor(ancestor: &str, key: &str) -> bool {
+    key.len() >= ancestor.len()
+        && key.is_char_boundary(ancestor.len())
+        && key[..ancestor.len()].eq_ignore_ascii_case(ancestor)
+        && (key.len() == ancestor.len() || key[ancestor.len()..].starts_with('\\'))
+}
+
+/// The lines of a file with their 1-based numbers, dropping blank and comment lines and joining continued ones.
+fn logical_lines(text: &str) -> Vec<(usize, String)> {
+    let mut lines = Vec::new();
+    let mut pending: Option<(usize, String)> = None;
+
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        let (number, mut joined) = match pending.take() {
+            Some((number, joined)) => (number, joined),
+            None if line.is_empty() || line.starts_with(';') => continue,
+            None => (index + 1, String::new()),
+        };
+        match line.strip_suffix('\\') {
+            Some(start) => {
+                joined.push_str(start.trim_end());
+                pending = Some((number, joined));
+            }
+            None => {
+                joined.push_str(line);
+                lines.push((number, joined));
+            }
+        }
+    }
+    lines.extend(pending);
+    lines
+}
+
+/// A `"name"=data` line.
+fn parse_value(line: &str) -> Result<(String, Option<RegistryValue>), String> {
+    let (name, rest) = match line.strip_prefix('@') {
+        Some(rest) => (String::new(), rest),
+        None => unquote(line)?,
+    };
+    let data = rest
+        .trim_start()
+        .strip_prefix('=')
+        .ok_or_else(|| "expected '=' after the value name".to_string())?
+        .trim_start();
+
+    if data.starts_with('"') {
+        let (text, rest) = unquote(data)?;
+        check_trailing(rest)?;
+        return Ok((name, Some(RegistryValue::String(text))));
+    }
+
+    // Anything after a `;` is a comment.
+    let data = data.split(';').next().unwrap_or_default().trim();
+    if data == "-" {
+        return Ok((name, None));
+    }
+    if let Some(hex) = data.strip_prefix("dword:") {
+        let value =
+            u32::from_str_radix(hex.trim(), 16).map_err(|_| format!("invalid dword '{}'", hex))?;
+        return Ok((name, Some(RegistryValue::Dword(value))));
+    }
+
+    let (kind, bytes) = if let Some(bytes) = data.strip_prefix("hex:") {
+        (3, bytes)
+    } else if let Some(rest) = data.strip_prefix("hex(") {
+        let end = rest
+            .find("):")
+            .ok_or_else(|| format!("invalid value data '{}'", data))?;
+        let kind = u32::from_str_radix(&rest[..end], 16)
+            .map_err(|_| format!("invalid value type '{}'", &rest[..end]))?;
+        (kind, &rest[end + 2..])
+    } else {
+        return Err(format!("invalid value data '{}'", data));
+    };
+    let bytes = bytes
+        .split(',')
+        .map(str::trim)
+        .filter(|byte| !byte.is_empty())
+        .map(|byte| {
+            u8::from_str_radix(byte, 16).map_err(|_| format!("invalid hex byte '{}'", byte))
+        })
+        .collect::<Result<Vec<u8>, String>>()?;
+    RegistryValue::decode(kind, &bytes)
+        .map(|value| (name, Some(value)))
+        .map_err(|error| error.to_string())
+}
+
+/// A quoted string at the start of `s`, and what follows it.
+fn unquote(s: &str) -> Result<(String, &str), String> {
+    let mut chars = s
+        .strip_prefix('"')
+        .ok_or_else(|| format!("expected a quoted string in '{}'", s))?
+        .char_indices();
+    let mut text = String::new();
+    while let Some((index, c)) = chars.next() {
+        match c {
+            '"' => return Ok((text, &s[index + 2..])),
+            '\\' => match chars.next() {
+                Some((_, escaped)) => text.push(escaped),
+                None => break,
+            },
+            c => text.push(c),
+        }
+    }
+    Err(format!("unterminated string in '{}'", s))
+}
+
+fn check_trailing(rest: &str) -> Result<(), String> {
+    let rest = rest.trim();
+    if rest.is_empty() || rest.starts_with(';') {
+        Ok(())
+    } else {
+        Err(format!("unexpected '{}' after a value", rest))
+    }
+}
+
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// A value line, with hex data wrapped like `regedit` does.
+fn format_value(name: &str, value: Option<&RegistryValue>) -> String {
+    let (kind, data) = match value {
+        None => return format!("{}=-", name),
+        Some(RegistryValue::String(s)) if !s.chars().any(char::is_control) => {
+            return format!("{}={}", name, quote(s));
+        }
+        Some(RegistryValue::Dword(value)) => return format!("{}=dword:{:08x}", name, value),
+        Some(value) => value.encode(),
+    };
+
+    let mut line = match kind {
+        3 => format!("{}=hex:", name),
+        kind => format!("{}=hex({:x}):", name, kind),
+    };
+    let mut width = line.len();
+    for (index, byte) in data.iter().enumerate() {
+        let last = index + 1 == data.len();
+        let entry = if last {
+            format!("{:02x}", byte)
+        } else {
+            format!("{:02x},", byte)
+        };
+        // Leave room for the trailing backslash.
+        if width + entry.len() > LINE_WIDTH - 2 {
+            line.push_str("\\\r\n  ");
+            width = 2;
+        }
+        line.push_str(&entry);
+        width += entry.len();
+    }
+    line
+}
+
+fn invalid_data(line: usize, message: &str) -> std::io::Error {
+    let message = if line == 0 {
+        message.to_string()
+    } else {
+        format!("line {}: {}", line, message)
+    };
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::FirewallAction;
+
+    #[test]
+    fn round_trips_state() {
+        let mut state = DesiredState::default();
+        state.rules.push(FirewallRuleData {
+            id: Some("{web}".to_string()),
+            protocol: 6,
+            local_ports: Some("80".to_string()),
+            application_name: Some(r#"C:\Program Files\Web "Server"\web.exe"#.to_string()),
+            description: Some("Line one\nline two".to_string()),
+            interface_types: Some("All".to_string()),
+            ..FirewallRuleData::new("Wéb ✓")
+        });
+        state.profiles.insert(
+            FirewallProfile::PUBLIC,
+            FirewallProfileSettings {
+                default_inbound_action: FirewallAction::Block,
+                ..FirewallProfileSettings::default()
+            },
+        );
+
+        let file = RegFile::from_state(LOCAL_POLICY_KEY, &state).unwrap();
+        let text = file.to_string();
+        assert!(text.starts_with(
+            "Windows Registry Editor Version 5.00\r\n\r\n[HKEY_LOCAL_MACHINE\\SYSTEM\\"
+        ));
+        assert!(text.contains("\"{web}\"=hex(1):76,00,32,00,2e,00"));
+        assert!(text.contains("\"DefaultInboundAction\"=dword:00000001\r\n"));
+        assert!(text
+            .lines()
+            .filter(|line| line.contains("=hex") || line.starts_with("  "))
+            .all(|line| line.len() <= LINE_WIDTH));
+
+        let bytes = file.to_vec();
+        assert_eq!(&bytes[..4], &[0xff, 0xfe, b'W', 0]);
+        let read = RegFile::from_slice(&bytes).unwrap();
+        assert_eq!(read, file);
+        assert_eq!(read.desired_state().unwrap(), state);
+
+        // Plain strings are quoted, with backslashes and quotes escaped.
+        let plain = FirewallRuleData {
+            description: None,
+            ..state.rules[0].clone()
+        };
+        let file = RegFile::from_state(
+            GROUP_POLICY_KEY,
+            &DesiredState {
+                rules: vec![plain.clone()],
+                ..DesiredState::default()
+            },
+        )
+        .unwrap();
+        assert!(file
+            .to_string()
+            .contains(r#"|App=C:\\Program Files\\Web \"Server\"\\web.exe|"#));
+        assert_eq!(
+            RegFile::parse(&file.to_string())
+                .unwrap()
+                .firewall_rules()
+                .unwrap(),
+            [plain]
+        );
+    }
+
+    #[test]
+    fn reads_handwritten_files() {
+        let text = "\u{feff}REGEDIT4\n\
+            ; Firewall changes for the web farm\n\
+            \n\
+            [HKEY_LOCAL_MACHINE\\SOFTWARE\\Policies\\Microsoft\\WindowsFirewall\\FirewallRules]\n\
+            \"Old\"=\"v2.10|Action=Allow|Dir=In|Name=Old|\"\n\
+            \"Web\"=\"v2.10|Action=Allow|Active=TRUE|Dir=In|Protocol=6|LPort=80|Name=Web|\" ; the web rule\n\
+            \"Old\"=-\n\
+            [HKEY_LOCAL_MACHINE\\SOFTWARE\\Policies\\Microsoft\\WindowsFirewall\\DomainProfile]\n\
+            \"EnableFirewall\"=dword:00000000 ; off for now\n\
+            \"Note\"=hex(2):25,00,\\\n\
+              53,00,00,00\n\
+            [-HKEY_LOCAL_MACHINE\\SOFTWARE\\Policies\\Microsoft\\WindowsFirewall\\PublicProfile]\n\
+            [HKEY_LOCAL_MACHINE\\SOFTWARE\\Policies\\Microsoft\\WindowsFirewall\\PublicProfileX]\n\
+            @=\"default\"\n";
+        let file = RegFile::parse(text.trim_start_matches('\u{feff}')).unwrap();
+
+        let rules = file.firewall_rules().unwrap();
+        assert_eq!(rules.len(), 1);
+        assert_eq!(rules[0].local_ports.as_deref(), Some("80"));
+        let profiles = file.profile_settings();
+        assert_eq!(profiles.len(), 1);
+        assert!(!profiles[&FirewallProfile::DOMAIN].firewall_enabled);
+        let domain = format!(r"{}\DomainProfile", GROUP_POLICY_KEY);
+        assert_eq!(
+            file.values(&domain)[1],
+            ("Note", &RegistryValue::ExpandString("%S".to_string()))
+        );
+        assert!(file.sections[2].delete);
+        assert_eq!(file.sections[3].values[0].0, "");
+
+        for bad in &[
+            "[HKEY_LOCAL_MACHINE\\X]\n",
+            "Windows Registry Editor Version 5.00\n\"a\"=\"b\"\n",
+            "Windows Registry Editor Version 5.00\n[K]\n\"a\"=dword:xyz\n",
+            "Windows Registry Editor Version 5.00\n[K]\n\"a=\"b\"\n",
+        ] {
+            assert_eq!(
+                RegFile::parse(bad).unwrap_err().kind(),
+                std::io::ErrorKind::InvalidData,
+                "{}",
+                bad
+            );
+        }
+    }
+}
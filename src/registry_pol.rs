@@ -0,0 +1,1117 @@
+//! Group Policy `Registry.pol` files and the firewall settings in them.
+//!
+//! A `Registry.pol` file, as found under `Machine` in a GPO's SYSVOL folder, is a `PReg` header followed by
+//! `[key;value name;type;size;data]` entries in UTF-16. Entries are kept in file order, including the `**del.` and
+//! `**delvals.` markers policies use to remove values, so a file written back reads the same.
+//!
+//! Firewall rules are `REG_SZ` values under `FIREWALL_RULES_KEY`, named by rule ID and holding rule strings like
+//! `v2.10|Action=Allow|Active=TRUE|Dir=In|Protocol=6|LPort=80|Name=Web|`. Profile settings are `REG_DWORD` values
+//! under `DomainProfile`, `StandardProfile` (the private profile) and `PublicProfile`. Rule string keys this crate
+//! does not model, like `Platform` or `LUAuth`, are skipped when reading.
+
+use crate::{
+    data::{
+        every_profile,
+        list_entries,
+        PROTOCOL_ANY,
+    },
+    FirewallAction,
+    FirewallProfile,
+    FirewallProfileSettings,
+    FirewallRuleData,
+    FirewallRuleDirection,
+};
+use std::{
+    collections::BTreeMap,
+    path::Path,
+};
+
+/// The key firewall policy lives under.
+pub const FIREWALL_KEY: &str = r"Software\Policies\Microsoft\WindowsFirewall";
+
+/// The key holding one value per connection security rule.
+pub const CONNECTION_SECURITY_RULES_KEY: &str =
+    r"Software\Policies\Microsoft\WindowsFirewall\ConSecRules";
+
+/// The key holding one value per firewall rule.
+pub const FIREWALL_RULES_KEY: &str = r"Software\Policies\Microsoft\WindowsFirewall\FirewallRules";
+
+/// The rule string version written, understood by every Windows since 7.
+const RULE_VERSION: &str = "v2.10";
+
+const SIGNATURE: &[u8; 4] = b"PReg";
+const FILE_VERSION: u32 = 1;
+
+const REG_NONE: u32 = 0;
+const REG_SZ: u32 = 1;
+const REG_EXPAND_SZ: u32 = 2;
+const REG_BINARY: u32 = 3;
+const REG_DWORD: u32 = 4;
+const REG_DWORD_BIG_ENDIAN: u32 = 5;
+const REG_MULTI_SZ: u32 = 7;
+const REG_QWORD: u32 = 11;
+
+/// The data of a registry value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegistryValue {
+    None,
+    String(String),
+    ExpandString(String),
+    Binary(Vec<u8>),
+    Dword(u32),
+    DwordBigEndian(u32),
+    MultiString(Vec<String>),
+    Qword(u64),
+    /// A type this crate does not interpret, kept as its type number and raw bytes.
+    Other(u32, Vec<u8>),
+}
+
+impl RegistryValue {
+    pub(crate) fn decode(kind: u32, data: &[u8]) -> Result<Self, std::io::Error> {
+        let fixed = |len: usize| {
+            if data.len() == len {
+                Ok(data)
+            } else {
+                Err(invalid_data(format!(
+                    "a value of type {} must be {} bytes, not {}",
+                    kind,
+                    len,
+                    data.len()
+                )))
+            }
+        };
+
+        Ok(match kind {
+            REG_NONE => RegistryValue::None,
+            REG_SZ => RegistryValue::String(decode_string(data)?),
+            REG_EXPAND_SZ => RegistryValue::ExpandString(decode_string(data)?),
+            REG_BINARY => RegistryValue::Binary(data.to_vec()),
+            REG_DWORD => {
+                let data = fixed(4)?;
+                RegistryValue::Dword(u32::from_le_bytes([data[0], data[1], data[2], data[3]]))
+            }
+            REG_DWORD_BIG_ENDIAN => {
+                let data = fixed(4)?;
+                RegistryValue::DwordBigEndian(u32::from_be_bytes([
+                    data[0], data[1], data[2], data[3],
+                ]))
+            }
+            REG_MULTI_SZ => RegistryValue::MultiString(
+                decode_string(data)?
+                    .split('\0')
+                    .filter(|s| !s.is_empty())
+                    .map(str::to_string)
+                    .collect(),
+            ),
+            REG_QWORD => {
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(fixed(8)?);
+                RegistryValue::Qword(u64::from_le_bytes(bytes))
+            }
+            _ => RegistryValue::Other(kind, data.to_vec()),
+        })
+    }
+
+    /// The type number and data, as written to a file.
+    pub(crate) fn encode(&self) -> (u32, Vec<u8>) {
+        match self {
+            RegistryValue::None => (REG_NONE, Vec::new()),
+            RegistryValue::String(s) => (REG_SZ, encode_string(s)),
+            RegistryValue::ExpandString(s) => (REG_EXPAND_SZ, encode_string(s)),
+            RegistryValue::Binary(data) => (REG_BINARY, data.clone()),
+            RegistryValue::Dword(value) => (REG_DWORD, value.to_le_bytes().to_vec()),
+            RegistryValue::DwordBigEndian(value) => {
+                (REG_DWORD_BIG_ENDIAN, value.to_be_bytes().to_vec())
+            }
+            RegistryValue::MultiString(strings) => {
+                let mut data: Vec<u8> = strings.iter().flat_map(|s| encode_string(s)).collect();
+                data.extend_from_slice(&[0, 0]);
+                (REG_MULTI_SZ, data)
+            }
+            RegistryValue::Qword(value) => (REG_QWORD, value.to_le_bytes().to_vec()),
+            RegistryValue::Other(kind, data) => (*kind, data.clone()),
+        }
+    }
+
+    /// The value as a DWORD, for settings that are.
+    pub fn as_dword(&self) -> Option<u32> {
+        match self {
+            RegistryValue::Dword(value) | RegistryValue::DwordBigEndian(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// The value as a string, for either string type.
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            RegistryValue::String(s) | RegistryValue::ExpandString(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+/// One `[key;value name;type;size;data]` entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PolicyEntry {
+    pub key: String,
+    /// The value name, or a marker like `**del.Name` or `**delvals.`.
+    pub value_name: String,
+    pub value: RegistryValue,
+}
+
+/// The contents of a `Registry.pol` file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RegistryPolicy {
+    pub entries: Vec<PolicyEntry>,
+}
+
+impl RegistryPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Decode a file, failing with `InvalidData` if it is not a version 1 `PReg` file or is cut short.
+    pub fn from_slice(bytes: &[u8]) -> Result<Self, std::io::Error> {
+        if bytes.len() < 8 || &bytes[..4] != SIGNATURE {
+            return Err(invalid_data("not a PReg file".to_string()));
+        }
+        let version = u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+        if version != FILE_VERSION {
+            return Err(invalid_data(format!(
+                "unsupported PReg version {}",
+                version
+            )));
+        }
+
+        let mut reader = Reader { bytes, position: 8 };
+        let mut policy = RegistryPolicy::new();
+        while reader.position < bytes.len() {
+            reader.expect('[')?;
+            let key = reader.string()?;
+            reader.expect(';')?;
+            let value_name = reader.string()?;
+            reader.expect(';')?;
+            let kind = reader.u32()?;
+            reader.expect(';')?;
+            let size = reader.u32()? as usize;
+            reader.expect(';')?;
+            let value = RegistryValue::decode(kind, reader.take(size)?)?;
+            reader.expect(']')?;
+            policy.entries.push(PolicyEntry {
+                key,
+                value_name,
+                value,
+            });
+        }
+        Ok(policy)
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut bytes = SIGNATURE.to_vec();
+        bytes.extend_from_slice(&FILE_VERSION.to_le_bytes());
+        let delimiter = |c: char| [c as u8, 0];
+
+        for entry in &self.entries {
+            let (kind, data) = entry.value.encode();
+            bytes.extend_from_slice(&delimiter('['));
+            bytes.extend(encode_string(&entry.key));
+            bytes.extend_from_slice(&delimiter(';'));
+            bytes.extend(encode_string(&entry.value_name));
+            bytes.extend_from_slice(&delimiter(';'));
+            bytes.extend_from_slice(&kind.to_le_bytes());
+            bytes.extend_from_slice(&delimiter(';'));
+            bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(&delimiter(';'));
+            bytes.extend(data);
+            bytes.extend_from_slice(&delimiter(']'));
+        }
+        bytes
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, std::io::Error> {
+        Self::from_slice(&std::fs::read(path)?)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), std::io::Error> {
+        std::fs::write(path, self.to_vec())
+    }
+
+    /// The values a key ends up with once the file is applied, in the order they were set.
+    ///
+    /// Keys and value names are matched ignoring case, as the registry does.
+    pub fn values(&self, key: &str) -> Vec<(&str, &RegistryValue)> {
+        let mut values: Vec<(&str, &RegistryValue)> = Vec::new();
+        for entry in self
+            .entries
+            .iter()
+            .filter(|entry| entry.key.eq_ignore_ascii_case(key))
+        {
+            let name = entry.value_name.as_str();
+            let lower = name.to_ascii_lowercase();
+            if lower == "**delvals." {
+                values.clear();
+            } else if let Some(deleted) = lower.strip_prefix("**del.") {
+                values.retain(|(name, _)| !name.eq_ignore_ascii_case(deleted));
+            } else if lower == "**deletevalues" {
+                let deleted = entry.value.as_str().unwrap_or_default();
+                values.retain(|(name, _)| {
+                    !deleted
+                        .split(';')
+                        .any(|deleted| deleted.eq_ignore_ascii_case(name))
+                });
+            } else if !lower.starts_with("**") {
+                values.retain(|(other, _)| !other.eq_ignore_ascii_case(name));
+                values.push((name, &entry.value));
+            }
+        }
+        values
+    }
+
+    /// Set a value, replacing any earlier entries for it.
+    pub fn set(&mut self, key: &str, value_name: &str, value: RegistryValue) {
+        self.remove_entries(key, value_name);
+        self.entries.push(PolicyEntry {
+            key: key.to_string(),
+            value_name: value_name.to_string(),
+            value,
+        });
+    }
+
+    /// Mark a value for deletion, replacing any earlier entries for it.
+    pub fn delete(&mut self, key: &str, value_name: &str) {
+        self.remove_entries(key, value_name);
+        self.entries.push(PolicyEntry {
+            key: key.to_string(),
+            value_name: format!("**del.{}", value_name),
+            value: RegistryValue::String(" ".to_string()),
+        });
+    }
+
+    fn remove_entries(&mut self, key: &str, value_name: &str) {
+        let marker = format!("**del.{}", value_name);
+        self.entries.retain(|entry| {
+            !entry.key.eq_ignore_ascii_case(key)
+                || !(entry.value_name.eq_ignore_ascii_case(value_name)
+                    || entry.value_name.eq_ignore_ascii_case(&marker))
+        });
+    }
+
+    /// The firewall rules the policy defines, with their registry value names as IDs.
+    pub fn firewall_rules(&self) -> Result<Vec<FirewallRuleData>, std::io::Error> {
+        self.values(FIREWALL_RULES_KEY)
+            .into_iter()
+            .map(|(id, value)| {
+                let text = value
+                    .as_str()
+                    .ok_or_else(|| invalid_data(format!("rule '{}' is not a string", id)))?;
+                parse_rule_string(id, text)
+            })
+            .collect()
+    }
+
+    /// The connection security rules the policy defines.
+    pub fn connection_security_rules(&self) -> Result<Vec<ConnectionSecurityRule>, std::io::Error> {
+        self.values(CONNECTION_SECURITY_RULES_KEY)
+            .into_iter()
+            .map(|(id, value)| {
+                let text = value
+                    .as_str()
+                    .ok_or_else(|| invalid_data(format!("rule '{}' is not a string", id)))?;
+                parse_connection_security_rule(id, text)
+            })
+            .collect()
+    }
+
+    /// Add or replace a rule, under its ID or, without one, its name and direction.
+    pub fn set_firewall_rule(&mut self, rule: &FirewallRuleData) -> Result<(), std::io::Error> {
+        let text = rule_string(rule)?;
+        self.set(
+            FIREWALL_RULES_KEY,
+            &rule_id(rule),
+            RegistryValue::String(text),
+        );
+        Ok(())
+    }
+
+    /// The settings of each profile the policy configures.
+    ///
+    /// Settings the policy leaves unconfigured have the values of a fresh install.
+    pub fn profile_settings(&self) -> BTreeMap<FirewallProfile, FirewallProfileSettings> {
+        let mut profiles = BTreeMap::new();
+        for (profile, key) in profile_keys() {
+            let values = self.values(&key);
+            if !values.is_empty() {
+                profiles.insert(profile, profile_settings(values));
+            }
+        }
+        profiles
+    }
+
+    /// Configure every setting of a single profile.
+    pub fn set_profile_settings(
+        &mut self,
+        profile: FirewallProfile,
+        settings: &FirewallProfileSettings,
+    ) -> Result<(), std::io::Error> {
+        let key = profile_keys()
+            .into_iter()
+            .find(|(single, _)| *single == profile)
+            .map(|(_, key)| key)
+            .ok_or_else(|| invalid_input(format!("'{}' is not a single profile", profile)))?;
+        for (name, value) in profile_values(settings)?.iter() {
+            self.set(&key, name, RegistryValue::Dword(*value));
+        }
+        Ok(())
+    }
+}
+
+/// The value name of a rule: its ID or, without one, its name and direction.
+pub(crate) fn rule_id(rule: &FirewallRuleData) -> String {
+    rule.id
+        .clone()
+        .unwrap_or_else(|| format!("{}-{}", rule.name, rule.direction))
+}
+
+/// Each single profile and the name of the key holding its settings.
+pub(crate) const PROFILE_KEYS: [(FirewallProfile, &str); 3] = [
+    (FirewallProfile::DOMAIN, "DomainProfile"),
+    (FirewallProfile::PRIVATE, "StandardProfile"),
+    (FirewallProfile::PUBLIC, "PublicProfile"),
+];
+
+/// Each single profile and the full path of its key.
+fn profile_keys() -> Vec<(FirewallProfile, String)> {
+    PROFILE_KEYS
+        .iter()
+        .map(|(profile, name)| (*profile, format!(r"{}\{}", FIREWALL_KEY, name)))
+        .collect()
+}
+
+/// The settings held by the values of a profile key, with the values of a fresh install for those missing.
+pub(crate) fn profile_settings<'a>(
+    values: impl IntoIterator<Item = (&'a str, &'a RegistryValue)>,
+) -> FirewallProfileSettings {
+    let action = |block: bool| {
+        if block {
+            FirewallAction::Block
+        } else {
+            FirewallAction::Allow
+        }
+    };
+
+    let mut settings = FirewallProfileSettings::default();
+    for (name, value) in values {
+        let value = match value.as_dword() {
+            Some(value) => value != 0,
+            None => continue,
+        };
+        match name.to_ascii_lowercase().as_str() {
+            "enablefirewall" => settings.firewall_enabled = value,
+            "donotallowexceptions" => settings.block_all_inbound_traffic = value,
+            "disablenotifications" => settings.notifications_disabled = value,
+            "disableunicastresponsestomulticastbroadcast" => {
+                settings.unicast_responses_to_multicast_broadcast_disabled = value
+            }
+            "defaultinboundaction" => settings.default_inbound_action = action(value),
+            "defaultoutboundaction" => settings.default_outbound_action = action(value),
+            _ => {}
+        }
+    }
+    settings
+}
+
+/// The `REG_DWORD` values of a profile key.
+pub(crate) fn profile_values(
+    settings: &FirewallProfileSettings,
+) -> Result<[(&'static str, u32); 6], std::io::Error> {
+    let action = |action: FirewallAction| match action {
+        FirewallAction::Block => Ok(1),
+        FirewallAction::Allow => Ok(0),
+        FirewallAction::Max => Err(invalid_input(
+            "a default action must be Allow or Block".to_string(),
+        )),
+    };
+
+    Ok([
+        ("EnableFirewall", u32::from(settings.firewall_enabled)),
+        (
+            "DoNotAllowExceptions",
+            u32::from(settings.block_all_inbound_traffic),
+        ),
+        (
+            "DisableNotifications",
+            u32::from(settings.notifications_disabled),
+        ),
+        (
+            "DisableUnicastResponsesToMulticastBroadcast",
+            u32::from(settings.unicast_responses_to_multicast_broadcast_disabled),
+        ),
+        (
+            "DefaultInboundAction",
+            action(settings.default_inbound_action)?,
+        ),
+        (
+            "DefaultOutboundAction",
+            action(settings.default_outbound_action)?,
+        ),
+    ])
+}
+
+/// Parse a rule string, like `v2.10|Action=Block|Dir=Out|Name=Telnet|`, into a rule with the given ID.
+pub fn parse_rule_string(id: &str, text: &str) -> Result<FirewallRuleData, std::io::Error> {
+    let error = |message: String| invalid_data(format!("rule '{}': {}", id, message));
+
+    let mut rule = FirewallRuleData {
+        id: Some(id.to_string()),
+        enabled: false,
+        interface_types: Some("All".to_string()),
+        ..FirewallRuleData::new(id)
+    };
+    let mut profiles = FirewallProfile::empty();
+    let mut interface_types: Vec<&str> = Vec::new();
+
+    for (key, value) in rule_fields(text).map_err(error)? {
+        match key {
+            "Action" => {
+                rule.action = match value {
+                    "Allow" => FirewallAction::Allow,
+                    "Block" => FirewallAction::Block,
+                    _ => return Err(error(format!("unsupported action '{}'", value))),
+                }
+            }
+            "Active" => rule.enabled = flag(key, value).map_err(error)?,
+            "Dir" => {
+                rule.direction = value
+                    .parse()
+                    .map_err(|_| error(format!("invalid direction '{}'", value)))?
+            }
+            "Protocol" => {
+                rule.protocol = value
+                    .parse()
+                    .ok()
+                    .filter(|protocol| (0..=255).contains(protocol))
+                    .ok_or_else(|| error(format!("invalid protocol '{}'", value)))?
+            }
+            "Profile" => {
+                profiles |= match value {
+                    "Domain" => FirewallProfile::DOMAIN,
+                    "Private" => FirewallProfile::PRIVATE,
+                    "Public" => FirewallProfile::PUBLIC,
+                    _ => return Err(error(format!("invalid profile '{}'", value))),
+                }
+            }
+            "LPort" | "LPort2_10" => push(&mut rule.local_ports, value),
+            "RPort" | "RPort2_10" => push(&mut rule.remote_ports, value),
+            "LA4" | "LA6" => push(&mut rule.local_addresses, value),
+            "RA4" | "RA6" => push(&mut rule.remote_addresses, value),
+            "ICMP4" | "ICMP6" => push(&mut rule.icmp_types_and_codes, value),
+            "App" => rule.application_name = Some(value.to_string()),
+            "Svc" => rule.service_name = Some(value.to_string()),
+            "Name" => rule.name = value.to_string(),
+            "Desc" => rule.description = Some(value.to_string()),
+            "EmbedCtxt" => rule.grouping = Some(value.to_string()),
+            "IF" => rule
+                .interfaces
+                .get_or_insert_with(Vec::new)
+                .push(value.to_string()),
+            "IFType" => interface_types.push(match value {
+                "Lan" | "Wireless" | "RemoteAccess" => value,
+                _ => return Err(error(format!("invalid interface type '{}'", value))),
+            }),
+            "Edge" => rule.edge_traversal = flag(key, value).map_err(error)?,
+            _ => {}
+        }
+    }
+
+    // Like `FirewallRule`, a rule for every profile has `ALL` and one for every interface type `All`.
+    rule.profiles = if profiles.is_empty() || profiles == every_profile() {
+        FirewallProfile::ALL
+    } else {
+        profiles
+    };
+    if !interface_types.is_empty() && interface_types.len() < 3 {
+        rule.interface_types = Some(interface_types.join(","));
+    }
+    Ok(rule)
+}
+
+/// The `key=value` fields of a rule string, after its version.
+fn rule_fields(text: &str) -> Result<Vec<(&str, &str)>, String> {
+    let mut fields = text.trim_end_matches('\0').split('|');
+    match fields.next() {
+        Some(version) if version.starts_with('v') => {}
+        _ => return Err("missing the rule string version".to_string()),
+    }
+
+    fields
+        .filter(|field| !field.is_empty())
+        .map(|field| {
+            let mut parts = field.splitn(2, '=');
+            let key = parts.next().unwrap_or_default();
+            let value = parts
+                .next()
+                .ok_or_else(|| format!("malformed field '{}'", field))?;
+            Ok((key, value))
+        })
+        .collect()
+}
+
+fn flag(key: &str, value: &str) -> Result<bool, String> {
+    match value.to_ascii_uppercase().as_str() {
+        "TRUE" => Ok(true),
+        "FALSE" => Ok(false),
+        _ => Err(format!("invalid {} '{}'", key, value)),
+    }
+}
+
+/// An IPsec connection security rule, which `FirewallRule` does not model.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionSecurityRule {
+    pub id: String,
+    pub name: String,
+    pub description: Option<String>,
+    pub grouping: Option<String>,
+    pub enabled: bool,
+    pub profiles: FirewallProfile,
+    /// What the rule requires, like `RequireInRequestOut` or `DoNotSecure`.
+    pub action: String,
+    pub protocol: i32,
+    pub endpoint1_addresses: Option<String>,
+    pub endpoint2_addresses: Option<String>,
+    pub endpoint1_ports: Option<String>,
+    pub endpoint2_ports: Option<String>,
+    /// The fields not modelled above, like the authentication and crypto sets, in the order they were written.
+    pub other_fields: Vec<(String, String)>,
+}
+
+/// Parse a connection security rule string, like `v2.10|Action=RequireInRequestOut|Name=Servers|`.
+pub fn parse_connection_security_rule(
+    id: &str,
+    text: &str,
+) -> Result<ConnectionSecurityRule, std::io::Error> {
+    let error = |message: String| invalid_data(format!("rule '{}': {}", id, message));
+
+    let mut rule = ConnectionSecurityRule {
+        id: id.to_string(),
+        name: id.to_string(),
+        description: None,
+        grouping: None,
+        enabled: false,
+        profiles: FirewallProfile::empty(),
+        action: String::new(),
+        protocol: PROTOCOL_ANY,
+        endpoint1_addresses: None,
+        endpoint2_addresses: None,
+        endpoint1_ports: None,
+        endpoint2_ports: None,
+        other_fields: Vec::new(),
+    };
+    for (key, value) in rule_fields(text).map_err(error)? {
+        match key {
+            "Action" => rule.action = value.to_string(),
+            "Active" => rule.enabled = flag(key, value).map_err(error)?,
+            "Name" => rule.name = value.to_string(),
+            "Desc" => rule.description = Some(value.to_string()),
+            "EmbedCtxt" => rule.grouping = Some(value.to_string()),
+            "Profile" => {
+                rule.profiles |= match value {
+                    "Domain" => FirewallProfile::DOMAIN,
+                    "Private" => FirewallProfile::PRIVATE,
+                    "Public" => FirewallProfile::PUBLIC,
+                    _ => return Err(error(format!("invalid profile '{}'", value))),
+                }
+            }
+            "Protocol" => {
+                rule.protocol = value
+                    .parse()
+                    .map_err(|_| error(format!("invalid protocol '{}'", value)))?
+            }
+            "EP1_4" | "EP1_6" => push(&mut rule.endpoint1_addresses, value),
+            "EP2_4" | "EP2_6" => push(&mut rule.endpoint2_addresses, value),
+            "EP1Port" => push(&mut rule.endpoint1_ports, value),
+            "EP2Port" => push(&mut rule.endpoint2_ports, value),
+            _ => rule.other_fields.push((key.to_string(), value.to_string())),
+        }
+    }
+    if rule.profiles.is_empty() || rule.profiles == every_profile() {
+        rule.profiles = FirewallProfile::ALL;
+    }
+    Ok(rule)
+}
+
+/// The rule string for a rule, in the version this crate writes by default.
+///
+/// Fails with `InvalidInput` if a field holds a `|`, which rule strings cannot escape.
+pub fn rule_string(rule: &FirewallRuleData) -> Result<String, std::io::Error> {
+    rule_string_with_version(rule, RULE_VERSION)
+}
+
+/// The newest rule string version a Windows build understands, from `v2.10` for Windows 7 to `v2.31` for
+/// Windows 10 1903 and later. Builds older than Windows 7 get `v2.10` too.
+pub fn rule_version_for_build(build: u32) -> &'static str {
+    const VERSIONS: [(u32, &str); 10] = [
+        (18362, "v2.31"),
+        (17763, "v2.30"),
+        (17134, "v2.29"),
+        (16299, "v2.28"),
+        (15063, "v2.27"),
+        (14393, "v2.26"),
+        (10586, "v2.25"),
+        (10240, "v2.24"),
+        (9600, "v2.22"),
+        (9200, "v2.20"),
+    ];
+    VERSIONS
+        .iter()
+        .find(|(first, _)| build >= *first)
+        .map_or(RULE_VERSION, |(_, version)| version)
+}
+
+/// The rule string for a rule, headed by a version like `v2.26`.
+///
+/// The fields written exist in every version, so the version only decides which Windows builds accept the string.
+pub fn rule_string_with_version(
+    rule: &FirewallRuleData,
+    version: &str,
+) -> Result<String, std::io::Error> {
+    let mut fields = Fields::new(&rule.name, version)?;
+
+    fields.push(
+        "Action",
+        match rule.action {
+            FirewallAction::Allow => "Allow",
+            FirewallAction::Block => "Block",
+            FirewallAction::Max => {
+                return Err(invalid_input(format!(
+                    "rule '{}': the action must be Allow or Block",
+                    rule.name
+                )))
+            }
+        },
+    )?;
+    fields.push("Active", if rule.enabled { "TRUE" } else { "FALSE" })?;
+    fields.push(
+        "Dir",
+        match rule.direction {
+            FirewallRuleDirection::In => "In",
+            FirewallRuleDirection::Out => "Out",
+            FirewallRuleDirection::Max => {
+                return Err(invalid_input(format!(
+                    "rule '{}': the direction must be in or out",
+                    rule.name
+                )))
+            }
+        },
+    )?;
+    if rule.protocol != PROTOCOL_ANY {
+        fields.push("Protocol", &rule.protocol.to_string())?;
+    }
+    fields.profiles(rule.profiles)?;
+    for port in list(&rule.local_ports) {
+        fields.push("LPort", port)?;
+    }
+    for port in list(&rule.remote_ports) {
+        fields.push("RPort", port)?;
+    }
+    fields.addresses("LA", &rule.local_addresses)?;
+    fields.addresses("RA", &rule.remote_addresses)?;
+    for icmp in list(&rule.icmp_types_and_codes) {
+        fields.push(
+            if rule.protocol == 58 {
+                "ICMP6"
+            } else {
+                "ICMP4"
+            },
+            icmp,
+        )?;
+    }
+    if let Some(application) = &rule.application_name {
+        fields.push("App", application)?;
+    }
+    if let Some(service) = &rule.service_name {
+        fields.push("Svc", service)?;
+    }
+    fields.push("Name", &rule.name)?;
+    if let Some(description) = &rule.description {
+        fields.push("Desc", description)?;
+    }
+    if let Some(grouping) = &rule.grouping {
+        fields.push("EmbedCtxt", grouping)?;
+    }
+    for interface in rule.interfaces.iter().flatten() {
+        fields.push("IF", interface)?;
+    }
+    for interface_type in list(&rule.interface_types) {
+        if !interface_type.eq_ignore_ascii_case("All") {
+            fields.push("IFType", interface_type)?;
+        }
+    }
+    if rule.edge_traversal {
+        fields.push("Edge", "TRUE")?;
+    }
+    Ok(fields.finish())
+}
+
+/// The rule string for a connection security rule, headed by a version like `v2.10`.
+pub fn connection_security_rule_string(
+    rule: &ConnectionSecurityRule,
+    version: &str,
+) -> Result<String, std::io::Error> {
+    let mut fields = Fields::new(&rule.name, version)?;
+    fields.push("Action", &rule.action)?;
+    fields.push("Active", if rule.enabled { "TRUE" } else { "FALSE" })?;
+    fields.profiles(rule.profiles)?;
+    if rule.protocol != PROTOCOL_ANY {
+        fields.push("Protocol", &rule.protocol.to_string())?;
+    }
+    fields.addresses("EP1_", &rule.endpoint1_addresses)?;
+    fields.addresses("EP2_", &rule.endpoint2_addresses)?;
+    for port in list(&rule.endpoint1_ports) {
+        fields.push("EP1Port", port)?;
+    }
+    for port in list(&rule.endpoint2_ports) {
+        fields.push("EP2Port", port)?;
+    }
+    fields.push("Name", &rule.name)?;
+    if let Some(description) = &rule.description {
+        fields.push("Desc", description)?;
+    }
+    if let Some(grouping) = &rule.grouping {
+        fields.push("EmbedCtxt", grouping)?;
+    }
+    for (key, value) in &rule.other_fields {
+        fields.push(key, value)?;
+    }
+    Ok(fields.finish())
+}
+
+/// A rule string being written.
+struct Fields<'a> {
+    rule: &'a str,
+    fields: Vec<String>,
+}
+
+impl<'a> Fields<'a> {
+    fn new(rule: &'a str, version: &str) -> Result<Self, std::io::Error> {
+        let valid = version
+            .strip_prefix('v')
+            .and_then(|version| version.split_once('.'))
+            .is_some_and(|(major, minor)| {
+                major.parse::<u8>().is_ok() && minor.parse::<u8>().is_ok()
+            });
+        if !valid {
+            return Err(invalid_input(format!(
+                "invalid rule string version '{}'",
+                version
+            )));
+        }
+        Ok(Fields {
+            rule,
+            fields: vec![version.to_string()],
+        })
+    }
+
+    /// Add a field, failing with `InvalidInput` if it holds a `|`, which rule strings cannot escape.
+    fn push(&mut self, key: &str, value: &str) -> Result<(), std::io::Error> {
+        if value.contains('|') || value.contains('\0') || key.contains(&['|', '='][..]) {
+            return Err(invalid_input(format!(
+                "rule '{}': {} cannot hold '{}'",
+                self.rule, key, value
+            )));
+        }
+        self.fields.push(format!("{}={}", key, value));
+        Ok(())
+    }
+
+    /// A `Profile` field per profile, or none for all of them.
+    fn profiles(&mut self, profiles: FirewallProfile) -> Result<(), std::io::Error> {
+        if profiles.contains(every_profile()) {
+            return Ok(());
+        }
+        for (profile, name) in [
+            (FirewallProfile::DOMAIN, "Domain"),
+            (FirewallProfile::PRIVATE, "Private"),
+            (FirewallProfile::PUBLIC, "Public"),
+        ]
+        .iter()
+        {
+            if profiles.contains(*profile) {
+                self.push("Profile", name)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Addresses split by family into fields like `RA4` and `RA6`; keywords apply to both.
+    fn addresses(
+        &mut self,
+        prefix: &str,
+        addresses: &Option<String>,
+    ) -> Result<(), std::io::Error> {
+        for address in list(addresses) {
+            let keyword =
+                !address.contains(':') && !address.starts_with(|c: char| c.is_ascii_digit());
+            if keyword || !address.contains(':') {
+                self.push(&format!("{}4", prefix), address)?;
+            }
+            if keyword || address.contains(':') {
+                self.push(&format!("{}6", prefix), address)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> String {
+        self.fields.push(String::new());
+        self.fields.join("|")
+    }
+}
+
+/// Add an entry to a comma separated field, once; rule strings repeat keywords for each address family.
+fn push(list: &mut Option<String>, value: &str) {
+    let entries = list.get_or_insert_with(String::new);
+    if !entries.split(',').any(|entry| entry == value) {
+        if !entries.is_empty() {
+            entries.push(',');
+        }
+        entries.push_str(value);
+    }
+}
+
+/// The entries of a comma separated field, leaving out wildcards.
+fn list(field: &Option<String>) -> Vec<&str> {
+    let mut entries = list_entries(field.as_deref().unwrap_or_default());
+    entries.retain(|entry| *entry != "*" && !entry.eq_ignore_ascii_case("any"));
+    entries
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], std::io::Error> {
+        let end = self
+            .position
+            .checked_add(len)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or_else(|| {
+                invalid_data(format!("entry at offset {} is cut short", self.position))
+            })?;
+        let taken = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(taken)
+    }
+
+    fn expect(&mut self, delimiter: char) -> Result<(), std::io::Error> {
+        let position = self.position;
+        if self.take(2)? == [delimiter as u8, 0] {
+            Ok(())
+        } else {
+            Err(invalid_data(format!(
+                "expected '{}' at offset {}",
+                delimiter, position
+            )))
+        }
+    }
+
+    fn u32(&mut self) -> Result<u32, std::io::Error> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// A NUL-terminated UTF-16 string.
+    fn string(&mut self) -> Result<String, std::io::Error> {
+        let mut units = Vec::new();
+        loop {
+            let bytes = self.take(2)?;
+            match u16::from_le_bytes([bytes[0], bytes[1]]) {
+                0 => break,
+                unit => units.push(unit),
+            }
+        }
+        String::from_utf16(&units).map_err(|error| invalid_data(error.to_string()))
+    }
+}
+
+/// A UTF-16 string, up to its first NUL or the end of the data.
+fn decode_string(data: &[u8]) -> Result<String, std::io::Error> {
+    let mut units: Vec<u16> = data
+        .chunks_exact(2)
+        .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+        .collect();
+    // Multi-strings hold several NUL-terminated strings and end with an empty one.
+    while units.last() == Some(&0) {
+        units.pop();
+    }
+    String::from_utf16(&units).map_err(|error| invalid_data(error.to_string()))
+}
+
+/// A NUL-terminated UTF-16 string.
+fn encode_string(s: &str) -> Vec<u8> {
+    s.encode_utf16()
+        .chain(std::iter::once(0))
+        .flat_map(u16::to_le_bytes)
+        .collect()
+}
+
+fn invalid_data(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+fn invalid_input(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, message)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trips_files() {
+        let mut policy = RegistryPolicy::new();
+        policy.set(FIREWALL_KEY, "PolicyVersion", RegistryValue::Dword(0x21b));
+        policy.set(
+            "Software\\Other",
+            "Blob",
+            RegistryValue::Binary(vec![1, 2, 3]),
+        );
+        policy.set(
+            "Software\\Other",
+            "List",
+            RegistryValue::MultiString(vec!["a".to_string(), "b".to_string()]),
+        );
+        policy.set("Software\\Other", "Big", RegistryValue::Qword(1 << 40));
+        policy.set("Software\\Other", "Odd", RegistryValue::Other(9, vec![7]));
+        policy.delete(FIREWALL_RULES_KEY, "Old");
+
+        let bytes = policy.to_vec();
+        assert_eq!(&bytes[..8], b"PReg\x01\0\0\0");
+        assert_eq!(
+            &bytes[8..20],
+            &[b'[', 0, b'S', 0, b'o', 0, b'f', 0, b't', 0, b'w', 0]
+        );
+        assert_eq!(RegistryPolicy::from_slice(&bytes).unwrap(), policy);
+
+        for bad in &[&b"PReg\x02\0\0\0"[..], b"Junk", &bytes[..bytes.len() - 1]] {
+            assert_eq!(
+                RegistryPolicy::from_slice(bad).unwrap_err().kind(),
+                std::io::ErrorKind::InvalidData
+            );
+        }
+    }
+
+    #[test]
+    fn reads_firewall_policy() {
+        let mut policy = RegistryPolicy::new();
+        let mut set_rule = |id: &str, text: &str| {
+            policy.entries.push(PolicyEntry {
+                key: FIREWALL_RULES_KEY.to_string(),
+                value_name: id.to_string(),
+                value: RegistryValue::String(text.to_string()),
+            })
+        };
+        set_rule(
+            "{web}",
+            "v2.31|Action=Allow|Active=TRUE|Dir=In|Protocol=6|Profile=Domain|Profile=Private|LPort=80|LPort=443|\
+             RA4=LocalSubnet|RA6=LocalSubnet|RA4=10.0.0.0/255.0.0.0|App=%SystemRoot%\\web.exe|Name=Web|\
+             EmbedCtxt=Servers|IFType=Lan|Edge=TRUE|Platform=2:6:2|",
+        );
+        set_rule("{gone}", "v2.10|Action=Block|Dir=Out|Name=Gone|");
+        set_rule(
+            "{ping}",
+            "v2.10|Action=Block|Active=FALSE|Dir=Out|Protocol=1|ICMP4=8:*|Name=Ping|",
+        );
+        policy.entries.push(PolicyEntry {
+            key: FIREWALL_RULES_KEY.to_string(),
+            value_name: "**del.{gone}".to_string(),
+            value: RegistryValue::String(" ".to_string()),
+        });
+        policy.entries.push(PolicyEntry {
+            key: format!(r"{}\PublicProfile", FIREWALL_KEY),
+            value_name: "DefaultInboundAction".to_string(),
+            value: RegistryValue::Dword(1),
+        });
+        policy.entries.push(PolicyEntry {
+            key: format!(r"{}\PublicProfile", FIREWALL_KEY).to_uppercase(),
+            value_name: "disablenotifications".to_string(),
+            value: RegistryValue::Dword(1),
+        });
+
+        let rules = policy.firewall_rules().unwrap();
+        assert_eq!(rules.len(), 2);
+        let web = &rules[0];
+        assert_eq!(web.id.as_deref(), Some("{web}"));
+        assert_eq!(web.name, "Web");
+        assert_eq!(
+            web.profiles,
+            FirewallProfile::DOMAIN | FirewallProfile::PRIVATE
+        );
+        assert_eq!(web.local_ports.as_deref(), Some("80,443"));
+        assert_eq!(
+            web.remote_addresses.as_deref(),
+            Some("LocalSubnet,10.0.0.0/255.0.0.0")
+        );
+        assert_eq!(web.interface_types.as_deref(), Some("Lan"));
+        assert!(web.enabled && web.edge_traversal);
+        let ping = &rules[1];
+        assert!(!ping.enabled);
+        assert_eq!(ping.profiles, FirewallProfile::ALL);
+        assert_eq!(ping.interface_types.as_deref(), Some("All"));
+        assert_eq!(ping.icmp_types_and_codes.as_deref(), Some("8:*"));
+
+        let profiles = policy.profile_settings();
+        assert_eq!(
+            profiles.keys().collect::<Vec<_>>(),
+            [&FirewallProfile::PUBLIC]
+        );
+        let public = &profiles[&FirewallProfile::PUBLIC];
+        assert_eq!(public.default_inbound_action, FirewallAction::Block);
+        assert!(public.notifications_disabled);
+        assert!(public.firewall_enabled);
+
+        // Rules and settings survive being written back.
+        let mut written = RegistryPolicy::new();
+        for rule in &rules {
+            written.set_firewall_rule(rule).unwrap();
+        }
+        written
+            .set_profile_settings(FirewallProfile::PUBLIC, public)
+            .unwrap();
+        let written = RegistryPolicy::from_slice(&written.to_vec()).unwrap();
+        assert_eq!(written.firewall_rules().unwrap(), rules);
+        assert_eq!(written.profile_settings(), profiles);
+    }
+
+    #[test]
+    fn writes_rule_strings() {
+        let rule = FirewallRuleData {
+            protocol: 17,
+            remote_ports: Some("53".to_string()),
+            remote_addresses: Some("DNS,fe80::/64,192.168.0.1".to_string()),
+            direction: FirewallRuleDirection::Out,
+            profiles: FirewallProfile::PUBLIC,
+            ..FirewallRuleData::new("DNS")
+        };
+        assert_eq!(
+            rule_string(&rule).unwrap(),
+            "v2.10|Action=Allow|Active=TRUE|Dir=Out|Protocol=17|Profile=Public|RPort=53|RA4=DNS|RA6=DNS|\
+             RA6=fe80::/64|RA4=192.168.0.1|Name=DNS|"
+        );
+
+        assert_eq!(rule_version_for_build(7601), "v2.10");
+        assert_eq!(rule_version_for_build(14393), "v2.26");
+        assert_eq!(rule_version_for_build(26100), "v2.31");
+        assert!(rule_string_with_version(&rule, "v2.26")
+            .unwrap()
+            .starts_with("v2.26|Action=Allow|"));
+        assert!(rule_string_with_version(&rule, "2.26").is_err());
+
+        let piped = FirewallRuleData::new("a|b");
+        assert_eq!(
+            rule_string(&piped).unwrap_err().kind(),
+            std::io::ErrorKind::InvalidInput
+        );
+    }
+}
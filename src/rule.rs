@@ -1,8 +1,13 @@
 use crate::{
     bstr_to_os_string,
     os_str_to_bstr,
+    ownership::{
+        self,
+        Ownership,
+    },
     FirewallAction,
     FirewallProfile,
+    FirewallRuleData,
     FirewallRuleDirection,
 };
 use com::{
@@ -12,6 +17,7 @@ use com::{
 use netfw_sys::{
     variant::VariantType,
     INetFwRule,
+    SafeArray,
     Variant,
     CLSID_INETFWRULE,
     NET_FW_ACTION,
@@ -81,6 +87,25 @@ impl FirewallRule {
         }
     }
 
+    /// Set the description, keeping any ownership marker already on the rule unless `description` carries its own.
+    pub fn set_description(&self, description: &OsStr) -> Result<(), std::io::Error> {
+        let description = preserve_marker(self.get_description()?, description);
+        self.put_description(&description)
+    }
+
+    /// Set the description as given, marker and all.
+    fn put_description(&self, description: &OsStr) -> Result<(), std::io::Error> {
+        let description = os_str_to_bstr(description);
+        let ret = unsafe { self.0.put_description(description) };
+        unsafe { SysFreeString(description) }
+
+        if FAILED(ret) {
+            Err(std::io::Error::from_raw_os_error(ret))
+        } else {
+            Ok(())
+        }
+    }
+
     pub fn get_application_name(&self) -> Result<Option<OsString>, std::io::Error> {
         let mut bstr = std::ptr::null_mut();
         let ret = unsafe { self.0.get_application_name(&mut bstr) };
@@ -119,6 +144,18 @@ impl FirewallRule {
         }
     }
 
+    pub fn set_service_name(&self, name: &OsStr) -> Result<(), std::io::Error> {
+        let name = os_str_to_bstr(name);
+        let ret = unsafe { self.0.put_service_name(name) };
+        unsafe { SysFreeString(name) }
+
+        if FAILED(ret) {
+            Err(std::io::Error::from_raw_os_error(ret))
+        } else {
+            Ok(())
+        }
+    }
+
     pub fn get_protocol(&self) -> Result<LONG, std::io::Error> {
         let mut protocol = 0;
         let ret = unsafe { self.0.get_protocol(&mut protocol) };
@@ -130,6 +167,16 @@ impl FirewallRule {
         }
     }
 
+    pub fn set_protocol(&self, protocol: LONG) -> Result<(), std::io::Error> {
+        let ret = unsafe { self.0.put_protocol(protocol) };
+
+        if FAILED(ret) {
+            Err(std::io::Error::from_raw_os_error(ret))
+        } else {
+            Ok(())
+        }
+    }
+
     pub fn get_local_ports(&self) -> Result<Option<OsString>, std::io::Error> {
         let mut bstr = std::ptr::null_mut();
         let ret = unsafe { self.0.get_local_ports(&mut bstr) };
@@ -143,6 +190,18 @@ impl FirewallRule {
         }
     }
 
+    pub fn set_local_ports(&self, ports: &OsStr) -> Result<(), std::io::Error> {
+        let ports = os_str_to_bstr(ports);
+        let ret = unsafe { self.0.put_local_ports(ports) };
+        unsafe { SysFreeString(ports) }
+
+        if FAILED(ret) {
+            Err(std::io::Error::from_raw_os_error(ret))
+        } else {
+            Ok(())
+        }
+    }
+
     pub fn get_remote_ports(&self) -> Result<Option<OsString>, std::io::Error> {
         let mut bstr = std::ptr::null_mut();
         let ret = unsafe { self.0.get_remote_ports(&mut bstr) };
@@ -156,6 +215,18 @@ impl FirewallRule {
         }
     }
 
+    pub fn set_remote_ports(&self, ports: &OsStr) -> Result<(), std::io::Error> {
+        let ports = os_str_to_bstr(ports);
+        let ret = unsafe { self.0.put_remote_ports(ports) };
+        unsafe { SysFreeString(ports) }
+
+        if FAILED(ret) {
+            Err(std::io::Error::from_raw_os_error(ret))
+        } else {
+            Ok(())
+        }
+    }
+
     pub fn get_local_addresses(&self) -> Result<Option<OsString>, std::io::Error> {
         let mut bstr = std::ptr::null_mut();
         let ret = unsafe { self.0.get_local_addresses(&mut bstr) };
@@ -169,6 +240,18 @@ impl FirewallRule {
         }
     }
 
+    pub fn set_local_addresses(&self, addresses: &OsStr) -> Result<(), std::io::Error> {
+        let addresses = os_str_to_bstr(addresses);
+        let ret = unsafe { self.0.put_local_addresses(addresses) };
+        unsafe { SysFreeString(addresses) }
+
+        if FAILED(ret) {
+            Err(std::io::Error::from_raw_os_error(ret))
+        } else {
+            Ok(())
+        }
+    }
+
     pub fn get_remote_addresses(&self) -> Result<Option<OsString>, std::io::Error> {
         let mut bstr = std::ptr::null_mut();
         let ret = unsafe { self.0.get_remote_addresses(&mut bstr) };
@@ -207,6 +290,21 @@ impl FirewallRule {
         }
     }
 
+    pub fn set_icmp_types_and_codes(
+        &self,
+        icmp_types_and_codes: &OsStr,
+    ) -> Result<(), std::io::Error> {
+        let icmp_types_and_codes = os_str_to_bstr(icmp_types_and_codes);
+        let ret = unsafe { self.0.put_icmp_types_and_codes(icmp_types_and_codes) };
+        unsafe { SysFreeString(icmp_types_and_codes) }
+
+        if FAILED(ret) {
+            Err(std::io::Error::from_raw_os_error(ret))
+        } else {
+            Ok(())
+        }
+    }
+
     pub fn get_direction(&self) -> Result<FirewallRuleDirection, std::io::Error> {
         let mut dir = 0;
         let ret = unsafe { self.0.get_direction(&mut dir) };
@@ -258,6 +356,21 @@ impl FirewallRule {
         Ok(Some(ret))
     }
 
+    pub fn set_interfaces<S: AsRef<OsStr>>(&self, interfaces: &[S]) -> Result<(), std::io::Error> {
+        let mut array = SafeArray::new_vector(VariantType::Variant, interfaces.len())?;
+        for (i, interface) in interfaces.iter().enumerate() {
+            let interface = Variant::from_bstr(os_str_to_bstr(interface.as_ref()));
+            unsafe { array.put(&[i as LONG], &interface)? };
+        }
+        let ret = unsafe { self.0.put_interfaces(Variant::from_variant_array(array)) };
+
+        if FAILED(ret) {
+            Err(std::io::Error::from_raw_os_error(ret))
+        } else {
+            Ok(())
+        }
+    }
+
     pub fn get_interface_types(&self) -> Result<Option<OsString>, std::io::Error> {
         let mut bstr = std::ptr::null_mut();
         let ret = unsafe { self.0.get_interface_types(&mut bstr) };
@@ -271,6 +384,18 @@ impl FirewallRule {
         }
     }
 
+    pub fn set_interface_types(&self, interface_types: &OsStr) -> Result<(), std::io::Error> {
+        let interface_types = os_str_to_bstr(interface_types);
+        let ret = unsafe { self.0.put_interface_types(interface_types) };
+        unsafe { SysFreeString(interface_types) }
+
+        if FAILED(ret) {
+            Err(std::io::Error::from_raw_os_error(ret))
+        } else {
+            Ok(())
+        }
+    }
+
     pub fn get_enabled(&self) -> Result<bool, std::io::Error> {
         let mut enabled = VARIANT_FALSE;
         let ret = unsafe { self.0.get_enabled(&mut enabled) };
@@ -306,6 +431,25 @@ impl FirewallRule {
         }
     }
 
+    /// Set the grouping, keeping any ownership marker already on it unless `name` carries its own.
+    pub fn set_grouping(&self, name: &OsStr) -> Result<(), std::io::Error> {
+        let name = preserve_marker(self.get_grouping()?, name);
+        self.put_grouping(&name)
+    }
+
+    /// Set the grouping as given, marker and all.
+    fn put_grouping(&self, name: &OsStr) -> Result<(), std::io::Error> {
+        let name = os_str_to_bstr(name);
+        let ret = unsafe { self.0.put_grouping(name) };
+        unsafe { SysFreeString(name) }
+
+        if FAILED(ret) {
+            Err(std::io::Error::from_raw_os_error(ret))
+        } else {
+            Ok(())
+        }
+    }
+
     pub fn get_profiles(&self) -> Result<FirewallProfile, std::io::Error> {
         let mut profiles = 0;
         let ret = unsafe { self.0.get_profiles(&mut profiles) };
@@ -317,6 +461,16 @@ impl FirewallRule {
         }
     }
 
+    pub fn set_profiles(&self, profiles: FirewallProfile) -> Result<(), std::io::Error> {
+        let ret = unsafe { self.0.put_profiles(profiles.bits() as LONG) };
+
+        if FAILED(ret) {
+            Err(std::io::Error::from_raw_os_error(ret))
+        } else {
+            Ok(())
+        }
+    }
+
     pub fn get_edge_traversal(&self) -> Result<bool, std::io::Error> {
         let mut enabled = VARIANT_FALSE;
         let ret = unsafe { self.0.get_edge_traversal(&mut enabled) };
@@ -328,6 +482,17 @@ impl FirewallRule {
         }
     }
 
+    pub fn set_edge_traversal(&self, enabled: bool) -> Result<(), std::io::Error> {
+        let enabled = if enabled { VARIANT_TRUE } else { VARIANT_FALSE };
+        let ret = unsafe { self.0.put_edge_traversal(enabled) };
+
+        if FAILED(ret) {
+            Err(std::io::Error::from_raw_os_error(ret))
+        } else {
+            Ok(())
+        }
+    }
+
     pub fn get_action(&self) -> Result<FirewallAction, std::io::Error> {
         let mut action = 0;
         let ret = unsafe { self.0.get_action(&mut action) };
@@ -349,6 +514,147 @@ impl FirewallRule {
             Ok(())
         }
     }
+
+    /// The ownership marker from the description, or failing that the grouping.
+    pub fn get_ownership(&self) -> Result<Option<Ownership>, std::io::Error> {
+        let description = self.get_description()?;
+        if let Some(ownership) = description
+            .as_deref()
+            .and_then(|d| Ownership::parse(&d.to_string_lossy()))
+        {
+            return Ok(Some(ownership));
+        }
+
+        Ok(self
+            .get_grouping()?
+            .as_deref()
+            .and_then(|g| Ownership::parse(&g.to_string_lossy())))
+    }
+
+    /// Embed an ownership marker in the description, or remove it with `None`.
+    ///
+    /// The human readable part of the description is kept either way.
+    pub fn set_ownership(&self, ownership: Option<&Ownership>) -> Result<(), std::io::Error> {
+        let description = self
+            .get_description()?
+            .map(|d| d.to_string_lossy().into_owned());
+        let description = match ownership {
+            Some(ownership) => ownership.embed(description.as_deref()),
+            None => description
+                .as_deref()
+                .map(ownership::strip)
+                .unwrap_or("")
+                .to_string(),
+        };
+
+        self.put_description(OsStr::new(&description))
+    }
+
+    /// Copy every field out of this rule.
+    pub fn to_data(&self) -> Result<FirewallRuleData, std::io::Error> {
+        Ok(FirewallRuleData {
+            id: None,
+            name: self.get_name()?.to_string_lossy().into_owned(),
+            description: lossy(self.get_description()?),
+            application_name: lossy(self.get_application_name()?),
+            service_name: lossy(self.get_service_name()?),
+            protocol: self.get_protocol()?,
+            local_ports: lossy(self.get_local_ports()?),
+            remote_ports: lossy(self.get_remote_ports()?),
+            local_addresses: lossy(self.get_local_addresses()?),
+            remote_addresses: lossy(self.get_remote_addresses()?),
+            icmp_types_and_codes: lossy(self.get_icmp_types_and_codes()?),
+            direction: self.get_direction()?,
+            interfaces: self.get_interfaces()?.map(|interfaces| {
+                interfaces
+                    .iter()
+                    .map(|interface| interface.to_string_lossy().into_owned())
+                    .collect()
+            }),
+            interface_types: lossy(self.get_interface_types()?),
+            enabled: self.get_enabled()?,
+            grouping: lossy(self.get_grouping()?),
+            profiles: self.get_profiles()?,
+            edge_traversal: self.get_edge_traversal()?,
+            action: self.get_action()?,
+        })
+    }
+
+    /// Make a new rule from owned data.
+    pub fn from_data(data: &FirewallRuleData) -> Result<Self, std::io::Error> {
+        let rule = FirewallRule::new()?;
+        rule.update_from_data(data)?;
+        Ok(rule)
+    }
+
+    /// Overwrite the fields of this rule with the given data. Optional fields that are `None` are left as they are;
+    /// use `FirewallPolicy::replace_rule` to clear them.
+    ///
+    /// The description and grouping are written as given, so an ownership marker only survives if `data` carries
+    /// it. The protocol is written before the ports and ICMP settings since COM rejects those for the wrong protocol.
+    pub fn update_from_data(&self, data: &FirewallRuleData) -> Result<(), std::io::Error> {
+        self.set_name(OsStr::new(&data.name))?;
+        if let Some(description) = data.description.as_deref() {
+            self.put_description(OsStr::new(description))?;
+        }
+        if let Some(application_name) = data.application_name.as_deref() {
+            self.set_application_name(OsStr::new(application_name))?;
+        }
+        if let Some(service_name) = data.service_name.as_deref() {
+            self.set_service_name(OsStr::new(service_name))?;
+        }
+        self.set_protocol(data.protocol)?;
+        if let Some(local_ports) = data.local_ports.as_deref() {
+            self.set_local_ports(OsStr::new(local_ports))?;
+        }
+        if let Some(remote_ports) = data.remote_ports.as_deref() {
+            self.set_remote_ports(OsStr::new(remote_ports))?;
+        }
+        if let Some(local_addresses) = data.local_addresses.as_deref() {
+            self.set_local_addresses(OsStr::new(local_addresses))?;
+        }
+        if let Some(remote_addresses) = data.remote_addresses.as_deref() {
+            self.set_remote_addresses(OsStr::new(remote_addresses))?;
+        }
+        if let Some(icmp_types_and_codes) = data.icmp_types_and_codes.as_deref() {
+            self.set_icmp_types_and_codes(OsStr::new(icmp_types_and_codes))?;
+        }
+        self.set_direction(data.direction)?;
+        if let Some(interfaces) = data.interfaces.as_deref() {
+            self.set_interfaces(interfaces)?;
+        }
+        if let Some(interface_types) = data.interface_types.as_deref() {
+            self.set_interface_types(OsStr::new(interface_types))?;
+        }
+        if let Some(grouping) = data.grouping.as_deref() {
+            self.put_grouping(OsStr::new(grouping))?;
+        }
+        self.set_profiles(data.profiles)?;
+        self.set_edge_traversal(data.edge_traversal)?;
+        self.set_action(data.action)?;
+        self.set_enabled(data.enabled)?;
+
+        Ok(())
+    }
+}
+
+/// Carry an ownership marker from `existing` over to `new` if `new` doesn't have one.
+fn preserve_marker(existing: Option<OsString>, new: &OsStr) -> OsString {
+    let existing = existing
+        .as_deref()
+        .and_then(|existing| Ownership::parse(&existing.to_string_lossy()));
+    let new_lossy = new.to_string_lossy();
+
+    match existing {
+        Some(ownership) if Ownership::parse(&new_lossy).is_none() => {
+            ownership.embed(Some(&new_lossy)).into()
+        }
+        _ => new.to_os_string(),
+    }
+}
+
+fn lossy(s: Option<OsString>) -> Option<String> {
+    s.map(|s| s.to_string_lossy().into_owned())
 }
 
 impl std::fmt::Debug for FirewallRule {
@@ -52,6 +52,19 @@ impl FirewallRules {
         }
     }
 
+    pub fn get(&self, name: &OsStr) -> Result<FirewallRule, std::io::Error> {
+        let name = os_str_to_bstr(name);
+        let mut rule = MaybeUninit::zeroed(); // NULL
+        let ret = unsafe { self.0.item(name, rule.as_mut_ptr()) };
+        unsafe { SysFreeString(name) }
+
+        if FAILED(ret) {
+            Err(std::io::Error::from_raw_os_error(ret))
+        } else {
+            Ok(unsafe { FirewallRule(rule.assume_init()) })
+        }
+    }
+
     pub fn get_enumerator(&self) -> Result<VariantEnumerator, std::io::Error> {
         let mut ptr = MaybeUninit::zeroed();
         let ret = unsafe { self.0.get_new_enum(ptr.as_mut_ptr()) };
@@ -69,6 +82,20 @@ impl FirewallRules {
     pub fn iter(&self) -> Result<FirewallRulesIter, std::io::Error> {
         Ok(FirewallRulesIter::new(self.get_enumerator()?))
     }
+
+    /// Every rule carrying an ownership marker naming `owner`.
+    pub fn owned_by(&self, owner: &str) -> Result<Vec<FirewallRule>, std::io::Error> {
+        let mut owned = Vec::new();
+
+        for rule in self.iter()? {
+            let rule = rule?;
+            if matches!(rule.get_ownership()?, Some(ownership) if ownership.owner == owner) {
+                owned.push(rule);
+            }
+        }
+
+        Ok(owned)
+    }
 }
 
 impl std::fmt::Debug for FirewallRules {
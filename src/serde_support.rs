@@ -0,0 +1,231 @@
+//! `Serialize` and `Deserialize` impls, enabled by the `serde` feature.
+//!
+//! Enums and profile sets are written as the strings `netsh` and PowerShell show, like "Allow", "Inbound" and
+//! "Domain,Private", port and address lists in their canonical form, like "80,443", and log timestamps as the log
+//! writes them. All are read back through their `FromStr` impls, so any spelling those accept is accepted here.
+
+use crate::{
+    addresses::AddressList,
+    firewall_log::LogTimestamp,
+    ports::PortList,
+    FirewallAction,
+    FirewallProfile,
+    FirewallRuleDirection,
+};
+use serde::{
+    de::Error as _,
+    Deserialize,
+    Deserializer,
+    Serialize,
+    Serializer,
+};
+
+macro_rules! serde_via_str {
+    ($($ty:ty),*) => {
+        $(
+            impl Serialize for $ty {
+                fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                    serializer.collect_str(self)
+                }
+            }
+
+            impl<'de> Deserialize<'de> for $ty {
+                fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                    let s = String::deserialize(deserializer)?;
+                    s.parse().map_err(D::Error::custom)
+                }
+            }
+        )*
+    };
+}
+
+serde_via_str!(
+    FirewallAction,
+    FirewallRuleDirection,
+    FirewallProfile,
+    LogTimestamp,
+    PortList,
+    AddressList
+);
+
+/// For `#[serde(with)]` on protocol numbers: well-known protocols are written by name, others as numbers.
+pub(crate) mod protocol {
+    use crate::data::{
+        parse_protocol,
+        protocol_name,
+    };
+    use serde::{
+        de::{
+            Error,
+            Unexpected,
+            Visitor,
+        },
+        Deserializer,
+        Serializer,
+    };
+
+    pub fn serialize<S: Serializer>(protocol: &i32, serializer: S) -> Result<S::Ok, S::Error> {
+        match protocol_name(*protocol) {
+            Some(name) => serializer.serialize_str(name),
+            None => serializer.serialize_i32(*protocol),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<i32, D::Error> {
+        struct ProtocolVisitor;
+
+        impl<'de> Visitor<'de> for ProtocolVisitor {
+            type Value = i32;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.write_str("a protocol name or number between 0 and 256")
+            }
+
+            fn visit_str<E: Error>(self, s: &str) -> Result<i32, E> {
+                parse_protocol(s).ok_or_else(|| E::invalid_value(Unexpected::Str(s), &self))
+            }
+
+            fn visit_i64<E: Error>(self, n: i64) -> Result<i32, E> {
+                self.visit_str(&n.to_string())
+            }
+
+            fn visit_u64<E: Error>(self, n: u64) -> Result<i32, E> {
+                self.visit_str(&n.to_string())
+            }
+        }
+
+        deserializer.deserialize_any(ProtocolVisitor)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        addresses::AddressList,
+        ports::PortList,
+        snapshot::Snapshot,
+        FirewallAction,
+        FirewallProfile,
+        FirewallProfileSettings,
+        FirewallRuleData,
+        FirewallRuleDirection,
+    };
+    use serde_json::json;
+
+    #[test]
+    fn enum_vocabulary() {
+        assert_eq!(
+            serde_json::to_value(FirewallAction::Allow).unwrap(),
+            json!("Allow")
+        );
+        assert_eq!(
+            serde_json::to_value(FirewallRuleDirection::Out).unwrap(),
+            json!("Outbound")
+        );
+        assert_eq!(
+            serde_json::to_value(FirewallProfile::DOMAIN | FirewallProfile::PRIVATE).unwrap(),
+            json!("Domain,Private")
+        );
+        assert_eq!(
+            serde_json::to_value(FirewallProfile::ALL).unwrap(),
+            json!("Any")
+        );
+
+        let direction: FirewallRuleDirection = serde_json::from_value(json!("in")).unwrap();
+        assert_eq!(direction, FirewallRuleDirection::In);
+        let profiles: FirewallProfile = serde_json::from_value(json!("public, Domain")).unwrap();
+        assert_eq!(profiles, FirewallProfile::PUBLIC | FirewallProfile::DOMAIN);
+        assert!(serde_json::from_value::<FirewallAction>(json!("Deny")).is_err());
+    }
+
+    #[test]
+    fn list_round_trip() {
+        let ports: PortList = serde_json::from_value(json!("443, 80-80, rpc")).unwrap();
+        assert_eq!(
+            serde_json::to_value(&ports).unwrap(),
+            json!(ports.to_string())
+        );
+        let json = serde_json::to_string(&ports).unwrap();
+        assert_eq!(serde_json::from_str::<PortList>(&json).unwrap(), ports);
+        assert!(serde_json::from_value::<PortList>(json!("80-")).is_err());
+
+        let addresses: AddressList =
+            serde_json::from_value(json!("10.0.0.0/24, LocalSubnet, ::1")).unwrap();
+        let json = serde_json::to_string(&addresses).unwrap();
+        assert_eq!(
+            serde_json::from_str::<AddressList>(&json).unwrap(),
+            addresses
+        );
+        assert!(serde_json::from_value::<AddressList>(json!("10.0.0.0/33")).is_err());
+    }
+
+    #[test]
+    fn rule_round_trip() {
+        let rule = FirewallRuleData {
+            description: Some("Web".into()),
+            protocol: 6,
+            local_ports: Some("80,443".into()),
+            remote_addresses: Some("LocalSubnet".into()),
+            interfaces: Some(vec!["Ethernet".into()]),
+            profiles: FirewallProfile::DOMAIN,
+            action: FirewallAction::Block,
+            ..FirewallRuleData::new("web")
+        };
+
+        let value = serde_json::to_value(&rule).unwrap();
+        assert_eq!(value["protocol"], json!("TCP"));
+        assert_eq!(value["direction"], json!("Inbound"));
+        assert_eq!(value["profiles"], json!("Domain"));
+        assert_eq!(
+            serde_json::from_value::<FirewallRuleData>(value).unwrap(),
+            rule
+        );
+
+        let odd = FirewallRuleData {
+            protocol: 47,
+            ..rule
+        };
+        let value = serde_json::to_value(&odd).unwrap();
+        assert_eq!(value["protocol"], json!(47));
+        assert_eq!(
+            serde_json::from_value::<FirewallRuleData>(value).unwrap(),
+            odd
+        );
+    }
+
+    #[test]
+    fn partial_rule_uses_defaults() {
+        let rule: FirewallRuleData = serde_json::from_value(
+            json!({ "name": "dns", "protocol": "udp", "local_ports": "53" }),
+        )
+        .unwrap();
+        assert_eq!(
+            rule,
+            FirewallRuleData {
+                protocol: 17,
+                local_ports: Some("53".into()),
+                ..FirewallRuleData::new("dns")
+            }
+        );
+        assert!(serde_json::from_value::<FirewallRuleData>(json!({ "protocol": 300 })).is_err());
+    }
+
+    #[test]
+    fn snapshot_round_trip() {
+        let mut snapshot = Snapshot {
+            rules: vec![FirewallRuleData::new("a")],
+            ..Default::default()
+        };
+        snapshot.profiles.insert(
+            FirewallProfile::PUBLIC,
+            FirewallProfileSettings {
+                block_all_inbound_traffic: true,
+                ..Default::default()
+            },
+        );
+
+        let text = serde_json::to_string(&snapshot).unwrap();
+        assert!(text.contains(r#""Public":{"firewall_enabled":true"#));
+        assert_eq!(serde_json::from_str::<Snapshot>(&text).unwrap(), snapshot);
+    }
+}
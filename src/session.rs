@@ -0,0 +1,755 @@
+//! A `Send + Sync` handle to the firewall.
+//!
+//! `FirewallPolicy`, `FirewallRules` and `FirewallRule` wrap apartment-bound COM pointers, so they can't leave the thread that made them.
+//! A `FirewallSession` instead owns a dedicated worker thread with its own apartment and talks to it over a channel,
+//! handing back owned `FirewallRuleData` and `FirewallProfileSettings` values.
+
+#[cfg(windows)]
+use crate::{
+    FirewallPolicy,
+    FirewallRule,
+};
+use crate::{
+    FirewallProfile,
+    FirewallProfileSettings,
+    FirewallRuleData,
+};
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{
+        mpsc::{
+            channel,
+            sync_channel,
+            Sender,
+        },
+        Arc,
+        Condvar,
+        Mutex,
+    },
+    task::{
+        Context,
+        Poll,
+        Waker,
+    },
+};
+
+/// A request for the worker thread.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    CurrentProfileTypes,
+    GetProfileSettings(FirewallProfile),
+    SetProfileSettings(FirewallProfile, FirewallProfileSettings),
+    RuleCount,
+    ListRules,
+    GetRule(String),
+    AddRule(FirewallRuleData),
+    /// Overwrite the rule with the given name.
+    UpdateRule(String, FirewallRuleData),
+    RemoveRule(String),
+}
+
+/// The worker's answer to a `Request`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    ProfileTypes(FirewallProfile),
+    ProfileSettings(FirewallProfileSettings),
+    Count(usize),
+    Rules(Vec<FirewallRuleData>),
+    Rule(Box<FirewallRuleData>),
+    Done,
+}
+
+impl Response {
+    fn into_profile_types(self) -> Result<FirewallProfile, std::io::Error> {
+        match self {
+            Response::ProfileTypes(profile) => Ok(profile),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    fn into_profile_settings(self) -> Result<FirewallProfileSettings, std::io::Error> {
+        match self {
+            Response::ProfileSettings(settings) => Ok(settings),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    fn into_count(self) -> Result<usize, std::io::Error> {
+        match self {
+            Response::Count(count) => Ok(count),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    fn into_rules(self) -> Result<Vec<FirewallRuleData>, std::io::Error> {
+        match self {
+            Response::Rules(rules) => Ok(rules),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    fn into_rule(self) -> Result<FirewallRuleData, std::io::Error> {
+        match self {
+            Response::Rule(rule) => Ok(*rule),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    fn into_done(self) -> Result<(), std::io::Error> {
+        match self {
+            Response::Done => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+}
+
+fn unexpected(response: Response) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("unexpected response: {:?}", response),
+    )
+}
+
+/// Something that can serve requests on the worker thread.
+///
+/// The COM backed worker is `ComWorker`; tests can plug in their own.
+pub trait Worker {
+    fn handle(&mut self, request: Request) -> Result<Response, std::io::Error>;
+}
+
+/// Where a worker's answer ends up.
+struct Slot {
+    state: Mutex<SlotState>,
+    ready: Condvar,
+}
+
+#[derive(Default)]
+struct SlotState {
+    result: Option<Result<Response, std::io::Error>>,
+    waker: Option<Waker>,
+}
+
+impl Slot {
+    fn new() -> Self {
+        Slot {
+            state: Mutex::new(SlotState::default()),
+            ready: Condvar::new(),
+        }
+    }
+
+    fn fill(&self, result: Result<Response, std::io::Error>) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.result = Some(result);
+
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+        self.ready.notify_all();
+    }
+}
+
+/// A request in flight to the worker.
+struct Job {
+    request: Option<Request>,
+    reply: Option<Arc<Slot>>,
+}
+
+impl Job {
+    fn complete(mut self, result: Result<Response, std::io::Error>) {
+        if let Some(reply) = self.reply.take() {
+            reply.fill(result);
+        }
+    }
+}
+
+impl Drop for Job {
+    // A job dropped without an answer means the worker is gone; don't leave the caller waiting forever.
+    fn drop(&mut self) {
+        if let Some(reply) = self.reply.take() {
+            reply.fill(Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "firewall worker thread stopped",
+            )));
+        }
+    }
+}
+
+/// The answer to a request that has been sent.
+///
+/// Either block on it with `wait` or `.await` it.
+pub struct PendingResponse {
+    slot: Arc<Slot>,
+}
+
+impl PendingResponse {
+    /// Block the current thread until the worker answers.
+    pub fn wait(self) -> Result<Response, std::io::Error> {
+        let mut state = self.slot.state.lock().unwrap_or_else(|e| e.into_inner());
+        loop {
+            if let Some(result) = state.result.take() {
+                return result;
+            }
+            state = self
+                .slot
+                .ready
+                .wait(state)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+    }
+}
+
+impl Future for PendingResponse {
+    type Output = Result<Response, std::io::Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.slot.state.lock().unwrap_or_else(|e| e.into_inner());
+        match state.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// A cloneable, `Send + Sync` handle to a firewall worker thread.
+///
+/// The worker exits once every handle has been dropped.
+pub struct FirewallSession {
+    // The queue is unbounded so that queueing a request never blocks an async executor. `Sender` is only `Sync`
+    // from Rust 1.72, hence the mutex.
+    sender: Mutex<Sender<Job>>,
+}
+
+impl Clone for FirewallSession {
+    fn clone(&self) -> Self {
+        let sender = self.sender.lock().unwrap_or_else(|e| e.into_inner());
+        FirewallSession {
+            sender: Mutex::new(sender.clone()),
+        }
+    }
+}
+
+impl FirewallSession {
+    /// Start a worker thread talking to the local firewall through COM.
+    #[cfg(windows)]
+    pub fn new() -> Result<Self, std::io::Error> {
+        Self::spawn(ComWorker::new)
+    }
+
+    /// Start a worker thread running the worker `make_worker` returns.
+    ///
+    /// The worker is made on the new thread, so it may hold thread-bound state. Errors from `make_worker` are returned here.
+    pub fn spawn<F, W>(make_worker: F) -> Result<Self, std::io::Error>
+    where
+        F: FnOnce() -> Result<W, std::io::Error> + Send + 'static,
+        W: Worker,
+    {
+        let (sender, receiver) = channel::<Job>();
+        let (init_sender, init_receiver) = sync_channel(1);
+
+        std::thread::Builder::new()
+            .name("netfw-session".into())
+            .spawn(move || {
+                let mut worker = match make_worker() {
+                    Ok(worker) => {
+                        let _ = init_sender.send(Ok(()));
+                        worker
+                    }
+                    Err(e) => {
+                        let _ = init_sender.send(Err(e));
+                        return;
+                    }
+                };
+
+                for mut job in receiver.iter() {
+                    if let Some(request) = job.request.take() {
+                        let result = worker.handle(request);
+                        job.complete(result);
+                    }
+                }
+            })?;
+
+        init_receiver.recv().map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "firewall worker thread stopped during startup",
+            )
+        })??;
+
+        Ok(FirewallSession {
+            sender: Mutex::new(sender),
+        })
+    }
+
+    /// Queue a request without waiting for the answer. This never blocks.
+    pub fn request(&self, request: Request) -> PendingResponse {
+        let slot = Arc::new(Slot::new());
+        let job = Job {
+            request: Some(request),
+            reply: Some(slot.clone()),
+        };
+
+        // If the worker is gone the job is dropped here, which fills the slot with an error.
+        let _ = self
+            .sender
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .send(job);
+
+        PendingResponse { slot }
+    }
+
+    /// Send a request and block until the worker answers.
+    pub fn call(&self, request: Request) -> Result<Response, std::io::Error> {
+        self.request(request).wait()
+    }
+
+    pub fn current_profile_types(&self) -> Result<FirewallProfile, std::io::Error> {
+        self.call(Request::CurrentProfileTypes)?
+            .into_profile_types()
+    }
+
+    pub fn get_profile_settings(
+        &self,
+        profile: FirewallProfile,
+    ) -> Result<FirewallProfileSettings, std::io::Error> {
+        self.call(Request::GetProfileSettings(profile))?
+            .into_profile_settings()
+    }
+
+    pub fn set_profile_settings(
+        &self,
+        profile: FirewallProfile,
+        settings: FirewallProfileSettings,
+    ) -> Result<(), std::io::Error> {
+        self.call(Request::SetProfileSettings(profile, settings))?
+            .into_done()
+    }
+
+    pub fn rule_count(&self) -> Result<usize, std::io::Error> {
+        self.call(Request::RuleCount)?.into_count()
+    }
+
+    pub fn rules(&self) -> Result<Vec<FirewallRuleData>, std::io::Error> {
+        self.call(Request::ListRules)?.into_rules()
+    }
+
+    pub fn get_rule(&self, name: &str) -> Result<FirewallRuleData, std::io::Error> {
+        self.call(Request::GetRule(name.into()))?.into_rule()
+    }
+
+    pub fn add_rule(&self, rule: FirewallRuleData) -> Result<(), std::io::Error> {
+        self.call(Request::AddRule(rule))?.into_done()
+    }
+
+    pub fn update_rule(&self, name: &str, rule: FirewallRuleData) -> Result<(), std::io::Error> {
+        self.call(Request::UpdateRule(name.into(), rule))?
+            .into_done()
+    }
+
+    pub fn remove_rule(&self, name: &str) -> Result<(), std::io::Error> {
+        self.call(Request::RemoveRule(name.into()))?.into_done()
+    }
+
+    pub async fn current_profile_types_async(&self) -> Result<FirewallProfile, std::io::Error> {
+        self.request(Request::CurrentProfileTypes)
+            .await?
+            .into_profile_types()
+    }
+
+    pub async fn get_profile_settings_async(
+        &self,
+        profile: FirewallProfile,
+    ) -> Result<FirewallProfileSettings, std::io::Error> {
+        self.request(Request::GetProfileSettings(profile))
+            .await?
+            .into_profile_settings()
+    }
+
+    pub async fn set_profile_settings_async(
+        &self,
+        profile: FirewallProfile,
+        settings: FirewallProfileSettings,
+    ) -> Result<(), std::io::Error> {
+        self.request(Request::SetProfileSettings(profile, settings))
+            .await?
+            .into_done()
+    }
+
+    pub async fn rule_count_async(&self) -> Result<usize, std::io::Error> {
+        self.request(Request::RuleCount).await?.into_count()
+    }
+
+    pub async fn rules_async(&self) -> Result<Vec<FirewallRuleData>, std::io::Error> {
+        self.request(Request::ListRules).await?.into_rules()
+    }
+
+    pub async fn get_rule_async(&self, name: &str) -> Result<FirewallRuleData, std::io::Error> {
+        self.request(Request::GetRule(name.into()))
+            .await?
+            .into_rule()
+    }
+
+    pub async fn add_rule_async(&self, rule: FirewallRuleData) -> Result<(), std::io::Error> {
+        self.request(Request::AddRule(rule)).await?.into_done()
+    }
+
+    pub async fn update_rule_async(
+        &self,
+        name: &str,
+        rule: FirewallRuleData,
+    ) -> Result<(), std::io::Error> {
+        self.request(Request::UpdateRule(name.into(), rule))
+            .await?
+            .into_done()
+    }
+
+    pub async fn remove_rule_async(&self, name: &str) -> Result<(), std::io::Error> {
+        self.request(Request::RemoveRule(name.into()))
+            .await?
+            .into_done()
+    }
+}
+
+impl std::fmt::Debug for FirewallSession {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FirewallSession").finish()
+    }
+}
+
+/// The worker `FirewallSession::new` uses, backed by COM.
+///
+/// It initializes COM on construction, so it must be made on the thread it will be used from.
+#[cfg(windows)]
+pub struct ComWorker {
+    policy: std::mem::ManuallyDrop<FirewallPolicy>,
+}
+
+#[cfg(windows)]
+impl ComWorker {
+    pub fn new() -> Result<Self, std::io::Error> {
+        com::runtime::init_runtime().map_err(std::io::Error::from_raw_os_error)?;
+
+        match FirewallPolicy::new() {
+            Ok(policy) => Ok(ComWorker {
+                policy: std::mem::ManuallyDrop::new(policy),
+            }),
+            Err(e) => {
+                com::runtime::deinit_runtime();
+                Err(e)
+            }
+        }
+    }
+}
+
+#[cfg(windows)]
+impl Worker for ComWorker {
+    fn handle(&mut self, request: Request) -> Result<Response, std::io::Error> {
+        use std::ffi::OsStr;
+
+        match request {
+            Request::CurrentProfileTypes => {
+                Ok(Response::ProfileTypes(self.policy.current_profile_types()?))
+            }
+            Request::GetProfileSettings(profile) => Ok(Response::ProfileSettings(
+                self.policy.get_profile_settings(profile)?,
+            )),
+            Request::SetProfileSettings(profile, settings) => {
+                self.policy.set_profile_settings(profile, &settings)?;
+                Ok(Response::Done)
+            }
+            Request::RuleCount => Ok(Response::Count(self.policy.get_rules()?.get_count()?)),
+            Request::ListRules => {
+                let rules = self
+                    .policy
+                    .get_rules()?
+                    .iter()?
+                    .map(|rule| rule?.to_data())
+                    .collect::<Result<_, _>>()?;
+                Ok(Response::Rules(rules))
+            }
+            Request::GetRule(name) => {
+                let rule = self.policy.get_rules()?.get(OsStr::new(&name))?;
+                Ok(Response::Rule(Box::new(rule.to_data()?)))
+            }
+            Request::AddRule(data) => {
+                let rule = FirewallRule::from_data(&data)?;
+                self.policy.get_rules()?.add(rule)?;
+                Ok(Response::Done)
+            }
+            Request::UpdateRule(name, data) => {
+                self.policy.replace_rule(OsStr::new(&name), &data)?;
+                Ok(Response::Done)
+            }
+            Request::RemoveRule(name) => {
+                self.policy.get_rules()?.remove(OsStr::new(&name))?;
+                Ok(Response::Done)
+            }
+        }
+    }
+}
+
+#[cfg(windows)]
+impl Drop for ComWorker {
+    fn drop(&mut self) {
+        // The policy has to be released before COM is torn down.
+        unsafe { std::mem::ManuallyDrop::drop(&mut self.policy) }
+        com::runtime::deinit_runtime();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{
+        sync::Arc,
+        task::Wake,
+        thread::Thread,
+    };
+
+    /// An in-memory stand-in for the COM worker.
+    ///
+    /// Like `FirewallPolicy::replace_rule` it moves a rule to the end when an update has to re-add it.
+    #[derive(Default)]
+    struct MemoryWorker {
+        rules: Vec<FirewallRuleData>,
+        settings: FirewallProfileSettings,
+    }
+
+    fn not_found() -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::NotFound, "no such rule")
+    }
+
+    impl Worker for MemoryWorker {
+        fn handle(&mut self, request: Request) -> Result<Response, std::io::Error> {
+            match request {
+                Request::CurrentProfileTypes => {
+                    Ok(Response::ProfileTypes(FirewallProfile::PRIVATE))
+                }
+                Request::GetProfileSettings(_) => Ok(Response::ProfileSettings(self.settings)),
+                Request::SetProfileSettings(_, settings) => {
+                    self.settings = settings;
+                    Ok(Response::Done)
+                }
+                Request::RuleCount => Ok(Response::Count(self.rules.len())),
+                Request::ListRules => Ok(Response::Rules(self.rules.clone())),
+                Request::GetRule(name) => self
+                    .rules
+                    .iter()
+                    .find(|rule| rule.name == name)
+                    .map(|rule| Response::Rule(Box::new(rule.clone())))
+                    .ok_or_else(not_found),
+                Request::AddRule(rule) => {
+                    self.rules.push(rule);
+                    Ok(Response::Done)
+                }
+                Request::UpdateRule(name, data) => {
+                    let index = self
+                        .rules
+                        .iter()
+                        .position(|rule| rule.name == name)
+                        .ok_or_else(not_found)?;
+                    if self.rules[index].needs_replacing(&data) {
+                        self.rules.remove(index);
+                        self.rules.push(data);
+                    } else {
+                        self.rules[index] = data;
+                    }
+                    Ok(Response::Done)
+                }
+                Request::RemoveRule(name) => {
+                    let index = self
+                        .rules
+                        .iter()
+                        .position(|rule| rule.name == name)
+                        .ok_or_else(not_found)?;
+                    self.rules.remove(index);
+                    Ok(Response::Done)
+                }
+            }
+        }
+    }
+
+    struct ThreadWaker(Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = Box::pin(future);
+        let waker = Waker::from(Arc::new(ThreadWaker(std::thread::current())));
+        let mut cx = Context::from_waker(&waker);
+
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => std::thread::park(),
+            }
+        }
+    }
+
+    /// A worker that holds on to its first request until the gate opens.
+    struct GatedWorker(Option<std::sync::mpsc::Receiver<()>>);
+
+    impl Worker for GatedWorker {
+        fn handle(&mut self, _: Request) -> Result<Response, std::io::Error> {
+            if let Some(gate) = self.0.take() {
+                let _ = gate.recv();
+            }
+            Ok(Response::Count(0))
+        }
+    }
+
+    fn assert_send_sync_clone<T: Send + Sync + Clone>() {}
+
+    #[test]
+    fn queueing_never_blocks() {
+        let (open, gate) = channel();
+        let session = FirewallSession::spawn(move || Ok(GatedWorker(Some(gate)))).unwrap();
+
+        // Far more requests than a bounded queue would hold while the worker is busy.
+        let pending: Vec<_> = (0..1000)
+            .map(|_| session.request(Request::RuleCount))
+            .collect();
+        open.send(()).unwrap();
+        for response in pending {
+            assert_eq!(block_on(response).unwrap(), Response::Count(0));
+        }
+    }
+
+    #[test]
+    fn session_is_send_sync_clone() {
+        assert_send_sync_clone::<FirewallSession>();
+    }
+
+    #[test]
+    fn blocking_round_trip() {
+        let session = FirewallSession::spawn(|| Ok(MemoryWorker::default())).unwrap();
+
+        assert_eq!(session.rule_count().unwrap(), 0);
+        session.add_rule(FirewallRuleData::new("test")).unwrap();
+        assert_eq!(session.rule_count().unwrap(), 1);
+
+        let mut rule = session.get_rule("test").unwrap();
+        assert_eq!(rule, FirewallRuleData::new("test"));
+
+        rule.local_ports = Some("80".into());
+        session.update_rule("test", rule.clone()).unwrap();
+        assert_eq!(session.rules().unwrap(), vec![rule]);
+
+        session.remove_rule("test").unwrap();
+        assert_eq!(
+            session.get_rule("test").unwrap_err().kind(),
+            std::io::ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn update_overwrites_every_field() {
+        let session = FirewallSession::spawn(|| Ok(MemoryWorker::default())).unwrap();
+        let rule = FirewallRuleData {
+            description: Some("Web".into()),
+            local_ports: Some("80".into()),
+            interfaces: Some(vec!["Ethernet".into()]),
+            ..FirewallRuleData::new("web")
+        };
+        session.add_rule(rule).unwrap();
+        session.add_rule(FirewallRuleData::new("other")).unwrap();
+
+        session
+            .update_rule("web", FirewallRuleData::new("web"))
+            .unwrap();
+        assert_eq!(
+            session.rules().unwrap(),
+            vec![FirewallRuleData::new("other"), FirewallRuleData::new("web")]
+        );
+    }
+
+    #[test]
+    fn async_round_trip() {
+        let session = FirewallSession::spawn(|| Ok(MemoryWorker::default())).unwrap();
+
+        block_on(async {
+            let mut settings = session
+                .get_profile_settings_async(FirewallProfile::PUBLIC)
+                .await
+                .unwrap();
+            settings.block_all_inbound_traffic = true;
+            session
+                .set_profile_settings_async(FirewallProfile::PUBLIC, settings)
+                .await
+                .unwrap();
+
+            assert_eq!(
+                session
+                    .get_profile_settings_async(FirewallProfile::PUBLIC)
+                    .await
+                    .unwrap(),
+                settings
+            );
+            assert_eq!(
+                session.current_profile_types_async().await.unwrap(),
+                FirewallProfile::PRIVATE
+            );
+        });
+    }
+
+    #[test]
+    fn shared_between_threads() {
+        let session = FirewallSession::spawn(|| Ok(MemoryWorker::default())).unwrap();
+
+        let handles: Vec<_> = (0..4)
+            .map(|i| {
+                let session = session.clone();
+                std::thread::spawn(move || {
+                    session
+                        .add_rule(FirewallRuleData::new(format!("rule-{}", i)))
+                        .unwrap()
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(session.rule_count().unwrap(), 4);
+    }
+
+    #[test]
+    fn startup_error_is_returned() {
+        let error = FirewallSession::spawn(|| -> Result<MemoryWorker, _> {
+            Err(std::io::Error::other("no com"))
+        })
+        .unwrap_err();
+
+        assert_eq!(error.to_string(), "no com");
+    }
+
+    struct PanickingWorker;
+
+    impl Worker for PanickingWorker {
+        fn handle(&mut self, _request: Request) -> Result<Response, std::io::Error> {
+            panic!("worker panicked");
+        }
+    }
+
+    #[test]
+    fn dead_worker_does_not_hang() {
+        let session = FirewallSession::spawn(|| Ok(PanickingWorker)).unwrap();
+
+        assert_eq!(
+            session.rule_count().unwrap_err().kind(),
+            std::io::ErrorKind::BrokenPipe
+        );
+        assert_eq!(
+            session.rule_count().unwrap_err().kind(),
+            std::io::ErrorKind::BrokenPipe
+        );
+    }
+}
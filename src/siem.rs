@@ -0,0 +1,925 @@
+//! Firewall log records and rule changes in the formats SIEMs ingest.
+//!
+//! A `Converter` turns `firewall_log::LogRecord`s and `watch::Event`s into ArcSight CEF, IBM QRadar LEEF 1.0 or,
+//! with the `serde` feature, Elastic Common Schema JSON lines, adding what it knows about the host. A `Syslog` frames
+//! the results as RFC 5424 messages, and a `SyslogSender` sends them to a listener over UDP, or over TCP with
+//! RFC 6587 octet counting.
+
+use crate::{
+    data::protocol_name,
+    firewall_log::{
+        LogAction,
+        LogPath,
+        LogRecord,
+        LogTimestamp,
+    },
+    watch::Event,
+    FirewallRuleData,
+    FirewallRuleDirection,
+};
+use std::{
+    io::Write,
+    net::{
+        IpAddr,
+        TcpStream,
+        ToSocketAddrs,
+        UdpSocket,
+    },
+    time::SystemTime,
+};
+
+const VENDOR: &str = "Microsoft";
+const PRODUCT: &str = "Windows Firewall";
+#[cfg(feature = "serde")]
+const ECS_VERSION: &str = "8.11.0";
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// What is known about the host a log or change comes from.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HostMetadata {
+    pub hostname: Option<String>,
+    pub addresses: Vec<IpAddr>,
+    pub domain: Option<String>,
+    /// The operating system version, like "10.0.20348".
+    pub os_version: Option<String>,
+    /// How far the host's clock is ahead of UTC, in minutes, like 120 for UTC+02:00.
+    ///
+    /// The firewall logs in local time. Without the offset, times are written without a time zone.
+    pub utc_offset: Option<i32>,
+}
+
+impl HostMetadata {
+    /// The host this process runs on, as far as the environment tells: `COMPUTERNAME` and `USERDNSDOMAIN` on
+    /// Windows, `HOSTNAME` elsewhere.
+    pub fn local() -> Self {
+        let var = |name| {
+            std::env::var(name)
+                .ok()
+                .filter(|value: &String| !value.is_empty())
+        };
+        HostMetadata {
+            hostname: var("COMPUTERNAME").or_else(|| var("HOSTNAME")),
+            domain: var("USERDNSDOMAIN"),
+            ..HostMetadata::default()
+        }
+    }
+}
+
+/// The severities of RFC 5424, most severe first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+    Emergency,
+    Alert,
+    Critical,
+    Error,
+    Warning,
+    Notice,
+    Informational,
+    Debug,
+}
+
+impl Severity {
+    /// The number syslog uses, from 0 for `Emergency` to 7 for `Debug`.
+    pub fn code(self) -> u8 {
+        self as u8
+    }
+
+    /// The CEF severity, from 0 to 10 with 10 the most severe.
+    fn cef(self) -> u8 {
+        [10, 9, 8, 7, 5, 3, 1, 0][self as usize]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Format {
+    Cef,
+    Leef,
+    /// One Elastic Common Schema JSON document per line.
+    #[cfg(feature = "serde")]
+    Ecs,
+}
+
+/// A converted record or change.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    /// What happened, like "DROP" or "rule-added"; the syslog MSGID.
+    pub id: String,
+    pub severity: Severity,
+    pub text: String,
+}
+
+impl std::fmt::Display for Message {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+/// What the formats have in common, gathered from a record or change.
+#[derive(Debug, Default)]
+struct Entry<'a> {
+    id: String,
+    name: String,
+    severity: Option<Severity>,
+    /// Whether this is a change to configuration rather than traffic.
+    configuration: bool,
+    /// The ECS `event.type`s.
+    #[cfg_attr(not(feature = "serde"), allow(dead_code))]
+    types: Vec<&'static str>,
+    time: Option<LogTimestamp>,
+    action: Option<String>,
+    protocol: Option<i32>,
+    source: Option<IpAddr>,
+    destination: Option<IpAddr>,
+    source_port: Option<u16>,
+    destination_port: Option<u16>,
+    bytes: Option<u32>,
+    direction: Option<FirewallRuleDirection>,
+    pid: Option<u32>,
+    rule: Option<&'a FirewallRuleData>,
+    message: Option<String>,
+}
+
+/// Converts records and changes to one format.
+#[derive(Debug, Clone)]
+pub struct Converter {
+    format: Format,
+    host: HostMetadata,
+}
+
+impl Converter {
+    /// Make a converter with no host metadata.
+    pub fn new(format: Format) -> Self {
+        Converter {
+            format,
+            host: HostMetadata::default(),
+        }
+    }
+
+    /// The host to describe in every message.
+    pub fn host(mut self, host: HostMetadata) -> Self {
+        self.host = host;
+        self
+    }
+
+    /// Convert a log record. Dropped connections are warnings and everything else informational.
+    pub fn record(&self, record: &LogRecord) -> Message {
+        let (name, severity, types): (&str, _, &[&str]) = match &record.action {
+            Some(LogAction::Allow) => (
+                "Connection allowed",
+                Severity::Informational,
+                &["connection", "allowed"],
+            ),
+            Some(LogAction::Drop) => (
+                "Connection dropped",
+                Severity::Warning,
+                &["connection", "denied"],
+            ),
+            Some(LogAction::Open) | Some(LogAction::OpenInbound) => (
+                "Connection opened",
+                Severity::Informational,
+                &["connection", "start"],
+            ),
+            Some(LogAction::Close) => (
+                "Connection closed",
+                Severity::Informational,
+                &["connection", "end"],
+            ),
+            Some(LogAction::InfoEventsLost) => ("Log events lost", Severity::Warning, &["info"]),
+            Some(LogAction::Other(_)) | None => {
+                ("Firewall log entry", Severity::Informational, &["info"])
+            }
+        };
+        let message = match record.action {
+            Some(LogAction::InfoEventsLost) => record
+                .info
+                .as_ref()
+                .map(|lost| format!("{} events lost", lost)),
+            _ => record.info.clone(),
+        };
+        self.convert(Entry {
+            id: record
+                .action
+                .as_ref()
+                .map_or_else(|| "UNKNOWN".to_string(), LogAction::to_string),
+            name: name.to_string(),
+            severity: Some(severity),
+            types: types.to_vec(),
+            time: record.timestamp,
+            action: record.action.as_ref().map(LogAction::to_string),
+            protocol: record.protocol,
+            source: record.source_address,
+            destination: record.destination_address,
+            source_port: record.source_port,
+            destination_port: record.destination_port,
+            bytes: record.size,
+            direction: record.path.as_ref().and_then(LogPath::direction),
+            pid: record.pid,
+            message,
+            ..Entry::default()
+        })
+    }
+
+    /// Convert a change seen by a `watch::Watcher`, which happened at `time` on the host's clock. Changes are
+    /// notices.
+    pub fn event(&self, event: &Event, time: LogTimestamp) -> Message {
+        let (id, name, rule, types) = match event {
+            Event::RuleAdded(rule) => ("rule-added", "Firewall rule added", Some(rule), "creation"),
+            Event::RuleRemoved(rule) => (
+                "rule-removed",
+                "Firewall rule removed",
+                Some(rule),
+                "deletion",
+            ),
+            Event::RuleModified { new, .. } => (
+                "rule-modified",
+                "Firewall rule modified",
+                Some(&**new),
+                "change",
+            ),
+            Event::ProfileChanged { .. } => (
+                "profile-changed",
+                "Firewall profile changed",
+                None,
+                "change",
+            ),
+        };
+        self.convert(Entry {
+            id: id.to_string(),
+            name: name.to_string(),
+            severity: Some(Severity::Notice),
+            configuration: true,
+            types: vec![types],
+            time: Some(time),
+            action: rule.map(|rule| rule.action.to_string()),
+            protocol: rule.map(|rule| rule.protocol),
+            direction: rule.map(|rule| rule.direction),
+            rule,
+            message: Some(event.to_string()),
+            ..Entry::default()
+        })
+    }
+
+    fn convert(&self, entry: Entry<'_>) -> Message {
+        let text = match self.format {
+            Format::Cef => self.cef(&entry),
+            Format::Leef => self.leef(&entry),
+            #[cfg(feature = "serde")]
+            Format::Ecs => self.ecs(&entry),
+        };
+        Message {
+            id: entry.id,
+            severity: entry.severity.unwrap_or(Severity::Informational),
+            text,
+        }
+    }
+
+    fn cef(&self, entry: &Entry<'_>) -> String {
+        let severity = entry.severity.unwrap_or(Severity::Informational);
+        let mut text = format!(
+            "CEF:0|{}|{}|{}|{}|{}|{}|",
+            cef_header(VENDOR),
+            cef_header(PRODUCT),
+            cef_header(self.host.os_version.as_deref().unwrap_or_default()),
+            cef_header(&entry.id),
+            cef_header(&entry.name),
+            severity.cef()
+        );
+        let mut extension = Vec::new();
+        let mut push = |key: &str, value: String| {
+            extension.push(format!("{}={}", key, cef_value(&value)));
+        };
+        if let Some(time) = entry.time {
+            // CEF knows zones by name, so a time with a known offset is given in UTC.
+            let (time, zone) = match self.host.utc_offset {
+                Some(offset) => (utc(time, offset), " UTC"),
+                None => (time, ""),
+            };
+            push(
+                "rt",
+                format!(
+                    "{} {:02} {:04} {:02}:{:02}:{:02}{}",
+                    MONTHS[usize::from(time.month.clamp(1, 12)) - 1],
+                    time.day,
+                    time.year,
+                    time.hour,
+                    time.minute,
+                    time.second,
+                    zone
+                ),
+            );
+        }
+        if entry.configuration {
+            push("cat", "configuration".to_string());
+        }
+        if let Some(action) = &entry.action {
+            push("act", action.clone());
+        }
+        if let Some(protocol) = entry.protocol {
+            push("proto", protocol_text(protocol));
+        }
+        if let Some(source) = entry.source {
+            push("src", source.to_string());
+        }
+        if let Some(port) = entry.source_port {
+            push("spt", port.to_string());
+        }
+        if let Some(destination) = entry.destination {
+            push("dst", destination.to_string());
+        }
+        if let Some(port) = entry.destination_port {
+            push("dpt", port.to_string());
+        }
+        if let Some(direction) = entry.direction {
+            let (code, bytes) = match direction {
+                FirewallRuleDirection::Out => ("1", "out"),
+                _ => ("0", "in"),
+            };
+            push("deviceDirection", code.to_string());
+            if let Some(size) = entry.bytes {
+                push(bytes, size.to_string());
+            }
+            if let Some(pid) = entry.pid {
+                // The process is on this host: the destination of inbound traffic and the source of outbound.
+                let key = if code == "1" { "spid" } else { "dpid" };
+                push(key, pid.to_string());
+            }
+        }
+        if let Some(hostname) = &self.host.hostname {
+            push("dvchost", hostname.clone());
+        }
+        if let Some(domain) = &self.host.domain {
+            push("deviceNtDomain", domain.clone());
+        }
+        if let Some(address) = self.host.addresses.first() {
+            push("dvc", address.to_string());
+        }
+        if let Some(rule) = entry.rule {
+            push("cs1Label", "Rule Name".to_string());
+            push("cs1", rule.name.clone());
+            if let Some(id) = &rule.id {
+                push("cs2Label", "Rule ID".to_string());
+                push("cs2", id.clone());
+            }
+        }
+        if let Some(message) = &entry.message {
+            push("msg", message.clone());
+        }
+        text.push_str(&extension.join(" "));
+        text
+    }
+
+    fn leef(&self, entry: &Entry<'_>) -> String {
+        let mut text = format!(
+            "LEEF:1.0|{}|{}|{}|{}|",
+            leef_header(VENDOR),
+            leef_header(PRODUCT),
+            leef_header(self.host.os_version.as_deref().unwrap_or_default()),
+            leef_header(&entry.id)
+        );
+        let mut attributes = Vec::new();
+        let mut push = |key: &str, value: String| {
+            attributes.push(format!("{}={}", key, leef_value(&value)));
+        };
+        if let Some(time) = entry.time {
+            match self.host.utc_offset {
+                Some(offset) => {
+                    push("devTime", format!("{}{}", time, offset_text(offset)));
+                    push("devTimeFormat", "yyyy-MM-dd HH:mm:ssXXX".to_string());
+                }
+                None => {
+                    push("devTime", time.to_string());
+                    push("devTimeFormat", "yyyy-MM-dd HH:mm:ss".to_string());
+                }
+            }
+        }
+        push(
+            "cat",
+            if entry.configuration {
+                "configuration"
+            } else {
+                "network"
+            }
+            .to_string(),
+        );
+        push(
+            "sev",
+            entry
+                .severity
+                .unwrap_or(Severity::Informational)
+                .cef()
+                .to_string(),
+        );
+        push("name", entry.name.clone());
+        if let Some(action) = &entry.action {
+            push("action", action.clone());
+        }
+        if let Some(protocol) = entry.protocol {
+            push("proto", protocol_text(protocol));
+        }
+        if let Some(source) = entry.source {
+            push("src", source.to_string());
+        }
+        if let Some(port) = entry.source_port {
+            push("srcPort", port.to_string());
+        }
+        if let Some(destination) = entry.destination {
+            push("dst", destination.to_string());
+        }
+        if let Some(port) = entry.destination_port {
+            push("dstPort", port.to_string());
+        }
+        if let Some(size) = entry.bytes {
+            push("srcBytes", size.to_string());
+        }
+        if let Some(direction) = entry.direction {
+            push("direction", direction.to_string());
+        }
+        if let Some(pid) = entry.pid {
+            push("pid", pid.to_string());
+        }
+        if let Some(hostname) = &self.host.hostname {
+            push("dvchost", hostname.clone());
+        }
+        if let Some(domain) = &self.host.domain {
+            push("domain", domain.clone());
+        }
+        if let Some(address) = self.host.addresses.first() {
+            push("dvc", address.to_string());
+        }
+        if let Some(rule) = entry.rule {
+            push("ruleName", rule.name.clone());
+            if let Some(id) = &rule.id {
+                push("ruleId", id.clone());
+            }
+        }
+        if let Some(message) = &entry.message {
+            push("msg", message.clone());
+        }
+        text.push_str(&attributes.join("\t"));
+        text
+    }
+
+    #[cfg(feature = "serde")]
+    fn ecs(&self, entry: &Entry<'_>) -> String {
+        use serde_json::{
+            json,
+            Map,
+            Value,
+        };
+
+        fn object(fields: Vec<(&str, Option<Value>)>) -> Option<Value> {
+            let object: Map<String, Value> = fields
+                .into_iter()
+                .filter_map(|(key, value)| Some((key.to_string(), value?)))
+                .collect();
+            if object.is_empty() {
+                None
+            } else {
+                Some(Value::Object(object))
+            }
+        }
+
+        let category = if entry.configuration {
+            "configuration"
+        } else {
+            "network"
+        };
+        let outcome = match entry.types.get(1) {
+            Some(&"allowed") => Some("success"),
+            Some(&"denied") => Some("failure"),
+            _ => None,
+        };
+        let endpoint = |address: Option<IpAddr>, port: Option<u16>, bytes: Option<u32>| {
+            object(vec![
+                ("ip", address.map(|address| json!(address))),
+                ("port", port.map(|port| json!(port))),
+                ("bytes", bytes.map(|bytes| json!(bytes))),
+            ])
+        };
+        let document = object(vec![
+            (
+                "@timestamp",
+                entry.time.map(|time| {
+                    let offset = self.host.utc_offset.map(offset_text);
+                    json!(format!(
+                        "{}{}",
+                        time.to_string().replace(' ', "T"),
+                        offset.unwrap_or_default()
+                    ))
+                }),
+            ),
+            (
+                "message",
+                entry.message.as_ref().map(|message| json!(message)),
+            ),
+            ("ecs", Some(json!({ "version": ECS_VERSION }))),
+            (
+                "event",
+                object(vec![
+                    ("kind", Some(json!("event"))),
+                    ("category", Some(json!([category]))),
+                    ("type", Some(json!(entry.types))),
+                    ("action", Some(json!(entry.id.to_ascii_lowercase()))),
+                    ("outcome", outcome.map(|outcome| json!(outcome))),
+                    (
+                        "severity",
+                        entry.severity.map(|severity| json!(severity.code())),
+                    ),
+                    ("provider", Some(json!(PRODUCT))),
+                    ("reason", Some(json!(entry.name))),
+                    (
+                        "timezone",
+                        self.host
+                            .utc_offset
+                            .map(|offset| json!(offset_text(offset))),
+                    ),
+                ]),
+            ),
+            (
+                "network",
+                object(vec![
+                    (
+                        "transport",
+                        entry
+                            .protocol
+                            .map(|protocol| json!(protocol_text(protocol).to_ascii_lowercase())),
+                    ),
+                    (
+                        "iana_number",
+                        entry.protocol.map(|protocol| json!(protocol.to_string())),
+                    ),
+                    (
+                        "direction",
+                        entry.direction.map(|direction| match direction {
+                            FirewallRuleDirection::Out => json!("egress"),
+                            _ => json!("ingress"),
+                        }),
+                    ),
+                ]),
+            ),
+            (
+                "source",
+                endpoint(entry.source, entry.source_port, entry.bytes),
+            ),
+            (
+                "destination",
+                endpoint(entry.destination, entry.destination_port, None),
+            ),
+            (
+                "process",
+                object(vec![("pid", entry.pid.map(|pid| json!(pid)))]),
+            ),
+            (
+                "rule",
+                entry.rule.and_then(|rule| {
+                    object(vec![
+                        ("name", Some(json!(rule.name))),
+                        ("id", rule.id.as_ref().map(|id| json!(id))),
+                        ("ruleset", rule.grouping.as_ref().map(|group| json!(group))),
+                        (
+                            "description",
+                            rule.description.as_ref().map(|text| json!(text)),
+                        ),
+                    ])
+                }),
+            ),
+            (
+                "host",
+                object(vec![
+                    (
+                        "hostname",
+                        self.host.hostname.as_ref().map(|name| json!(name)),
+                    ),
+                    (
+                        "domain",
+                        self.host.domain.as_ref().map(|domain| json!(domain)),
+                    ),
+                    (
+                        "ip",
+                        Some(json!(self.host.addresses))
+                            .filter(|_| !self.host.addresses.is_empty()),
+                    ),
+                    (
+                        "os",
+                        object(vec![(
+                            "version",
+                            self.host.os_version.as_ref().map(|version| json!(version)),
+                        )]),
+                    ),
+                ]),
+            ),
+        ]);
+        document.unwrap_or_default().to_string()
+    }
+}
+
+/// Frames messages as RFC 5424 syslog messages.
+#[derive(Debug, Clone)]
+pub struct Syslog {
+    facility: u8,
+    hostname: Option<String>,
+    app_name: String,
+    process_id: Option<u32>,
+}
+
+impl Syslog {
+    /// Frame messages from this process, with the "log audit" facility.
+    pub fn new(app_name: impl Into<String>) -> Self {
+        Syslog {
+            facility: 13,
+            hostname: None,
+            app_name: app_name.into(),
+            process_id: Some(std::process::id()),
+        }
+    }
+
+    /// The facility code, from 0 to 23.
+    pub fn facility(mut self, facility: u8) -> Self {
+        self.facility = facility.min(23);
+        self
+    }
+
+    pub fn hostname(mut self, hostname: impl Into<String>) -> Self {
+        self.hostname = Some(hostname.into());
+        self
+    }
+
+    pub fn process_id(mut self, process_id: Option<u32>) -> Self {
+        self.process_id = process_id;
+        self
+    }
+
+    /// Frame a message, stamped with the current time.
+    pub fn frame(&self, message: &Message) -> String {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs() as i64);
+        self.frame_at(message, now)
+    }
+
+    /// Like `frame`, but with the time supplied by the caller in seconds since the Unix epoch.
+    pub fn frame_at(&self, message: &Message, seconds_since_epoch: i64) -> String {
+        let time = LogTimestamp::from_seconds_since_epoch(seconds_since_epoch);
+        format!(
+            "<{}>1 {}Z {} {} {} {} - {}",
+            u16::from(self.facility) * 8 + u16::from(message.severity.code()),
+            time.to_string().replace(' ', "T"),
+            header_field(self.hostname.as_deref(), 255),
+            header_field(Some(&self.app_name), 48),
+            header_field(self.process_id.map(|id| id.to_string()).as_deref(), 128),
+            header_field(Some(&message.id), 32),
+            message.text
+        )
+    }
+}
+
+#[derive(Debug)]
+enum Socket {
+    Udp(UdpSocket),
+    Tcp(TcpStream),
+}
+
+/// Sends framed messages to a syslog listener.
+#[derive(Debug)]
+pub struct SyslogSender {
+    syslog: Syslog,
+    socket: Socket,
+}
+
+impl SyslogSender {
+    /// Send each message in its own datagram.
+    pub fn udp(syslog: Syslog, address: impl ToSocketAddrs) -> Result<Self, std::io::Error> {
+        let address = address.to_socket_addrs()?.next().ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "no address to send to")
+        })?;
+        let local: IpAddr = if address.is_ipv4() {
+            [0, 0, 0, 0].into()
+        } else {
+            [0u16; 8].into()
+        };
+        let socket = UdpSocket::bind((local, 0))?;
+        socket.connect(address)?;
+        Ok(SyslogSender {
+            syslog,
+            socket: Socket::Udp(socket),
+        })
+    }
+
+    /// Send messages over a TCP connection, each prefixed with its length.
+    pub fn tcp(syslog: Syslog, address: impl ToSocketAddrs) -> Result<Self, std::io::Error> {
+        Ok(SyslogSender {
+            syslog,
+            socket: Socket::Tcp(TcpStream::connect(address)?),
+        })
+    }
+
+    pub fn send(&mut self, message: &Message) -> Result<(), std::io::Error> {
+        let framed = self.syslog.frame(message);
+        match &mut self.socket {
+            Socket::Udp(socket) => socket.send(framed.as_bytes()).map(|_| ()),
+            Socket::Tcp(stream) => write!(stream, "{} {}", framed.len(), framed),
+        }
+    }
+}
+
+/// A syslog header field: printable ASCII up to a length, or `-` if there is none.
+fn header_field(value: Option<&str>, max: usize) -> String {
+    let value: String = value
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| c.is_ascii_graphic())
+        .take(max)
+        .collect();
+    if value.is_empty() {
+        "-".to_string()
+    } else {
+        value
+    }
+}
+
+/// A time on the host's clock in UTC.
+fn utc(time: LogTimestamp, utc_offset: i32) -> LogTimestamp {
+    LogTimestamp::from_seconds_since_epoch(time.seconds_since_epoch() - i64::from(utc_offset) * 60)
+}
+
+/// An offset from UTC as RFC 3339 writes it, like "+02:00".
+fn offset_text(utc_offset: i32) -> String {
+    let sign = if utc_offset < 0 { '-' } else { '+' };
+    let minutes = utc_offset.unsigned_abs();
+    format!("{}{:02}:{:02}", sign, minutes / 60, minutes % 60)
+}
+
+fn protocol_text(protocol: i32) -> String {
+    protocol_name(protocol).map_or_else(|| protocol.to_string(), str::to_string)
+}
+
+fn cef_header(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('|', "\\|")
+        .replace(['\r', '\n'], " ")
+}
+
+fn cef_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('=', "\\=")
+        .replace("\r\n", "\\n")
+        .replace(['\r', '\n'], "\\n")
+}
+
+fn leef_header(value: &str) -> String {
+    value.replace('|', "\\|").replace(['\r', '\n', '\t'], " ")
+}
+
+/// LEEF has no escapes in attributes, so the separators are replaced with spaces.
+fn leef_value(value: &str) -> String {
+    value.replace(['\r', '\n', '\t'], " ")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{
+        io::Read,
+        net::TcpListener,
+    };
+
+    fn record() -> LogRecord {
+        LogRecord {
+            timestamp: Some("2024-05-01 13:02:59".parse().unwrap()),
+            action: Some(LogAction::Drop),
+            protocol: Some(6),
+            source_address: Some("10.0.0.5".parse().unwrap()),
+            destination_address: Some("10.0.0.1".parse().unwrap()),
+            source_port: Some(51234),
+            destination_port: Some(3389),
+            size: Some(52),
+            path: Some(LogPath::Receive),
+            pid: Some(4),
+            ..LogRecord::default()
+        }
+    }
+
+    fn host() -> HostMetadata {
+        HostMetadata {
+            hostname: Some("web01".to_string()),
+            addresses: vec!["10.0.0.1".parse().unwrap()],
+            domain: Some("corp.example".to_string()),
+            os_version: Some("10.0.20348".to_string()),
+            utc_offset: Some(120),
+        }
+    }
+
+    #[test]
+    fn converts_records_and_events() {
+        let cef = Converter::new(Format::Cef).host(host()).record(&record());
+        assert_eq!(cef.severity, Severity::Warning);
+        assert_eq!(
+            cef.text,
+            "CEF:0|Microsoft|Windows Firewall|10.0.20348|DROP|Connection dropped|5|rt=May 01 2024 11:02:59 UTC act=DROP \
+             proto=TCP src=10.0.0.5 spt=51234 dst=10.0.0.1 dpt=3389 deviceDirection=0 in=52 dpid=4 dvchost=web01 \
+             deviceNtDomain=corp.example dvc=10.0.0.1"
+        );
+
+        let leef = Converter::new(Format::Leef).record(&record());
+        assert!(leef.text.starts_with(
+            "LEEF:1.0|Microsoft|Windows Firewall||DROP|devTime=2024-05-01 13:02:59\t"
+        ));
+        let leef = Converter::new(Format::Leef).host(host()).record(&record());
+        assert!(leef.text.contains(
+            "|devTime=2024-05-01 13:02:59+02:00\tdevTimeFormat=yyyy-MM-dd HH:mm:ssXXX\t"
+        ));
+        assert!(leef.text.contains("\tsrc=10.0.0.5\tsrcPort=51234\t"));
+
+        let rule = FirewallRuleData {
+            id: Some("{web}".to_string()),
+            ..FirewallRuleData::new("Web | a=b")
+        };
+        let event = Event::RuleAdded(rule);
+        let time = "2024-05-01 13:05:00".parse().unwrap();
+        let cef = Converter::new(Format::Cef).event(&event, time);
+        assert_eq!(cef.id, "rule-added");
+        assert!(cef
+            .text
+            .starts_with("CEF:0|Microsoft|Windows Firewall||rule-added|Firewall rule added|3|"));
+        assert!(cef
+            .text
+            .contains(" cs1=Web | a\\=b cs2Label=Rule ID cs2={web} msg=rule 'Web | a\\=b' added"));
+        let leef = Converter::new(Format::Leef).event(&event, time);
+        assert!(leef.text.contains("\tcat=configuration\t"));
+        assert!(leef.text.contains("\truleName=Web | a=b\t"));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn converts_to_ecs() {
+        let ecs = Converter::new(Format::Ecs).host(host()).record(&record());
+        let document: serde_json::Value = serde_json::from_str(&ecs.text).unwrap();
+        assert_eq!(document["@timestamp"], "2024-05-01T13:02:59+02:00");
+        assert_eq!(document["event"]["timezone"], "+02:00");
+        assert_eq!(
+            document["event"]["type"],
+            serde_json::json!(["connection", "denied"])
+        );
+        assert_eq!(document["event"]["outcome"], "failure");
+        assert_eq!(document["network"]["transport"], "tcp");
+        assert_eq!(document["network"]["direction"], "ingress");
+        assert_eq!(document["source"]["port"], 51234);
+        assert_eq!(document["destination"]["ip"], "10.0.0.1");
+        assert_eq!(document["host"]["ip"], serde_json::json!(["10.0.0.1"]));
+        assert!(document.get("rule").is_none());
+        assert!(!ecs.text.contains('\n'));
+
+        let host = HostMetadata {
+            utc_offset: Some(-330),
+            ..host()
+        };
+        let ecs = Converter::new(Format::Ecs).host(host).record(&record());
+        let document: serde_json::Value = serde_json::from_str(&ecs.text).unwrap();
+        assert_eq!(document["@timestamp"], "2024-05-01T13:02:59-05:30");
+        let ecs = Converter::new(Format::Ecs).record(&record());
+        let document: serde_json::Value = serde_json::from_str(&ecs.text).unwrap();
+        assert_eq!(document["@timestamp"], "2024-05-01T13:02:59");
+        assert!(document["event"].get("timezone").is_none());
+    }
+
+    #[test]
+    fn sends_syslog() {
+        let syslog = Syslog::new("netfw").hostname("web 01").process_id(Some(42));
+        let message = Converter::new(Format::Cef).record(&record());
+        let framed = syslog.frame_at(&message, 1_714_568_579);
+        assert_eq!(
+            framed,
+            format!(
+                "<108>1 2024-05-01T13:02:59Z web01 netfw 42 DROP - {}",
+                message.text
+            )
+        );
+
+        let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut sender = SyslogSender::udp(syslog.clone(), listener.local_addr().unwrap()).unwrap();
+        sender.send(&message).unwrap();
+        let mut datagram = [0; 2048];
+        let length = listener.recv(&mut datagram).unwrap();
+        let received = std::str::from_utf8(&datagram[..length]).unwrap();
+        assert!(received.starts_with("<108>1 ") && received.ends_with(&message.text));
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut sender = SyslogSender::tcp(syslog, listener.local_addr().unwrap()).unwrap();
+        sender.send(&message).unwrap();
+        drop(sender);
+        let mut received = String::new();
+        listener
+            .accept()
+            .unwrap()
+            .0
+            .read_to_string(&mut received)
+            .unwrap();
+        let (length, framed) = received.split_at(received.find(' ').unwrap());
+        assert_eq!(length.parse::<usize>().unwrap(), framed.len() - 1);
+    }
+}
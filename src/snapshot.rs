@@ -0,0 +1,60 @@
+//! Point-in-time copies of a firewall's rules and profile settings.
+
+#[cfg(feature = "serde")]
+pub mod file;
+
+use crate::{
+    store::RuleStore,
+    FirewallProfile,
+    FirewallProfileSettings,
+    FirewallRuleData,
+};
+use std::collections::BTreeMap;
+
+/// The rules and per-profile settings of a firewall at one point in time.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Snapshot {
+    pub rules: Vec<FirewallRuleData>,
+    pub profiles: BTreeMap<FirewallProfile, FirewallProfileSettings>,
+}
+
+impl Snapshot {
+    /// Read every rule and the settings of each single profile from a store.
+    pub fn capture<S: RuleStore>(mut store: S) -> Result<Self, std::io::Error> {
+        let rules = store.rules()?;
+        let mut profiles = BTreeMap::new();
+        for profile in FirewallProfile::SINGLE.iter() {
+            profiles.insert(*profile, store.get_profile_settings(*profile)?);
+        }
+
+        Ok(Snapshot { rules, profiles })
+    }
+
+    /// Sort the rules into a stable order, so equal states compare and serialize identically.
+    ///
+    /// Rules are ordered by name, then direction, then the rest of their fields.
+    pub fn canonicalize(&mut self) {
+        self.rules.sort_by_cached_key(|rule| {
+            (
+                rule.name.clone(),
+                rule.direction.to_string(),
+                rule.field_values(),
+                rule.id.clone(),
+            )
+        });
+    }
+}
+
+/// Something that can produce snapshots on demand.
+///
+/// Every `RuleStore` is a source; tests can plug in their own.
+pub trait SnapshotSource {
+    fn snapshot(&mut self) -> Result<Snapshot, std::io::Error>;
+}
+
+impl<S: RuleStore> SnapshotSource for S {
+    fn snapshot(&mut self) -> Result<Snapshot, std::io::Error> {
+        Snapshot::capture(self)
+    }
+}
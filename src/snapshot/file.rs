@@ -0,0 +1,417 @@
+//! A self-describing, versioned file format for snapshots.
+//!
+//! Files are pretty-printed JSON with a header naming the format and its version, metadata about the host they were
+//! taken on, and the rules in canonical order, so the same state taken with the same metadata is byte-identical.
+//! Older versions are migrated on load; unknown fields are ignored so files from newer minor revisions still load,
+//! though the fingerprint of a file with unknown rule or profile fields can't be checked.
+//!
+//! Versions:
+//! 1. A bare serialized `Snapshot` with no header, as written with the `serde` feature before this format existed.
+//! 2. Adds the header, host metadata, the current profile types and a fingerprint.
+
+use crate::{
+    snapshot::Snapshot,
+    store::RuleStore,
+    FirewallProfile,
+    FirewallProfileSettings,
+    FirewallRuleData,
+};
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use serde_json::{
+    Map,
+    Value,
+};
+use std::{
+    collections::BTreeMap,
+    io::{
+        Read,
+        Write,
+    },
+    path::Path,
+};
+
+/// The value of the `format` field.
+pub const FORMAT: &str = "netfw-snapshot";
+
+/// The version this crate writes.
+pub const VERSION: u32 = 2;
+
+/// Rewrites a document from one version into the next.
+type Migration = fn(Map<String, Value>) -> Map<String, Value>;
+
+/// The migration out of each version, starting from version 1.
+const MIGRATIONS: [Migration; VERSION as usize - 1] = [v1_to_v2];
+
+/// Where and when a snapshot was taken.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HostInfo {
+    pub hostname: Option<String>,
+    pub os: Option<String>,
+    /// Seconds since the Unix epoch.
+    pub captured_at: Option<u64>,
+    /// The tool that wrote the file.
+    pub generator: Option<String>,
+}
+
+impl HostInfo {
+    /// Describe the current host, as far as it can be found out.
+    pub fn current() -> Self {
+        let captured_at = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .ok();
+
+        HostInfo {
+            hostname: hostname(),
+            os: Some(std::env::consts::OS.to_string()),
+            captured_at,
+            generator: Some(concat!("netfw ", env!("CARGO_PKG_VERSION")).to_string()),
+        }
+    }
+}
+
+fn hostname() -> Option<String> {
+    std::env::var("COMPUTERNAME")
+        .or_else(|_| std::env::var("HOSTNAME"))
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+}
+
+/// A snapshot together with everything the file format records about it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SnapshotFile {
+    pub host: HostInfo,
+    /// The profiles of the networks the host was connected to, if known.
+    pub current_profiles: Option<FirewallProfile>,
+    pub snapshot: Snapshot,
+}
+
+/// The on-disk layout of the current version.
+#[derive(Serialize, Deserialize)]
+struct Document {
+    format: String,
+    version: u32,
+    #[serde(default)]
+    fingerprint: Option<String>,
+    #[serde(default)]
+    host: HostInfo,
+    #[serde(default)]
+    current_profiles: Option<FirewallProfile>,
+    #[serde(default)]
+    profiles: BTreeMap<FirewallProfile, FirewallProfileSettings>,
+    #[serde(default)]
+    rules: Vec<FirewallRuleData>,
+}
+
+impl SnapshotFile {
+    /// Wrap a snapshot with no metadata.
+    pub fn new(snapshot: Snapshot) -> Self {
+        SnapshotFile {
+            snapshot,
+            ..Default::default()
+        }
+    }
+
+    /// Capture a store's state along with the current host's metadata.
+    pub fn capture<S: RuleStore>(mut store: S) -> Result<Self, std::io::Error> {
+        let snapshot = Snapshot::capture(&mut store)?;
+        let current_profiles = store.current_profile_types()?;
+
+        Ok(SnapshotFile {
+            host: HostInfo::current(),
+            current_profiles: Some(current_profiles),
+            snapshot,
+        })
+    }
+
+    /// The fingerprint of the snapshot. See `Snapshot::fingerprint`.
+    pub fn fingerprint(&self) -> String {
+        self.snapshot.fingerprint()
+    }
+
+    /// Encode in the current version, in canonical order.
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut snapshot = self.snapshot.clone();
+        snapshot.canonicalize();
+
+        let document = Document {
+            format: FORMAT.to_string(),
+            version: VERSION,
+            fingerprint: Some(snapshot.fingerprint()),
+            host: self.host.clone(),
+            current_profiles: self.current_profiles,
+            profiles: snapshot.profiles,
+            rules: snapshot.rules,
+        };
+
+        let mut bytes =
+            serde_json::to_vec_pretty(&document).expect("snapshot documents always serialize");
+        bytes.push(b'\n');
+        bytes
+    }
+
+    pub fn write<W: Write>(&self, mut writer: W) -> Result<(), std::io::Error> {
+        writer.write_all(&self.to_vec())
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), std::io::Error> {
+        std::fs::write(path, self.to_vec())
+    }
+
+    /// Decode a file of any supported version.
+    ///
+    /// Fails with `InvalidData` if the file is malformed, from a newer version, or does not match its fingerprint.
+    pub fn from_slice(bytes: &[u8]) -> Result<Self, std::io::Error> {
+        let value: Value = serde_json::from_slice(bytes).map_err(invalid_data)?;
+        let raw = Value::Object(migrate(value)?);
+        let document: Document = serde_json::from_value(raw.clone()).map_err(invalid_data)?;
+
+        if document.format != FORMAT {
+            return Err(invalid_data(format!(
+                "expected format '{}', found '{}'",
+                FORMAT, document.format
+            )));
+        }
+
+        let snapshot = Snapshot {
+            rules: document.rules,
+            profiles: document.profiles,
+        };
+        // A newer writer hashed fields this version drops, so its fingerprint can't be reproduced.
+        let unknown_fields = has_unknown_fields(&raw["rules"], &to_value(&snapshot.rules))
+            || has_unknown_fields(&raw["profiles"], &to_value(&snapshot.profiles));
+        if let Some(expected) = document.fingerprint.filter(|_| !unknown_fields) {
+            let actual = snapshot.fingerprint();
+            if actual != expected {
+                return Err(invalid_data(format!(
+                    "fingerprint mismatch: file says {}, contents hash to {}",
+                    expected, actual
+                )));
+            }
+        }
+
+        Ok(SnapshotFile {
+            host: document.host,
+            current_profiles: document.current_profiles,
+            snapshot,
+        })
+    }
+
+    pub fn read<R: Read>(mut reader: R) -> Result<Self, std::io::Error> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        Self::from_slice(&bytes)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, std::io::Error> {
+        Self::from_slice(&std::fs::read(path)?)
+    }
+}
+
+impl Snapshot {
+    /// A hash of the rules and profile settings, ignoring rule order, for cheap drift detection.
+    ///
+    /// Formatted like `fnv1a64:0123456789abcdef`.
+    pub fn fingerprint(&self) -> String {
+        let mut snapshot = self.clone();
+        snapshot.canonicalize();
+        let bytes = serde_json::to_vec(&snapshot).expect("snapshots always serialize");
+        format!("fnv1a64:{:016x}", fnv1a64(&bytes))
+    }
+}
+
+/// Whether `raw` has object keys missing from `known`, the same value as this version serializes it.
+fn has_unknown_fields(raw: &Value, known: &Value) -> bool {
+    match (raw, known) {
+        (Value::Object(raw), Value::Object(known)) => {
+            raw.iter().any(|(key, raw)| match known.get(key) {
+                Some(known) => has_unknown_fields(raw, known),
+                None => true,
+            })
+        }
+        (Value::Array(raw), Value::Array(known)) => raw
+            .iter()
+            .zip(known)
+            .any(|(raw, known)| has_unknown_fields(raw, known)),
+        _ => false,
+    }
+}
+
+fn to_value<T: Serialize>(value: &T) -> Value {
+    serde_json::to_value(value).expect("snapshots always serialize")
+}
+
+fn fnv1a64(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+        (hash ^ u64::from(*b)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// Bring a document of any supported version up to the current one.
+fn migrate(value: Value) -> Result<Map<String, Value>, std::io::Error> {
+    let mut document = match value {
+        Value::Object(document) => document,
+        _ => return Err(invalid_data("a snapshot file must hold a JSON object")),
+    };
+
+    let version = match document.get("version") {
+        None => 1,
+        Some(version) => version
+            .as_u64()
+            .filter(|version| (1..=u64::from(VERSION)).contains(version))
+            .ok_or_else(|| {
+                invalid_data(format!(
+                    "unsupported snapshot file version {}, expected at most {}",
+                    version, VERSION
+                ))
+            })?,
+    };
+
+    for migration in MIGRATIONS[version as usize - 1..].iter() {
+        document = migration(document);
+    }
+
+    Ok(document)
+}
+
+fn v1_to_v2(mut document: Map<String, Value>) -> Map<String, Value> {
+    document.insert("format".into(), FORMAT.into());
+    document.insert("version".into(), 2.into());
+    document
+}
+
+fn invalid_data<E>(error: E) -> std::io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    std::io::Error::new(std::io::ErrorKind::InvalidData, error)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        FirewallRuleDirection,
+        MemoryStore,
+    };
+
+    fn sample() -> SnapshotFile {
+        let mut store = MemoryStore::with_rules(vec![
+            FirewallRuleData::new("b"),
+            FirewallRuleData {
+                direction: FirewallRuleDirection::Out,
+                ..FirewallRuleData::new("a")
+            },
+            FirewallRuleData::new("a"),
+        ]);
+        store.current_profiles = FirewallProfile::PRIVATE;
+
+        SnapshotFile {
+            host: HostInfo {
+                hostname: Some("host".into()),
+                captured_at: Some(1_600_000_000),
+                ..Default::default()
+            },
+            ..SnapshotFile::capture(&mut store).unwrap()
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let file = sample();
+        assert_eq!(file.current_profiles, Some(FirewallProfile::PRIVATE));
+
+        let bytes = file.to_vec();
+        let text = std::str::from_utf8(&bytes).unwrap();
+        assert!(text.starts_with("{\n  \"format\": \"netfw-snapshot\",\n  \"version\": 2,\n"));
+
+        let loaded = SnapshotFile::from_slice(&bytes).unwrap();
+        let names: Vec<_> = loaded
+            .snapshot
+            .rules
+            .iter()
+            .map(|rule| (rule.name.as_str(), rule.direction))
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                ("a", FirewallRuleDirection::In),
+                ("a", FirewallRuleDirection::Out),
+                ("b", FirewallRuleDirection::In),
+            ]
+        );
+        assert_eq!(loaded.host, file.host);
+        assert_eq!(loaded.to_vec(), bytes);
+    }
+
+    #[test]
+    fn canonical_bytes() {
+        let file = sample();
+        let mut shuffled = file.clone();
+        shuffled.snapshot.rules.reverse();
+
+        assert_eq!(file.to_vec(), shuffled.to_vec());
+        assert_eq!(file.fingerprint(), shuffled.fingerprint());
+
+        let other_host = SnapshotFile {
+            host: HostInfo::default(),
+            ..file.clone()
+        };
+        assert_ne!(file.to_vec(), other_host.to_vec());
+        assert_eq!(file.fingerprint(), other_host.fingerprint());
+
+        let mut drifted = file.clone();
+        drifted.snapshot.rules[0].enabled = false;
+        assert_ne!(file.fingerprint(), drifted.fingerprint());
+    }
+
+    #[test]
+    fn migrates_v1() {
+        let snapshot = sample().snapshot;
+        let bytes = serde_json::to_vec(&snapshot).unwrap();
+
+        let loaded = SnapshotFile::from_slice(&bytes).unwrap();
+        assert_eq!(loaded.snapshot, snapshot);
+        assert_eq!(loaded.host, HostInfo::default());
+        assert_eq!(loaded.current_profiles, None);
+    }
+
+    #[test]
+    fn rejects_bad_files() {
+        let newer = br#"{ "format": "netfw-snapshot", "version": 3 }"#;
+        let wrong_format = br#"{ "format": "something-else", "version": 2 }"#;
+        for bytes in [&newer[..], &wrong_format[..], b"[]", b"{"].iter() {
+            assert_eq!(
+                SnapshotFile::from_slice(bytes).unwrap_err().kind(),
+                std::io::ErrorKind::InvalidData
+            );
+        }
+
+        let mut value: Value = serde_json::from_slice(&sample().to_vec()).unwrap();
+        value["rules"][0]["enabled"] = false.into();
+        let error = SnapshotFile::from_slice(&serde_json::to_vec(&value).unwrap()).unwrap_err();
+        assert!(error.to_string().starts_with("fingerprint mismatch"));
+    }
+
+    #[test]
+    fn ignores_unknown_fields() {
+        let mut value: Value = serde_json::from_slice(&sample().to_vec()).unwrap();
+        value["comment"] = "added by a newer writer".into();
+        value["host"]["uptime"] = 5.into();
+        assert!(SnapshotFile::from_slice(&serde_json::to_vec(&value).unwrap()).is_ok());
+
+        // A newer writer's fingerprint covers rule and profile fields this version drops.
+        let mut value: Value = serde_json::from_slice(&sample().to_vec()).unwrap();
+        value["rules"][0]["security"] = "authenticate".into();
+        value["profiles"]["Public"]["log_dropped"] = true.into();
+        value["fingerprint"] = "fnv1a64:0000000000000000".into();
+        let loaded = SnapshotFile::from_slice(&serde_json::to_vec(&value).unwrap()).unwrap();
+        assert_eq!(loaded.fingerprint(), sample().fingerprint());
+    }
+}
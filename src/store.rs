@@ -0,0 +1,322 @@
+//! Pluggable access to a set of firewall rules and profile settings.
+//!
+//! Higher level tools like transactions are written against `RuleStore` so they can drive COM, a `FirewallSession`,
+//! or a `MemoryStore` in tests.
+
+#[cfg(windows)]
+use crate::{
+    FirewallPolicy,
+    FirewallRule,
+};
+use crate::{
+    FirewallProfile,
+    FirewallProfileSettings,
+    FirewallRuleData,
+    FirewallSession,
+};
+use std::collections::BTreeMap;
+
+/// `HRESULT_FROM_WIN32(ERROR_FILE_NOT_FOUND)`, which COM returns when a rule name is unknown.
+const HRESULT_FILE_NOT_FOUND: i32 = 0x8007_0002_u32 as i32;
+
+/// Whether an error means the requested rule does not exist.
+pub fn is_not_found(error: &std::io::Error) -> bool {
+    error.kind() == std::io::ErrorKind::NotFound
+        || error.raw_os_error() == Some(HRESULT_FILE_NOT_FOUND)
+}
+
+fn not_found(name: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::NotFound,
+        format!("no rule named '{}'", name),
+    )
+}
+
+/// Something holding firewall rules and per-profile settings.
+///
+/// Rules are addressed by name, so names are assumed to be unique within a store.
+pub trait RuleStore {
+    fn rules(&mut self) -> Result<Vec<FirewallRuleData>, std::io::Error>;
+
+    /// Look up a rule by name, returning `None` if there is none.
+    fn get_rule(&mut self, name: &str) -> Result<Option<FirewallRuleData>, std::io::Error>;
+
+    fn add_rule(&mut self, rule: &FirewallRuleData) -> Result<(), std::io::Error>;
+
+    /// Replace the rule called `name` with `rule`, which may carry a different name.
+    fn update_rule(&mut self, name: &str, rule: &FirewallRuleData) -> Result<(), std::io::Error>;
+
+    fn remove_rule(&mut self, name: &str) -> Result<(), std::io::Error>;
+
+    fn get_profile_settings(
+        &mut self,
+        profile: FirewallProfile,
+    ) -> Result<FirewallProfileSettings, std::io::Error>;
+
+    fn set_profile_settings(
+        &mut self,
+        profile: FirewallProfile,
+        settings: &FirewallProfileSettings,
+    ) -> Result<(), std::io::Error>;
+
+    /// The profiles of the networks the host is currently connected to.
+    fn current_profile_types(&mut self) -> Result<FirewallProfile, std::io::Error>;
+}
+
+impl<S: RuleStore + ?Sized> RuleStore for &mut S {
+    fn rules(&mut self) -> Result<Vec<FirewallRuleData>, std::io::Error> {
+        (**self).rules()
+    }
+
+    fn get_rule(&mut self, name: &str) -> Result<Option<FirewallRuleData>, std::io::Error> {
+        (**self).get_rule(name)
+    }
+
+    fn add_rule(&mut self, rule: &FirewallRuleData) -> Result<(), std::io::Error> {
+        (**self).add_rule(rule)
+    }
+
+    fn update_rule(&mut self, name: &str, rule: &FirewallRuleData) -> Result<(), std::io::Error> {
+        (**self).update_rule(name, rule)
+    }
+
+    fn remove_rule(&mut self, name: &str) -> Result<(), std::io::Error> {
+        (**self).remove_rule(name)
+    }
+
+    fn get_profile_settings(
+        &mut self,
+        profile: FirewallProfile,
+    ) -> Result<FirewallProfileSettings, std::io::Error> {
+        (**self).get_profile_settings(profile)
+    }
+
+    fn set_profile_settings(
+        &mut self,
+        profile: FirewallProfile,
+        settings: &FirewallProfileSettings,
+    ) -> Result<(), std::io::Error> {
+        (**self).set_profile_settings(profile, settings)
+    }
+
+    fn current_profile_types(&mut self) -> Result<FirewallProfile, std::io::Error> {
+        (**self).current_profile_types()
+    }
+}
+
+/// A `RuleStore` that lives entirely in memory.
+///
+/// Profiles without stored settings report `FirewallProfileSettings::default()`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryStore {
+    pub rules: Vec<FirewallRuleData>,
+    pub profiles: BTreeMap<FirewallProfile, FirewallProfileSettings>,
+    pub current_profiles: FirewallProfile,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_rules(rules: Vec<FirewallRuleData>) -> Self {
+        MemoryStore {
+            rules,
+            ..Default::default()
+        }
+    }
+
+    fn position(&self, name: &str) -> Option<usize> {
+        self.rules.iter().position(|rule| rule.name == name)
+    }
+}
+
+impl RuleStore for MemoryStore {
+    fn rules(&mut self) -> Result<Vec<FirewallRuleData>, std::io::Error> {
+        Ok(self.rules.clone())
+    }
+
+    fn get_rule(&mut self, name: &str) -> Result<Option<FirewallRuleData>, std::io::Error> {
+        Ok(self.position(name).map(|i| self.rules[i].clone()))
+    }
+
+    fn add_rule(&mut self, rule: &FirewallRuleData) -> Result<(), std::io::Error> {
+        self.rules.push(rule.clone());
+        Ok(())
+    }
+
+    fn update_rule(&mut self, name: &str, rule: &FirewallRuleData) -> Result<(), std::io::Error> {
+        let i = self.position(name).ok_or_else(|| not_found(name))?;
+        self.rules[i] = rule.clone();
+        Ok(())
+    }
+
+    fn remove_rule(&mut self, name: &str) -> Result<(), std::io::Error> {
+        let i = self.position(name).ok_or_else(|| not_found(name))?;
+        self.rules.remove(i);
+        Ok(())
+    }
+
+    fn get_profile_settings(
+        &mut self,
+        profile: FirewallProfile,
+    ) -> Result<FirewallProfileSettings, std::io::Error> {
+        Ok(self.profiles.get(&profile).copied().unwrap_or_default())
+    }
+
+    fn set_profile_settings(
+        &mut self,
+        profile: FirewallProfile,
+        settings: &FirewallProfileSettings,
+    ) -> Result<(), std::io::Error> {
+        self.profiles.insert(profile, *settings);
+        Ok(())
+    }
+
+    fn current_profile_types(&mut self) -> Result<FirewallProfile, std::io::Error> {
+        Ok(self.current_profiles)
+    }
+}
+
+impl RuleStore for FirewallSession {
+    fn rules(&mut self) -> Result<Vec<FirewallRuleData>, std::io::Error> {
+        FirewallSession::rules(self)
+    }
+
+    fn get_rule(&mut self, name: &str) -> Result<Option<FirewallRuleData>, std::io::Error> {
+        match FirewallSession::get_rule(self, name) {
+            Ok(rule) => Ok(Some(rule)),
+            Err(e) if is_not_found(&e) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn add_rule(&mut self, rule: &FirewallRuleData) -> Result<(), std::io::Error> {
+        FirewallSession::add_rule(self, rule.clone())
+    }
+
+    fn update_rule(&mut self, name: &str, rule: &FirewallRuleData) -> Result<(), std::io::Error> {
+        FirewallSession::update_rule(self, name, rule.clone())
+    }
+
+    fn remove_rule(&mut self, name: &str) -> Result<(), std::io::Error> {
+        FirewallSession::remove_rule(self, name)
+    }
+
+    fn get_profile_settings(
+        &mut self,
+        profile: FirewallProfile,
+    ) -> Result<FirewallProfileSettings, std::io::Error> {
+        FirewallSession::get_profile_settings(self, profile)
+    }
+
+    fn set_profile_settings(
+        &mut self,
+        profile: FirewallProfile,
+        settings: &FirewallProfileSettings,
+    ) -> Result<(), std::io::Error> {
+        FirewallSession::set_profile_settings(self, profile, *settings)
+    }
+
+    fn current_profile_types(&mut self) -> Result<FirewallProfile, std::io::Error> {
+        FirewallSession::current_profile_types(self)
+    }
+}
+
+#[cfg(windows)]
+impl RuleStore for FirewallPolicy {
+    fn rules(&mut self) -> Result<Vec<FirewallRuleData>, std::io::Error> {
+        self.get_rules()?
+            .iter()?
+            .map(|rule| rule?.to_data())
+            .collect()
+    }
+
+    fn get_rule(&mut self, name: &str) -> Result<Option<FirewallRuleData>, std::io::Error> {
+        match self.get_rules()?.get(std::ffi::OsStr::new(name)) {
+            Ok(rule) => Ok(Some(rule.to_data()?)),
+            Err(e) if is_not_found(&e) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn add_rule(&mut self, rule: &FirewallRuleData) -> Result<(), std::io::Error> {
+        self.get_rules()?.add(FirewallRule::from_data(rule)?)
+    }
+
+    fn update_rule(&mut self, name: &str, rule: &FirewallRuleData) -> Result<(), std::io::Error> {
+        self.replace_rule(std::ffi::OsStr::new(name), rule)
+    }
+
+    fn remove_rule(&mut self, name: &str) -> Result<(), std::io::Error> {
+        self.get_rules()?.remove(std::ffi::OsStr::new(name))
+    }
+
+    fn get_profile_settings(
+        &mut self,
+        profile: FirewallProfile,
+    ) -> Result<FirewallProfileSettings, std::io::Error> {
+        FirewallPolicy::get_profile_settings(self, profile)
+    }
+
+    fn set_profile_settings(
+        &mut self,
+        profile: FirewallProfile,
+        settings: &FirewallProfileSettings,
+    ) -> Result<(), std::io::Error> {
+        FirewallPolicy::set_profile_settings(self, profile, settings)
+    }
+
+    fn current_profile_types(&mut self) -> Result<FirewallProfile, std::io::Error> {
+        FirewallPolicy::current_profile_types(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn memory_store_round_trip() {
+        let mut store = MemoryStore::new();
+        store.add_rule(&FirewallRuleData::new("a")).unwrap();
+        assert_eq!(
+            store.get_rule("a").unwrap(),
+            Some(FirewallRuleData::new("a"))
+        );
+        assert_eq!(store.get_rule("b").unwrap(), None);
+
+        store.update_rule("a", &FirewallRuleData::new("b")).unwrap();
+        assert_eq!(store.get_rule("a").unwrap(), None);
+        assert_eq!(store.rules().unwrap(), vec![FirewallRuleData::new("b")]);
+
+        assert!(is_not_found(&store.remove_rule("a").unwrap_err()));
+        store.remove_rule("b").unwrap();
+        assert!(store.rules().unwrap().is_empty());
+    }
+
+    #[test]
+    fn memory_store_profile_defaults() {
+        let mut store = MemoryStore::new();
+        assert_eq!(
+            store.get_profile_settings(FirewallProfile::DOMAIN).unwrap(),
+            FirewallProfileSettings::default()
+        );
+
+        let settings = FirewallProfileSettings {
+            firewall_enabled: false,
+            ..Default::default()
+        };
+        store
+            .set_profile_settings(FirewallProfile::DOMAIN, &settings)
+            .unwrap();
+        assert_eq!(
+            store.get_profile_settings(FirewallProfile::DOMAIN).unwrap(),
+            settings
+        );
+        assert_eq!(
+            store.get_profile_settings(FirewallProfile::PUBLIC).unwrap(),
+            FirewallProfileSettings::default()
+        );
+    }
+}
@@ -0,0 +1,559 @@
+//! All-or-nothing batches of rule and policy changes.
+//!
+//! A `Transaction` records the prior state of everything it touches while applying its operations.
+//! If an operation fails, or the applied transaction is aborted, the recorded state is restored in reverse order.
+
+use crate::{
+    store::RuleStore,
+    FirewallProfile,
+    FirewallProfileSettings,
+    FirewallRuleData,
+};
+
+/// A single change in a `Transaction`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Operation {
+    /// Add a rule. Fails if a rule with its name already exists.
+    AddRule(FirewallRuleData),
+    RemoveRule(String),
+    /// Replace the rule with the given name.
+    UpdateRule(String, FirewallRuleData),
+    SetProfileSettings(FirewallProfile, FirewallProfileSettings),
+}
+
+/// What has to be done to take back an applied `Operation`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Undo {
+    /// Remove a rule that was added.
+    RemoveRule(String),
+    /// Put back a rule that was removed.
+    AddRule(FirewallRuleData),
+    /// Put back the prior state of a rule that was updated, which is currently called `name`.
+    RestoreRule {
+        name: String,
+        prior: FirewallRuleData,
+    },
+    /// Put back the prior settings of a profile.
+    SetProfileSettings(FirewallProfile, FirewallProfileSettings),
+}
+
+impl Undo {
+    fn run<S: RuleStore>(&self, store: &mut S) -> Result<(), std::io::Error> {
+        match self {
+            Undo::RemoveRule(name) => store.remove_rule(name),
+            Undo::AddRule(rule) => store.add_rule(rule),
+            Undo::RestoreRule { name, prior } => store.update_rule(name, prior),
+            Undo::SetProfileSettings(profile, settings) => {
+                store.set_profile_settings(*profile, settings)
+            }
+        }
+    }
+}
+
+/// An undo step that could not be run.
+#[derive(Debug)]
+pub struct RollbackFailure {
+    pub undo: Undo,
+    pub error: std::io::Error,
+}
+
+/// The error returned when a transaction fails to apply.
+///
+/// By the time this is returned everything that could be rolled back has been.
+#[derive(Debug)]
+pub struct TransactionError {
+    /// The index of the operation that failed.
+    pub index: usize,
+    pub operation: Operation,
+    pub error: std::io::Error,
+    /// Undo steps that failed, in the order they were attempted. Empty if the rollback was clean.
+    pub rollback_failures: Vec<RollbackFailure>,
+}
+
+impl std::fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "operation {} ({:?}) failed: {}",
+            self.index, self.operation, self.error
+        )?;
+
+        if !self.rollback_failures.is_empty() {
+            write!(
+                f,
+                "; {} change(s) could not be rolled back",
+                self.rollback_failures.len()
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for TransactionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// A batch of operations applied as a unit.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Transaction {
+    operations: Vec<Operation>,
+}
+
+impl Transaction {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, operation: Operation) -> &mut Self {
+        self.operations.push(operation);
+        self
+    }
+
+    pub fn add_rule(&mut self, rule: FirewallRuleData) -> &mut Self {
+        self.push(Operation::AddRule(rule))
+    }
+
+    pub fn remove_rule(&mut self, name: impl Into<String>) -> &mut Self {
+        self.push(Operation::RemoveRule(name.into()))
+    }
+
+    pub fn update_rule(&mut self, name: impl Into<String>, rule: FirewallRuleData) -> &mut Self {
+        self.push(Operation::UpdateRule(name.into(), rule))
+    }
+
+    pub fn set_profile_settings(
+        &mut self,
+        profile: FirewallProfile,
+        settings: FirewallProfileSettings,
+    ) -> &mut Self {
+        self.push(Operation::SetProfileSettings(profile, settings))
+    }
+
+    pub fn operations(&self) -> &[Operation] {
+        &self.operations
+    }
+
+    pub fn len(&self) -> usize {
+        self.operations.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.operations.is_empty()
+    }
+
+    /// Apply every operation in order.
+    ///
+    /// If one fails, everything applied so far is rolled back and the failure is returned.
+    /// On success the returned `AppliedTransaction` must be committed, or it is rolled back when dropped.
+    pub fn apply<S: RuleStore>(
+        self,
+        mut store: S,
+    ) -> Result<AppliedTransaction<S>, Box<TransactionError>> {
+        let mut undo_log = Vec::with_capacity(self.operations.len());
+
+        for (index, operation) in self.operations.into_iter().enumerate() {
+            if let Err(error) = apply_one(&mut store, &operation, &mut undo_log) {
+                let rollback_failures = rollback(&mut store, &mut undo_log);
+                return Err(Box::new(TransactionError {
+                    index,
+                    operation,
+                    error,
+                    rollback_failures,
+                }));
+            }
+        }
+
+        Ok(AppliedTransaction {
+            store: Some(store),
+            undo_log,
+        })
+    }
+}
+
+impl Extend<Operation> for Transaction {
+    fn extend<I: IntoIterator<Item = Operation>>(&mut self, iter: I) {
+        self.operations.extend(iter);
+    }
+}
+
+impl std::iter::FromIterator<Operation> for Transaction {
+    fn from_iter<I: IntoIterator<Item = Operation>>(iter: I) -> Self {
+        Transaction {
+            operations: iter.into_iter().collect(),
+        }
+    }
+}
+
+/// Record the prior state an operation touches, then run it.
+///
+/// The undo step is only logged once the operation has succeeded.
+fn apply_one<S: RuleStore>(
+    store: &mut S,
+    operation: &Operation,
+    undo_log: &mut Vec<Undo>,
+) -> Result<(), std::io::Error> {
+    match operation {
+        Operation::AddRule(rule) => {
+            // Undoing the add removes the rule by name, which must then be the added one.
+            if store.get_rule(&rule.name)?.is_some() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::AlreadyExists,
+                    format!("a rule named '{}' already exists", rule.name),
+                ));
+            }
+            store.add_rule(rule)?;
+            undo_log.push(Undo::RemoveRule(rule.name.clone()));
+        }
+        Operation::RemoveRule(name) => {
+            let prior = store.get_rule(name)?.ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("no rule named '{}'", name),
+                )
+            })?;
+            store.remove_rule(name)?;
+            undo_log.push(Undo::AddRule(prior));
+        }
+        Operation::UpdateRule(name, rule) => {
+            let prior = store.get_rule(name)?.ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("no rule named '{}'", name),
+                )
+            })?;
+            store.update_rule(name, rule)?;
+            undo_log.push(Undo::RestoreRule {
+                name: rule.name.clone(),
+                prior,
+            });
+        }
+        Operation::SetProfileSettings(profile, settings) => {
+            let prior = store.get_profile_settings(*profile)?;
+            store.set_profile_settings(*profile, settings)?;
+            undo_log.push(Undo::SetProfileSettings(*profile, prior));
+        }
+    }
+
+    Ok(())
+}
+
+/// Run the undo log backwards, draining it. Every step is attempted even if an earlier one fails.
+fn rollback<S: RuleStore>(store: &mut S, undo_log: &mut Vec<Undo>) -> Vec<RollbackFailure> {
+    let mut failures = Vec::new();
+
+    while let Some(undo) = undo_log.pop() {
+        if let Err(error) = undo.run(store) {
+            failures.push(RollbackFailure { undo, error });
+        }
+    }
+
+    failures
+}
+
+/// A transaction whose operations have all been applied.
+///
+/// Call `commit` to keep the changes or `abort` to take them back. Dropping it without doing either aborts it,
+/// though any rollback failures are then lost.
+#[derive(Debug)]
+pub struct AppliedTransaction<S: RuleStore> {
+    // Only `None` once committed or aborted.
+    store: Option<S>,
+    undo_log: Vec<Undo>,
+}
+
+impl<S: RuleStore> AppliedTransaction<S> {
+    /// The steps that `abort` would run, in the order they were recorded.
+    pub fn undo_log(&self) -> &[Undo] {
+        &self.undo_log
+    }
+
+    /// Keep the changes, handing back the store.
+    pub fn commit(mut self) -> S {
+        self.undo_log.clear();
+        self.store.take().expect("store")
+    }
+
+    /// Take back every change, in reverse order.
+    ///
+    /// Every undo step is attempted; the ones that failed are returned.
+    pub fn abort(mut self) -> Result<S, (S, Vec<RollbackFailure>)> {
+        let mut store = self.store.take().expect("store");
+        let failures = rollback(&mut store, &mut self.undo_log);
+
+        if failures.is_empty() {
+            Ok(store)
+        } else {
+            Err((store, failures))
+        }
+    }
+}
+
+impl<S: RuleStore> Drop for AppliedTransaction<S> {
+    fn drop(&mut self) {
+        if let Some(mut store) = self.store.take() {
+            rollback(&mut store, &mut self.undo_log);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        ownership::Ownership,
+        store::MemoryStore,
+    };
+
+    /// Wraps a `MemoryStore`, failing the writes whose index is in `fail_writes`.
+    #[derive(Debug, Default)]
+    struct FailingStore {
+        inner: MemoryStore,
+        writes: usize,
+        fail_writes: Vec<usize>,
+    }
+
+    impl FailingStore {
+        fn write(&mut self) -> Result<(), std::io::Error> {
+            let write = self.writes;
+            self.writes += 1;
+
+            if self.fail_writes.contains(&write) {
+                Err(std::io::Error::other(format!(
+                    "injected failure on write {}",
+                    write
+                )))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    impl RuleStore for FailingStore {
+        fn rules(&mut self) -> Result<Vec<FirewallRuleData>, std::io::Error> {
+            self.inner.rules()
+        }
+
+        fn get_rule(&mut self, name: &str) -> Result<Option<FirewallRuleData>, std::io::Error> {
+            self.inner.get_rule(name)
+        }
+
+        fn add_rule(&mut self, rule: &FirewallRuleData) -> Result<(), std::io::Error> {
+            self.write()?;
+            self.inner.add_rule(rule)
+        }
+
+        fn update_rule(
+            &mut self,
+            name: &str,
+            rule: &FirewallRuleData,
+        ) -> Result<(), std::io::Error> {
+            self.write()?;
+            self.inner.update_rule(name, rule)
+        }
+
+        fn remove_rule(&mut self, name: &str) -> Result<(), std::io::Error> {
+            self.write()?;
+            self.inner.remove_rule(name)
+        }
+
+        fn get_profile_settings(
+            &mut self,
+            profile: FirewallProfile,
+        ) -> Result<FirewallProfileSettings, std::io::Error> {
+            self.inner.get_profile_settings(profile)
+        }
+
+        fn set_profile_settings(
+            &mut self,
+            profile: FirewallProfile,
+            settings: &FirewallProfileSettings,
+        ) -> Result<(), std::io::Error> {
+            self.write()?;
+            self.inner.set_profile_settings(profile, settings)
+        }
+
+        fn current_profile_types(&mut self) -> Result<FirewallProfile, std::io::Error> {
+            self.inner.current_profile_types()
+        }
+    }
+
+    fn rule(name: &str, ports: &str) -> FirewallRuleData {
+        FirewallRuleData {
+            local_ports: Some(ports.into()),
+            ..FirewallRuleData::new(name)
+        }
+    }
+
+    fn initial_store() -> MemoryStore {
+        MemoryStore::with_rules(vec![rule("keep", "1"), rule("old", "2")])
+    }
+
+    fn batch() -> Transaction {
+        let mut transaction = Transaction::new();
+        transaction
+            .add_rule(rule("new", "3"))
+            .remove_rule("old")
+            .update_rule("keep", rule("kept", "4"))
+            .set_profile_settings(
+                FirewallProfile::PUBLIC,
+                FirewallProfileSettings {
+                    block_all_inbound_traffic: true,
+                    ..Default::default()
+                },
+            );
+        transaction
+    }
+
+    #[test]
+    fn commit_keeps_changes() {
+        let mut store = initial_store();
+        batch().apply(&mut store).unwrap().commit();
+
+        assert_eq!(store.rules, vec![rule("kept", "4"), rule("new", "3")]);
+        assert!(store.profiles[&FirewallProfile::PUBLIC].block_all_inbound_traffic);
+    }
+
+    #[test]
+    fn abort_restores_everything() {
+        let mut store = initial_store();
+        let applied = batch().apply(&mut store).unwrap();
+        assert_eq!(applied.undo_log().len(), 4);
+        applied.abort().unwrap();
+
+        assert_eq!(store.rules.len(), 2);
+        assert_eq!(store.get_rule("keep").unwrap(), Some(rule("keep", "1")));
+        assert_eq!(store.get_rule("old").unwrap(), Some(rule("old", "2")));
+        assert!(
+            !store
+                .get_profile_settings(FirewallProfile::PUBLIC)
+                .unwrap()
+                .block_all_inbound_traffic
+        );
+    }
+
+    #[test]
+    fn drop_aborts() {
+        let mut store = initial_store();
+        drop(batch().apply(&mut store).unwrap());
+
+        assert_eq!(store.get_rule("new").unwrap(), None);
+        assert_eq!(store.get_rule("keep").unwrap(), Some(rule("keep", "1")));
+    }
+
+    #[test]
+    fn failure_rolls_back() {
+        for fail_write in 0..4 {
+            let mut store = FailingStore {
+                inner: initial_store(),
+                fail_writes: vec![fail_write],
+                ..Default::default()
+            };
+
+            let error = batch().apply(&mut store).unwrap_err();
+            assert_eq!(error.index, fail_write);
+            assert!(error.rollback_failures.is_empty());
+
+            let mut rules = store.inner.rules.clone();
+            rules.sort_by(|a, b| a.name.cmp(&b.name));
+            assert_eq!(rules, vec![rule("keep", "1"), rule("old", "2")]);
+            assert!(
+                !store
+                    .inner
+                    .get_profile_settings(FirewallProfile::PUBLIC)
+                    .unwrap()
+                    .block_all_inbound_traffic
+            );
+        }
+    }
+
+    #[test]
+    fn rollback_removes_ownership_marker() {
+        let plain = FirewallRuleData {
+            description: Some("Web".into()),
+            ..rule("web", "80")
+        };
+        let mut marked = plain.clone();
+        marked.set_ownership(&Ownership::new("agent-x"));
+        let mut store = MemoryStore::with_rules(vec![plain.clone()]);
+
+        let mut transaction = Transaction::new();
+        transaction.update_rule("web", marked);
+        transaction.apply(&mut store).unwrap().abort().unwrap();
+
+        let restored = store.get_rule("web").unwrap().unwrap();
+        assert_eq!(restored.description.as_deref(), Some("Web"));
+        assert_eq!(restored.ownership(), None);
+    }
+
+    #[test]
+    fn missing_rule_fails() {
+        let mut store = initial_store();
+        let mut transaction = Transaction::new();
+        transaction
+            .add_rule(rule("new", "3"))
+            .remove_rule("missing");
+
+        let error = transaction.apply(&mut store).unwrap_err();
+        assert_eq!(error.index, 1);
+        assert_eq!(error.error.kind(), std::io::ErrorKind::NotFound);
+        assert_eq!(store, initial_store());
+    }
+
+    #[test]
+    fn duplicate_add_fails() {
+        let mut store = initial_store();
+        let mut transaction = Transaction::new();
+        transaction.remove_rule("keep").add_rule(rule("old", "3"));
+
+        let error = transaction.apply(&mut store).unwrap_err();
+        assert_eq!(error.index, 1);
+        assert_eq!(error.error.kind(), std::io::ErrorKind::AlreadyExists);
+        assert!(error.rollback_failures.is_empty());
+        assert_eq!(store.get_rule("keep").unwrap(), Some(rule("keep", "1")));
+        assert_eq!(store.get_rule("old").unwrap(), Some(rule("old", "2")));
+        assert_eq!(store.rules.len(), 2);
+    }
+
+    #[test]
+    fn rollback_failures_are_reported() {
+        let mut store = FailingStore {
+            inner: initial_store(),
+            // Writes 0 and 1 add the rules, 2 and 3 are the rollback.
+            fail_writes: vec![2],
+            ..Default::default()
+        };
+        let mut transaction = Transaction::new();
+        transaction
+            .add_rule(rule("a", "5"))
+            .add_rule(rule("b", "6"))
+            .remove_rule("missing");
+
+        let error = transaction.apply(&mut store).unwrap_err();
+        assert_eq!(error.index, 2);
+        assert_eq!(error.rollback_failures.len(), 1);
+        assert_eq!(
+            error.rollback_failures[0].undo,
+            Undo::RemoveRule("b".into())
+        );
+        assert_eq!(store.inner.get_rule("a").unwrap(), None);
+        assert_eq!(store.inner.get_rule("b").unwrap(), Some(rule("b", "6")));
+    }
+
+    #[test]
+    fn abort_reports_failures() {
+        let mut store = FailingStore {
+            inner: initial_store(),
+            fail_writes: vec![1],
+            ..Default::default()
+        };
+        let mut transaction = Transaction::new();
+        transaction.add_rule(rule("a", "5"));
+
+        let (_, failures) = transaction.apply(&mut store).unwrap().abort().unwrap_err();
+        assert_eq!(failures.len(), 1);
+        assert!(store.inner.get_rule("a").unwrap().is_some());
+    }
+}
@@ -0,0 +1,403 @@
+//! Polling for changes made to the firewall.
+//!
+//! A `Watcher` periodically takes a `Snapshot` from a `SnapshotSource` and compares it with the last one it reported,
+//! emitting typed `Event`s for added, removed and modified rules and changed profile settings.
+//! A change is only reported once it has held still for the debounce period, so bursts of edits arrive together.
+
+use crate::{
+    data::FieldChange,
+    diff::diff_rules,
+    snapshot::{
+        Snapshot,
+        SnapshotSource,
+    },
+    FirewallProfile,
+    FirewallProfileSettings,
+    FirewallRuleData,
+};
+use std::{
+    sync::{
+        atomic::{
+            AtomicBool,
+            Ordering,
+        },
+        mpsc::Sender,
+        Arc,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+/// A change observed between two snapshots.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    RuleAdded(FirewallRuleData),
+    RuleRemoved(FirewallRuleData),
+    RuleModified {
+        old: Box<FirewallRuleData>,
+        new: Box<FirewallRuleData>,
+        changes: Vec<FieldChange>,
+    },
+    ProfileChanged {
+        profile: FirewallProfile,
+        old: FirewallProfileSettings,
+        new: FirewallProfileSettings,
+        changes: Vec<FieldChange>,
+    },
+}
+
+impl std::fmt::Display for Event {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Event::RuleAdded(rule) => write!(f, "rule '{}' added", rule.name),
+            Event::RuleRemoved(rule) => write!(f, "rule '{}' removed", rule.name),
+            Event::RuleModified { new, changes, .. } => {
+                write!(f, "rule '{}' modified", new.name)?;
+                for change in changes.iter() {
+                    write!(f, "; {}", change)?;
+                }
+                Ok(())
+            }
+            Event::ProfileChanged {
+                profile, changes, ..
+            } => {
+                write!(f, "profile {} changed", profile)?;
+                for change in changes.iter() {
+                    write!(f, "; {}", change)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Compute the events that turn `old` into `new`.
+///
+/// Rules are matched as `diff::diff_rules` does, so changes in spelling alone are not reported.
+pub fn diff_snapshots(old: &Snapshot, new: &Snapshot) -> Vec<Event> {
+    let rules = diff_rules(&old.rules, &new.rules);
+    let mut events: Vec<_> = rules.added.into_iter().map(Event::RuleAdded).collect();
+    events.extend(rules.removed.into_iter().map(Event::RuleRemoved));
+    events.extend(
+        rules
+            .modified
+            .into_iter()
+            .map(|change| Event::RuleModified {
+                old: Box::new(change.old),
+                new: Box::new(change.new),
+                changes: change.changes,
+            }),
+    );
+
+    for (profile, new_settings) in new.profiles.iter() {
+        if let Some(old_settings) = old.profiles.get(profile) {
+            let changes = old_settings.changes_to(new_settings);
+            if !changes.is_empty() {
+                events.push(Event::ProfileChanged {
+                    profile: *profile,
+                    old: *old_settings,
+                    new: *new_settings,
+                    changes,
+                });
+            }
+        }
+    }
+
+    events
+}
+
+/// A change that has been seen but not yet reported.
+#[derive(Debug)]
+struct Pending {
+    snapshot: Snapshot,
+    since: Instant,
+}
+
+/// Polls a `SnapshotSource` and reports what changed.
+#[derive(Debug)]
+pub struct Watcher<S> {
+    source: S,
+    interval: Duration,
+    debounce: Duration,
+    baseline: Option<Snapshot>,
+    pending: Option<Pending>,
+}
+
+impl<S: SnapshotSource> Watcher<S> {
+    /// Make a watcher polling every 5 seconds with no debounce.
+    pub fn new(source: S) -> Self {
+        Watcher {
+            source,
+            interval: Duration::from_secs(5),
+            debounce: Duration::from_secs(0),
+            baseline: None,
+            pending: None,
+        }
+    }
+
+    /// How long `run` and `spawn` sleep between polls.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// How long a change has to stay put before it is reported.
+    pub fn debounce(mut self, debounce: Duration) -> Self {
+        self.debounce = debounce;
+        self
+    }
+
+    /// The last state that was reported, or the first one seen.
+    pub fn baseline(&self) -> Option<&Snapshot> {
+        self.baseline.as_ref()
+    }
+
+    /// Take a snapshot now and return any changes that are due.
+    ///
+    /// The first poll only records a baseline.
+    pub fn poll(&mut self) -> Result<Vec<Event>, std::io::Error> {
+        self.poll_at(Instant::now())
+    }
+
+    /// Like `poll`, but with the current time supplied by the caller.
+    pub fn poll_at(&mut self, now: Instant) -> Result<Vec<Event>, std::io::Error> {
+        let snapshot = self.source.snapshot()?;
+
+        let baseline = match self.baseline.as_ref() {
+            Some(baseline) => baseline,
+            None => {
+                self.baseline = Some(snapshot);
+                return Ok(Vec::new());
+            }
+        };
+
+        if *baseline == snapshot {
+            // Whatever was pending has been undone.
+            self.pending = None;
+            return Ok(Vec::new());
+        }
+
+        let since = match self.pending.take() {
+            Some(pending) if pending.snapshot == snapshot => pending.since,
+            _ => now,
+        };
+
+        if now.saturating_duration_since(since) >= self.debounce {
+            let events = diff_snapshots(baseline, &snapshot);
+            self.baseline = Some(snapshot);
+            Ok(events)
+        } else {
+            self.pending = Some(Pending { snapshot, since });
+            Ok(Vec::new())
+        }
+    }
+
+    /// Poll forever on the current thread, handing each event to `on_event`.
+    ///
+    /// Returns when `on_event` returns `false` or a snapshot fails.
+    pub fn run<F>(&mut self, mut on_event: F) -> Result<(), std::io::Error>
+    where
+        F: FnMut(Event) -> bool,
+    {
+        loop {
+            for event in self.poll()? {
+                if !on_event(event) {
+                    return Ok(());
+                }
+            }
+            std::thread::sleep(self.interval);
+        }
+    }
+}
+
+impl<S: SnapshotSource + Send + 'static> Watcher<S> {
+    /// Poll on a new thread, sending events down a channel.
+    ///
+    /// The thread stops when asked to through the returned handle, when a snapshot fails, or when it next has an
+    /// event to send after the receiver is dropped. A channel only reports a dropped receiver on sending, so a
+    /// watcher that sees no changes keeps polling until it is stopped.
+    pub fn spawn(mut self, sender: Sender<Event>) -> WatchHandle {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopping = stop.clone();
+        let thread = std::thread::spawn(move || {
+            while !stopping.load(Ordering::SeqCst) {
+                for event in self.poll()? {
+                    if sender.send(event).is_err() {
+                        return Ok(());
+                    }
+                }
+                std::thread::sleep(self.interval);
+            }
+            Ok(())
+        });
+
+        WatchHandle { stop, thread }
+    }
+}
+
+/// Controls a watcher running on its own thread.
+#[derive(Debug)]
+pub struct WatchHandle {
+    stop: Arc<AtomicBool>,
+    thread: std::thread::JoinHandle<Result<(), std::io::Error>>,
+}
+
+impl WatchHandle {
+    /// Ask the watcher to stop after its current poll.
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+
+    /// Stop the watcher and wait for it, returning the error that ended it, if any.
+    pub fn join(self) -> Result<(), std::io::Error> {
+        self.stop();
+        self.thread
+            .join()
+            .unwrap_or_else(|_| Err(std::io::Error::other("watcher thread panicked")))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::FirewallAction;
+    use std::collections::VecDeque;
+
+    /// Hands out a fixed sequence of snapshots, repeating the last one.
+    struct Scripted(VecDeque<Snapshot>);
+
+    impl SnapshotSource for Scripted {
+        fn snapshot(&mut self) -> Result<Snapshot, std::io::Error> {
+            if self.0.len() > 1 {
+                Ok(self.0.pop_front().unwrap())
+            } else {
+                self.0.front().cloned().ok_or_else(|| {
+                    std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "out of snapshots")
+                })
+            }
+        }
+    }
+
+    fn rule(name: &str, ports: &str) -> FirewallRuleData {
+        FirewallRuleData {
+            local_ports: Some(ports.into()),
+            ..FirewallRuleData::new(name)
+        }
+    }
+
+    fn snapshot(rules: Vec<FirewallRuleData>) -> Snapshot {
+        Snapshot {
+            rules,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn diff_events() {
+        let mut old = snapshot(vec![rule("a", "1"), rule("b", "2"), rule("dup", "3")]);
+        old.profiles
+            .insert(FirewallProfile::DOMAIN, FirewallProfileSettings::default());
+        let mut new = snapshot(vec![
+            rule("b", "20"),
+            rule("c", "4"),
+            rule("dup", "3"),
+            rule("dup", "5"),
+        ]);
+        new.profiles.insert(
+            FirewallProfile::DOMAIN,
+            FirewallProfileSettings {
+                default_inbound_action: FirewallAction::Allow,
+                ..Default::default()
+            },
+        );
+
+        let events = diff_snapshots(&old, &new);
+        assert_eq!(events.len(), 5);
+        assert!(events.contains(&Event::RuleRemoved(rule("a", "1"))));
+        assert!(events.contains(&Event::RuleAdded(rule("c", "4"))));
+        assert!(events.contains(&Event::RuleAdded(rule("dup", "5"))));
+        assert!(events.contains(&Event::RuleModified {
+            old: Box::new(rule("b", "2")),
+            new: Box::new(rule("b", "20")),
+            changes: vec![FieldChange {
+                field: "local_ports",
+                old: Some("2".into()),
+                new: Some("20".into()),
+            }],
+        }));
+        assert_eq!(
+            events.last().unwrap().to_string(),
+            "profile Domain changed; default_inbound_action: Block -> Allow"
+        );
+    }
+
+    #[test]
+    fn first_poll_is_baseline() {
+        let mut watcher = Watcher::new(Scripted(vec![snapshot(vec![rule("a", "1")])].into()));
+        assert!(watcher.poll().unwrap().is_empty());
+        assert!(watcher.poll().unwrap().is_empty());
+        assert_eq!(watcher.baseline().unwrap().rules.len(), 1);
+    }
+
+    #[test]
+    fn debounce() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut watcher = Watcher::new(Scripted(
+            vec![
+                snapshot(vec![]),
+                snapshot(vec![rule("a", "1")]),
+                snapshot(vec![rule("a", "2")]),
+                snapshot(vec![rule("a", "2")]),
+                snapshot(vec![rule("a", "2")]),
+            ]
+            .into(),
+        ))
+        .debounce(Duration::from_secs(10));
+
+        assert!(watcher.poll_at(at(0)).unwrap().is_empty());
+        // Still changing, nothing reported yet.
+        assert!(watcher.poll_at(at(5)).unwrap().is_empty());
+        assert!(watcher.poll_at(at(10)).unwrap().is_empty());
+        assert!(watcher.poll_at(at(15)).unwrap().is_empty());
+        // Stable for 10 seconds, reported as a single add.
+        assert_eq!(
+            watcher.poll_at(at(20)).unwrap(),
+            vec![Event::RuleAdded(rule("a", "2"))]
+        );
+        assert!(watcher.poll_at(at(30)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn reverted_change_is_not_reported() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut watcher = Watcher::new(Scripted(
+            vec![
+                snapshot(vec![]),
+                snapshot(vec![rule("a", "1")]),
+                snapshot(vec![]),
+            ]
+            .into(),
+        ))
+        .debounce(Duration::from_secs(10));
+
+        for secs in [0, 5, 20, 40].iter() {
+            assert!(watcher.poll_at(at(*secs)).unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn spawn_sends_events() {
+        let source = Scripted(vec![snapshot(vec![]), snapshot(vec![rule("a", "1")])].into());
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let handle = Watcher::new(source)
+            .interval(Duration::from_millis(1))
+            .spawn(sender);
+
+        assert_eq!(receiver.recv().unwrap(), Event::RuleAdded(rule("a", "1")));
+        handle.join().unwrap();
+    }
+}
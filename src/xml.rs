@@ -0,0 +1,295 @@
+//! A small, non-validating XML reader for the documents the importers deal with.
+//!
+//! Documents are read whole into a tree. Namespaces are not resolved; `local_name` strips prefixes for callers
+//! that do not care about them. DTDs are skipped, so only the predefined and numeric entities are understood.
+
+/// How deeply elements may nest before a document is refused, so hostile input cannot exhaust the stack.
+const MAX_DEPTH: usize = 256;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Element {
+    pub name: String,
+    pub attributes: Vec<(String, String)>,
+    pub children: Vec<Node>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Node {
+    Element(Element),
+    Text(String),
+}
+
+impl Element {
+    /// The name without any namespace prefix.
+    pub fn local_name(&self) -> &str {
+        local_name(&self.name)
+    }
+
+    /// The value of an attribute, matched by local name.
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(key, _)| local_name(key) == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn elements(&self) -> impl Iterator<Item = &Element> {
+        self.children.iter().filter_map(|child| match child {
+            Node::Element(element) => Some(element),
+            Node::Text(_) => None,
+        })
+    }
+
+    /// The first child element with a local name.
+    pub fn child(&self, name: &str) -> Option<&Element> {
+        self.elements().find(|element| element.local_name() == name)
+    }
+
+    /// The text directly inside the element.
+    pub fn text(&self) -> String {
+        self.children
+            .iter()
+            .filter_map(|child| match child {
+                Node::Text(text) => Some(text.as_str()),
+                Node::Element(_) => None,
+            })
+            .collect()
+    }
+}
+
+fn local_name(name: &str) -> &str {
+    name.rsplit(':').next().unwrap_or(name)
+}
+
+/// Parse a document and return its root element.
+pub(crate) fn parse(text: &str) -> Result<Element, std::io::Error> {
+    let mut reader = Reader {
+        text: text.trim_start_matches('\u{feff}'),
+        position: 0,
+    };
+
+    reader.skip_misc()?;
+    if !reader.rest().starts_with('<') {
+        return Err(reader.error("expected the root element"));
+    }
+    let root = reader.element(0)?;
+    reader.skip_misc()?;
+    if !reader.rest().is_empty() {
+        return Err(reader.error("unexpected content after the root element"));
+    }
+    Ok(root)
+}
+
+struct Reader<'a> {
+    text: &'a str,
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn rest(&self) -> &'a str {
+        &self.text[self.position..]
+    }
+
+    fn error(&self, message: &str) -> std::io::Error {
+        let line = self.text[..self.position].matches('\n').count() + 1;
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("invalid XML at line {}: {}", line, message),
+        )
+    }
+
+    /// Skip past `end`, failing if it never comes.
+    fn skip_past(&mut self, end: &str) -> Result<&'a str, std::io::Error> {
+        let rest = self.rest();
+        let index = rest
+            .find(end)
+            .ok_or_else(|| self.error(&format!("missing '{}'", end)))?;
+        self.position += index + end.len();
+        Ok(&rest[..index])
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.position += rest.len() - rest.trim_start().len();
+    }
+
+    /// Skip whitespace, the XML declaration, processing instructions, comments and doctypes.
+    fn skip_misc(&mut self) -> Result<(), std::io::Error> {
+        loop {
+            self.skip_whitespace();
+            let rest = self.rest();
+            if rest.starts_with("<?") {
+                self.skip_past("?>")?;
+            } else if rest.starts_with("<!--") {
+                self.skip_past("-->")?;
+            } else if rest.starts_with("<!DOCTYPE") {
+                self.skip_past(">")?;
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    fn name(&mut self) -> Result<&'a str, std::io::Error> {
+        let rest = self.rest();
+        let len = rest
+            .find(|c: char| c.is_whitespace() || "/>=".contains(c))
+            .unwrap_or(rest.len());
+        if len == 0 {
+            return Err(self.error("expected a name"));
+        }
+        self.position += len;
+        Ok(&rest[..len])
+    }
+
+    /// Read an element, starting at its `<`.
+    fn element(&mut self, depth: usize) -> Result<Element, std::io::Error> {
+        if depth > MAX_DEPTH {
+            return Err(self.error("elements are nested too deeply"));
+        }
+
+        self.position += 1;
+        let name = self.name()?.to_string();
+        let mut attributes = Vec::new();
+        loop {
+            self.skip_whitespace();
+            let rest = self.rest();
+            if rest.starts_with("/>") {
+                self.position += 2;
+                return Ok(Element {
+                    name,
+                    attributes,
+                    children: Vec::new(),
+                });
+            }
+            if rest.starts_with('>') {
+                self.position += 1;
+                break;
+            }
+
+            let key = self.name()?.to_string();
+            self.skip_whitespace();
+            if !self.rest().starts_with('=') {
+                return Err(self.error("expected '=' after an attribute name"));
+            }
+            self.position += 1;
+            self.skip_whitespace();
+            let quote = match self.rest().chars().next() {
+                Some(quote) if quote == '"' || quote == '\'' => quote,
+                _ => return Err(self.error("expected a quoted attribute value")),
+            };
+            self.position += 1;
+            let value = self.skip_past(if quote == '"' { "\"" } else { "'" })?;
+            attributes.push((key, self.unescape(value)?));
+        }
+
+        let mut children = Vec::new();
+        loop {
+            let rest = self.rest();
+            if rest.is_empty() {
+                return Err(self.error(&format!("unclosed element '{}'", name)));
+            } else if rest.starts_with("</") {
+                self.position += 2;
+                let end = self.name()?;
+                if end != name {
+                    return Err(self.error(&format!("expected '</{}>', found '</{}>'", name, end)));
+                }
+                self.skip_whitespace();
+                self.skip_past(">")?;
+                return Ok(Element {
+                    name,
+                    attributes,
+                    children,
+                });
+            } else if rest.starts_with("<!--") {
+                self.skip_past("-->")?;
+            } else if rest.starts_with("<![CDATA[") {
+                self.position += "<![CDATA[".len();
+                let text = self.skip_past("]]>")?;
+                children.push(Node::Text(text.to_string()));
+            } else if rest.starts_with("<?") {
+                self.skip_past("?>")?;
+            } else if rest.starts_with('<') {
+                children.push(Node::Element(self.element(depth + 1)?));
+            } else {
+                let len = rest.find('<').unwrap_or(rest.len());
+                self.position += len;
+                children.push(Node::Text(self.unescape(&rest[..len])?));
+            }
+        }
+    }
+
+    fn unescape(&self, text: &str) -> Result<String, std::io::Error> {
+        let mut unescaped = String::with_capacity(text.len());
+        let mut rest = text;
+        while let Some(index) = rest.find('&') {
+            unescaped.push_str(&rest[..index]);
+            rest = &rest[index + 1..];
+            let end = rest
+                .find(';')
+                .ok_or_else(|| self.error("unterminated entity"))?;
+            let entity = &rest[..end];
+            let c = match entity {
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "amp" => Some('&'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                _ if entity.starts_with("#x") => u32::from_str_radix(&entity[2..], 16)
+                    .ok()
+                    .and_then(std::char::from_u32),
+                _ if entity.starts_with('#') => {
+                    entity[1..].parse().ok().and_then(std::char::from_u32)
+                }
+                _ => None,
+            };
+            unescaped.push(c.ok_or_else(|| self.error(&format!("unknown entity '&{};'", entity)))?);
+            rest = &rest[end + 1..];
+        }
+        unescaped.push_str(rest);
+        Ok(unescaped)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_documents() {
+        let root = parse(
+            "\u{feff}<?xml version=\"1.0\"?>\n<!-- c -->\n<a:Root xmlns:a=\"urn:x\" k='v &amp; w'>\
+             <Child N=\"x\">1 &lt; 2 &#x41;&#66;</Child><Empty/><![CDATA[<raw>]]></a:Root>\n",
+        )
+        .unwrap();
+
+        assert_eq!(root.local_name(), "Root");
+        assert_eq!(root.attribute("k"), Some("v & w"));
+        assert_eq!(root.child("Child").unwrap().text(), "1 < 2 AB");
+        assert_eq!(root.child("Child").unwrap().attribute("N"), Some("x"));
+        assert_eq!(root.elements().count(), 2);
+        assert_eq!(root.text(), "<raw>");
+    }
+
+    #[test]
+    fn rejects_malformed_documents() {
+        let deep = "<a>".repeat(MAX_DEPTH + 2);
+        for text in &[
+            "",
+            "text",
+            "<a>",
+            "<a></b>",
+            "<a x=1/>",
+            "<a>&bogus;</a>",
+            "<a/><b/>",
+            deep.as_str(),
+        ] {
+            assert_eq!(
+                parse(text).unwrap_err().kind(),
+                std::io::ErrorKind::InvalidData,
+                "{}",
+                text
+            );
+        }
+    }
+}